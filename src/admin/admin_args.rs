@@ -0,0 +1,9 @@
+use lib::{admin_messages::AdminRequest, connection_protocol::Transport};
+
+/// Los argumentos que acepta la consola de administracion.
+/// Las direcciones de administracion de los servidores a consultar, el pedido y el transporte de la conexion
+pub struct AdminArgs {
+    pub server_addresses: Vec<String>,
+    pub request: AdminRequest,
+    pub transport: Transport,
+}
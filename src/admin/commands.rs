@@ -0,0 +1,171 @@
+use std::fmt::Write;
+
+use lib::admin_messages::{AccountSummary, AdminRequest, AdminResponse, ServerStatus};
+
+use crate::errors::AdminError;
+
+/// Devuelve el pedido de un comando de la consola: `status`, `accounts list`, `accounts get ID`, `queue` o `shutdown`
+pub fn parse_command(words: &[&str]) -> Result<AdminRequest, AdminError> {
+    match words {
+        ["status"] => Ok(AdminRequest::Status),
+        ["accounts", "list"] => Ok(AdminRequest::ListAccounts),
+        ["accounts", "get", id] => id
+            .parse()
+            .map(AdminRequest::GetAccount)
+            .map_err(|_| AdminError::CommandFormat(words.join(" "))),
+        ["queue"] => Ok(AdminRequest::Queue),
+        ["shutdown"] => Ok(AdminRequest::Shutdown),
+        _ => Err(AdminError::CommandFormat(words.join(" "))),
+    }
+}
+
+/// Devuelve la respuesta de un servidor como texto para mostrarla en la consola
+pub fn format_response(address: &str, response: &AdminResponse) -> String {
+    let mut text = format!("Server at {}\n", address);
+    match response {
+        AdminResponse::Status(status) => format_status(&mut text, status),
+        AdminResponse::Accounts(accounts) if accounts.is_empty() => {
+            text.push_str("  No accounts\n");
+        }
+        AdminResponse::Accounts(accounts) => {
+            for account in accounts {
+                format_account(&mut text, account);
+            }
+        }
+        AdminResponse::Account(Some(account)) => format_account(&mut text, account),
+        AdminResponse::Account(None) => text.push_str("  Account not found\n"),
+        AdminResponse::Queue(orders) if orders.is_empty() => {
+            text.push_str("  No pending orders\n");
+        }
+        AdminResponse::Queue(orders) => {
+            for order in orders {
+                let _ = writeln!(
+                    text,
+                    "  coffee maker {}: {:?} of {} points on account {} (request {}-{})",
+                    order.coffee_maker_id,
+                    order.request.message_type,
+                    order.request.points,
+                    order.request.account_id,
+                    order.request.request_id.dispenser_id,
+                    order.request.request_id.sequence
+                );
+            }
+        }
+        AdminResponse::ShuttingDown => {
+            text.push_str("  Leaving the ring, it stops after passing the token\n");
+        }
+    }
+    text
+}
+
+fn format_status(text: &mut String, status: &ServerStatus) {
+    let neighbour = |id: Option<usize>, online: bool| {
+        let state = if online { "online" } else { "offline" };
+        match id {
+            Some(id) => format!("{} ({})", id, state),
+            None => format!("unknown ({})", state),
+        }
+    };
+    let _ = writeln!(text, "  id: {}", status.id);
+    let _ = writeln!(
+        text,
+        "  previous: {}",
+        neighbour(status.listening_to_id, status.prev_online)
+    );
+    let _ = writeln!(
+        text,
+        "  next: {}",
+        neighbour(status.next_id, status.next_online)
+    );
+    // El servidor solo sabe si tiene el token, no quien lo tiene
+    let token = if status.have_token {
+        "held by this server"
+    } else {
+        "not held by this server"
+    };
+    let _ = writeln!(
+        text,
+        "  token: {}, generation {}",
+        token, status.token_generation
+    );
+    let leader = status
+        .leader
+        .map_or(String::from("unknown"), |leader| leader.to_string());
+    let _ = writeln!(text, "  leader: {}", leader);
+    let members: Vec<String> = status.members.iter().map(usize::to_string).collect();
+    let _ = writeln!(text, "  members: {}", members.join(", "));
+}
+
+fn format_account(text: &mut String, account: &AccountSummary) {
+    let _ = writeln!(
+        text,
+        "  account {}: {} points, last updated on {}.{} by server {}",
+        account.id,
+        account.points,
+        account.last_updated_on.physical,
+        account.last_updated_on.logical,
+        account.last_updated_on.node_id
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use lib::hybrid_timestamp::HybridTimestamp;
+
+    use super::*;
+
+    #[test]
+    fn should_parse_the_commands_of_the_console() {
+        assert_eq!(Ok(AdminRequest::Status), parse_command(&["status"]));
+        assert_eq!(
+            Ok(AdminRequest::ListAccounts),
+            parse_command(&["accounts", "list"])
+        );
+        assert_eq!(
+            Ok(AdminRequest::GetAccount(7)),
+            parse_command(&["accounts", "get", "7"])
+        );
+        assert_eq!(Ok(AdminRequest::Queue), parse_command(&["queue"]));
+        assert_eq!(Ok(AdminRequest::Shutdown), parse_command(&["shutdown"]));
+        for words in [
+            &["accounts", "get", "x"][..],
+            &["accounts"],
+            &["explode"],
+            &[],
+        ] {
+            assert_eq!(
+                Err(AdminError::CommandFormat(words.join(" "))),
+                parse_command(words)
+            );
+        }
+    }
+
+    #[test]
+    fn should_format_the_status_and_the_accounts_of_a_server() {
+        let status = AdminResponse::Status(ServerStatus {
+            id: 1,
+            prev_online: true,
+            next_online: false,
+            listening_to_id: Some(0),
+            next_id: None,
+            have_token: true,
+            token_generation: 2,
+            leader: Some(3),
+            members: vec![0, 1, 3],
+        });
+        assert_eq!(
+            "Server at 127.0.0.1:30001\n  id: 1\n  previous: 0 (online)\n  next: unknown (offline)\n  token: held by this server, generation 2\n  leader: 3\n  members: 0, 1, 3\n",
+            format_response("127.0.0.1:30001", &status)
+        );
+
+        let accounts = AdminResponse::Accounts(vec![AccountSummary {
+            id: 4,
+            points: 15,
+            last_updated_on: HybridTimestamp::new(100, 1, 2),
+        }]);
+        assert_eq!(
+            "Server at 127.0.0.1:30001\n  account 4: 15 points, last updated on 100.1 by server 2\n",
+            format_response("127.0.0.1:30001", &accounts)
+        );
+    }
+}
@@ -0,0 +1,11 @@
+#[derive(Debug, PartialEq, Eq)]
+pub enum AdminError {
+    /// Faltan argumentos al iniciar la aplicacion
+    ArgsMissing,
+
+    /// Alguno de los argumentos tiene un formato invalido
+    ArgsFormat,
+
+    /// El comando no existe o le faltan argumentos. Incluye el comando
+    CommandFormat(String),
+}
@@ -0,0 +1,93 @@
+/// Modulo que representa los parametros que puede recibir la consola para su ejecucion
+pub mod admin_args;
+/// Modulo que convierte los comandos en pedidos y las respuestas en texto
+pub mod commands;
+/// Modulo de errores que utiliza unicamente la consola
+pub mod errors;
+
+use std::env;
+
+use admin_args::AdminArgs;
+use async_std::task;
+use commands::{format_response, parse_command};
+use errors::AdminError;
+use lib::{
+    admin_messages::AdminResponse,
+    common_errors::CoffeeSystemError,
+    connection_protocol::{recv_message, send_message, Transport},
+    handshake::{Hello, NodeId},
+    logger::set_logger_config_with_default,
+};
+use log::error;
+
+fn get_args() -> Result<AdminArgs, AdminError> {
+    let args: Vec<String> = env::args().collect();
+    let servers = args.get(1).ok_or(AdminError::ArgsMissing)?;
+    let server_addresses: Vec<String> = servers
+        .split(',')
+        .map(|address| address.trim().to_string())
+        .filter(|address| !address.is_empty())
+        .collect();
+    if server_addresses.is_empty() {
+        return Err(AdminError::ArgsFormat);
+    }
+
+    let command_end = args
+        .iter()
+        .skip(2)
+        .position(|arg| arg.starts_with("--"))
+        .map_or(args.len(), |position| position + 2);
+    let command: Vec<&str> = args[2..command_end].iter().map(String::as_str).collect();
+    if command.is_empty() {
+        return Err(AdminError::ArgsMissing);
+    }
+    let request = parse_command(&command)?;
+
+    let mut transport = Transport::default();
+    let mut options = args[command_end..].iter();
+    while let Some(option) = options.next() {
+        let value = options.next().ok_or(AdminError::ArgsMissing)?;
+        match option.as_str() {
+            "--transport" => {
+                transport = Transport::from_name(value).ok_or(AdminError::ArgsFormat)?
+            }
+            _ => return Err(AdminError::ArgsFormat),
+        }
+    }
+    Ok(AdminArgs {
+        server_addresses,
+        request,
+        transport,
+    })
+}
+
+/// Envia el pedido al servidor y espera su respuesta
+fn ask(
+    args: &AdminArgs,
+    address: &String,
+    hello: &Hello,
+) -> Result<AdminResponse, CoffeeSystemError> {
+    let mut connection = args.transport.connect(address, hello)?;
+    task::block_on(send_message(connection.as_mut(), &args.request))?;
+    task::block_on(recv_message(connection.as_mut()))
+}
+
+fn main() {
+    // La salida es la respuesta de los servidores, por defecto solo se muestran los errores
+    set_logger_config_with_default(log::Level::Warn);
+    let args = match get_args() {
+        Ok(args) => args,
+        Err(e) => {
+            error!("Error setting args {:?}. Use [IP:PORT,IP:PORT...] [status | accounts list | accounts get ID | queue | shutdown] [--transport tcp|udp - OPTIONAL]", e);
+            return;
+        }
+    };
+
+    let hello = Hello::new(NodeId::Admin(rand::random()));
+    for address in &args.server_addresses {
+        match ask(&args, address, &hello) {
+            Ok(response) => print!("{}", format_response(address, &response)),
+            Err(e) => error!("Error asking the server at {}, {:?}", address, e),
+        }
+    }
+}
@@ -1,6 +1,356 @@
+use std::fs;
+
+use lib::connection_protocol::Transport;
+use log::error;
+use serde::Deserialize;
+
+use crate::{
+    constants::{
+        DEFAULT_JOURNAL_DIR, DEFAULT_ORDERS_FILE, DEFAULT_REPORT_FILE, DISPENSERS,
+        PROCESS_ORDER_TIME_IN_MS, SERVERS_UNREACHABLE_TIMEOUT_IN_MS, SUCCESS_CHANCE,
+    },
+    errors::CoffeeMakerError,
+};
+
+/// Uso de la cafetera, se muestra si los argumentos son invalidos
+pub const USAGE: &str = "Use [IP:PORT,IP:PORT... - OPTIONAL WITH --config] [FILE - OPTIONAL] [--config FILE] [--servers IP:PORT,IP:PORT...] [--orders FILE] [--dispensers N] [--brew-time MS|MIN_MS-MAX_MS] [--success-chance 0-100] [--seed SEED] [--unreachable-timeout MS] [--journal-dir DIR] [--transport tcp|udp] [--report FILE]";
+
+/// Tiempo que tarda en prepararse un cafe, se elige al azar de manera uniforme entre el minimo y el maximo en ms
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BrewTime {
+    pub min_ms: u64,
+    pub max_ms: u64,
+}
+
+impl BrewTime {
+    /// Devuelve el tiempo a partir de `MS` para un tiempo fijo o `MIN_MS-MAX_MS` para un rango
+    pub fn from_text(text: &str) -> Result<BrewTime, CoffeeMakerError> {
+        let parse = |ms: &str| {
+            ms.trim()
+                .parse::<u64>()
+                .map_err(|_| CoffeeMakerError::ArgsFormat)
+        };
+        match text.split_once('-') {
+            Some((min, max)) => Ok(BrewTime {
+                min_ms: parse(min)?,
+                max_ms: parse(max)?,
+            }),
+            None => {
+                let ms = parse(text)?;
+                Ok(BrewTime {
+                    min_ms: ms,
+                    max_ms: ms,
+                })
+            }
+        }
+    }
+}
+
 /// Los argumentos que acepta la cafetera.
-/// La direccion a un archivo y la direccion del servidor local
+/// La direccion a un archivo, las direcciones de los servidores locales (se usan en orden si alguno se cae)
+/// el tiempo maximo sin poder conectarse a ninguno, el directorio donde se guardan los pedidos pendientes,
+/// el transporte de la conexion, el archivo donde se escribe el reporte de la ejecucion, la cantidad de dispensers,
+/// el tiempo que tarda un cafe, la probabilidad de exito de un cafe y la semilla de los numeros al azar
+#[derive(Debug, PartialEq)]
 pub struct CoffeeArgs {
     pub orders_file_path: String,
-    pub server_ip_and_port: String,
+    pub server_addresses: Vec<String>,
+    pub unreachable_timeout_in_ms: u64,
+    pub journal_dir: String,
+    pub transport: Transport,
+    pub report_file: String,
+    pub dispensers: usize,
+    pub brew_time: BrewTime,
+    pub success_chance: i32,
+    /// Si no se indica se elige una al azar al iniciar
+    pub seed: Option<u64>,
+}
+
+/// Valores que se pueden indicar en el archivo de configuracion (`--config`), en JSON. Todos son opcionales,
+/// los que falten toman el valor por defecto y los argumentos de la linea de comandos tienen prioridad
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CoffeeConfig {
+    pub servers: Option<Vec<String>>,
+    pub orders_file: Option<String>,
+    pub dispensers: Option<usize>,
+    pub brew_time: Option<BrewTime>,
+    pub success_chance: Option<i32>,
+    pub seed: Option<u64>,
+    pub unreachable_timeout_ms: Option<u64>,
+    pub journal_dir: Option<String>,
+    pub transport: Option<String>,
+    pub report_file: Option<String>,
+}
+
+impl CoffeeConfig {
+    pub fn from_file(path: &str) -> Result<CoffeeConfig, CoffeeMakerError> {
+        let content = fs::read_to_string(path).map_err(|e| {
+            error!("[COFFEE MAKER] Error reading config file {}, {}", path, e);
+            CoffeeMakerError::ConfigError
+        })?;
+        serde_json::from_str(&content).map_err(|e| {
+            error!("[COFFEE MAKER] Malformed config file {}, {}", path, e);
+            CoffeeMakerError::ConfigError
+        })
+    }
+
+    /// Devuelve la configuracion con los valores de `overrides` reemplazando a los propios
+    fn merge(self, overrides: CoffeeConfig) -> CoffeeConfig {
+        CoffeeConfig {
+            servers: overrides.servers.or(self.servers),
+            orders_file: overrides.orders_file.or(self.orders_file),
+            dispensers: overrides.dispensers.or(self.dispensers),
+            brew_time: overrides.brew_time.or(self.brew_time),
+            success_chance: overrides.success_chance.or(self.success_chance),
+            seed: overrides.seed.or(self.seed),
+            unreachable_timeout_ms: overrides
+                .unreachable_timeout_ms
+                .or(self.unreachable_timeout_ms),
+            journal_dir: overrides.journal_dir.or(self.journal_dir),
+            transport: overrides.transport.or(self.transport),
+            report_file: overrides.report_file.or(self.report_file),
+        }
+    }
+}
+
+impl CoffeeArgs {
+    /// Interpreta los argumentos de la linea de comandos, sin el nombre del binario. Se mantiene la forma
+    /// posicional `[IP:PORT,IP:PORT...] [FILE]`, y las opciones aceptan `--opcion valor` o `--opcion=valor`.
+    /// Si se indica `--config` los valores del archivo se usan para lo que no venga en la linea de comandos
+    pub fn parse(args: &[String]) -> Result<CoffeeArgs, CoffeeMakerError> {
+        let mut cli = CoffeeConfig::default();
+        let mut config_file = None;
+        let mut args = args.iter().peekable();
+
+        if let Some(servers) = args.next_if(|arg| !arg.starts_with("--")) {
+            cli.servers = Some(parse_servers(servers));
+        }
+        if let Some(file) = args.next_if(|arg| !arg.starts_with("--")) {
+            cli.orders_file = Some(file.clone());
+        }
+
+        while let Some(arg) = args.next() {
+            let (option, value) = match arg.split_once('=') {
+                Some((option, value)) => (option, value.to_string()),
+                None => (
+                    arg.as_str(),
+                    args.next().ok_or(CoffeeMakerError::ArgsMissing)?.clone(),
+                ),
+            };
+            match option {
+                "--config" => config_file = Some(value),
+                "--servers" => cli.servers = Some(parse_servers(&value)),
+                "--orders" => cli.orders_file = Some(value),
+                "--dispensers" => cli.dispensers = Some(parse_number(&value)?),
+                "--brew-time" => cli.brew_time = Some(BrewTime::from_text(&value)?),
+                "--success-chance" => cli.success_chance = Some(parse_number(&value)?),
+                "--seed" => cli.seed = Some(parse_number(&value)?),
+                "--unreachable-timeout" => cli.unreachable_timeout_ms = Some(parse_number(&value)?),
+                "--journal-dir" => cli.journal_dir = Some(value),
+                "--transport" => cli.transport = Some(value),
+                "--report" => cli.report_file = Some(value),
+                _ => return Err(CoffeeMakerError::ArgsFormat),
+            }
+        }
+
+        let config = match config_file {
+            Some(path) => CoffeeConfig::from_file(&path)?.merge(cli),
+            None => cli,
+        };
+        CoffeeArgs::from_config(config)
+    }
+
+    /// Completa la configuracion con los valores por defecto y valida que los valores sean correctos
+    pub fn from_config(config: CoffeeConfig) -> Result<CoffeeArgs, CoffeeMakerError> {
+        let server_addresses = config.servers.ok_or(CoffeeMakerError::ArgsMissing)?;
+        if server_addresses.is_empty() {
+            return Err(CoffeeMakerError::ArgsFormat);
+        }
+        let transport = match config.transport {
+            Some(name) => Transport::from_name(&name).ok_or(CoffeeMakerError::ArgsFormat)?,
+            None => Transport::default(),
+        };
+        let dispensers = config.dispensers.unwrap_or(DISPENSERS);
+        let brew_time = config.brew_time.unwrap_or(BrewTime {
+            min_ms: PROCESS_ORDER_TIME_IN_MS,
+            max_ms: PROCESS_ORDER_TIME_IN_MS,
+        });
+        let success_chance = config.success_chance.unwrap_or(SUCCESS_CHANCE);
+        if dispensers == 0
+            || brew_time.min_ms > brew_time.max_ms
+            || !(0..=100).contains(&success_chance)
+        {
+            return Err(CoffeeMakerError::ArgsFormat);
+        }
+        Ok(CoffeeArgs {
+            orders_file_path: config
+                .orders_file
+                .unwrap_or_else(|| String::from(DEFAULT_ORDERS_FILE)),
+            server_addresses,
+            unreachable_timeout_in_ms: config
+                .unreachable_timeout_ms
+                .unwrap_or(SERVERS_UNREACHABLE_TIMEOUT_IN_MS),
+            journal_dir: config
+                .journal_dir
+                .unwrap_or_else(|| String::from(DEFAULT_JOURNAL_DIR)),
+            transport,
+            report_file: config
+                .report_file
+                .unwrap_or_else(|| String::from(DEFAULT_REPORT_FILE)),
+            dispensers,
+            brew_time,
+            success_chance,
+            seed: config.seed,
+        })
+    }
+}
+
+/// Separa la lista de servidores, descartando los vacios
+fn parse_servers(servers: &str) -> Vec<String> {
+    servers
+        .split(',')
+        .map(|address| address.trim().to_string())
+        .filter(|address| !address.is_empty())
+        .collect()
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> Result<T, CoffeeMakerError> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| CoffeeMakerError::ArgsFormat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &str) -> Vec<String> {
+        args.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn should_keep_the_positional_servers_and_file_with_the_default_values() {
+        let parsed = CoffeeArgs::parse(&args("127.0.0.1:20000,127.0.0.1:20001 orders.csv"))
+            .expect("Invalid args");
+        assert_eq!(
+            CoffeeArgs {
+                orders_file_path: String::from("orders.csv"),
+                server_addresses: vec![
+                    String::from("127.0.0.1:20000"),
+                    String::from("127.0.0.1:20001")
+                ],
+                unreachable_timeout_in_ms: SERVERS_UNREACHABLE_TIMEOUT_IN_MS,
+                journal_dir: String::from(DEFAULT_JOURNAL_DIR),
+                transport: Transport::Tcp,
+                report_file: String::from(DEFAULT_REPORT_FILE),
+                dispensers: DISPENSERS,
+                brew_time: BrewTime {
+                    min_ms: PROCESS_ORDER_TIME_IN_MS,
+                    max_ms: PROCESS_ORDER_TIME_IN_MS,
+                },
+                success_chance: SUCCESS_CHANCE,
+                seed: None,
+            },
+            parsed
+        );
+    }
+
+    #[test]
+    fn should_parse_the_tunables_from_the_options() {
+        let parsed = CoffeeArgs::parse(&args(
+            "--servers 127.0.0.1:20000 --dispensers 3 --brew-time=100-500 --success-chance 100 --seed 42 --transport udp",
+        ))
+        .expect("Invalid args");
+        assert_eq!(
+            vec![String::from("127.0.0.1:20000")],
+            parsed.server_addresses
+        );
+        assert_eq!(3, parsed.dispensers);
+        assert_eq!(
+            BrewTime {
+                min_ms: 100,
+                max_ms: 500
+            },
+            parsed.brew_time
+        );
+        assert_eq!(100, parsed.success_chance);
+        assert_eq!(Some(42), parsed.seed);
+        assert!(matches!(parsed.transport, Transport::Udp(_)));
+    }
+
+    #[test]
+    fn should_reject_invalid_tunables() {
+        let invalid = [
+            "127.0.0.1:20000 --dispensers 0",
+            "127.0.0.1:20000 --brew-time 500-100",
+            "127.0.0.1:20000 --success-chance 101",
+            "127.0.0.1:20000 --seed",
+            "127.0.0.1:20000 --unknown 1",
+            "--dispensers 2",
+        ];
+        for invalid in invalid {
+            assert!(CoffeeArgs::parse(&args(invalid)).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn should_use_the_config_file_for_what_is_missing_in_the_command_line() {
+        let path =
+            std::env::temp_dir().join(format!("coffee_maker_config_{}.json", std::process::id()));
+        fs::write(
+            &path,
+            r#"{"servers": ["127.0.0.1:20000"], "dispensers": 2, "brew_time": {"min_ms": 10, "max_ms": 20}, "seed": 7}"#,
+        )
+        .expect("Error writing config");
+        let config = path.to_string_lossy().to_string();
+
+        let parsed = CoffeeArgs::parse(&args(&format!("--config {} --seed 8", config)));
+        let with_servers =
+            CoffeeArgs::parse(&args(&format!("127.0.0.1:20001 --config {}", config)));
+        let _ = fs::remove_file(&path);
+
+        let parsed = parsed.expect("Invalid args");
+        assert_eq!(
+            vec![String::from("127.0.0.1:20000")],
+            parsed.server_addresses
+        );
+        assert_eq!(2, parsed.dispensers);
+        assert_eq!(
+            BrewTime {
+                min_ms: 10,
+                max_ms: 20
+            },
+            parsed.brew_time
+        );
+        assert_eq!(Some(8), parsed.seed);
+        assert_eq!(
+            vec![String::from("127.0.0.1:20001")],
+            with_servers.expect("Invalid args").server_addresses
+        );
+    }
+
+    #[test]
+    fn should_reject_unknown_fields_in_the_config_file() {
+        let path = std::env::temp_dir().join(format!(
+            "coffee_maker_config_unknown_{}.json",
+            std::process::id()
+        ));
+        fs::write(&path, r#"{"servers": ["127.0.0.1:20000"], "dispenser": 2}"#)
+            .expect("Error writing config");
+        let result = CoffeeConfig::from_file(&path.to_string_lossy());
+        let _ = fs::remove_file(&path);
+        assert!(matches!(result, Err(CoffeeMakerError::ConfigError)));
+    }
+
+    #[test]
+    fn should_load_the_example_config_file() {
+        let parsed =
+            CoffeeArgs::parse(&args("--config tests/coffee_maker.json")).expect("Invalid args");
+        assert_eq!(2, parsed.server_addresses.len());
+        assert_eq!(4, parsed.dispensers);
+        assert_eq!(Some(1234), parsed.seed);
+    }
 }
@@ -1,5 +1,4 @@
 use std::sync::Arc;
-use std::time::Duration;
 
 use actix::{
     Actor, ActorContext, ActorFutureExt, Addr, Context, Handler, Message, ResponseActFuture,
@@ -7,14 +6,15 @@ use actix::{
 };
 use actix_rt::System;
 use async_std::sync::Mutex;
-use log::{debug, error};
+use log::{debug, error, info, warn};
 
 use crate::actor_messages::{OpenedFile, ProcessOrder, ReadAnOrder};
-use crate::constants::PROCESS_ORDER_TIME_IN_MS;
-use crate::local_server_client::{LocalServer, LocalServerClient};
+use crate::local_server_client::{LocalServer, LocalServerClient, ServerConnection};
+use crate::offline_journal::{JournaledOrder, OfflineJournal};
 use crate::order::{ConsumptionType, Order};
 use crate::orders_reader::OrdersReader;
 use crate::randomizer::Randomizer;
+use crate::run_metrics::{MeteredClient, RunMetrics};
 use lib::common_errors::CoffeeSystemError;
 
 use self::sync::sleep;
@@ -36,28 +36,40 @@ mod sync {
 }
 
 /// Representa a una cafetera que procesa los pedidos. Tiene la direccion del lector,
-/// la conexion con el servidor, un generador de exitos de pedidos y su id
+/// la conexion con el servidor, un generador de exitos de pedidos, el archivo de sumas pendientes y su id
 pub struct CoffeeMaker {
     reader_addr: Addr<OrdersReader>,
     server_conn: Arc<Mutex<Box<dyn LocalServerClient>>>,
     order_randomizer: Arc<Mutex<Box<dyn Randomizer>>>,
+    journal: Arc<Mutex<OfflineJournal>>,
     id: usize,
 }
 
 impl CoffeeMaker {
+    /// Crea la cafetera con su cliente sobre la conexion con los servidores, que comparte con las demas cafeteras.
+    /// El id de dispenser identifica a sus pedidos en toda la red, por lo que no se debe repetir entre distintas cafeteras.
+    /// Las llamadas al servidor se registran en las metricas de la ejecucion
     pub fn new(
         reader_addr: Addr<OrdersReader>,
-        server_addr: &String,
+        connection: Arc<ServerConnection>,
         order_randomizer: Box<dyn Randomizer>,
+        journal: OfflineJournal,
+        metrics: Arc<std::sync::Mutex<RunMetrics>>,
         id: usize,
-    ) -> Result<CoffeeMaker, CoffeeSystemError> {
-        let connection = LocalServer::new(server_addr)?;
-        Ok(CoffeeMaker {
+        dispenser_id: u64,
+    ) -> CoffeeMaker {
+        let client = MeteredClient::new(
+            Box::new(LocalServer::new(connection, dispenser_id)),
+            metrics,
+            id,
+        );
+        CoffeeMaker {
             reader_addr,
-            server_conn: Arc::new(Mutex::new(Box::new(connection))),
+            server_conn: Arc::new(Mutex::new(Box::new(client))),
             order_randomizer: Arc::new(Mutex::new(order_randomizer)),
+            journal: Arc::new(Mutex::new(journal)),
             id,
-        })
+        }
     }
 
     fn send_message<ToReaderMessage>(&self, msg: ToReaderMessage)
@@ -75,7 +87,7 @@ impl CoffeeMaker {
         }
     }
     /// Handler de resultados que retorna el servidor a traves de su cliente y detiene la cafetera
-    /// en caso de no poder conectarse a este
+    /// en caso de no poder conectarse a ningun servidor
     fn handle_server_result(
         &mut self,
         result: Result<(), CoffeeSystemError>,
@@ -84,7 +96,7 @@ impl CoffeeMaker {
         match result {
             Err(CoffeeSystemError::ConnectionLost) => {
                 error!(
-                    "[COFFEE MAKER {}] can't connect to server, stopping...",
+                    "[COFFEE MAKER {}] can't connect to any server, stopping...",
                     self.id
                 );
                 self.stop_system(ctx);
@@ -129,41 +141,124 @@ impl Handler<ProcessOrder> for CoffeeMaker {
         let order = msg.0;
         let randomizer = self.order_randomizer.clone();
         let server = self.server_conn.clone();
+        let journal = self.journal.clone();
+        let id = self.id;
         match order.consumption_type {
             ConsumptionType::Cash => Box::pin(
-                add_points(order, server, randomizer, self.id)
-                    .into_actor(self)
-                    .map(|result, me, ctx| {
-                        me.handle_server_result(result, ctx);
-                    }),
+                async move {
+                    flush_journal(server.clone(), journal.clone(), id).await?;
+                    add_points(order, server, randomizer, journal, id).await
+                }
+                .into_actor(self)
+                .map(|result, me, ctx| {
+                    me.handle_server_result(result, ctx);
+                }),
             ),
             ConsumptionType::Points => Box::pin(
-                consume_points(order, server, randomizer, self.id)
-                    .into_actor(self)
-                    .map(|result, me, ctx| {
-                        me.handle_server_result(result, ctx);
-                    }),
+                async move {
+                    flush_journal(server.clone(), journal, id).await?;
+                    consume_points(order, server, randomizer, id).await
+                }
+                .into_actor(self)
+                .map(|result, me, ctx| {
+                    me.handle_server_result(result, ctx);
+                }),
             ),
         }
     }
 }
-/// Metodo para comunicar al actor de cliente de servidor que debe sumar puntos a una cuenta del servidor
+/// Metodo para comunicar al actor de cliente de servidor que debe sumar puntos a una cuenta del servidor.
+/// Si no hay conexion con ningun servidor el pedido se guarda en el archivo de pendientes para enviarlo luego
 async fn add_points(
     order: Order,
     server: Arc<Mutex<Box<dyn LocalServerClient>>>,
     randomizer: Arc<Mutex<Box<dyn Randomizer>>>,
+    journal: Arc<Mutex<OfflineJournal>>,
     id: usize,
 ) -> Result<(), CoffeeSystemError> {
-    sleep(Duration::from_millis(PROCESS_ORDER_TIME_IN_MS)).await;
+    let brew_time = randomizer.lock().await.get_brew_time();
+    sleep(brew_time).await;
     let success = randomizer.lock().await.get_random_success();
     if !success {
         debug!("[COFFEE MAKER {}] Failed to process order of cash", id);
         return Ok(());
     }
     let server_conn = server.lock().await;
-    server_conn
-        .add_points(order.account_id, order.consumption)
-        .await
+    let request_id = server_conn.next_request_id();
+    let result = server_conn
+        .add_points(order.account_id, order.consumption, request_id)
+        .await;
+    match result {
+        Err(CoffeeSystemError::Offline) | Err(CoffeeSystemError::ConnectionLost) => {
+            let pending = JournaledOrder {
+                request_id,
+                account_id: order.account_id,
+                points: order.consumption,
+            };
+            if let Err(e) = journal.lock().await.append(pending) {
+                error!(
+                    "[COFFEE MAKER {}] Error saving order of account {} to the journal, {}",
+                    id, order.account_id, e
+                );
+                return result;
+            }
+            info!(
+                "[COFFEE MAKER {}] No server available, order of account {} saved to be sent later",
+                id, order.account_id
+            );
+            match result {
+                Err(CoffeeSystemError::Offline) => Ok(()),
+                _ => result,
+            }
+        }
+        _ => result,
+    }
+}
+
+/// Envia los pedidos de suma que quedaron pendientes, con su id original para que no se sumen dos veces.
+/// Si sigue sin haber conexion quedan para la proxima vez
+async fn flush_journal(
+    server: Arc<Mutex<Box<dyn LocalServerClient>>>,
+    journal: Arc<Mutex<OfflineJournal>>,
+    id: usize,
+) -> Result<(), CoffeeSystemError> {
+    let mut journal = journal.lock().await;
+    if journal.is_empty() {
+        return Ok(());
+    }
+    let server_conn = server.lock().await;
+    let mut sent = 0;
+    let mut result = Ok(());
+    for pending in journal.orders() {
+        match server_conn
+            .add_points(pending.account_id, pending.points, pending.request_id)
+            .await
+        {
+            Err(CoffeeSystemError::Offline) => break,
+            Err(CoffeeSystemError::ConnectionLost) => {
+                result = Err(CoffeeSystemError::ConnectionLost);
+                break;
+            }
+            Err(e) => {
+                warn!(
+                    "[COFFEE MAKER {}] Discarding pending order of account {}, {:?}",
+                    id, pending.account_id, e
+                );
+                sent += 1;
+            }
+            Ok(()) => sent += 1,
+        }
+    }
+    if sent > 0 {
+        info!("[COFFEE MAKER {}] Sent {} pending orders", id, sent);
+    }
+    if let Err(e) = journal.remove_first(sent) {
+        error!(
+            "[COFFEE MAKER {}] Error removing sent orders from the journal, {}",
+            id, e
+        );
+    }
+    result
 }
 /// Metodo para comunicar al actor de cliente de servidor que han sido consumidos puntos al servidor
 /// en caso de que la orden sea producida correctamente
@@ -179,7 +274,8 @@ async fn consume_points(
         .request_points(order.account_id, order.consumption)
         .await;
     if let Ok(()) = result {
-        sleep(Duration::from_millis(PROCESS_ORDER_TIME_IN_MS)).await;
+        let brew_time = randomizer.lock().await.get_brew_time();
+        sleep(brew_time).await;
         let success = randomizer.lock().await.get_random_success();
         if !success {
             debug!("[COFFEE MAKER {}] Failed to process order of points", id);
@@ -204,9 +300,27 @@ mod tests {
         local_server_client::MockLocalServerClient, order::ConsumptionType,
         randomizer::MockRandomizer,
     };
+    use lib::local_connection_messages::RequestId;
+    use std::time::Duration;
 
     use super::*;
 
+    fn test_journal() -> Arc<Mutex<OfflineJournal>> {
+        test_journal_in(&format!("none_{}", rand::random::<u32>()))
+    }
+
+    fn test_journal_in(name: &str) -> Arc<Mutex<OfflineJournal>> {
+        let dir = std::env::temp_dir().join(format!(
+            "coffee_maker_journal_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let journal =
+            OfflineJournal::open(&dir.to_string_lossy(), 0).expect("Error opening journal");
+        Arc::new(Mutex::new(journal))
+    }
+
     #[actix_rt::test]
     async fn should_add_points_to_account() {
         let order = Order {
@@ -217,13 +331,28 @@ mod tests {
 
         let mut rand_mock = MockRandomizer::new();
         rand_mock.expect_get_random_success().returning(|| true);
+        rand_mock
+            .expect_get_brew_time()
+            .returning(|| Duration::ZERO);
         let rand_mock: Arc<Mutex<Box<dyn Randomizer>>> = Arc::new(Mutex::new(Box::new(rand_mock)));
 
         let mut connection_mock = MockLocalServerClient::new();
-        connection_mock.expect_add_points().returning(|_, _| Ok(()));
+        connection_mock
+            .expect_next_request_id()
+            .returning(RequestId::default);
+        connection_mock
+            .expect_add_points()
+            .returning(|_, _, _| Ok(()));
         let connection_mock: Arc<Mutex<Box<dyn LocalServerClient>>> =
             Arc::new(Mutex::new(Box::new(connection_mock)));
-        let result = add_points(order, connection_mock.clone(), rand_mock.clone(), 0).await;
+        let result = add_points(
+            order,
+            connection_mock.clone(),
+            rand_mock.clone(),
+            test_journal(),
+            0,
+        )
+        .await;
         assert!(result.is_ok());
     }
 
@@ -237,12 +366,22 @@ mod tests {
 
         let mut rand_mock = MockRandomizer::new();
         rand_mock.expect_get_random_success().returning(|| false);
+        rand_mock
+            .expect_get_brew_time()
+            .returning(|| Duration::ZERO);
         let rand_mock: Arc<Mutex<Box<dyn Randomizer>>> = Arc::new(Mutex::new(Box::new(rand_mock)));
 
         let connection_mock = MockLocalServerClient::new();
         let connection_mock: Arc<Mutex<Box<dyn LocalServerClient>>> =
             Arc::new(Mutex::new(Box::new(connection_mock)));
-        let result = add_points(order, connection_mock.clone(), rand_mock.clone(), 0).await;
+        let result = add_points(
+            order,
+            connection_mock.clone(),
+            rand_mock.clone(),
+            test_journal(),
+            0,
+        )
+        .await;
         assert!(result.is_ok());
     }
 
@@ -256,15 +395,28 @@ mod tests {
 
         let mut rand_mock = MockRandomizer::new();
         rand_mock.expect_get_random_success().returning(|| true);
+        rand_mock
+            .expect_get_brew_time()
+            .returning(|| Duration::ZERO);
         let rand_mock: Arc<Mutex<Box<dyn Randomizer>>> = Arc::new(Mutex::new(Box::new(rand_mock)));
 
         let mut connection_mock = MockLocalServerClient::new();
+        connection_mock
+            .expect_next_request_id()
+            .returning(RequestId::default);
         connection_mock
             .expect_add_points()
-            .returning(|_, _| Err(CoffeeSystemError::ConnectionLost));
+            .returning(|_, _, _| Err(CoffeeSystemError::ConnectionLost));
         let connection_mock: Arc<Mutex<Box<dyn LocalServerClient>>> =
             Arc::new(Mutex::new(Box::new(connection_mock)));
-        let result = add_points(order, connection_mock.clone(), rand_mock.clone(), 0).await;
+        let result = add_points(
+            order,
+            connection_mock.clone(),
+            rand_mock.clone(),
+            test_journal(),
+            0,
+        )
+        .await;
         assert!(result.is_err());
         assert_eq!(CoffeeSystemError::ConnectionLost, result.unwrap_err());
     }
@@ -279,6 +431,9 @@ mod tests {
 
         let mut rand_mock = MockRandomizer::new();
         rand_mock.expect_get_random_success().returning(|| false);
+        rand_mock
+            .expect_get_brew_time()
+            .returning(|| Duration::ZERO);
         let rand_mock: Arc<Mutex<Box<dyn Randomizer>>> = Arc::new(Mutex::new(Box::new(rand_mock)));
 
         let mut connection_mock = MockLocalServerClient::new();
@@ -305,6 +460,9 @@ mod tests {
 
         let mut rand_mock = MockRandomizer::new();
         rand_mock.expect_get_random_success().returning(|| true);
+        rand_mock
+            .expect_get_brew_time()
+            .returning(|| Duration::ZERO);
         let rand_mock: Arc<Mutex<Box<dyn Randomizer>>> = Arc::new(Mutex::new(Box::new(rand_mock)));
 
         let mut connection_mock = MockLocalServerClient::new();
@@ -342,4 +500,96 @@ mod tests {
         assert!(result.is_err());
         assert_eq!(CoffeeSystemError::NotEnoughPoints, result.unwrap_err());
     }
+
+    #[actix_rt::test]
+    async fn should_save_the_cash_order_to_send_it_later_if_there_is_no_server_available() {
+        let order = Order {
+            account_id: 100,
+            consumption: 1000,
+            consumption_type: ConsumptionType::Cash,
+        };
+
+        let mut rand_mock = MockRandomizer::new();
+        rand_mock.expect_get_random_success().returning(|| true);
+        rand_mock
+            .expect_get_brew_time()
+            .returning(|| Duration::ZERO);
+        let rand_mock: Arc<Mutex<Box<dyn Randomizer>>> = Arc::new(Mutex::new(Box::new(rand_mock)));
+
+        let request_id = RequestId {
+            dispenser_id: 1,
+            sequence: 7,
+        };
+        let mut connection_mock = MockLocalServerClient::new();
+        connection_mock
+            .expect_next_request_id()
+            .returning(move || request_id);
+        connection_mock
+            .expect_add_points()
+            .returning(|_, _, _| Err(CoffeeSystemError::Offline));
+        let connection_mock: Arc<Mutex<Box<dyn LocalServerClient>>> =
+            Arc::new(Mutex::new(Box::new(connection_mock)));
+        let journal = test_journal_in("offline");
+        let result = add_points(
+            order,
+            connection_mock.clone(),
+            rand_mock.clone(),
+            journal.clone(),
+            0,
+        )
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(
+            vec![JournaledOrder {
+                request_id,
+                account_id: 100,
+                points: 1000,
+            }],
+            journal.lock().await.orders()
+        );
+    }
+
+    #[actix_rt::test]
+    async fn should_send_the_pending_orders_with_their_original_ids_until_the_connection_fails() {
+        let journal = test_journal_in("flush");
+        for sequence in 0..3 {
+            journal
+                .lock()
+                .await
+                .append(JournaledOrder {
+                    request_id: RequestId {
+                        dispenser_id: 1,
+                        sequence,
+                    },
+                    account_id: 100,
+                    points: 10,
+                })
+                .expect("Error appending");
+        }
+
+        let mut connection_mock = MockLocalServerClient::new();
+        let mut seq = mockall::Sequence::new();
+        for sequence in 0..2 {
+            connection_mock
+                .expect_add_points()
+                .withf(move |_, _, request_id| request_id.sequence == sequence)
+                .times(1)
+                .returning(|_, _, _| Ok(()))
+                .in_sequence(&mut seq);
+        }
+        connection_mock
+            .expect_add_points()
+            .times(1)
+            .returning(|_, _, _| Err(CoffeeSystemError::Offline))
+            .in_sequence(&mut seq);
+        let connection_mock: Arc<Mutex<Box<dyn LocalServerClient>>> =
+            Arc::new(Mutex::new(Box::new(connection_mock)));
+
+        let result = flush_journal(connection_mock, journal.clone(), 0).await;
+
+        assert!(result.is_ok());
+        let pending = journal.lock().await.orders();
+        assert_eq!(1, pending.len());
+        assert_eq!(2, pending[0].request_id.sequence);
+    }
 }
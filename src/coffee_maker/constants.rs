@@ -1,11 +1,27 @@
-/// Indica el tiempo que tarda la cafetera en realizar un pedido en ms
+/// Indica el tiempo por defecto que tarda la cafetera en realizar un pedido en ms. Se puede cambiar con `--brew-time`
 pub const PROCESS_ORDER_TIME_IN_MS: u64 = 25000;
 
-/// Indica la probabilidad de exito de que la cafetera realice un pedido exitosamente. El valor debe estar en tre 0 y 100.
+/// Indica la probabilidad por defecto de que la cafetera realice un pedido exitosamente. El valor debe estar entre 0 y 100.
+/// Se puede cambiar con `--success-chance`
 pub const SUCCESS_CHANCE: i32 = 80;
 
 /// Es el archivo de ordenes por defecto a abrir
 pub const DEFAULT_ORDERS_FILE: &str = "tests/orders.csv";
 
-/// Es la cantidad de dispensers de cafe que tiene la cafetera robot
+/// Es la cantidad por defecto de dispensers de cafe que tiene la cafetera robot. Se puede cambiar con `--dispensers`
 pub const DISPENSERS: usize = 10;
+
+/// Tiempo de espera inicial entre intentos de reconexion con los servidores en ms. Se duplica en cada intento
+pub const RECONNECT_INITIAL_BACKOFF_IN_MS: u64 = 100;
+
+/// Tiempo de espera maximo entre intentos de reconexion con los servidores en ms
+pub const RECONNECT_MAX_BACKOFF_IN_MS: u64 = 5000;
+
+/// Tiempo en ms que la cafetera sigue intentando conectarse si ningun servidor responde, luego se detiene
+pub const SERVERS_UNREACHABLE_TIMEOUT_IN_MS: u64 = 60000;
+
+/// Directorio por defecto donde la cafetera guarda los pedidos de suma que no pudo enviar
+pub const DEFAULT_JOURNAL_DIR: &str = "coffee_maker_journal";
+
+/// Archivo por defecto donde la cafetera escribe el reporte de la ejecucion al terminar
+pub const DEFAULT_REPORT_FILE: &str = "coffee_maker_report.json";
@@ -8,6 +8,12 @@ pub enum CoffeeMakerError {
 
     /// Faltan argumentos al iniciar la aplicacion
     ArgsMissing,
+
+    /// Alguno de los argumentos tiene un formato invalido
+    ArgsFormat,
+
+    /// No se pudo leer el archivo de configuracion o tiene un formato invalido
+    ConfigError,
 }
 
 impl From<std::num::ParseIntError> for CoffeeMakerError {
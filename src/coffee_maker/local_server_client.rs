@@ -1,24 +1,49 @@
 #[cfg(test)]
 use mockall::automock;
 
-use std::sync::Arc;
+use std::{
+    cmp::min,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use async_std::sync::Mutex;
+use async_std::{
+    channel::{self, Sender},
+    sync::Mutex,
+    task,
+};
 use async_trait::async_trait;
 use lib::{
     common_errors::CoffeeSystemError,
-    connection_protocol::{ConnectionProtocol, TcpConnection},
+    connection_protocol::{recv_message, send_message, ConnectionProtocol, Transport},
+    handshake::{Hello, NodeId, Peer},
     local_connection_messages::{
-        CoffeeMakerRequest, CoffeeMakerResponse, MessageType, ResponseStatus,
+        AccountBalance, CoffeeMakerRequest, CoffeeMakerResponse, MessageType, RequestId,
+        ResponseStatus,
     },
-    serializer::{deserialize, serialize},
+};
+use log::{debug, error, info, warn};
+
+use crate::constants::{
+    RECONNECT_INITIAL_BACKOFF_IN_MS, RECONNECT_MAX_BACKOFF_IN_MS, SERVERS_UNREACHABLE_TIMEOUT_IN_MS,
 };
 
 /// Interfaz de las operaciones que se puede hacer con el servidor local
 #[cfg_attr(test, automock)]
 #[async_trait]
-pub trait LocalServerClient {
-    async fn add_points(&self, account_id: usize, points: usize) -> Result<(), CoffeeSystemError>;
+pub trait LocalServerClient: Send {
+    /// Genera el id de un pedido nuevo. Reenviar un pedido con el mismo id no lo vuelve a aplicar
+    fn next_request_id(&self) -> RequestId;
+    async fn add_points(
+        &self,
+        account_id: usize,
+        points: usize,
+        request_id: RequestId,
+    ) -> Result<(), CoffeeSystemError>;
     async fn request_points(
         &self,
         account_id: usize,
@@ -26,55 +51,476 @@ pub trait LocalServerClient {
     ) -> Result<(), CoffeeSystemError>;
     async fn take_points(&self, account_id: usize, points: usize) -> Result<(), CoffeeSystemError>;
     async fn cancel_point_request(&self, account_id: usize) -> Result<(), CoffeeSystemError>;
+    async fn get_balance(&self, account_id: usize) -> Result<AccountBalance, CoffeeSystemError>;
+}
+
+type Connection = Box<dyn ConnectionProtocol + Send + Sync>;
+/// Se conecta y hace el handshake de forma bloqueante, por eso al reconectar se usa fuera del executor
+type Connector = Arc<dyn Fn(&String) -> Result<Connection, CoffeeSystemError> + Send + Sync>;
+
+/// Parametros de la reconexion con los servidores locales
+#[derive(Debug, Clone, Copy)]
+pub struct FailoverConfig {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Tiempo que se sigue intentando mientras ningun servidor responde, luego se da la conexion por perdida.
+    /// Mientras tanto los pedidos fallan con `Offline` sin esperar
+    pub unreachable_timeout: Duration,
+}
+
+impl Default for FailoverConfig {
+    fn default() -> Self {
+        FailoverConfig {
+            initial_backoff: Duration::from_millis(RECONNECT_INITIAL_BACKOFF_IN_MS),
+            max_backoff: Duration::from_millis(RECONNECT_MAX_BACKOFF_IN_MS),
+            unreachable_timeout: Duration::from_millis(SERVERS_UNREACHABLE_TIMEOUT_IN_MS),
+        }
+    }
 }
 
-/// Conexion con el servidor local, arma los mensajes, los envia y espera
+/// Pedidos en vuelo de una conexion, cada uno con el canal por el que se entrega su respuesta.
+/// Es None cuando la conexion se perdio: los pedidos pendientes fallan y no se aceptan nuevos
+type PendingResponses = Arc<std::sync::Mutex<Option<HashMap<RequestId, Sender<ResponseStatus>>>>>;
+
+/// Conexion establecida con un servidor. Un lector propio de la conexion entrega cada respuesta a quien envio
+/// el pedido, asi varios pedidos pueden estar en vuelo a la vez sin que uno lento frene a los demas.
+/// La epoca aumenta con cada nueva conexion
+#[derive(Clone)]
+struct Link {
+    epoch: u64,
+    /// Lo acordado con el servidor en el handshake
+    peer: Peer,
+    writer: Arc<Mutex<Connection>>,
+    pending: PendingResponses,
+}
+
+impl Link {
+    /// Registra el pedido para recibir su respuesta. Falla si la conexion ya se perdio
+    fn register(
+        &self,
+        request_id: RequestId,
+        response: Sender<ResponseStatus>,
+    ) -> Result<(), CoffeeSystemError> {
+        self.pending
+            .lock()?
+            .as_mut()
+            .ok_or(CoffeeSystemError::ConnectionLost)?
+            .insert(request_id, response);
+        Ok(())
+    }
+
+    fn unregister(&self, request_id: &RequestId) {
+        if let Ok(mut pending) = self.pending.lock() {
+            if let Some(pending) = pending.as_mut() {
+                pending.remove(request_id);
+            }
+        }
+    }
+
+    /// Da la conexion por perdida. Al descartar los canales los pedidos pendientes fallan y se reenvian
+    fn close(&self) {
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.take();
+        }
+    }
+}
+
+/// Estado de la conexion. Sin conexion guarda desde cuando ningun servidor responde y cuando se puede volver a intentar
+struct ServerState {
+    link: Option<Link>,
+    current: usize,
+    epoch: u64,
+    unreachable_since: Option<Instant>,
+    next_attempt: Instant,
+    backoff: Duration,
+}
+
+/// Conexion con los servidores locales compartida por todos los dispensers de la cafetera.
+/// Los pedidos se envian sin esperar las respuestas de los anteriores, y cada respuesta se reconoce por el id de su pedido.
+/// Si se pierde la conexion se pasa a los demas servidores de la lista
+pub struct ServerConnection {
+    servers: Vec<String>,
+    connector: Connector,
+    state: Mutex<ServerState>,
+    /// Hay una reconexion en curso. Mientras tanto los demas pedidos fallan con `Offline` en lugar de esperarla
+    connecting: AtomicBool,
+    failover: FailoverConfig,
+}
+
+impl ServerConnection {
+    /// Se conecta al primer servidor de la lista que responda. Devuelve error si no responde ninguno.
+    /// En el handshake la cafetera se presenta con el id al azar de su ejecucion
+    pub fn new(
+        servers: &[String],
+        failover: FailoverConfig,
+        instance_id: u32,
+        transport: Transport,
+    ) -> Result<ServerConnection, CoffeeSystemError> {
+        let hello = Hello::new(NodeId::CoffeeMaker(instance_id));
+        let connector: Connector =
+            Arc::new(move |server_addr| transport.connect(server_addr, &hello));
+        ServerConnection::new_with_connector(servers, failover, connector)
+    }
+
+    fn new_with_connector(
+        servers: &[String],
+        failover: FailoverConfig,
+        connector: Connector,
+    ) -> Result<ServerConnection, CoffeeSystemError> {
+        for (index, server_addr) in servers.iter().enumerate() {
+            if let Ok(link) = connect(&connector, server_addr, 0) {
+                return Ok(ServerConnection {
+                    servers: servers.to_vec(),
+                    connector,
+                    state: Mutex::new(ServerState {
+                        link: Some(link),
+                        current: index,
+                        epoch: 0,
+                        unreachable_since: None,
+                        next_attempt: Instant::now(),
+                        backoff: failover.initial_backoff,
+                    }),
+                    connecting: AtomicBool::new(false),
+                    failover,
+                });
+            }
+        }
+        Err(CoffeeSystemError::ConnectionLost)
+    }
+
+    /// Devuelve la conexion actual, o se conecta a otro servidor si se perdio.
+    /// Solo un pedido a la vez intenta reconectarse, y lo hace sin retener el estado
+    async fn link(&self) -> Result<Link, CoffeeSystemError> {
+        let (current, epoch) = {
+            let mut state = self.state.lock().await;
+            if let Some(link) = &state.link {
+                return Ok(link.clone());
+            }
+            if Instant::now() < state.next_attempt {
+                return self.unreachable(&mut state);
+            }
+            if self.connecting.swap(true, Ordering::AcqRel) {
+                return Err(CoffeeSystemError::Offline);
+            }
+            (state.current, state.epoch + 1)
+        };
+        let _connecting = Connecting(&self.connecting);
+        let connected = self.reconnect(current, epoch).await;
+        let mut state = self.state.lock().await;
+        match connected {
+            Some((index, link)) => {
+                info!(
+                    "[LOCAL SERVER CLIENT] Connected to server {}",
+                    self.servers[index]
+                );
+                state.link = Some(link.clone());
+                state.current = index;
+                state.epoch = link.epoch;
+                state.unreachable_since = None;
+                state.backoff = self.failover.initial_backoff;
+                Ok(link)
+            }
+            None => {
+                state.next_attempt = Instant::now() + state.backoff;
+                state.backoff = min(state.backoff * 2, self.failover.max_backoff);
+                self.unreachable(&mut state)
+            }
+        }
+    }
+
+    /// Descarta la conexion si sigue siendo la actual, el proximo pedido se conecta a otro servidor
+    async fn lost(&self, link: &Link) {
+        let mut state = self.state.lock().await;
+        if state
+            .link
+            .as_ref()
+            .is_some_and(|current| current.epoch == link.epoch)
+        {
+            warn!(
+                "[LOCAL SERVER CLIENT] Lost connection with server {}",
+                self.servers[state.current]
+            );
+            link.close();
+            state.link = None;
+        }
+    }
+
+    /// Envia el pedido por la conexion y espera su respuesta
+    async fn exchange(
+        &self,
+        link: &Link,
+        request: CoffeeMakerRequest,
+    ) -> Result<ResponseStatus, CoffeeSystemError> {
+        // Un servidor de una version anterior no puede leer el pedido, se rechaza sin enviarlo
+        if let Some(feature) = request.message_type.required_feature() {
+            if !link.peer.supports(&feature) {
+                warn!(
+                    "[LOCAL SERVER CLIENT] Server {:?} does not support {:?}",
+                    link.peer.node_id, feature
+                );
+                return Err(CoffeeSystemError::IncompatibleProtocol);
+            }
+        }
+        // Se registra antes de enviar, la respuesta puede llegar antes de que termine el envio
+        let (sender, response) = channel::bounded(1);
+        link.register(request.request_id, sender)?;
+        if let Err(e) = send_message(link.writer.lock().await.as_mut(), &request).await {
+            link.unregister(&request.request_id);
+            return Err(e);
+        }
+        response
+            .recv()
+            .await
+            .map_err(|_| CoffeeSystemError::ConnectionLost)
+    }
+
+    /// Intenta conectarse a los servidores empezando por el siguiente al actual. La conexion y el handshake
+    /// bloquean, por lo que se hacen en un hilo aparte para no frenar al executor
+    async fn reconnect(&self, current: usize, epoch: u64) -> Option<(usize, Link)> {
+        let servers = self.servers.clone();
+        let connector = self.connector.clone();
+        let (sender, connected) = channel::bounded(1);
+        std::thread::spawn(move || {
+            let link = (1..=servers.len())
+                .map(|offset| (current + offset) % servers.len())
+                .find_map(|index| {
+                    connect(&connector, &servers[index], epoch)
+                        .ok()
+                        .map(|link| (index, link))
+                });
+            let _ = sender.try_send(link);
+        });
+        connected.recv().await.ok().flatten()
+    }
+
+    /// Sin conexion devuelve `Offline` y no se vuelve a intentar hasta que pase un tiempo que se duplica
+    /// en cada vuelta fallida. Si ningun servidor responde durante `unreachable_timeout` devuelve la conexion perdida
+    fn unreachable(&self, state: &mut ServerState) -> Result<Link, CoffeeSystemError> {
+        let unreachable_since = *state.unreachable_since.get_or_insert_with(Instant::now);
+        if unreachable_since.elapsed() >= self.failover.unreachable_timeout {
+            error!(
+                "[LOCAL SERVER CLIENT] No server answered for {:?}",
+                self.failover.unreachable_timeout
+            );
+            return Err(CoffeeSystemError::ConnectionLost);
+        }
+        Err(CoffeeSystemError::Offline)
+    }
+}
+
+/// Marca la reconexion en curso hasta que termina, aunque se descarte el pedido que la inicio
+struct Connecting<'a>(&'a AtomicBool);
+
+impl Drop for Connecting<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+fn connect(
+    connector: &Connector,
+    server_addr: &String,
+    epoch: u64,
+) -> Result<Link, CoffeeSystemError> {
+    let writer = connector(server_addr)?;
+    let reader = writer.try_clone()?;
+    let pending: PendingResponses = Arc::new(std::sync::Mutex::new(Some(HashMap::new())));
+    task::spawn(read_responses(reader, pending.clone()));
+    Ok(Link {
+        epoch,
+        peer: writer.peer(),
+        writer: Arc::new(Mutex::new(writer)),
+        pending,
+    })
+}
+
+/// Lee las respuestas de la conexion y entrega cada una a quien envio su pedido. Si la conexion falla
+/// da por perdidos los pedidos pendientes, y termina tambien si la conexion se descarto
+async fn read_responses(mut reader: Connection, pending: PendingResponses) {
+    loop {
+        let received: Result<CoffeeMakerResponse, CoffeeSystemError> =
+            recv_message(reader.as_mut()).await;
+        let Ok(mut pending) = pending.lock() else {
+            return;
+        };
+        let Some(waiting) = pending.as_mut() else {
+            return;
+        };
+        match received {
+            Ok(response) => match waiting.remove(&response.request_id) {
+                Some(sender) => {
+                    let _ = sender.try_send(response.status);
+                }
+                None => debug!(
+                    "[LOCAL SERVER CLIENT] Discarding response of request {:?}, nobody is waiting for it",
+                    response.request_id
+                ),
+            },
+            Err(e) => {
+                debug!("[LOCAL SERVER CLIENT] Error reading responses, {:?}", e);
+                pending.take();
+                return;
+            }
+        }
+    }
+}
+
+/// Cliente del servidor local de un dispenser, arma los mensajes, los envia por la conexion compartida y espera.
+/// Cada pedido lleva un id unico formado por el id del dispenser y un numero de secuencia.
+/// Si se pierde la conexion el pedido se reenvia con el mismo id al servidor al que se pase
 pub struct LocalServer {
-    connection: Arc<Mutex<Box<dyn ConnectionProtocol + Send + Sync>>>,
+    connection: Arc<ServerConnection>,
+    reservations: Mutex<HashMap<usize, u64>>,
+    dispenser_id: u64,
+    sequence: AtomicU64,
 }
 
 impl LocalServer {
-    pub fn new(server_addr: &String) -> Result<LocalServer, CoffeeSystemError> {
-        let protocol = TcpConnection::new_client_connection(server_addr)?;
-        Ok(LocalServer {
-            connection: Arc::new(Mutex::new(Box::new(protocol))),
-        })
+    pub fn new(connection: Arc<ServerConnection>, dispenser_id: u64) -> LocalServer {
+        LocalServer {
+            connection,
+            reservations: Mutex::new(HashMap::new()),
+            dispenser_id,
+            sequence: AtomicU64::new(0),
+        }
     }
-}
 
-async fn handle_request(
-    connection: Arc<Mutex<Box<dyn ConnectionProtocol + Send + Sync>>>,
-    message_type: MessageType,
-    account_id: usize,
-    points: usize,
-) -> Result<(), CoffeeSystemError> {
-    let req = CoffeeMakerRequest {
-        message_type,
-        account_id,
-        points,
-    };
-    let serialized = serialize(&req)?;
-    let mut connection = connection.lock().await;
-    connection.send(&serialized).await?;
-    let mut encoded = connection.recv().await?;
-    let decoded: CoffeeMakerResponse = deserialize(&mut encoded)?;
-    match decoded.status {
-        ResponseStatus::Ok => Ok(()),
-        ResponseStatus::Err(error) => Err(error),
+    /// Envia el pedido y espera su respuesta. Si se pierde la conexion se conecta a otro servidor y lo reenvia.
+    /// Las reservas quedan en el servidor donde se hicieron, por lo que si se cambio de servidor antes de confirmar
+    /// se vuelven a pedir los puntos solo si la resta no se habia aplicado, y al cancelar no hace falta avisar al nuevo servidor
+    async fn send_request(
+        &self,
+        message_type: MessageType,
+        account_id: usize,
+        points: usize,
+        request_id: RequestId,
+    ) -> Result<ResponseStatus, CoffeeSystemError> {
+        loop {
+            let link = self.connection.link().await?;
+            let result = self
+                .send_with_reservation(&link, message_type, account_id, points, request_id)
+                .await;
+            match result {
+                Err(CoffeeSystemError::ConnectionLost)
+                | Err(CoffeeSystemError::ConnectionClosed) => {
+                    self.connection.lost(&link).await;
+                }
+                _ => return result,
+            }
+        }
+    }
+
+    async fn send_with_reservation(
+        &self,
+        link: &Link,
+        message_type: MessageType,
+        account_id: usize,
+        points: usize,
+        request_id: RequestId,
+    ) -> Result<ResponseStatus, CoffeeSystemError> {
+        let mut reservations = self.reservations.lock().await;
+        let reserved_on_other_server = reservations
+            .get(&account_id)
+            .is_some_and(|epoch| *epoch != link.epoch);
+        match message_type {
+            MessageType::TakePoints if reserved_on_other_server => {
+                // La resta pudo haberse aplicado en el servidor anterior aunque se perdio su respuesta. Se envia
+                // primero con su id original: si se aplico el nuevo servidor la reconoce, si no responde que no hay reserva
+                let request = self.request(message_type, account_id, points, request_id);
+                let status = self.connection.exchange(link, request).await?;
+                if !matches!(
+                    status,
+                    ResponseStatus::Err(CoffeeSystemError::ReservationNotFound)
+                ) {
+                    reservations.remove(&account_id);
+                    return Ok(status);
+                }
+                let request = self.request(
+                    MessageType::RequestPoints,
+                    account_id,
+                    points,
+                    self.next_request_id(),
+                );
+                let status = self.connection.exchange(link, request).await?;
+                if !matches!(status, ResponseStatus::Ok) {
+                    reservations.remove(&account_id);
+                    return Ok(status);
+                }
+                reservations.insert(account_id, link.epoch);
+            }
+            MessageType::CancelPointsRequest if reserved_on_other_server => {
+                reservations.remove(&account_id);
+                return Ok(ResponseStatus::Ok);
+            }
+            _ => {}
+        }
+        let request = self.request(message_type, account_id, points, request_id);
+        let status = self.connection.exchange(link, request).await?;
+        match message_type {
+            MessageType::RequestPoints if matches!(status, ResponseStatus::Ok) => {
+                reservations.insert(account_id, link.epoch);
+            }
+            MessageType::TakePoints | MessageType::CancelPointsRequest => {
+                reservations.remove(&account_id);
+            }
+            _ => {}
+        }
+        Ok(status)
+    }
+
+    fn request(
+        &self,
+        message_type: MessageType,
+        account_id: usize,
+        points: usize,
+        request_id: RequestId,
+    ) -> CoffeeMakerRequest {
+        CoffeeMakerRequest {
+            message_type,
+            account_id,
+            points,
+            request_id,
+        }
+    }
+
+    async fn handle_request(
+        &self,
+        message_type: MessageType,
+        account_id: usize,
+        points: usize,
+        request_id: RequestId,
+    ) -> Result<(), CoffeeSystemError> {
+        match self
+            .send_request(message_type, account_id, points, request_id)
+            .await?
+        {
+            ResponseStatus::Ok => Ok(()),
+            ResponseStatus::Err(error) => Err(error),
+            ResponseStatus::Balance(_) => Err(CoffeeSystemError::UnexpectedError),
+        }
     }
 }
 
 #[async_trait]
 impl LocalServerClient for LocalServer {
-    /// Metodo mediante el cual la cafetera le pide al servidor que sume puntos a una cuenta
-    async fn add_points(&self, account_id: usize, points: usize) -> Result<(), CoffeeSystemError> {
-        handle_request(
-            self.connection.clone(),
-            MessageType::AddPoints,
-            account_id,
-            points,
-        )
-        .await
+    /// Genera el id del proximo pedido
+    fn next_request_id(&self) -> RequestId {
+        RequestId {
+            dispenser_id: self.dispenser_id,
+            sequence: self.sequence.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// Metodo mediante el cual la cafetera le pide al servidor que sume puntos a una cuenta.
+    /// Recibe el id del pedido para poder reenviarlo luego si no hay conexion
+    async fn add_points(
+        &self,
+        account_id: usize,
+        points: usize,
+        request_id: RequestId,
+    ) -> Result<(), CoffeeSystemError> {
+        self.handle_request(MessageType::AddPoints, account_id, points, request_id)
+            .await
     }
 
     /// Metodo mediante el cual la cafetera le pide al servidor que reserve los puntos de una
@@ -83,33 +529,498 @@ impl LocalServerClient for LocalServer {
         account_id: usize,
         points: usize,
     ) -> Result<(), CoffeeSystemError> {
-        handle_request(
-            self.connection.clone(),
+        self.handle_request(
             MessageType::RequestPoints,
             account_id,
             points,
+            self.next_request_id(),
         )
         .await
     }
 
     /// Metodo mediante el cual la cafetera le pide al servidor que reste puntos a una cuenta
     async fn take_points(&self, account_id: usize, points: usize) -> Result<(), CoffeeSystemError> {
-        handle_request(
-            self.connection.clone(),
+        self.handle_request(
             MessageType::TakePoints,
             account_id,
             points,
+            self.next_request_id(),
         )
         .await
     }
     /// Metodo mediante el cual la cafetera le pide al servidor que cancele la reserva que realizo sobre los puntos de una cuenta
     async fn cancel_point_request(&self, account_id: usize) -> Result<(), CoffeeSystemError> {
-        handle_request(
-            self.connection.clone(),
+        self.handle_request(
             MessageType::CancelPointsRequest,
             account_id,
             0,
+            self.next_request_id(),
         )
         .await
     }
+
+    /// Metodo mediante el cual la cafetera consulta el saldo de una cuenta. El servidor responde con su base local
+    /// sin esperar al token, por lo que el saldo puede estar desactualizado (ver `AccountBalance`)
+    async fn get_balance(&self, account_id: usize) -> Result<AccountBalance, CoffeeSystemError> {
+        match self
+            .send_request(
+                MessageType::QueryBalance,
+                account_id,
+                0,
+                self.next_request_id(),
+            )
+            .await?
+        {
+            ResponseStatus::Balance(balance) => Ok(balance),
+            ResponseStatus::Err(error) => Err(error),
+            ResponseStatus::Ok => Err(CoffeeSystemError::UnexpectedError),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, sync::Arc};
+
+    use lib::{
+        codec::Codec,
+        connection_protocol::{MockConnectionProtocol, TcpConnection},
+        handshake::{Feature, NodeKind, PROTOCOL_VERSION, SUPPORTED_FEATURES},
+    };
+
+    use super::*;
+
+    type Received = Arc<std::sync::Mutex<Vec<CoffeeMakerRequest>>>;
+
+    /// Servidor que responde Ok a los primeros `answers` pedidos y luego pierde la conexion. Como el servidor real,
+    /// rechaza las restas sin una reserva previa en la misma conexion.
+    /// Responde los pedidos en el orden en que llegan, su lector espera hasta que llegue el proximo
+    fn server(received: Received, answers: usize) -> Connection {
+        mock_server(received, answers, SUPPORTED_FEATURES.to_vec(), vec![])
+    }
+
+    fn server_with_features(
+        received: Received,
+        answers: usize,
+        features: Vec<Feature>,
+    ) -> Connection {
+        mock_server(received, answers, features, vec![])
+    }
+
+    /// Servidor al que ya llegaron en el token las restas `applied`, aplicadas en otro servidor
+    fn mock_server(
+        received: Received,
+        answers: usize,
+        features: Vec<Feature>,
+        applied: Vec<RequestId>,
+    ) -> Connection {
+        let mut connection = MockConnectionProtocol::new();
+        let sent = received.clone();
+        connection.expect_codec().return_const(Codec::JsonLine);
+        connection.expect_peer().return_const(Peer {
+            node_id: NodeId::Server(0),
+            protocol_version: PROTOCOL_VERSION,
+            features,
+            codec: Codec::JsonLine,
+        });
+        connection.expect_send().returning(move |data| {
+            let mut sent = sent.lock().expect("Lock error");
+            if sent.len() >= answers {
+                return Err(CoffeeSystemError::ConnectionLost);
+            }
+            sent.push(Codec::JsonLine.decode(data).expect("Error deserializing"));
+            Ok(())
+        });
+        connection.expect_try_clone().times(1).returning(move || {
+            // El lector termina cuando el test ya no usa el servidor, asi no queda esperando para siempre
+            let received = Arc::downgrade(&received);
+            let applied = applied.clone();
+            let mut answered = 0;
+            let mut reader = MockConnectionProtocol::new();
+            reader.expect_codec().return_const(Codec::JsonLine);
+            reader.expect_recv().returning(move || {
+                let (request, reserved) = loop {
+                    if answered >= answers {
+                        return Err(CoffeeSystemError::ConnectionLost);
+                    }
+                    let received = received
+                        .upgrade()
+                        .ok_or(CoffeeSystemError::ConnectionLost)?;
+                    let received = received.lock().expect("Lock error");
+                    if let Some(request) = received.get(answered) {
+                        let reserved = received[..answered].iter().any(|previous| {
+                            previous.message_type == MessageType::RequestPoints
+                                && previous.account_id == request.account_id
+                        });
+                        break (*request, reserved);
+                    }
+                    drop(received);
+                    std::thread::sleep(Duration::from_millis(1));
+                };
+                answered += 1;
+                let status = match request.message_type {
+                    MessageType::TakePoints
+                        if !reserved && !applied.contains(&request.request_id) =>
+                    {
+                        ResponseStatus::Err(CoffeeSystemError::ReservationNotFound)
+                    }
+                    _ => ResponseStatus::Ok,
+                };
+                let response = CoffeeMakerResponse {
+                    message_type: request.message_type,
+                    status,
+                    request_id: request.request_id,
+                };
+                Codec::JsonLine.encode(&response)
+            });
+            Ok(Box::new(reader))
+        });
+        Box::new(connection)
+    }
+
+    /// Cada intento de conexion toma la proxima conexion de la lista, None representa un servidor caido
+    fn connector(connections: Vec<Option<Connection>>) -> Connector {
+        let connections = std::sync::Mutex::new(VecDeque::from(connections));
+        Arc::new(move |_| {
+            connections
+                .lock()
+                .expect("Lock error")
+                .pop_front()
+                .flatten()
+                .ok_or(CoffeeSystemError::ConnectionLost)
+        })
+    }
+
+    fn local_server(connections: Vec<Option<Connection>>) -> LocalServer {
+        let servers = vec![String::from("first"), String::from("second")];
+        let failover = FailoverConfig {
+            unreachable_timeout: Duration::ZERO,
+            ..FailoverConfig::default()
+        };
+        let connection =
+            ServerConnection::new_with_connector(&servers, failover, connector(connections))
+                .expect("Error connecting");
+        LocalServer::new(Arc::new(connection), 1)
+    }
+
+    fn message_types(received: &Received) -> Vec<MessageType> {
+        received
+            .lock()
+            .expect("Lock error")
+            .iter()
+            .map(|request| request.message_type)
+            .collect()
+    }
+
+    #[actix_rt::test]
+    async fn should_resend_the_request_with_the_same_id_to_the_next_server() {
+        let first = Arc::new(std::sync::Mutex::new(vec![]));
+        let second = Arc::new(std::sync::Mutex::new(vec![]));
+        let client = local_server(vec![
+            Some(server(first.clone(), 1)),
+            Some(server(second.clone(), 10)),
+        ]);
+
+        assert!(client
+            .add_points(4, 10, client.next_request_id())
+            .await
+            .is_ok());
+        assert!(client
+            .add_points(4, 20, client.next_request_id())
+            .await
+            .is_ok());
+
+        assert_eq!(1, first.lock().expect("Lock error").len());
+        let resent = second.lock().expect("Lock error")[0];
+        assert_eq!(MessageType::AddPoints, resent.message_type);
+        assert_eq!(20, resent.points);
+        assert_eq!(1, resent.request_id.sequence);
+    }
+
+    #[actix_rt::test]
+    async fn should_request_the_points_again_if_the_server_changed_before_taking_them() {
+        let first = Arc::new(std::sync::Mutex::new(vec![]));
+        let second = Arc::new(std::sync::Mutex::new(vec![]));
+        let client = local_server(vec![
+            Some(server(first.clone(), 1)),
+            None,
+            Some(server(second.clone(), 10)),
+        ]);
+
+        assert!(client.request_points(4, 10).await.is_ok());
+        assert!(client.take_points(4, 10).await.is_ok());
+
+        assert_eq!(vec![MessageType::RequestPoints], message_types(&first));
+        assert_eq!(
+            vec![
+                MessageType::TakePoints,
+                MessageType::RequestPoints,
+                MessageType::TakePoints
+            ],
+            message_types(&second)
+        );
+        let second = second.lock().expect("Lock error");
+        assert_eq!(second[0].request_id, second[2].request_id);
+    }
+
+    #[actix_rt::test]
+    async fn should_not_take_the_points_again_if_the_take_was_applied_before_the_failover() {
+        let first = Arc::new(std::sync::Mutex::new(vec![]));
+        let second = Arc::new(std::sync::Mutex::new(vec![]));
+        // La resta llego al primer servidor y se aplico, pero se perdio la conexion antes de la respuesta.
+        // El segundo servidor ya la recibio en el token
+        let take_id = RequestId {
+            dispenser_id: 1,
+            sequence: 1,
+        };
+        let client = local_server(vec![
+            Some(server(first.clone(), 1)),
+            Some(mock_server(
+                second.clone(),
+                10,
+                SUPPORTED_FEATURES.to_vec(),
+                vec![take_id],
+            )),
+        ]);
+
+        assert!(client.request_points(4, 10).await.is_ok());
+        assert!(client.take_points(4, 10).await.is_ok());
+        assert!(client
+            .add_points(4, 5, client.next_request_id())
+            .await
+            .is_ok());
+
+        assert_eq!(vec![MessageType::RequestPoints], message_types(&first));
+        assert_eq!(
+            vec![MessageType::TakePoints, MessageType::AddPoints],
+            message_types(&second)
+        );
+        assert_eq!(take_id, second.lock().expect("Lock error")[0].request_id);
+    }
+
+    #[actix_rt::test]
+    async fn should_not_cancel_in_the_new_server_a_reservation_of_the_previous_one() {
+        let first = Arc::new(std::sync::Mutex::new(vec![]));
+        let second = Arc::new(std::sync::Mutex::new(vec![]));
+        let client = local_server(vec![
+            Some(server(first.clone(), 1)),
+            Some(server(second.clone(), 10)),
+        ]);
+
+        assert!(client.request_points(4, 10).await.is_ok());
+        assert!(client.cancel_point_request(4).await.is_ok());
+        assert!(client
+            .add_points(5, 10, client.next_request_id())
+            .await
+            .is_ok());
+
+        assert_eq!(vec![MessageType::AddPoints], message_types(&second));
+    }
+
+    #[actix_rt::test]
+    async fn should_return_connection_lost_when_no_server_answers() {
+        let first = Arc::new(std::sync::Mutex::new(vec![]));
+        let client = local_server(vec![Some(server(first, 0)), None, None]);
+
+        let result = client.add_points(4, 10, client.next_request_id()).await;
+
+        assert_eq!(Err(CoffeeSystemError::ConnectionLost), result);
+    }
+
+    #[actix_rt::test]
+    async fn should_fail_fast_while_offline_and_reconnect_when_a_server_answers() {
+        let first = Arc::new(std::sync::Mutex::new(vec![]));
+        let second = Arc::new(std::sync::Mutex::new(vec![]));
+        let servers = vec![String::from("first"), String::from("second")];
+        let failover = FailoverConfig {
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+            unreachable_timeout: Duration::from_secs(60),
+        };
+        let connection = ServerConnection::new_with_connector(
+            &servers,
+            failover,
+            connector(vec![
+                Some(server(first, 0)),
+                None,
+                None,
+                Some(server(second.clone(), 10)),
+            ]),
+        )
+        .expect("Error connecting");
+        let client = LocalServer::new(Arc::new(connection), 1);
+
+        let offline = client.add_points(4, 10, client.next_request_id()).await;
+        let reconnected = client.add_points(4, 20, client.next_request_id()).await;
+
+        assert_eq!(Err(CoffeeSystemError::Offline), offline);
+        assert!(reconnected.is_ok());
+        assert_eq!(vec![MessageType::AddPoints], message_types(&second));
+    }
+
+    #[actix_rt::test]
+    async fn should_answer_offline_to_other_requests_while_reconnecting_without_blocking() {
+        let first = Arc::new(std::sync::Mutex::new(vec![]));
+        let second = Arc::new(std::sync::Mutex::new(vec![]));
+        let servers = vec![String::from("first"), String::from("second")];
+        let failover = FailoverConfig {
+            unreachable_timeout: Duration::from_secs(60),
+            ..FailoverConfig::default()
+        };
+        // La reconexion queda bloqueada hasta que el test la libera
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let released = std::sync::Mutex::new(released);
+        let connections = connector(vec![
+            Some(server(first, 0)),
+            Some(server(second.clone(), 10)),
+        ]);
+        let attempts = AtomicU64::new(0);
+        let blocking: Connector = Arc::new(move |server_addr| {
+            if attempts.fetch_add(1, Ordering::Relaxed) > 0 {
+                let _ = released
+                    .lock()
+                    .expect("Lock error")
+                    .recv_timeout(Duration::from_secs(5));
+            }
+            connections(server_addr)
+        });
+        let connection = Arc::new(
+            ServerConnection::new_with_connector(&servers, failover, blocking)
+                .expect("Error connecting"),
+        );
+        let reconnecting = LocalServer::new(connection.clone(), 1);
+        let other = LocalServer::new(connection, 2);
+
+        let reconnecting = actix_rt::spawn(async move {
+            reconnecting
+                .add_points(4, 10, reconnecting.next_request_id())
+                .await
+        });
+        async_std::task::sleep(Duration::from_millis(100)).await;
+        let offline = other.add_points(5, 10, other.next_request_id()).await;
+        let _ = release.send(());
+
+        assert_eq!(Err(CoffeeSystemError::Offline), offline);
+        assert_eq!(Ok(()), reconnecting.await.expect("Task failed"));
+        assert_eq!(vec![MessageType::AddPoints], message_types(&second));
+    }
+
+    #[actix_rt::test]
+    async fn should_not_send_a_balance_query_to_a_server_that_does_not_support_it() {
+        let received = Arc::new(std::sync::Mutex::new(vec![]));
+        let client = local_server(vec![Some(server_with_features(
+            received.clone(),
+            1,
+            vec![Feature::DynamicMembership],
+        ))]);
+
+        let balance = client.get_balance(4).await;
+
+        assert_eq!(Err(CoffeeSystemError::IncompatibleProtocol), balance);
+        assert!(message_types(&received).is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn should_route_the_responses_to_each_dispenser_when_they_arrive_out_of_order() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Error binding");
+        let address = listener.local_addr().expect("No address").to_string();
+        let server = std::thread::spawn(move || {
+            let (stream, addr) = listener.accept().expect("Error accepting");
+            let mut connection = async_std::task::block_on(TcpConnection::new_server_connection(
+                stream.into(),
+                addr,
+                &Hello::new(NodeId::Server(0)),
+                NodeKind::CoffeeMaker,
+            ))
+            .expect("Error in handshake");
+            assert_eq!(Codec::LengthPrefixed, connection.codec());
+            let mut requests = vec![];
+            for _ in 0..2 {
+                let request: CoffeeMakerRequest =
+                    async_std::task::block_on(recv_message(&mut connection))
+                        .expect("Error receiving");
+                requests.push(request);
+            }
+            // Se responde primero el ultimo pedido, como si el primero esperara al token
+            for request in requests.iter().rev() {
+                let status = match request.account_id {
+                    1 => ResponseStatus::Ok,
+                    _ => ResponseStatus::Err(CoffeeSystemError::AccountNotFound),
+                };
+                let response = CoffeeMakerResponse {
+                    message_type: request.message_type,
+                    status,
+                    request_id: request.request_id,
+                };
+                async_std::task::block_on(send_message(&mut connection, &response))
+                    .expect("Error writing");
+            }
+        });
+
+        let connection = Arc::new(
+            ServerConnection::new(&[address], FailoverConfig::default(), 1, Transport::Tcp)
+                .expect("Error connecting"),
+        );
+        let first = LocalServer::new(connection.clone(), 1);
+        let second = LocalServer::new(connection, 2);
+        let first = actix_rt::spawn(async move { first.request_points(1, 10).await });
+        let second = actix_rt::spawn(async move { second.request_points(2, 10).await });
+
+        assert_eq!(Ok(()), first.await.expect("Task failed"));
+        assert_eq!(
+            Err(CoffeeSystemError::AccountNotFound),
+            second.await.expect("Task failed")
+        );
+        server.join().expect("Server failed");
+    }
+
+    #[actix_rt::test]
+    async fn should_deliver_a_response_while_another_request_never_gets_one() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Error binding");
+        let address = listener.local_addr().expect("No address").to_string();
+        let (close, closed) = std::sync::mpsc::channel::<()>();
+        let server = std::thread::spawn(move || {
+            let (stream, addr) = listener.accept().expect("Error accepting");
+            let mut connection = async_std::task::block_on(TcpConnection::new_server_connection(
+                stream.into(),
+                addr,
+                &Hello::new(NodeId::Server(0)),
+                NodeKind::CoffeeMaker,
+            ))
+            .expect("Error in handshake");
+            for _ in 0..2 {
+                let request: CoffeeMakerRequest =
+                    async_std::task::block_on(recv_message(&mut connection))
+                        .expect("Error receiving");
+                // Solo se responde el segundo, el primero queda esperando al token
+                if request.account_id == 2 {
+                    let response = CoffeeMakerResponse {
+                        message_type: request.message_type,
+                        status: ResponseStatus::Ok,
+                        request_id: request.request_id,
+                    };
+                    async_std::task::block_on(send_message(&mut connection, &response))
+                        .expect("Error writing");
+                }
+            }
+            let _ = closed.recv();
+        });
+
+        let connection = Arc::new(
+            ServerConnection::new(&[address], FailoverConfig::default(), 1, Transport::Tcp)
+                .expect("Error connecting"),
+        );
+        let first = LocalServer::new(connection.clone(), 1);
+        let second = LocalServer::new(connection, 2);
+        let _waiting = actix_rt::spawn(async move { first.request_points(1, 10).await });
+        async_std::task::sleep(Duration::from_millis(100)).await;
+        let answered = async_std::future::timeout(Duration::from_secs(5), async move {
+            second.request_points(2, 10).await
+        })
+        .await;
+
+        let _ = close.send(());
+        server.join().expect("Server failed");
+        assert_eq!(Ok(Ok(())), answered);
+    }
 }
@@ -10,44 +10,73 @@ pub mod constants;
 pub mod errors;
 /// Modulo que representa al actor que realizara la comunicacion entre la cafetera y el servidor local.
 pub mod local_server_client;
+/// Modulo del archivo donde se guardan los pedidos de suma mientras no hay conexion con los servidores.
+pub mod offline_journal;
 /// Modulo que representa un pedido de los clientes de la cafeteria.
 pub mod order;
 /// Modulo de que representa al actor que procesa archivos de pedidos de clientes y los convierte en structs order.
 pub mod orders_reader;
 /// Modulo que devuelve exito o error utilizando un numero generado al azar y un porcentaje de exito.
 pub mod randomizer;
+/// Modulo de las metricas de cada dispenser y del reporte que se escribe al terminar la ejecucion.
+pub mod run_metrics;
 
-use std::{collections::HashMap, env};
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use actix::Actor;
 use actix_rt::System;
 
 use actor_messages::OpenFile;
-use coffee_args::CoffeeArgs;
+use coffee_args::{CoffeeArgs, USAGE};
 use coffee_maker::CoffeeMaker;
-use constants::{DEFAULT_ORDERS_FILE, DISPENSERS, SUCCESS_CHANCE};
 use errors::CoffeeMakerError;
 use lib::logger::set_logger_config;
-use log::error;
+use local_server_client::{FailoverConfig, ServerConnection};
+use log::{error, info};
+use offline_journal::OfflineJournal;
 use orders_reader::OrdersReader;
 use randomizer::RealRandomizer;
+use run_metrics::RunMetrics;
 
 fn get_args() -> Result<CoffeeArgs, CoffeeMakerError> {
     let args: Vec<String> = env::args().collect();
-    let mut orders_file_path = String::from(DEFAULT_ORDERS_FILE);
-    let server_ip_and_port;
-    if args.len() == 3 {
-        server_ip_and_port = args[1].clone();
-        orders_file_path = args[2].clone();
-    } else if args.len() == 2 {
-        server_ip_and_port = args[1].clone();
-    } else {
-        return Err(CoffeeMakerError::ArgsMissing);
+    CoffeeArgs::parse(&args[1..])
+}
+
+/// Id de dispenser unico en la red: combina un id al azar de esta ejecucion con el numero de dispenser
+fn dispenser_id(instance_id: u32, index: usize) -> u64 {
+    ((instance_id as u64) << 32) | index as u64
+}
+
+/// Los journals de dispensers que ya no existen (ej. se reinicio con menos `--dispensers`)
+/// se pasan a los de los dispensers actuales, asi sus pedidos pendientes tambien se envian
+fn absorb_orphan_journals(
+    journal_dir: &str,
+    journals: &mut [OfflineJournal],
+) -> std::io::Result<()> {
+    if journals.is_empty() {
+        return Ok(());
+    }
+    for dispenser in OfflineJournal::journaled_dispensers(journal_dir)? {
+        if dispenser < journals.len() {
+            continue;
+        }
+        let orphan = OfflineJournal::open(journal_dir, dispenser)?;
+        let target = dispenser % journals.len();
+        info!(
+            "[COFFEE MAKER] Moving {} pending orders of removed dispenser {} to dispenser {}",
+            orphan.orders().len(),
+            dispenser,
+            target
+        );
+        journals[target].absorb(orphan)?;
     }
-    Ok(CoffeeArgs {
-        orders_file_path,
-        server_ip_and_port,
-    })
+    Ok(())
 }
 
 pub fn main() {
@@ -55,31 +84,69 @@ pub fn main() {
     set_logger_config();
     let args = get_args();
     if args.is_err() {
-        error!("Error setting args. Use [IP:PORT] [FILE - OPTIONAL]");
+        error!("Error setting args. {}", USAGE);
         return;
     }
     let args = args.unwrap();
+    let seed = args.seed.unwrap_or_else(rand::random);
+    info!("[COFFEE MAKER] Using seed {}", seed);
+    let failover = FailoverConfig {
+        unreachable_timeout: Duration::from_millis(args.unreachable_timeout_in_ms),
+        ..FailoverConfig::default()
+    };
+    let metrics = Arc::new(Mutex::new(RunMetrics::new()));
     system.block_on(async {
-        let reader = OrdersReader::new(args.orders_file_path);
+        let reader = OrdersReader::new(args.orders_file_path.clone(), metrics.clone());
         let reader_addr = reader.start();
+        let instance_id: u32 = rand::random();
+        let connection = match ServerConnection::new(
+            &args.server_addresses,
+            failover,
+            instance_id,
+            args.transport,
+        ) {
+            Ok(connection) => Arc::new(connection),
+            Err(_) => {
+                error!("[COFFEE MAKER] Unable to connect to any server, stopping...");
+                System::current().stop();
+                return;
+            }
+        };
+        let mut journals = vec![];
+        for id in 0..args.dispensers {
+            match OfflineJournal::open(&args.journal_dir, id) {
+                Ok(journal) => journals.push(journal),
+                Err(e) => {
+                    error!("[COFFEE MAKER {}] Unable to open the journal, {}", id, e);
+                    System::current().stop();
+                    return;
+                }
+            };
+        }
+        if let Err(e) = absorb_orphan_journals(&args.journal_dir, &mut journals) {
+            error!(
+                "[COFFEE MAKER] Unable to recover the journals of removed dispensers, {}",
+                e
+            );
+            System::current().stop();
+            return;
+        }
         let mut coffee_addresses = HashMap::new();
-        for id in 0..DISPENSERS {
+        for (id, journal) in journals.into_iter().enumerate() {
             let coffee_maker = CoffeeMaker::new(
                 reader_addr.clone(),
-                &args.server_ip_and_port,
-                Box::new(RealRandomizer::new(SUCCESS_CHANCE)),
+                connection.clone(),
+                Box::new(RealRandomizer::new(
+                    args.success_chance,
+                    args.brew_time,
+                    seed.wrapping_add(id as u64),
+                )),
+                journal,
+                metrics.clone(),
                 id,
+                dispenser_id(instance_id, id),
             );
-            match coffee_maker {
-                Err(_) => {
-                    System::current().stop();
-                    return;
-                }
-                Ok(coffee_maker) => {
-                    let coffee_maker_addr = coffee_maker.start();
-                    coffee_addresses.insert(id, coffee_maker_addr);
-                }
-            }
+            coffee_addresses.insert(id, coffee_maker.start());
         }
         if reader_addr.try_send(OpenFile(coffee_addresses)).is_err() {
             error!("[COFFEE MAKER] Unable to send OpenFile message to file reader");
@@ -88,4 +155,23 @@ pub fn main() {
     });
 
     system.run().unwrap();
+    write_report(&metrics, &args.orders_file_path, &args.report_file);
+}
+
+/// Escribe el reporte de la ejecucion, para comparar lo que se proceso con lo esperado del archivo de pedidos
+fn write_report(metrics: &Mutex<RunMetrics>, orders_file: &str, report_file: &str) {
+    let report = match metrics.lock() {
+        Ok(metrics) => metrics.report(orders_file),
+        Err(e) => {
+            error!("[COFFEE MAKER] Error building the run report, {}", e);
+            return;
+        }
+    };
+    match report.write(report_file) {
+        Ok(()) => info!("[COFFEE MAKER] Run report written to {}", report_file),
+        Err(e) => error!(
+            "[COFFEE MAKER] Error writing the run report to {}, {:?}",
+            report_file, e
+        ),
+    }
 }
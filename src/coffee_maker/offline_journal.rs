@@ -0,0 +1,204 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use lib::{local_connection_messages::RequestId, serializer::serialize};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// Pedido de suma de puntos que no se pudo enviar al servidor. Guarda el id del pedido original,
+/// asi si el servidor lo habia recibido no se suma dos veces al reenviarlo
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct JournaledOrder {
+    pub request_id: RequestId,
+    pub account_id: usize,
+    pub points: usize,
+}
+
+/// Archivo donde la cafetera guarda los pedidos de suma mientras no tiene conexion con ningun servidor.
+/// Los pedidos se envian al reconectarse, o al volver a iniciar la cafetera si se detuvo antes
+pub struct OfflineJournal {
+    path: PathBuf,
+    file: File,
+    orders: Vec<JournaledOrder>,
+}
+
+impl OfflineJournal {
+    /// Abre (o crea) el archivo de pedidos pendientes del dispenser y carga los que quedaron de ejecuciones anteriores
+    pub fn open(journal_dir: &str, dispenser: usize) -> io::Result<OfflineJournal> {
+        let dir = Path::new(journal_dir);
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!("coffee_maker_{}.journal", dispenser));
+        let orders = load_orders(&path)?;
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(OfflineJournal { path, file, orders })
+    }
+
+    /// Devuelve los dispensers que tienen un archivo de pedidos pendientes en el directorio,
+    /// incluso los de ejecuciones anteriores con mas dispensers que la actual
+    pub fn journaled_dispensers(journal_dir: &str) -> io::Result<Vec<usize>> {
+        let dir = Path::new(journal_dir);
+        if !dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut dispensers = vec![];
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            let dispenser = name
+                .to_str()
+                .and_then(|name| name.strip_prefix("coffee_maker_"))
+                .and_then(|name| name.strip_suffix(".journal"))
+                .and_then(|index| index.parse::<usize>().ok());
+            if let Some(dispenser) = dispenser {
+                dispensers.push(dispenser);
+            }
+        }
+        dispensers.sort_unstable();
+        Ok(dispensers)
+    }
+
+    /// Pasa los pedidos pendientes de otro journal a este y borra el archivo del otro.
+    /// Si se corta antes de borrarlo los pedidos quedan en los dos, pero conservan su id
+    /// asi el servidor no los suma dos veces
+    pub fn absorb(&mut self, other: OfflineJournal) -> io::Result<()> {
+        for order in other.orders.iter() {
+            self.append(*order)?;
+        }
+        drop(other.file);
+        fs::remove_file(&other.path)
+    }
+
+    /// Agrega un pedido pendiente, queda en disco antes de devolver
+    pub fn append(&mut self, order: JournaledOrder) -> io::Result<()> {
+        self.file.write_all(&serialize(&order)?)?;
+        self.file.sync_data()?;
+        self.orders.push(order);
+        Ok(())
+    }
+
+    /// Devuelve los pedidos pendientes, del mas viejo al mas nuevo
+    pub fn orders(&self) -> Vec<JournaledOrder> {
+        self.orders.clone()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orders.is_empty()
+    }
+
+    /// Descarta los primeros `count` pedidos, que ya fueron enviados.
+    /// El archivo se reescribe en uno temporal para no perder los pendientes si se corta la escritura
+    pub fn remove_first(&mut self, count: usize) -> io::Result<()> {
+        if count == 0 {
+            return Ok(());
+        }
+        let remaining = self.orders[count.min(self.orders.len())..].to_vec();
+        let tmp_path = self.path.with_extension("journal.tmp");
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            for order in remaining.iter() {
+                tmp.write_all(&serialize(order)?)?;
+            }
+            tmp.sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.orders = remaining;
+        Ok(())
+    }
+}
+
+/// Carga los pedidos pendientes. Las entradas que no se pueden leer (ej. escritura cortada) se descartan
+fn load_orders(path: &Path) -> io::Result<Vec<JournaledOrder>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let reader = BufReader::new(File::open(path)?);
+    let mut orders = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        match serde_json::from_str::<JournaledOrder>(&line) {
+            Ok(order) => orders.push(order),
+            Err(_) => warn!("[OFFLINE JOURNAL] Discarding unreadable entry {}", line),
+        }
+    }
+    Ok(orders)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(sequence: u64, points: usize) -> JournaledOrder {
+        JournaledOrder {
+            request_id: RequestId {
+                dispenser_id: 1,
+                sequence,
+            },
+            account_id: 4,
+            points,
+        }
+    }
+
+    #[test]
+    fn should_keep_the_pending_orders_after_a_restart_and_remove_the_sent_ones() {
+        let dir = std::env::temp_dir().join(format!("coffee_journal_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let dir = dir.to_string_lossy().to_string();
+        {
+            let mut journal = OfflineJournal::open(&dir, 0).expect("Error opening journal");
+            journal.append(order(1, 10)).expect("Error appending");
+            journal.append(order(2, 20)).expect("Error appending");
+            journal.append(order(3, 30)).expect("Error appending");
+        }
+        let mut journal = OfflineJournal::open(&dir, 0).expect("Error opening journal");
+        assert_eq!(
+            vec![order(1, 10), order(2, 20), order(3, 30)],
+            journal.orders()
+        );
+
+        journal.remove_first(2).expect("Error removing");
+        journal.append(order(4, 40)).expect("Error appending");
+
+        let journal = OfflineJournal::open(&dir, 0).expect("Error opening journal");
+        assert_eq!(vec![order(3, 30), order(4, 40)], journal.orders());
+        assert!(OfflineJournal::open(&dir, 1)
+            .expect("Error opening journal")
+            .is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn should_move_the_orders_of_a_journal_without_dispenser_to_another() {
+        let dir =
+            std::env::temp_dir().join(format!("coffee_journal_absorb_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let dir = dir.to_string_lossy().to_string();
+        {
+            let mut journal = OfflineJournal::open(&dir, 3).expect("Error opening journal");
+            journal.append(order(1, 10)).expect("Error appending");
+            journal.append(order(2, 20)).expect("Error appending");
+        }
+        let mut journal = OfflineJournal::open(&dir, 0).expect("Error opening journal");
+        journal.append(order(5, 50)).expect("Error appending");
+        assert_eq!(
+            vec![0, 3],
+            OfflineJournal::journaled_dispensers(&dir).expect("Error scanning")
+        );
+
+        let other = OfflineJournal::open(&dir, 3).expect("Error opening journal");
+        journal.absorb(other).expect("Error absorbing");
+
+        assert_eq!(
+            vec![0],
+            OfflineJournal::journaled_dispensers(&dir).expect("Error scanning")
+        );
+        let journal = OfflineJournal::open(&dir, 0).expect("Error opening journal");
+        assert_eq!(
+            vec![order(5, 50), order(1, 10), order(2, 20)],
+            journal.orders()
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -3,6 +3,7 @@ use std::sync::Arc;
 
 use crate::actor_messages::{OpenFile, OpenedFile, ProcessOrder, ReadAnOrder};
 use crate::order::Order;
+use crate::run_metrics::RunMetrics;
 use crate::CoffeeMaker;
 use actix::fut::{ready, wrap_future};
 use actix::{
@@ -21,6 +22,7 @@ pub struct OrdersReader {
     file_name: String,
     file: Option<Arc<Mutex<BufReader<File>>>>,
     coffee_maker_addr: Option<HashMap<usize, Addr<CoffeeMaker>>>,
+    metrics: Arc<std::sync::Mutex<RunMetrics>>,
 }
 
 /// Estados posibles al leer una linea del archio
@@ -33,11 +35,20 @@ enum OrdersReaderState {
 }
 
 impl OrdersReader {
-    pub fn new(file_name: String) -> OrdersReader {
+    pub fn new(file_name: String, metrics: Arc<std::sync::Mutex<RunMetrics>>) -> OrdersReader {
         OrdersReader {
             file: None,
             file_name,
             coffee_maker_addr: None,
+            metrics,
+        }
+    }
+
+    /// Registra en las metricas de la ejecucion lo leido por el dispenser
+    fn record(&self, update: impl FnOnce(&mut RunMetrics)) {
+        match self.metrics.lock() {
+            Ok(mut metrics) => update(&mut metrics),
+            Err(e) => error!("[READER] Error recording metrics, {}", e),
         }
     }
 
@@ -194,9 +205,11 @@ fn send_message_depending_on_result(
 ) {
     match result {
         OrdersReaderState::ParserErrorRetry(id) => {
+            me.record(|metrics| metrics.parse_failure(id));
             me.try_to_read_next_line(ctx, id);
         }
         OrdersReaderState::Reading(order, id) => {
+            me.record(|metrics| metrics.order_read(id));
             me.send_message(ProcessOrder(order), id);
         }
         OrdersReaderState::Finished(id) => {
@@ -207,6 +220,7 @@ fn send_message_depending_on_result(
 }
 
 #[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
 mod tests {
     use crate::order::ConsumptionType;
 
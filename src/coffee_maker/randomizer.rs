@@ -1,31 +1,91 @@
 #[cfg(test)]
 use mockall::automock;
 
-use rand::Rng;
+use std::{sync::Mutex, time::Duration};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::coffee_args::BrewTime;
 
 /// Interfaz del generador de chances de exito de un pedido
 #[cfg_attr(test, automock)]
-pub trait Randomizer {
+pub trait Randomizer: Send {
     /// Retorna true o false de manera azarosa
     fn get_random_success(&self) -> bool;
+    /// Retorna cuanto tarda en prepararse un cafe
+    fn get_brew_time(&self) -> Duration;
 }
 
-/// Generador de chances de exito de un pedido real.
+/// Generador de chances de exito de un pedido real. Con la misma semilla toma las mismas decisiones
 pub struct RealRandomizer {
     success_chance: i32,
+    brew_time: BrewTime,
+    rng: Mutex<StdRng>,
 }
 
 impl RealRandomizer {
-    pub fn new(success_chance: i32) -> Self {
-        Self { success_chance }
+    pub fn new(success_chance: i32, brew_time: BrewTime, seed: u64) -> Self {
+        Self {
+            success_chance,
+            brew_time,
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+        }
+    }
+
+    fn gen_range(&self, low: u64, high: u64) -> u64 {
+        match self.rng.lock() {
+            Ok(mut rng) => rng.gen_range(low, high),
+            Err(mut poisoned) => poisoned.get_mut().gen_range(low, high),
+        }
     }
 }
 
 impl Randomizer for RealRandomizer {
-    /// Retornara true o false dependiendo de un numero autogenerado al azar entre 0 y 100. Si este es
-    /// es menor o igual que la el porcentaje de exito determinado al instanciar la clase
+    /// Retornara true o false dependiendo de un numero autogenerado al azar entre 0 y 99. Si este es
+    /// menor que el porcentaje de exito determinado al instanciar la clase. Con 0 nunca hay exito y con 100 siempre
     fn get_random_success(&self) -> bool {
-        let num = rand::thread_rng().gen_range(0, 100);
-        num <= self.success_chance
+        let num = self.gen_range(0, 100);
+        (num as i32) < self.success_chance
+    }
+
+    /// Retornara un tiempo al azar entre el minimo y el maximo, ambos incluidos
+    fn get_brew_time(&self) -> Duration {
+        let ms = self.gen_range(
+            self.brew_time.min_ms,
+            self.brew_time.max_ms.saturating_add(1),
+        );
+        Duration::from_millis(ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn brew_time(min_ms: u64, max_ms: u64) -> BrewTime {
+        BrewTime { min_ms, max_ms }
+    }
+
+    #[test]
+    fn should_repeat_the_decisions_with_the_same_seed() {
+        let first = RealRandomizer::new(50, brew_time(100, 5000), 42);
+        let second = RealRandomizer::new(50, brew_time(100, 5000), 42);
+        for _ in 0..20 {
+            assert_eq!(first.get_random_success(), second.get_random_success());
+            assert_eq!(first.get_brew_time(), second.get_brew_time());
+        }
+    }
+
+    #[test]
+    fn should_respect_the_limits_of_the_success_chance_and_the_brew_time() {
+        let never = RealRandomizer::new(0, brew_time(100, 200), 1);
+        let always = RealRandomizer::new(100, brew_time(300, 300), 1);
+        for _ in 0..100 {
+            assert!(!never.get_random_success());
+            assert!(always.get_random_success());
+            let brew = never.get_brew_time();
+            assert!(brew >= Duration::from_millis(100) && brew <= Duration::from_millis(200));
+            assert_eq!(Duration::from_millis(300), always.get_brew_time());
+        }
     }
 }
@@ -0,0 +1,410 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use lib::{
+    common_errors::CoffeeSystemError,
+    local_connection_messages::{AccountBalance, RequestId},
+};
+use log::error;
+use serde::Serialize;
+
+use crate::local_server_client::LocalServerClient;
+
+/// Latencias de una de las llamadas al servidor, en ms
+#[derive(Debug, Default, Clone, Serialize, PartialEq)]
+pub struct LatencyStats {
+    pub count: u64,
+    pub mean_ms: f64,
+    pub min_ms: f64,
+    pub max_ms: f64,
+    #[serde(skip)]
+    total_ms: f64,
+}
+
+impl LatencyStats {
+    pub fn observe(&mut self, latency: Duration) {
+        let ms = latency.as_secs_f64() * 1000.0;
+        if self.count == 0 || ms < self.min_ms {
+            self.min_ms = ms;
+        }
+        if ms > self.max_ms {
+            self.max_ms = ms;
+        }
+        self.count += 1;
+        self.total_ms += ms;
+        self.mean_ms = self.total_ms / self.count as f64;
+    }
+
+    fn merge(&mut self, other: &LatencyStats) {
+        if other.count == 0 {
+            return;
+        }
+        if self.count == 0 || other.min_ms < self.min_ms {
+            self.min_ms = other.min_ms;
+        }
+        self.max_ms = self.max_ms.max(other.max_ms);
+        self.count += other.count;
+        self.total_ms += other.total_ms;
+        self.mean_ms = self.total_ms / self.count as f64;
+    }
+}
+
+/// Contadores de un dispenser durante la ejecucion
+#[derive(Debug, Default, Clone, Serialize, PartialEq)]
+pub struct DispenserStats {
+    /// Pedidos leidos del archivo
+    pub orders_read: u64,
+    /// Lineas del archivo que no se pudieron interpretar
+    pub parse_failures: u64,
+    /// Sumas de puntos que confirmo el servidor, incluye las que se enviaron luego desde el journal
+    pub cash_orders_credited: u64,
+    /// Cafes con puntos que se sirvieron y se restaron de la cuenta
+    pub point_orders_completed: u64,
+    /// Cafes con puntos que fallaron al prepararse, su reserva se cancelo
+    pub point_orders_cancelled: u64,
+    /// Pedidos de puntos que el servidor no reservo, por motivo
+    pub point_orders_rejected: BTreeMap<String, u64>,
+    /// Llamadas al servidor que fallaron por no tener conexion
+    pub connection_errors: u64,
+    /// Latencia de cada llamada al servidor, por nombre de la llamada
+    pub latency: BTreeMap<String, LatencyStats>,
+}
+
+impl DispenserStats {
+    pub fn point_order_rejected(&mut self, reason: &CoffeeSystemError) {
+        *self
+            .point_orders_rejected
+            .entry(format!("{:?}", reason))
+            .or_insert(0) += 1;
+    }
+
+    fn merge(&mut self, other: &DispenserStats) {
+        self.orders_read += other.orders_read;
+        self.parse_failures += other.parse_failures;
+        self.cash_orders_credited += other.cash_orders_credited;
+        self.point_orders_completed += other.point_orders_completed;
+        self.point_orders_cancelled += other.point_orders_cancelled;
+        for (reason, count) in &other.point_orders_rejected {
+            *self
+                .point_orders_rejected
+                .entry(reason.clone())
+                .or_insert(0) += count;
+        }
+        self.connection_errors += other.connection_errors;
+        for (call, latency) in &other.latency {
+            self.latency.entry(call.clone()).or_default().merge(latency);
+        }
+    }
+}
+
+/// Metricas de la ejecucion de la cafetera, por dispenser. Las comparten el lector y los clientes de cada dispenser
+#[derive(Debug)]
+pub struct RunMetrics {
+    started_at: SystemTime,
+    dispensers: BTreeMap<usize, DispenserStats>,
+}
+
+/// Reporte de la ejecucion que se escribe al terminar, con los totales y el detalle de cada dispenser
+#[derive(Debug, Serialize)]
+pub struct RunReport {
+    pub orders_file: String,
+    /// Inicio de la ejecucion, en ms desde epoch
+    pub started_at_ms: u128,
+    pub duration_ms: u128,
+    pub totals: DispenserStats,
+    pub dispensers: BTreeMap<usize, DispenserStats>,
+}
+
+impl RunMetrics {
+    pub fn new() -> RunMetrics {
+        RunMetrics {
+            started_at: SystemTime::now(),
+            dispensers: BTreeMap::new(),
+        }
+    }
+
+    /// Devuelve los contadores del dispenser para actualizarlos
+    pub fn dispenser(&mut self, id: usize) -> &mut DispenserStats {
+        self.dispensers.entry(id).or_default()
+    }
+
+    pub fn order_read(&mut self, id: usize) {
+        self.dispenser(id).orders_read += 1;
+    }
+
+    pub fn parse_failure(&mut self, id: usize) {
+        self.dispenser(id).parse_failures += 1;
+    }
+
+    pub fn call_finished(&mut self, id: usize, call: &str, latency: Duration) {
+        self.dispenser(id)
+            .latency
+            .entry(call.to_string())
+            .or_default()
+            .observe(latency);
+    }
+
+    /// Arma el reporte de la ejecucion hasta el momento
+    pub fn report(&self, orders_file: &str) -> RunReport {
+        let mut totals = DispenserStats::default();
+        for stats in self.dispensers.values() {
+            totals.merge(stats);
+        }
+        RunReport {
+            orders_file: orders_file.to_string(),
+            started_at_ms: self
+                .started_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+            duration_ms: self.started_at.elapsed().unwrap_or_default().as_millis(),
+            totals,
+            dispensers: self.dispensers.clone(),
+        }
+    }
+}
+
+impl Default for RunMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RunReport {
+    /// Escribe el reporte como JSON en el archivo indicado
+    pub fn write(&self, path: &str) -> Result<(), CoffeeSystemError> {
+        let content = serde_json::to_string_pretty(self)?;
+        fs::write(path, content)?;
+        Ok(())
+    }
+}
+
+/// Cliente del servidor local que registra en las metricas del dispenser la latencia y el resultado
+/// de cada llamada del cliente que envuelve
+pub struct MeteredClient {
+    inner: Box<dyn LocalServerClient + Sync>,
+    metrics: Arc<Mutex<RunMetrics>>,
+    dispenser: usize,
+}
+
+impl MeteredClient {
+    pub fn new(
+        inner: Box<dyn LocalServerClient + Sync>,
+        metrics: Arc<Mutex<RunMetrics>>,
+        dispenser: usize,
+    ) -> MeteredClient {
+        MeteredClient {
+            inner,
+            metrics,
+            dispenser,
+        }
+    }
+
+    /// Registra la latencia de la llamada y cuenta los errores de conexion
+    fn record<T>(
+        &self,
+        call: &str,
+        started_at: Instant,
+        result: &Result<T, CoffeeSystemError>,
+        on_result: impl FnOnce(&mut DispenserStats, &Result<T, CoffeeSystemError>),
+    ) {
+        let mut metrics = match self.metrics.lock() {
+            Ok(metrics) => metrics,
+            Err(e) => {
+                error!("[METRICS] Error recording call {}, {}", call, e);
+                return;
+            }
+        };
+        metrics.call_finished(self.dispenser, call, started_at.elapsed());
+        let stats = metrics.dispenser(self.dispenser);
+        if let Err(
+            CoffeeSystemError::ConnectionLost
+            | CoffeeSystemError::ConnectionClosed
+            | CoffeeSystemError::Offline,
+        ) = result
+        {
+            stats.connection_errors += 1;
+            return;
+        }
+        on_result(stats, result);
+    }
+}
+
+#[async_trait]
+impl LocalServerClient for MeteredClient {
+    fn next_request_id(&self) -> RequestId {
+        self.inner.next_request_id()
+    }
+
+    async fn add_points(
+        &self,
+        account_id: usize,
+        points: usize,
+        request_id: RequestId,
+    ) -> Result<(), CoffeeSystemError> {
+        let started_at = Instant::now();
+        let result = self.inner.add_points(account_id, points, request_id).await;
+        self.record("add_points", started_at, &result, |stats, result| {
+            if result.is_ok() {
+                stats.cash_orders_credited += 1;
+            }
+        });
+        result
+    }
+
+    async fn request_points(
+        &self,
+        account_id: usize,
+        points: usize,
+    ) -> Result<(), CoffeeSystemError> {
+        let started_at = Instant::now();
+        let result = self.inner.request_points(account_id, points).await;
+        self.record("request_points", started_at, &result, |stats, result| {
+            if let Err(e) = result {
+                stats.point_order_rejected(e);
+            }
+        });
+        result
+    }
+
+    async fn take_points(&self, account_id: usize, points: usize) -> Result<(), CoffeeSystemError> {
+        let started_at = Instant::now();
+        let result = self.inner.take_points(account_id, points).await;
+        self.record("take_points", started_at, &result, |stats, result| {
+            if result.is_ok() {
+                stats.point_orders_completed += 1;
+            }
+        });
+        result
+    }
+
+    async fn cancel_point_request(&self, account_id: usize) -> Result<(), CoffeeSystemError> {
+        let started_at = Instant::now();
+        let result = self.inner.cancel_point_request(account_id).await;
+        self.record(
+            "cancel_point_request",
+            started_at,
+            &result,
+            |stats, result| {
+                if result.is_ok() {
+                    stats.point_orders_cancelled += 1;
+                }
+            },
+        );
+        result
+    }
+
+    async fn get_balance(&self, account_id: usize) -> Result<AccountBalance, CoffeeSystemError> {
+        let started_at = Instant::now();
+        let result = self.inner.get_balance(account_id).await;
+        self.record("get_balance", started_at, &result, |_, _| {});
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::local_server_client::MockLocalServerClient;
+
+    use super::*;
+
+    #[test]
+    fn should_add_up_the_counters_of_every_dispenser_in_the_totals() {
+        let mut metrics = RunMetrics::new();
+        metrics.order_read(0);
+        metrics.order_read(0);
+        metrics.order_read(1);
+        metrics.parse_failure(1);
+        metrics.dispenser(0).cash_orders_credited += 1;
+        metrics
+            .dispenser(0)
+            .point_order_rejected(&CoffeeSystemError::NotEnoughPoints);
+        metrics
+            .dispenser(1)
+            .point_order_rejected(&CoffeeSystemError::NotEnoughPoints);
+        metrics.call_finished(0, "add_points", Duration::from_millis(10));
+        metrics.call_finished(1, "add_points", Duration::from_millis(30));
+
+        let report = metrics.report("tests/orders.csv");
+        assert_eq!(3, report.totals.orders_read);
+        assert_eq!(1, report.totals.parse_failures);
+        assert_eq!(1, report.totals.cash_orders_credited);
+        assert_eq!(
+            Some(&2),
+            report.totals.point_orders_rejected.get("NotEnoughPoints")
+        );
+        let latency = &report.totals.latency["add_points"];
+        assert_eq!(2, latency.count);
+        assert_eq!(20.0, latency.mean_ms);
+        assert_eq!(10.0, latency.min_ms);
+        assert_eq!(30.0, latency.max_ms);
+        assert_eq!(2, report.dispensers.len());
+        assert_eq!(2, report.dispensers[&0].orders_read);
+    }
+
+    #[test]
+    fn should_write_the_report_as_json() {
+        let mut metrics = RunMetrics::new();
+        metrics.order_read(3);
+        let path =
+            std::env::temp_dir().join(format!("coffee_maker_report_{}.json", std::process::id()));
+        let path = path.to_string_lossy();
+
+        metrics
+            .report("tests/orders.csv")
+            .write(&path)
+            .expect("Error writing report");
+        let content = fs::read_to_string(path.as_ref()).expect("Error reading report");
+        let _ = fs::remove_file(path.as_ref());
+        let json: serde_json::Value = serde_json::from_str(&content).expect("Invalid JSON");
+        assert_eq!("tests/orders.csv", json["orders_file"]);
+        assert_eq!(1, json["totals"]["orders_read"]);
+        assert_eq!(1, json["dispensers"]["3"]["orders_read"]);
+    }
+
+    #[actix_rt::test]
+    async fn should_count_the_outcome_of_every_call_of_the_dispenser() {
+        let mut client = MockLocalServerClient::new();
+        client.expect_add_points().returning(|_, _, _| Ok(()));
+        client
+            .expect_request_points()
+            .returning(|account_id, _| match account_id {
+                1 => Ok(()),
+                2 => Err(CoffeeSystemError::NotEnoughPoints),
+                _ => Err(CoffeeSystemError::Offline),
+            });
+        client.expect_take_points().returning(|_, _| Ok(()));
+        client.expect_cancel_point_request().returning(|_| Ok(()));
+        let metrics = Arc::new(Mutex::new(RunMetrics::new()));
+        let client = MeteredClient::new(Box::new(client), metrics.clone(), 4);
+
+        assert!(client.add_points(1, 10, RequestId::default()).await.is_ok());
+        assert!(client.request_points(1, 10).await.is_ok());
+        assert!(client.take_points(1, 10).await.is_ok());
+        assert!(client.request_points(1, 10).await.is_ok());
+        assert!(client.cancel_point_request(1).await.is_ok());
+        assert!(client.request_points(2, 10).await.is_err());
+        assert!(client.request_points(3, 10).await.is_err());
+
+        let report = metrics
+            .lock()
+            .expect("Lock error")
+            .report("tests/orders.csv");
+        let stats = &report.dispensers[&4];
+        assert_eq!(1, stats.cash_orders_credited);
+        assert_eq!(1, stats.point_orders_completed);
+        assert_eq!(1, stats.point_orders_cancelled);
+        assert_eq!(
+            BTreeMap::from([(String::from("NotEnoughPoints"), 1)]),
+            stats.point_orders_rejected
+        );
+        assert_eq!(1, stats.connection_errors);
+        assert_eq!(4, stats.latency["request_points"].count);
+        assert_eq!(1, stats.latency["add_points"].count);
+    }
+}
@@ -0,0 +1,67 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{hybrid_timestamp::HybridTimestamp, local_connection_messages::CoffeeMakerRequest};
+
+/// Representa un pedido desde la consola de administración hacia un servidor local.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum AdminRequest {
+    /// Estado del servidor en el anillo
+    Status,
+    /// Saldo de todas las cuentas segun la base local
+    ListAccounts,
+    /// Saldo de una cuenta segun la base local
+    GetAccount(usize),
+    /// Pedidos de las cafeteras que esperan al token
+    Queue,
+    /// Pide al servidor que se retire del anillo de forma ordenada y termine
+    Shutdown,
+}
+
+/// Representa una respuesta de un servidor local a la consola de administración.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum AdminResponse {
+    Status(ServerStatus),
+    Accounts(Vec<AccountSummary>),
+    /// La cuenta pedida, o None si el servidor no la conoce
+    Account(Option<AccountSummary>),
+    Queue(Vec<QueuedOrder>),
+    /// El servidor aviso al anillo que se va, termina luego de pasar el token si lo tiene
+    ShuttingDown,
+}
+
+/// Estado de un servidor en el anillo. Cada servidor solo sabe si el token lo tiene él, para saber quién lo tiene
+/// hay que consultar a todos.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ServerStatus {
+    pub id: usize,
+    pub prev_online: bool,
+    pub next_online: bool,
+    /// Servidor del que se reciben los mensajes del anillo, si ya se conoce
+    pub listening_to_id: Option<usize>,
+    /// Servidor al que se envian los mensajes del anillo, si se pudo conectar
+    pub next_id: Option<usize>,
+    /// Si este servidor tiene el token. Cada servidor solo sabe si lo tiene el, no quien lo tiene
+    pub have_token: bool,
+    /// Ultima generacion del token que vio el servidor
+    pub token_generation: u64,
+    /// Servidor que gano la ultima eleccion, si se conoce
+    pub leader: Option<usize>,
+    /// Miembros activos del anillo segun la vista del servidor
+    pub members: Vec<usize>,
+}
+
+/// Saldo de una cuenta segun la base local del servidor que responde.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct AccountSummary {
+    pub id: usize,
+    pub points: usize,
+    pub last_updated_on: HybridTimestamp,
+}
+
+/// Pedido de una cafetera que todavia no se proceso.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct QueuedOrder {
+    /// Id de la conexion de la cafetera en el servidor
+    pub coffee_maker_id: usize,
+    pub request: CoffeeMakerRequest,
+}
@@ -0,0 +1,153 @@
+use async_std::io::{prelude::BufReadExt, BufRead, ReadExt, Write, WriteExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::common_errors::CoffeeSystemError;
+
+/// Tamaño máximo de un mensaje con prefijo de longitud. Evita reservar memoria de más si llega un prefijo inválido.
+pub const MAX_FRAME_LENGTH: usize = 64 * 1024 * 1024;
+
+/// Formatos de mensaje que soporta este nodo, en orden de preferencia.
+pub const SUPPORTED_CODECS: [Codec; 2] = [Codec::LengthPrefixed, Codec::JsonLine];
+
+/// Codec representa el formato con el que se codifican y delimitan los mensajes en una conexión.
+/// Se negocia en el handshake al establecer la conexión: el cliente ofrece los que soporta y el servidor elige uno.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    /// JSON terminado en salto de línea. Es el formato original y el que se usa en el handshake.
+    #[default]
+    JsonLine,
+    /// MessagePack precedido por su longitud en 4 bytes big endian. Es más compacto y el mensaje
+    /// puede contener cualquier byte.
+    LengthPrefixed,
+}
+
+impl Codec {
+    /// Codifica un mensaje, sin delimitarlo.
+    pub fn encode<T>(&self, message: &T) -> Result<Vec<u8>, CoffeeSystemError>
+    where
+        T: Serialize + ?Sized,
+    {
+        match self {
+            Codec::JsonLine => Ok(serde_json::to_vec(message)?),
+            Codec::LengthPrefixed => {
+                rmp_serde::to_vec_named(message).map_err(|_| CoffeeSystemError::SerializationError)
+            }
+        }
+    }
+
+    /// Decodifica un mensaje a partir de los bytes recibidos, sin el delimitador.
+    pub fn decode<T>(&self, payload: &[u8]) -> Result<T, CoffeeSystemError>
+    where
+        T: DeserializeOwned,
+    {
+        match self {
+            Codec::JsonLine => Ok(serde_json::from_slice(payload)?),
+            Codec::LengthPrefixed => {
+                rmp_serde::from_slice(payload).map_err(|_| CoffeeSystemError::SerializationError)
+            }
+        }
+    }
+
+    /// Escribe un mensaje ya codificado agregándole el delimitador del codec.
+    pub async fn write_frame<W>(&self, writer: &mut W, payload: &[u8]) -> std::io::Result<()>
+    where
+        W: Write + Unpin,
+    {
+        let mut frame = Vec::with_capacity(payload.len() + 4);
+        match self {
+            Codec::JsonLine => {
+                frame.extend_from_slice(payload);
+                frame.push(b'\n');
+            }
+            Codec::LengthPrefixed => {
+                let length = u32::try_from(payload.len()).map_err(|_| invalid_length())?;
+                frame.extend_from_slice(&length.to_be_bytes());
+                frame.extend_from_slice(payload);
+            }
+        }
+        writer.write_all(&frame).await
+    }
+
+    /// Lee el próximo mensaje y lo devuelve sin el delimitador. Devuelve None si la conexión se cerró.
+    pub async fn read_frame<R>(&self, reader: &mut R) -> std::io::Result<Option<Vec<u8>>>
+    where
+        R: BufRead + Unpin,
+    {
+        match self {
+            Codec::JsonLine => {
+                let mut payload = vec![];
+                if reader.read_until(b'\n', &mut payload).await? == 0 {
+                    return Ok(None);
+                }
+                if payload.last() == Some(&b'\n') {
+                    payload.pop();
+                }
+                Ok(Some(payload))
+            }
+            Codec::LengthPrefixed => {
+                let mut length = [0; 4];
+                if let Err(e) = reader.read_exact(&mut length).await {
+                    return match e.kind() {
+                        std::io::ErrorKind::UnexpectedEof => Ok(None),
+                        _ => Err(e),
+                    };
+                }
+                let length = u32::from_be_bytes(length) as usize;
+                if length > MAX_FRAME_LENGTH {
+                    return Err(invalid_length());
+                }
+                let mut payload = vec![0; length];
+                reader.read_exact(&mut payload).await?;
+                Ok(Some(payload))
+            }
+        }
+    }
+}
+
+fn invalid_length() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid frame length")
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::{io::BufReader, task};
+
+    use super::*;
+    use crate::local_connection_messages::{CoffeeMakerRequest, MessageType, RequestId};
+
+    fn request(account_id: usize) -> CoffeeMakerRequest {
+        CoffeeMakerRequest {
+            message_type: MessageType::AddPoints,
+            account_id,
+            points: 10,
+            request_id: RequestId {
+                dispenser_id: 1,
+                sequence: account_id as u64,
+            },
+        }
+    }
+
+    #[test]
+    fn should_read_back_the_written_messages_with_both_codecs() {
+        for codec in SUPPORTED_CODECS {
+            let mut wire = vec![];
+            for account_id in 0..3 {
+                let payload = codec.encode(&request(account_id)).expect("Error encoding");
+                task::block_on(codec.write_frame(&mut wire, &payload)).expect("Error writing");
+            }
+
+            let mut reader = BufReader::new(&wire[..]);
+            for account_id in 0..3 {
+                let payload = task::block_on(codec.read_frame(&mut reader))
+                    .expect("Error reading")
+                    .expect("Missing message");
+                let decoded: CoffeeMakerRequest = codec.decode(&payload).expect("Error decoding");
+                assert_eq!(account_id, decoded.account_id);
+                assert_eq!(account_id as u64, decoded.request_id.sequence);
+            }
+            assert!(task::block_on(codec.read_frame(&mut reader))
+                .expect("Error reading")
+                .is_none());
+        }
+    }
+}
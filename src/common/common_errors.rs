@@ -10,6 +10,9 @@ pub enum CoffeeSystemError {
     SerializationError,
     UnexpectedError,
     AccountIsReserved,
+    Offline,
+    IncompatibleProtocol,
+    ReservationNotFound,
 }
 
 impl From<serde_json::Error> for CoffeeSystemError {
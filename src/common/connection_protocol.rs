@@ -1,14 +1,21 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Duration};
 
-use async_std::{
-    io::{prelude::BufReadExt, BufReader, WriteExt},
-    net::TcpStream,
-    task,
-};
+use async_std::{future, io::BufReader, net::TcpStream, task};
 use async_trait::async_trait;
-use log::{error, info};
+use log::{error, info, warn};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    codec::Codec,
+    common_errors::CoffeeSystemError,
+    handshake::{HandshakeRejection, Hello, HelloReply, NodeKind, Peer},
+};
 
-use crate::common_errors::CoffeeSystemError;
+pub use crate::memory_connection::{MemoryConnection, MemoryListener, MemoryNetwork};
+pub use crate::udp_connection::{UdpConfig, UdpConnection, UdpListener};
+
+/// Tiempo máximo para el handshake al establecer una conexión.
+pub const HANDSHAKE_TIMEOUT_IN_MS: u64 = 5000;
 
 use mockall::automock;
 
@@ -17,8 +24,51 @@ use mockall::automock;
 /// Trait que representa la capa de conexión del sistema. Quienes la implementen podrán tanto enviar
 /// como recibir mensajes del protocolo.
 pub trait ConnectionProtocol {
+    /// Envía un mensaje ya codificado, la conexión se encarga de delimitarlo.
     async fn send(&mut self, data: &[u8]) -> Result<(), CoffeeSystemError>;
-    async fn recv(&mut self) -> Result<String, CoffeeSystemError>;
+    /// Devuelve el próximo mensaje recibido, sin el delimitador.
+    async fn recv(&mut self) -> Result<Vec<u8>, CoffeeSystemError>;
+    /// Devuelve el codec negociado para la conexión, con el que se codifican los mensajes.
+    fn codec(&self) -> Codec;
+    /// Devuelve lo acordado en el handshake con el otro extremo: su id, la versión y las funcionalidades en común.
+    fn peer(&self) -> Peer;
+    /// Devuelve otra conexión sobre el mismo canal. Permite enviar mensajes desde un hilo mientras otro espera recibirlos.
+    fn try_clone(&self) -> Result<Box<dyn ConnectionProtocol + Send + Sync>, CoffeeSystemError>;
+}
+
+/// Protocolo de transporte de las conexiones, tanto del anillo como de las cafeteras. Se elige por configuración.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Transport {
+    #[default]
+    Tcp,
+    Udp(UdpConfig),
+}
+
+impl Transport {
+    /// Devuelve el transporte a partir de su nombre, `tcp` o `udp`.
+    pub fn from_name(name: &str) -> Option<Transport> {
+        match name {
+            "tcp" => Some(Transport::Tcp),
+            "udp" => Some(Transport::Udp(UdpConfig::default())),
+            _ => None,
+        }
+    }
+
+    /// Abre una conexión con el servidor IP:PUERTO indicado y hace el handshake.
+    pub fn connect(
+        &self,
+        server_addr: &String,
+        hello: &Hello,
+    ) -> Result<Box<dyn ConnectionProtocol + Send + Sync>, CoffeeSystemError> {
+        Ok(match self {
+            Transport::Tcp => Box::new(TcpConnection::new_client_connection(server_addr, hello)?),
+            Transport::Udp(config) => Box::new(UdpConnection::new_client_connection(
+                server_addr,
+                hello,
+                *config,
+            )?),
+        })
+    }
 }
 
 /// Representa una conexión TCP, ya sea entre servidores o entre un servidor y una cafetera.
@@ -26,12 +76,18 @@ pub struct TcpConnection {
     writer: TcpStream,
     reader: BufReader<TcpStream>,
     addr: SocketAddr,
+    codec: Codec,
+    peer: Peer,
 }
 
 impl TcpConnection {
     /// Devuelve un nuevo cliente TcpConnection a partir de una dirección de servidor IP:PUERTO en caso de éxito, o
-    /// error de no poder establecer la conexión.
-    pub fn new_client_connection(server_addr: &String) -> Result<TcpConnection, CoffeeSystemError> {
+    /// error de no poder establecer la conexión. Al conectarse se envía el saludo de este nodo y se espera la
+    /// respuesta con la versión, las funcionalidades y el codec acordados.
+    pub fn new_client_connection(
+        server_addr: &String,
+        hello: &Hello,
+    ) -> Result<TcpConnection, CoffeeSystemError> {
         let result = task::block_on(TcpStream::connect(&server_addr));
         match result {
             Err(e) => {
@@ -46,32 +102,203 @@ impl TcpConnection {
                     "[TCP CONNECTION] Established connection to local server {}",
                     server_addr
                 );
-                Ok(TcpConnection {
-                    writer: stream.clone(),
-                    addr: stream.peer_addr()?,
-                    reader: BufReader::new(stream),
-                })
+                let addr = stream.peer_addr()?;
+                let mut writer = stream.clone();
+                let mut reader = BufReader::new(stream);
+                let mut channel = TcpHandshake {
+                    writer: &mut writer,
+                    reader: &mut reader,
+                };
+                let peer =
+                    task::block_on(client_handshake(&mut channel, hello, &addr.to_string()))?;
+                Ok(TcpConnection::new(writer, reader, addr, peer))
             }
         }
     }
 
-    /// Devuelve un nuevo TcpConnection a modo de servidor.
-    pub fn new_server_connection(stream: TcpStream, addr: SocketAddr) -> TcpConnection {
+    /// Devuelve un nuevo TcpConnection a modo de servidor. Espera el saludo del cliente y le responde con lo
+    /// acordado, o con el motivo del rechazo si no es del tipo de nodo esperado o no tienen una versión en común.
+    pub async fn new_server_connection(
+        stream: TcpStream,
+        addr: SocketAddr,
+        hello: &Hello,
+        accepted: NodeKind,
+    ) -> Result<TcpConnection, CoffeeSystemError> {
+        let mut writer = stream.clone();
+        let mut reader = BufReader::new(stream);
+        let mut channel = TcpHandshake {
+            writer: &mut writer,
+            reader: &mut reader,
+        };
+        let peer = server_handshake(&mut channel, hello, accepted, &addr.to_string()).await?;
+        Ok(TcpConnection::new(writer, reader, addr, peer))
+    }
+
+    fn new(
+        writer: TcpStream,
+        reader: BufReader<TcpStream>,
+        addr: SocketAddr,
+        peer: Peer,
+    ) -> TcpConnection {
         TcpConnection {
-            writer: stream.clone(),
-            reader: BufReader::new(stream),
+            writer,
+            reader,
             addr,
+            codec: peer.codec,
+            peer,
         }
     }
 }
 
+/// Canal por el que se envían los mensajes del handshake, antes de acordar el codec.
+/// Los mensajes del handshake siempre se codifican en JSON.
+#[async_trait]
+pub(crate) trait HandshakeChannel: Send {
+    async fn send_handshake(&mut self, payload: &[u8]) -> Result<(), CoffeeSystemError>;
+    async fn recv_handshake(&mut self) -> Result<Vec<u8>, CoffeeSystemError>;
+}
+
+struct TcpHandshake<'a> {
+    writer: &'a mut TcpStream,
+    reader: &'a mut BufReader<TcpStream>,
+}
+
+#[async_trait]
+impl HandshakeChannel for TcpHandshake<'_> {
+    async fn send_handshake(&mut self, payload: &[u8]) -> Result<(), CoffeeSystemError> {
+        Ok(Codec::JsonLine.write_frame(self.writer, payload).await?)
+    }
+
+    async fn recv_handshake(&mut self) -> Result<Vec<u8>, CoffeeSystemError> {
+        Codec::JsonLine
+            .read_frame(self.reader)
+            .await?
+            .ok_or(CoffeeSystemError::ConnectionClosed)
+    }
+}
+
+/// Envía el saludo de este nodo y espera la respuesta del servidor. Devuelve lo acordado, o
+/// `IncompatibleProtocol` si el servidor rechaza la conexión.
+pub(crate) async fn client_handshake<C>(
+    channel: &mut C,
+    hello: &Hello,
+    addr: &str,
+) -> Result<Peer, CoffeeSystemError>
+where
+    C: HandshakeChannel + ?Sized,
+{
+    let handshake = async {
+        channel
+            .send_handshake(&Codec::JsonLine.encode(hello)?)
+            .await?;
+        let reply: HelloReply = Codec::JsonLine.decode(&channel.recv_handshake().await?)?;
+        match reply {
+            HelloReply::Accepted(reply) => Ok(hello.agree(&reply)),
+            HelloReply::Rejected(rejection) => Ok(Err(rejection)),
+        }
+    };
+    match with_handshake_timeout(handshake).await? {
+        Ok(peer) => {
+            info!(
+                "[CONNECTION] Handshake with {:?} at {}, protocol version {}, features {:?}, codec {:?}",
+                peer.node_id, addr, peer.protocol_version, peer.features, peer.codec
+            );
+            Ok(peer)
+        }
+        Err(rejection) => {
+            error!(
+                "[CONNECTION] Server {} rejected the connection, {}",
+                addr, rejection
+            );
+            Err(CoffeeSystemError::IncompatibleProtocol)
+        }
+    }
+}
+
+/// Espera el saludo del cliente y le responde con lo acordado, o con el motivo del rechazo si no es del tipo
+/// de nodo esperado o no tienen una versión en común.
+pub(crate) async fn server_handshake<C>(
+    channel: &mut C,
+    hello: &Hello,
+    accepted: NodeKind,
+    addr: &str,
+) -> Result<Peer, CoffeeSystemError>
+where
+    C: HandshakeChannel + ?Sized,
+{
+    let handshake = async {
+        let client: Hello = Codec::JsonLine.decode(&channel.recv_handshake().await?)?;
+        let agreed = if client.node_id.kind() != accepted {
+            Err(HandshakeRejection::UnexpectedNode(client.node_id))
+        } else {
+            hello.agree(&client)
+        };
+        let reply = match &agreed {
+            Ok(peer) => HelloReply::Accepted(hello.reply(peer)),
+            Err(rejection) => HelloReply::Rejected(rejection.clone()),
+        };
+        channel
+            .send_handshake(&Codec::JsonLine.encode(&reply)?)
+            .await?;
+        Ok(agreed.map_err(|rejection| (client, rejection)))
+    };
+    match with_handshake_timeout(handshake).await {
+        Ok(Ok(peer)) => Ok(peer),
+        Ok(Err((client, rejection))) => {
+            error!(
+                "[CONNECTION] Rejecting {:?} at {}, it supports protocol versions {} to {}, {}",
+                client.node_id,
+                addr,
+                client.min_protocol_version,
+                client.protocol_version,
+                rejection
+            );
+            Err(CoffeeSystemError::IncompatibleProtocol)
+        }
+        Err(e) => {
+            warn!("[CONNECTION] Handshake with {} failed, {:?}", addr, e);
+            Err(e)
+        }
+    }
+}
+
+/// Espera el handshake hasta HANDSHAKE_TIMEOUT_IN_MS, si no termina se pierde la conexión.
+async fn with_handshake_timeout<F, T>(handshake: F) -> Result<T, CoffeeSystemError>
+where
+    F: std::future::Future<Output = Result<T, CoffeeSystemError>>,
+{
+    future::timeout(Duration::from_millis(HANDSHAKE_TIMEOUT_IN_MS), handshake)
+        .await
+        .map_err(|_| CoffeeSystemError::ConnectionLost)?
+}
+
+/// Codifica un mensaje con el codec de la conexión y lo envía.
+pub async fn send_message<C, T>(connection: &mut C, message: &T) -> Result<(), CoffeeSystemError>
+where
+    C: ConnectionProtocol + ?Sized,
+    T: Serialize + Sync + ?Sized,
+{
+    let encoded = connection.codec().encode(message)?;
+    connection.send(&encoded).await
+}
+
+/// Recibe el próximo mensaje de la conexión y lo decodifica con su codec.
+pub async fn recv_message<C, T>(connection: &mut C) -> Result<T, CoffeeSystemError>
+where
+    C: ConnectionProtocol + ?Sized,
+    T: DeserializeOwned,
+{
+    let encoded = connection.recv().await?;
+    connection.codec().decode(&encoded)
+}
+
 #[async_trait]
 impl ConnectionProtocol for TcpConnection {
     /// Envía un array de bytes a través de la conexión TCP, normalmente la serialización de
-    /// un struct de Request/Response o de mensaje entre servidores. Devuelve un error en caso de
-    /// haber perdido la conexión.
+    /// un struct de Request/Response o de mensaje entre servidores, delimitado según el codec.
+    /// Devuelve un error en caso de haber perdido la conexión.
     async fn send(&mut self, data: &[u8]) -> Result<(), CoffeeSystemError> {
-        match self.writer.write_all(data).await {
+        match self.codec.write_frame(&mut self.writer, data).await {
             Ok(()) => Ok(()),
             Err(error) => {
                 error!(
@@ -84,22 +311,19 @@ impl ConnectionProtocol for TcpConnection {
             }
         }
     }
-    /// Devuelve un buffer en forma de string del cuál podremos deserializar mensajes entrantes a
-    /// la conexión TCP. Devuelve un error en caso de haber perdido la conexión o en el caso de que
+    /// Devuelve el próximo mensaje entrante a la conexión TCP, del cuál podremos deserializar con el codec.
+    /// Devuelve un error en caso de haber perdido la conexión o en el caso de que
     /// haya sido cerrada intencionalmente del otro lado.
-    async fn recv(&mut self) -> Result<String, CoffeeSystemError> {
-        let mut buffer = String::new();
-        match self.reader.read_line(&mut buffer).await {
-            Ok(read) => {
-                if read == 0 {
-                    info!(
-                        "[TCP CONNECTION] Closed connection {} {}",
-                        self.addr.ip(),
-                        self.addr.port()
-                    );
-                    return Err(CoffeeSystemError::ConnectionClosed);
-                }
-                Ok(buffer)
+    async fn recv(&mut self) -> Result<Vec<u8>, CoffeeSystemError> {
+        match self.codec.read_frame(&mut self.reader).await {
+            Ok(Some(buffer)) => Ok(buffer),
+            Ok(None) => {
+                info!(
+                    "[TCP CONNECTION] Closed connection {} {}",
+                    self.addr.ip(),
+                    self.addr.port()
+                );
+                Err(CoffeeSystemError::ConnectionClosed)
             }
             Err(error) => {
                 error!(
@@ -112,4 +336,124 @@ impl ConnectionProtocol for TcpConnection {
             }
         }
     }
+
+    /// Devuelve otra conexión TCP sobre el mismo socket. Cada una tiene su propio buffer de lectura,
+    /// por lo que solo una de ellas debe recibir mensajes.
+    fn try_clone(&self) -> Result<Box<dyn ConnectionProtocol + Send + Sync>, CoffeeSystemError> {
+        Ok(Box::new(TcpConnection {
+            writer: self.writer.clone(),
+            reader: BufReader::new(self.writer.clone()),
+            addr: self.addr,
+            codec: self.codec,
+            peer: self.peer.clone(),
+        }))
+    }
+
+    fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    fn peer(&self) -> Peer {
+        self.peer.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        codec::SUPPORTED_CODECS,
+        handshake::{NodeId, PROTOCOL_VERSION},
+    };
+
+    /// Servidor que acepta una conexion de cafetera y devuelve el mensaje recibido.
+    /// Devuelve lo acordado en el handshake, o el error si lo rechazo
+    fn echo_server(
+        listener: TcpListener,
+    ) -> std::thread::JoinHandle<Result<Peer, CoffeeSystemError>> {
+        std::thread::spawn(move || {
+            task::block_on(async {
+                let (stream, addr) = listener.accept().await.expect("Error accepting");
+                let hello = Hello::new(NodeId::Server(0));
+                let mut connection = TcpConnection::new_server_connection(
+                    stream,
+                    addr,
+                    &hello,
+                    NodeKind::CoffeeMaker,
+                )
+                .await?;
+                let message: Vec<String> = recv_message(&mut connection).await?;
+                send_message(&mut connection, &message).await?;
+                Ok(connection.peer())
+            })
+        })
+    }
+
+    fn listen() -> (
+        String,
+        std::thread::JoinHandle<Result<Peer, CoffeeSystemError>>,
+    ) {
+        let listener = task::block_on(TcpListener::bind("127.0.0.1:0")).expect("Error binding");
+        let address = listener.local_addr().expect("No address").to_string();
+        (address, echo_server(listener))
+    }
+
+    #[test]
+    fn should_use_the_first_offered_codec_and_keep_messages_with_newlines() {
+        let offers: [&[Codec]; 2] = [&SUPPORTED_CODECS, &[Codec::JsonLine]];
+        for codecs in offers {
+            let (address, server) = listen();
+            let hello = Hello {
+                codecs: codecs.to_vec(),
+                ..Hello::new(NodeId::CoffeeMaker(7))
+            };
+
+            let mut connection =
+                TcpConnection::new_client_connection(&address, &hello).expect("Error connecting");
+            let message = vec!["first\nline".to_string(), "second".to_string()];
+            task::block_on(send_message(&mut connection, &message)).expect("Error sending");
+            let echoed: Vec<String> =
+                task::block_on(recv_message(&mut connection)).expect("Error receiving");
+
+            assert_eq!(message, echoed);
+            assert_eq!(codecs[0], connection.codec());
+            assert_eq!(NodeId::Server(0), connection.peer().node_id);
+            let server_peer = server.join().expect("Server failed").expect("Rejected");
+            assert_eq!(codecs[0], server_peer.codec);
+            assert_eq!(NodeId::CoffeeMaker(7), server_peer.node_id);
+        }
+    }
+
+    #[test]
+    fn should_reject_a_client_without_a_common_protocol_version() {
+        let (address, server) = listen();
+        let hello = Hello {
+            protocol_version: PROTOCOL_VERSION + 2,
+            min_protocol_version: PROTOCOL_VERSION + 1,
+            ..Hello::new(NodeId::CoffeeMaker(7))
+        };
+
+        let result = TcpConnection::new_client_connection(&address, &hello);
+
+        assert_eq!(Some(CoffeeSystemError::IncompatibleProtocol), result.err());
+        assert_eq!(
+            Some(CoffeeSystemError::IncompatibleProtocol),
+            server.join().expect("Server failed").err()
+        );
+    }
+
+    #[test]
+    fn should_reject_a_node_of_another_kind() {
+        let (address, server) = listen();
+
+        let result = TcpConnection::new_client_connection(&address, &Hello::new(NodeId::Server(3)));
+
+        assert_eq!(Some(CoffeeSystemError::IncompatibleProtocol), result.err());
+        assert_eq!(
+            Some(CoffeeSystemError::IncompatibleProtocol),
+            server.join().expect("Server failed").err()
+        );
+    }
 }
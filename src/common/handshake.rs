@@ -0,0 +1,285 @@
+use std::fmt;
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::codec::{Codec, SUPPORTED_CODECS};
+
+/// Version del protocolo que implementa este nodo. Se incrementa ante cambios en los mensajes que
+/// una version anterior no puede leer. En la version 2 la generacion del token incluye al servidor que lo genero.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Version mas vieja del protocolo con la que este nodo todavia puede comunicarse. A un servidor de la version 1
+/// se le envia la generacion del token solo con su contador.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Funcionalidades opcionales que soporta este nodo. Solo se envian los mensajes que las usan a quien tambien las soporta.
+pub const SUPPORTED_FEATURES: [Feature; 2] = [Feature::BalanceQuery, Feature::DynamicMembership];
+
+/// Identifica a quien abre o acepta una conexión.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NodeId {
+    /// Servidor local, con su id en la red.
+    Server(usize),
+    /// Cafetera, con el id al azar de su ejecucion.
+    CoffeeMaker(u32),
+    /// Consola de administración, con el id al azar de su ejecucion.
+    Admin(u32),
+}
+
+/// Tipo de nodo, permite que cada listener acepte solo las conexiones que le corresponden.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    Server,
+    CoffeeMaker,
+    Admin,
+}
+
+impl NodeId {
+    pub fn kind(&self) -> NodeKind {
+        match self {
+            NodeId::Server(_) => NodeKind::Server,
+            NodeId::CoffeeMaker(_) => NodeKind::CoffeeMaker,
+            NodeId::Admin(_) => NodeKind::Admin,
+        }
+    }
+}
+
+/// Funcionalidad opcional del protocolo. Viaja como texto para que un nodo pueda leer las que agregue una version
+/// mas nueva, que quedan como `Unknown`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(from = "String", into = "String")]
+pub enum Feature {
+    /// Consulta de saldo de la cafetera (`MessageType::QueryBalance`).
+    BalanceQuery,
+    /// Mensajes `Join`, `JoinAccepted` y `Leave` para cambiar los miembros del anillo.
+    DynamicMembership,
+    Unknown(String),
+}
+
+impl From<String> for Feature {
+    fn from(name: String) -> Self {
+        match name.as_str() {
+            "balance_query" => Feature::BalanceQuery,
+            "dynamic_membership" => Feature::DynamicMembership,
+            _ => Feature::Unknown(name),
+        }
+    }
+}
+
+impl From<Feature> for String {
+    fn from(feature: Feature) -> Self {
+        match feature {
+            Feature::BalanceQuery => "balance_query".to_string(),
+            Feature::DynamicMembership => "dynamic_membership".to_string(),
+            Feature::Unknown(name) => name,
+        }
+    }
+}
+
+/// Primer mensaje de una conexión, lo envían ambos lados. Siempre se codifica como JSON terminado en salto de línea,
+/// y los campos desconocidos se ignoran para que versiones nuevas puedan agregar datos.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub min_protocol_version: u32,
+    pub node_id: NodeId,
+    #[serde(default)]
+    pub features: Vec<Feature>,
+    /// Codecs soportados en orden de preferencia. Quien acepta la conexión responde solo con el elegido
+    #[serde(default, deserialize_with = "known_codecs")]
+    pub codecs: Vec<Codec>,
+}
+
+/// Descarta los codecs que agregue una version mas nueva, asi se puede elegir entre los demas
+fn known_codecs<'de, D>(deserializer: D) -> Result<Vec<Codec>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let codecs = Vec::<serde_json::Value>::deserialize(deserializer)?;
+    Ok(codecs
+        .into_iter()
+        .filter_map(|codec| serde_json::from_value(codec).ok())
+        .collect())
+}
+
+/// Respuesta de quien acepta la conexión al `Hello` del cliente.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum HelloReply {
+    Accepted(Hello),
+    Rejected(HandshakeRejection),
+}
+
+/// Motivo por el que se rechaza una conexión durante el handshake.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum HandshakeRejection {
+    /// No hay ninguna version del protocolo que soporten ambos. Lleva el rango que soporta quien rechaza
+    IncompatibleVersion {
+        min_protocol_version: u32,
+        protocol_version: u32,
+    },
+    /// El nodo no es del tipo que espera el listener, por ejemplo una cafetera conectándose al puerto del anillo
+    UnexpectedNode(NodeId),
+}
+
+impl fmt::Display for HandshakeRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeRejection::IncompatibleVersion {
+                min_protocol_version,
+                protocol_version,
+            } => write!(
+                f,
+                "incompatible protocol version, the peer supports versions {} to {} and this node {} to {}",
+                min_protocol_version, protocol_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+            HandshakeRejection::UnexpectedNode(node_id) => {
+                write!(f, "unexpected node {:?} for this listener", node_id)
+            }
+        }
+    }
+}
+
+/// Lo acordado con el otro extremo de una conexión.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub node_id: NodeId,
+    /// Version del protocolo a usar en la conexión, la mas nueva que soportan ambos
+    pub protocol_version: u32,
+    /// Funcionalidades que soportan ambos
+    pub features: Vec<Feature>,
+    pub codec: Codec,
+}
+
+impl Peer {
+    pub fn supports(&self, feature: &Feature) -> bool {
+        self.features.contains(feature)
+    }
+}
+
+impl Hello {
+    /// Devuelve el saludo de este nodo, con las versiones, funcionalidades y codecs que soporta.
+    pub fn new(node_id: NodeId) -> Hello {
+        Hello {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            node_id,
+            features: SUPPORTED_FEATURES.to_vec(),
+            codecs: SUPPORTED_CODECS.to_vec(),
+        }
+    }
+
+    /// Acuerda con el saludo del otro extremo la version mas nueva que soportan ambos, las funcionalidades en
+    /// comun y el codec. Devuelve el motivo del rechazo si no hay una version en comun.
+    pub fn agree(&self, peer: &Hello) -> Result<Peer, HandshakeRejection> {
+        let protocol_version = self.protocol_version.min(peer.protocol_version);
+        if protocol_version < self.min_protocol_version.max(peer.min_protocol_version) {
+            return Err(HandshakeRejection::IncompatibleVersion {
+                min_protocol_version: self.min_protocol_version,
+                protocol_version: self.protocol_version,
+            });
+        }
+        let features = self
+            .features
+            .iter()
+            .filter(|feature| peer.features.contains(feature))
+            .cloned()
+            .collect();
+        let codec = peer
+            .codecs
+            .iter()
+            .find(|codec| self.codecs.contains(codec))
+            .copied()
+            .unwrap_or_default();
+        Ok(Peer {
+            node_id: peer.node_id,
+            protocol_version,
+            features,
+            codec,
+        })
+    }
+
+    /// Respuesta al saludo del cliente con lo acordado, solo con el codec elegido.
+    pub fn reply(&self, agreed: &Peer) -> Hello {
+        Hello {
+            protocol_version: agreed.protocol_version,
+            min_protocol_version: self.min_protocol_version,
+            node_id: self.node_id,
+            features: agreed.features.clone(),
+            codecs: vec![agreed.codec],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(min_protocol_version: u32, protocol_version: u32, features: Vec<Feature>) -> Hello {
+        Hello {
+            protocol_version,
+            min_protocol_version,
+            node_id: NodeId::Server(1),
+            features,
+            codecs: SUPPORTED_CODECS.to_vec(),
+        }
+    }
+
+    #[test]
+    fn should_agree_on_the_newest_common_version_and_features() {
+        let old = hello(1, 1, vec![Feature::BalanceQuery]);
+        let new = hello(
+            1,
+            2,
+            vec![Feature::BalanceQuery, Feature::DynamicMembership],
+        );
+
+        let agreed = new.agree(&old).expect("Rejected");
+
+        assert_eq!(1, agreed.protocol_version);
+        assert_eq!(vec![Feature::BalanceQuery], agreed.features);
+        assert_eq!(Codec::LengthPrefixed, agreed.codec);
+        assert_eq!(Ok(agreed.clone()), old.agree(&new.reply(&agreed)));
+    }
+
+    #[test]
+    fn should_agree_on_version_1_with_a_node_of_the_previous_version() {
+        let old = hello(1, 1, vec![Feature::BalanceQuery]);
+        let new = Hello::new(NodeId::Server(2));
+
+        let agreed = new.agree(&old).expect("Rejected");
+
+        assert_eq!(1, agreed.protocol_version);
+        assert_eq!(Ok(1), old.agree(&new).map(|peer| peer.protocol_version));
+    }
+
+    #[test]
+    fn should_reject_a_peer_without_a_common_version() {
+        let old = hello(1, 1, vec![]);
+        let new = hello(2, 3, vec![]);
+
+        assert_eq!(
+            Err(HandshakeRejection::IncompatibleVersion {
+                min_protocol_version: 2,
+                protocol_version: 3,
+            }),
+            new.agree(&old)
+        );
+    }
+
+    #[test]
+    fn should_read_a_hello_with_features_and_fields_from_a_newer_version() {
+        let encoded = r#"{"protocol_version":2,"min_protocol_version":1,"node_id":{"CoffeeMaker":7},"features":["balance_query","compression"],"codecs":["Zstd","JsonLine"],"compression":"zstd"}"#;
+
+        let decoded: Hello = serde_json::from_str(encoded).expect("Error deserializing");
+
+        assert_eq!(NodeId::CoffeeMaker(7), decoded.node_id);
+        assert_eq!(
+            vec![
+                Feature::BalanceQuery,
+                Feature::Unknown("compression".to_string())
+            ],
+            decoded.features
+        );
+        assert_eq!(vec![Codec::JsonLine], decoded.codecs);
+    }
+}
@@ -0,0 +1,22 @@
+use serde::{Deserialize, Serialize};
+
+/// Marca de tiempo de un reloj logico hibrido. Se compara primero por el tiempo fisico, luego por el contador
+/// logico y por ultimo por el id del servidor, de esta forma dos marcas de distintos servidores nunca son iguales
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default,
+)]
+pub struct HybridTimestamp {
+    pub physical: u64,
+    pub logical: u32,
+    pub node_id: usize,
+}
+
+impl HybridTimestamp {
+    pub fn new(physical: u64, logical: u32, node_id: usize) -> HybridTimestamp {
+        HybridTimestamp {
+            physical,
+            logical,
+            node_id,
+        }
+    }
+}
@@ -1,5 +1,11 @@
+pub mod admin_messages;
+pub mod codec;
 pub mod common_errors;
 pub mod connection_protocol;
+pub mod handshake;
+pub mod hybrid_timestamp;
 pub mod local_connection_messages;
 pub mod logger;
+pub mod memory_connection;
 pub mod serializer;
+pub mod udp_connection;
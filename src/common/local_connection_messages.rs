@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
 
-use crate::common_errors::CoffeeSystemError;
+use crate::{
+    common_errors::CoffeeSystemError, handshake::Feature, hybrid_timestamp::HybridTimestamp,
+};
 
 /// Representa un pedido desde la cafetera hacia el servidor local.
 #[derive(Debug, Serialize, Deserialize, Clone, Copy)]
@@ -8,20 +10,41 @@ pub struct CoffeeMakerRequest {
     pub message_type: MessageType,
     pub account_id: usize,
     pub points: usize,
+    pub request_id: RequestId,
 }
 
-/// Representa una respuesta desde el servidor local hacia la cafetera.
-#[derive(Deserialize, Serialize, Clone, Copy)]
+/// Representa una respuesta desde el servidor local hacia la cafetera. Lleva el id del pedido que responde
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
 pub struct CoffeeMakerResponse {
     pub message_type: MessageType,
     pub status: ResponseStatus,
+    pub request_id: RequestId,
+}
+
+/// Identificador de un pedido, lo genera la cafetera con el id del dispenser y un numero de secuencia.
+/// Los reintentos de un mismo pedido usan el mismo identificador, de esta forma el servidor no lo aplica dos veces
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct RequestId {
+    pub dispenser_id: u64,
+    pub sequence: u64,
 }
 
 /// Enumera los estados posibles de una CoffeeMakerResponse
-#[derive(Deserialize, Serialize, Copy, Clone)]
+#[derive(Debug, Deserialize, Serialize, Copy, Clone)]
 pub enum ResponseStatus {
     Ok,
     Err(CoffeeSystemError),
+    /// Respuesta a una consulta de saldo
+    Balance(AccountBalance),
+}
+
+/// Saldo de una cuenta segun la base local del servidor que responde. No se espera al token para responder,
+/// por lo que puede no incluir las operaciones de otros servidores que todavia no llegaron.
+/// La marca de la ultima actualizacion permite saber que tan reciente es el dato
+#[derive(Debug, Deserialize, Serialize, Copy, Clone, PartialEq, Eq)]
+pub struct AccountBalance {
+    pub points: usize,
+    pub last_updated_on: HybridTimestamp,
 }
 
 /// Enumera los distintos tipos de mensajes en la comunicación Cafetera<->Servidor
@@ -31,4 +54,15 @@ pub enum MessageType {
     RequestPoints,
     TakePoints,
     CancelPointsRequest,
+    QueryBalance,
+}
+
+impl MessageType {
+    /// Funcionalidad opcional del protocolo que debe soportar el servidor para poder leer el pedido
+    pub fn required_feature(&self) -> Option<Feature> {
+        match self {
+            MessageType::QueryBalance => Some(Feature::BalanceQuery),
+            _ => None,
+        }
+    }
 }
@@ -3,8 +3,13 @@ use std::env;
 /// Configura el nivel de logs en base a las variables de entorno. Devuelve error en caso de fallar
 /// con el nivel establecido o con el nivel default.
 pub fn set_logger_config() {
+    set_logger_config_with_default(log::Level::Debug);
+}
+
+/// Igual que `set_logger_config`, pero con el nivel indicado si no se establece uno en las variables de entorno.
+pub fn set_logger_config_with_default(default_level: log::Level) {
     if env::var("RUST_LOG").is_err() {
-        if let Err(err) = simple_logger::init_with_level(log::Level::Debug) {
+        if let Err(err) = simple_logger::init_with_level(default_level) {
             println!("Error setting logger to default value. Error: {:?}", err);
         }
     } else if let Err(err) = simple_logger::init_with_env() {
@@ -0,0 +1,327 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, Weak},
+};
+
+use async_std::{
+    channel::{self, Receiver, Sender},
+    task,
+};
+use async_trait::async_trait;
+use log::{error, info};
+
+use crate::{
+    codec::Codec,
+    common_errors::CoffeeSystemError,
+    connection_protocol::{
+        client_handshake, server_handshake, ConnectionProtocol, HandshakeChannel,
+    },
+    handshake::{Hello, NodeId, NodeKind, Peer},
+};
+
+/// Red en memoria que reemplaza a la red real. Permite levantar varios nodos en un mismo proceso, por ejemplo
+/// en los tests, y simular la caída de alguno cortando todas sus conexiones.
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    state: Arc<Mutex<NetworkState>>,
+}
+
+#[derive(Default)]
+struct NetworkState {
+    /// Listener de cada dirección, con el nodo que escucha en ella
+    listeners: HashMap<String, (NodeId, Sender<MemoryChannel>)>,
+    links: Vec<Link>,
+    /// Nodos caídos, no pueden escuchar ni abrir conexiones
+    down: HashSet<NodeId>,
+}
+
+/// Conexión abierta entre dos nodos. Se guarda para poder cortarla si alguno de ellos se cae, sin impedir
+/// que se cierre normalmente cuando ambos extremos la descartan.
+struct Link {
+    nodes: [NodeId; 2],
+    senders: [Weak<Sender<Vec<u8>>>; 2],
+}
+
+impl Link {
+    fn is_open(&self) -> bool {
+        self.senders.iter().any(|sender| sender.strong_count() > 0)
+    }
+
+    fn close(&self) {
+        for sender in self.senders.iter().filter_map(Weak::upgrade) {
+            sender.close();
+        }
+    }
+}
+
+impl MemoryNetwork {
+    pub fn new() -> MemoryNetwork {
+        MemoryNetwork::default()
+    }
+
+    /// Empieza a escuchar conexiones en la dirección indicada a nombre del nodo. Devuelve error si la
+    /// dirección ya está en uso o si el nodo está caído.
+    pub fn listen(
+        &self,
+        address: &str,
+        owner: NodeId,
+    ) -> Result<MemoryListener, CoffeeSystemError> {
+        let mut state = self.state.lock()?;
+        if state.down.contains(&owner) || state.listeners.contains_key(address) {
+            error!(
+                "[MEMORY CONNECTION] {:?} can not listen on {}",
+                owner, address
+            );
+            return Err(CoffeeSystemError::ConnectionLost);
+        }
+        let (sender, incoming) = channel::unbounded();
+        state.listeners.insert(address.to_string(), (owner, sender));
+        Ok(MemoryListener { incoming })
+    }
+
+    /// Abre un canal con el nodo que escucha en la dirección indicada, todavía sin handshake.
+    pub fn connect(
+        &self,
+        address: &str,
+        node_id: NodeId,
+    ) -> Result<MemoryChannel, CoffeeSystemError> {
+        let mut state = self.state.lock()?;
+        if state.down.contains(&node_id) {
+            return Err(CoffeeSystemError::ConnectionLost);
+        }
+        let (owner, listener) = match state.listeners.get(address) {
+            Some((owner, listener)) => (*owner, listener.clone()),
+            None => {
+                error!(
+                    "[MEMORY CONNECTION] Error connecting to server {}, nobody is listening",
+                    address
+                );
+                return Err(CoffeeSystemError::ConnectionLost);
+            }
+        };
+        let (to_server, from_client) = channel::unbounded();
+        let (to_client, from_server) = channel::unbounded();
+        let client = MemoryChannel::new(to_server, from_server, address);
+        let server = MemoryChannel::new(to_client, from_client, address);
+        state.links.retain(Link::is_open);
+        state.links.push(Link {
+            nodes: [node_id, owner],
+            senders: [
+                Arc::downgrade(&client.sender),
+                Arc::downgrade(&server.sender),
+            ],
+        });
+        listener
+            .try_send(server)
+            .map_err(|_| CoffeeSystemError::ConnectionLost)?;
+        info!(
+            "[MEMORY CONNECTION] Established connection to local server {}",
+            address
+        );
+        Ok(client)
+    }
+
+    /// Simula la caída del nodo: cierra sus listeners y todas sus conexiones, y no le permite abrir nuevas.
+    pub fn kill(&self, node_id: NodeId) -> Result<(), CoffeeSystemError> {
+        let mut state = self.state.lock()?;
+        info!("[MEMORY CONNECTION] Killing {:?}", node_id);
+        state.down.insert(node_id);
+        state.listeners.retain(|_, (owner, _)| *owner != node_id);
+        state.links.retain(|link| {
+            if link.nodes.contains(&node_id) {
+                link.close();
+                return false;
+            }
+            true
+        });
+        Ok(())
+    }
+}
+
+/// Recibe los canales que abren los clientes con una dirección de la red en memoria.
+pub struct MemoryListener {
+    incoming: Receiver<MemoryChannel>,
+}
+
+impl MemoryListener {
+    /// Espera la próxima conexión. Devuelve error si el nodo que escucha se cayó.
+    pub async fn accept(&self) -> Result<MemoryChannel, CoffeeSystemError> {
+        self.incoming
+            .recv()
+            .await
+            .map_err(|_| CoffeeSystemError::ConnectionClosed)
+    }
+}
+
+/// Extremo de un canal de la red en memoria, cada mensaje se entrega completo y en orden.
+#[derive(Clone)]
+pub struct MemoryChannel {
+    sender: Arc<Sender<Vec<u8>>>,
+    receiver: Receiver<Vec<u8>>,
+    address: String,
+}
+
+impl MemoryChannel {
+    fn new(sender: Sender<Vec<u8>>, receiver: Receiver<Vec<u8>>, address: &str) -> MemoryChannel {
+        MemoryChannel {
+            sender: Arc::new(sender),
+            receiver,
+            address: address.to_string(),
+        }
+    }
+
+    async fn send(&self, payload: &[u8]) -> Result<(), CoffeeSystemError> {
+        self.sender.send(payload.to_vec()).await.map_err(|_| {
+            error!(
+                "[MEMORY CONNECTION] Error sending message to server {}",
+                self.address
+            );
+            CoffeeSystemError::ConnectionLost
+        })
+    }
+
+    async fn recv(&self) -> Result<Vec<u8>, CoffeeSystemError> {
+        self.receiver.recv().await.map_err(|_| {
+            info!("[MEMORY CONNECTION] Closed connection {}", self.address);
+            CoffeeSystemError::ConnectionClosed
+        })
+    }
+}
+
+#[async_trait]
+impl HandshakeChannel for MemoryChannel {
+    async fn send_handshake(&mut self, payload: &[u8]) -> Result<(), CoffeeSystemError> {
+        self.send(payload).await
+    }
+
+    async fn recv_handshake(&mut self) -> Result<Vec<u8>, CoffeeSystemError> {
+        self.recv().await
+    }
+}
+
+/// Representa una conexión de la red en memoria. Se comporta como una conexión TCP: hace el mismo handshake,
+/// y al descartarla o al caerse alguno de los nodos el otro extremo recibe el cierre.
+pub struct MemoryConnection {
+    channel: MemoryChannel,
+    peer: Peer,
+}
+
+impl MemoryConnection {
+    /// Se conecta con el nodo que escucha en la dirección indicada y hace el handshake.
+    pub fn new_client_connection(
+        network: &MemoryNetwork,
+        server_addr: &str,
+        hello: &Hello,
+    ) -> Result<MemoryConnection, CoffeeSystemError> {
+        let mut channel = network.connect(server_addr, hello.node_id)?;
+        let peer = task::block_on(client_handshake(&mut channel, hello, server_addr))?;
+        Ok(MemoryConnection { channel, peer })
+    }
+
+    /// Devuelve un nuevo MemoryConnection a modo de servidor, a partir de un canal aceptado por el listener.
+    pub async fn new_server_connection(
+        mut channel: MemoryChannel,
+        hello: &Hello,
+        accepted: NodeKind,
+    ) -> Result<MemoryConnection, CoffeeSystemError> {
+        let addr = channel.address.clone();
+        let peer = server_handshake(&mut channel, hello, accepted, &addr).await?;
+        Ok(MemoryConnection { channel, peer })
+    }
+}
+
+#[async_trait]
+impl ConnectionProtocol for MemoryConnection {
+    async fn send(&mut self, data: &[u8]) -> Result<(), CoffeeSystemError> {
+        self.channel.send(data).await
+    }
+
+    async fn recv(&mut self) -> Result<Vec<u8>, CoffeeSystemError> {
+        self.channel.recv().await
+    }
+
+    fn codec(&self) -> Codec {
+        self.peer.codec
+    }
+
+    fn peer(&self) -> Peer {
+        self.peer.clone()
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn ConnectionProtocol + Send + Sync>, CoffeeSystemError> {
+        Ok(Box::new(MemoryConnection {
+            channel: self.channel.clone(),
+            peer: self.peer.clone(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::connection_protocol::{recv_message, send_message};
+
+    fn accept_one(network: &MemoryNetwork, address: &str) -> thread::JoinHandle<MemoryConnection> {
+        let listener = network
+            .listen(address, NodeId::Server(1))
+            .expect("Error listening");
+        thread::spawn(move || {
+            task::block_on(async {
+                let channel = listener.accept().await.expect("Error accepting");
+                MemoryConnection::new_server_connection(
+                    channel,
+                    &Hello::new(NodeId::Server(1)),
+                    NodeKind::Server,
+                )
+                .await
+                .expect("Error in handshake")
+            })
+        })
+    }
+
+    #[test]
+    fn should_exchange_messages_after_the_handshake() {
+        let network = MemoryNetwork::new();
+        let server = accept_one(&network, "server-1");
+
+        let mut client = MemoryConnection::new_client_connection(
+            &network,
+            "server-1",
+            &Hello::new(NodeId::Server(0)),
+        )
+        .expect("Error connecting");
+        let mut server = server.join().expect("Server panicked");
+        task::block_on(send_message(&mut client, "hello")).expect("Error sending");
+        let received: String = task::block_on(recv_message(&mut server)).expect("Error receiving");
+
+        assert_eq!("hello", received);
+        assert_eq!(NodeId::Server(1), client.peer().node_id);
+        assert_eq!(NodeId::Server(0), server.peer().node_id);
+    }
+
+    #[test]
+    fn should_close_the_connections_of_a_killed_node() {
+        let network = MemoryNetwork::new();
+        let server = accept_one(&network, "server-1");
+        let mut client = MemoryConnection::new_client_connection(
+            &network,
+            "server-1",
+            &Hello::new(NodeId::Server(0)),
+        )
+        .expect("Error connecting");
+        let mut server = server.join().expect("Server panicked");
+
+        network.kill(NodeId::Server(1)).expect("Error killing");
+
+        assert!(task::block_on(client.recv()).is_err());
+        assert!(task::block_on(server.send(b"late")).is_err());
+        assert!(MemoryConnection::new_client_connection(
+            &network,
+            "server-1",
+            &Hello::new(NodeId::Server(0)),
+        )
+        .is_err());
+    }
+}
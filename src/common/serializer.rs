@@ -1,7 +1,7 @@
 /// Serializa un mensaje que implemente o derive el trait Serialize a un array de bytes.
-pub fn serialize<T: ?Sized>(req: &T) -> Result<Vec<u8>, serde_json::Error>
+pub fn serialize<T>(req: &T) -> Result<Vec<u8>, serde_json::Error>
 where
-    T: serde::Serialize,
+    T: serde::Serialize + ?Sized,
 {
     let mut encoded = serde_json::to_string(req)?;
     encoded.push('\n');
@@ -0,0 +1,582 @@
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket as StdUdpSocket},
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use async_std::{
+    channel::{self, Receiver, Sender},
+    future,
+    net::UdpSocket,
+    sync::Mutex as AsyncMutex,
+    task,
+};
+use async_trait::async_trait;
+use log::{debug, error, info, warn};
+
+use crate::{
+    codec::{Codec, MAX_FRAME_LENGTH},
+    common_errors::CoffeeSystemError,
+    connection_protocol::{
+        client_handshake, server_handshake, ConnectionProtocol, HandshakeChannel,
+    },
+    handshake::{Hello, NodeKind, Peer},
+};
+
+/// Tamaño máximo de los datos de un paquete. Los mensajes más grandes se envían en varios paquetes.
+pub const UDP_MAX_PAYLOAD_IN_BYTES: usize = 1200;
+
+const HEADER_LENGTH: usize = 9;
+const MAX_DATAGRAM_LENGTH: usize = 65536;
+
+/// Tipos de paquete. Los datos llevan un número de secuencia, y el último paquete de un mensaje se marca
+/// para poder rearmarlo del otro lado. El ACK es acumulativo: lleva el próximo número de secuencia que espera
+/// el receptor, y confirma todos los anteriores
+const DATA: u8 = 0;
+const DATA_LAST: u8 = 1;
+const ACK: u8 = 2;
+const PING: u8 = 3;
+const FIN: u8 = 4;
+
+/// Parámetros de la entrega confiable sobre UDP.
+/// Los paquetes de un mensaje se envían con una ventana (go-back-N): hasta `send_window` paquetes sin confirmar
+/// a la vez, y si vence la espera se reenvían desde el primero sin ACK. Los mensajes de una conexión se envían
+/// de a uno, el siguiente sale cuando se confirmó el anterior completo
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UdpConfig {
+    /// Paquetes que se envían sin esperar su ACK. Con 1 se envía de a un paquete (stop-and-wait)
+    pub send_window: u64,
+    /// Tiempo que se espera un ACK que confirme paquetes nuevos antes de reenviar los que no se confirmaron
+    pub retransmit_timeout: Duration,
+    /// Reenvíos seguidos sin que se confirme ningún paquete antes de dar la conexión por perdida
+    pub max_retransmissions: u32,
+    /// Cada cuanto se avisa al otro extremo que la conexión sigue viva
+    pub keepalive_interval: Duration,
+    /// Tiempo sin recibir paquetes del otro extremo luego del cual se da la conexión por perdida
+    pub peer_timeout: Duration,
+    /// Probabilidad de descartar un paquete antes de enviarlo, para probar la conexión con pérdida de paquetes
+    pub packet_loss: f64,
+}
+
+impl Default for UdpConfig {
+    fn default() -> Self {
+        UdpConfig {
+            send_window: 16,
+            retransmit_timeout: Duration::from_millis(200),
+            max_retransmissions: 10,
+            keepalive_interval: Duration::from_millis(500),
+            peer_timeout: Duration::from_millis(5000),
+            packet_loss: 0.0,
+        }
+    }
+}
+
+/// Estado de recepción de un extremo remoto: el próximo número de secuencia esperado y el mensaje que se está rearmando.
+struct Inbox {
+    expected: u64,
+    partial: Vec<u8>,
+    acks: Sender<u64>,
+    delivered: Sender<Result<Vec<u8>, CoffeeSystemError>>,
+    last_seen: Instant,
+}
+
+/// Socket UDP compartido por todas las conexiones de un listener, o por la de un cliente.
+/// Una tarea lee los paquetes y los reparte según la dirección de origen
+struct Endpoint {
+    socket: StdUdpSocket,
+    config: UdpConfig,
+    inboxes: Mutex<HashMap<SocketAddr, Inbox>>,
+    /// Solo en un listener, recibe las conexiones de direcciones nuevas
+    incoming: Option<Sender<Channel>>,
+}
+
+impl Endpoint {
+    fn bind(
+        address: SocketAddr,
+        config: UdpConfig,
+        incoming: Option<Sender<Channel>>,
+    ) -> Result<Arc<Endpoint>, CoffeeSystemError> {
+        let socket = StdUdpSocket::bind(address)?;
+        let receiver = UdpSocket::from(socket.try_clone()?);
+        let endpoint = Arc::new(Endpoint {
+            socket,
+            config,
+            inboxes: Mutex::new(HashMap::new()),
+            incoming,
+        });
+        task::spawn(receive_packets(Arc::downgrade(&endpoint), receiver));
+        Ok(endpoint)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, CoffeeSystemError> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Envía un paquete sin esperar confirmación. Descarta algunos si se configuró pérdida de paquetes
+    fn send_packet(&self, kind: u8, sequence: u64, payload: &[u8], addr: SocketAddr) {
+        if self.config.packet_loss > 0.0 && rand::random::<f64>() < self.config.packet_loss {
+            return;
+        }
+        let mut packet = Vec::with_capacity(HEADER_LENGTH + payload.len());
+        packet.push(kind);
+        packet.extend_from_slice(&sequence.to_be_bytes());
+        packet.extend_from_slice(payload);
+        if let Err(e) = self.socket.send_to(&packet, addr) {
+            debug!("[UDP CONNECTION] Error sending packet to {}, {}", addr, e);
+        }
+    }
+
+    /// Registra un extremo remoto y devuelve el canal para comunicarse con él
+    fn open_channel(self: &Arc<Self>, addr: SocketAddr) -> Result<Channel, CoffeeSystemError> {
+        let (acks_sender, acks) = channel::unbounded();
+        let (delivered_sender, delivered) = channel::unbounded();
+        self.inboxes.lock()?.insert(
+            addr,
+            Inbox {
+                expected: 0,
+                partial: vec![],
+                acks: acks_sender,
+                delivered: delivered_sender,
+                last_seen: Instant::now(),
+            },
+        );
+        Ok(Channel(Arc::new(ChannelState {
+            endpoint: self.clone(),
+            addr,
+            next_sequence: AsyncMutex::new(0),
+            acks,
+            delivered,
+        })))
+    }
+
+    fn handle_packet(
+        self: &Arc<Self>,
+        packet: &[u8],
+        addr: SocketAddr,
+    ) -> Result<(), CoffeeSystemError> {
+        if packet.len() < HEADER_LENGTH {
+            return Ok(());
+        }
+        let kind = packet[0];
+        let mut sequence = [0; 8];
+        sequence.copy_from_slice(&packet[1..HEADER_LENGTH]);
+        let sequence = u64::from_be_bytes(sequence);
+        let payload = &packet[HEADER_LENGTH..];
+
+        let mut inboxes = self.inboxes.lock()?;
+        if !inboxes.contains_key(&addr) {
+            match &self.incoming {
+                Some(incoming) if (kind == DATA || kind == DATA_LAST) && sequence == 0 => {
+                    drop(inboxes);
+                    let channel = self.open_channel(addr)?;
+                    if incoming.try_send(channel).is_err() {
+                        return Ok(());
+                    }
+                    inboxes = self.inboxes.lock()?;
+                }
+                _ => return Ok(()),
+            }
+        }
+        let inbox = match inboxes.get_mut(&addr) {
+            Some(inbox) => inbox,
+            None => return Ok(()),
+        };
+        inbox.last_seen = Instant::now();
+        match kind {
+            DATA | DATA_LAST => {
+                if sequence == inbox.expected {
+                    inbox.expected += 1;
+                    inbox.partial.extend_from_slice(payload);
+                    if inbox.partial.len() > MAX_FRAME_LENGTH {
+                        warn!(
+                            "[UDP CONNECTION] Message from {} is too long, closing",
+                            addr
+                        );
+                        let _ = inbox
+                            .delivered
+                            .try_send(Err(CoffeeSystemError::ConnectionLost));
+                        inboxes.remove(&addr);
+                        return Ok(());
+                    }
+                    if kind == DATA_LAST {
+                        let message = std::mem::take(&mut inbox.partial);
+                        let _ = inbox.delivered.try_send(Ok(message));
+                    }
+                }
+                // Los paquetes fuera de orden se descartan, y los repetidos se vuelven a confirmar porque el ACK
+                // anterior pudo haberse perdido. En ambos casos el ACK indica desde donde reenviar
+                self.send_packet(ACK, inbox.expected, &[], addr);
+            }
+            ACK => {
+                let _ = inbox.acks.try_send(sequence);
+            }
+            FIN => {
+                debug!("[UDP CONNECTION] {} closed the connection", addr);
+                inboxes.remove(&addr);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Avisa a cada extremo remoto que la conexión sigue viva, y da por perdidas las que no envían nada hace tiempo
+    fn keepalive(&self) -> Result<(), CoffeeSystemError> {
+        let mut inboxes = self.inboxes.lock()?;
+        let now = Instant::now();
+        let lost: Vec<SocketAddr> = inboxes
+            .iter()
+            .filter(|(_, inbox)| now.duration_since(inbox.last_seen) > self.config.peer_timeout)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in lost {
+            warn!("[UDP CONNECTION] No packets from {}, connection lost", addr);
+            if let Some(inbox) = inboxes.remove(&addr) {
+                let _ = inbox
+                    .delivered
+                    .try_send(Err(CoffeeSystemError::ConnectionLost));
+            }
+        }
+        for addr in inboxes.keys() {
+            self.send_packet(PING, 0, &[], *addr);
+        }
+        Ok(())
+    }
+}
+
+/// Lee los paquetes del socket mientras exista el endpoint
+async fn receive_packets(endpoint: Weak<Endpoint>, socket: UdpSocket) {
+    let mut buffer = vec![0; MAX_DATAGRAM_LENGTH];
+    let mut last_keepalive = Instant::now();
+    loop {
+        let interval = match endpoint.upgrade() {
+            Some(endpoint) => endpoint.config.keepalive_interval,
+            None => return,
+        };
+        let received = future::timeout(interval, socket.recv_from(&mut buffer)).await;
+        let endpoint = match endpoint.upgrade() {
+            Some(endpoint) => endpoint,
+            None => return,
+        };
+        if let Ok(Ok((length, addr))) = received {
+            if endpoint.handle_packet(&buffer[..length], addr).is_err() {
+                error!("[UDP CONNECTION] Error handling packet from {}", addr);
+            }
+        }
+        if last_keepalive.elapsed() >= interval {
+            last_keepalive = Instant::now();
+            if endpoint.keepalive().is_err() {
+                error!("[UDP CONNECTION] Error checking the connections");
+            }
+        }
+    }
+}
+
+struct ChannelState {
+    endpoint: Arc<Endpoint>,
+    addr: SocketAddr,
+    /// Próximo número de secuencia a enviar. Se mantiene tomado mientras se envía un mensaje, así se envía de a uno
+    next_sequence: AsyncMutex<u64>,
+    acks: Receiver<u64>,
+    delivered: Receiver<Result<Vec<u8>, CoffeeSystemError>>,
+}
+
+impl Drop for ChannelState {
+    fn drop(&mut self) {
+        self.endpoint.send_packet(FIN, 0, &[], self.addr);
+        if let Ok(mut inboxes) = self.endpoint.inboxes.lock() {
+            inboxes.remove(&self.addr);
+        }
+    }
+}
+
+/// Entrega confiable y ordenada de mensajes con un extremo remoto. Cada paquete lleva un número de secuencia y se
+/// reenvía hasta que un ACK lo confirma; del otro lado se descartan los repetidos y los que llegan fuera de
+/// orden, y se entregan los mensajes en orden.
+/// Sus clones comparten el estado, como las dos mitades de un socket TCP
+#[derive(Clone)]
+pub struct Channel(Arc<ChannelState>);
+
+impl Channel {
+    async fn send(&self, message: &[u8]) -> Result<(), CoffeeSystemError> {
+        let state = &self.0;
+        let config = state.endpoint.config;
+        let mut next_sequence = state.next_sequence.lock().await;
+        let mut chunks: Vec<&[u8]> = message.chunks(UDP_MAX_PAYLOAD_IN_BYTES).collect();
+        if chunks.is_empty() {
+            chunks.push(&[]);
+        }
+        let first = *next_sequence;
+        let end = first + chunks.len() as u64;
+        // Primer paquete sin confirmar y proximo a enviar
+        let mut acked = first;
+        let mut sent = first;
+        let mut retransmissions = 0;
+        let mut deadline = Instant::now() + config.retransmit_timeout;
+        while acked < end {
+            while sent < end && sent < acked + config.send_window.max(1) {
+                let kind = if sent + 1 == end { DATA_LAST } else { DATA };
+                let chunk = chunks[(sent - first) as usize];
+                state.endpoint.send_packet(kind, sent, chunk, state.addr);
+                sent += 1;
+            }
+            let wait = deadline.saturating_duration_since(Instant::now());
+            match future::timeout(wait, state.acks.recv()).await {
+                // Los ACK de paquetes ya confirmados llegan tarde o repetidos, se ignoran
+                Ok(Ok(next_expected)) if next_expected > acked => {
+                    acked = next_expected.min(end);
+                    retransmissions = 0;
+                    deadline = Instant::now() + config.retransmit_timeout;
+                }
+                Ok(Ok(_)) => {}
+                Ok(Err(_)) => return Err(CoffeeSystemError::ConnectionLost),
+                Err(_) => {
+                    retransmissions += 1;
+                    if retransmissions > config.max_retransmissions {
+                        error!(
+                            "[UDP CONNECTION] No ACK from {} after {} retransmissions",
+                            state.addr, config.max_retransmissions
+                        );
+                        return Err(CoffeeSystemError::ConnectionLost);
+                    }
+                    sent = acked;
+                    deadline = Instant::now() + config.retransmit_timeout;
+                }
+            }
+        }
+        *next_sequence = end;
+        Ok(())
+    }
+
+    async fn recv(&self) -> Result<Vec<u8>, CoffeeSystemError> {
+        match self.0.delivered.recv().await {
+            Ok(message) => message,
+            Err(_) => {
+                info!("[UDP CONNECTION] Closed connection {}", self.0.addr);
+                Err(CoffeeSystemError::ConnectionClosed)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl HandshakeChannel for Channel {
+    async fn send_handshake(&mut self, payload: &[u8]) -> Result<(), CoffeeSystemError> {
+        self.send(payload).await
+    }
+
+    async fn recv_handshake(&mut self) -> Result<Vec<u8>, CoffeeSystemError> {
+        self.recv().await
+    }
+}
+
+/// Representa una conexión sobre UDP, ya sea entre servidores o entre un servidor y una cafetera.
+/// Agrega sobre UDP la entrega confiable y ordenada que da TCP.
+pub struct UdpConnection {
+    channel: Channel,
+    peer: Peer,
+}
+
+impl UdpConnection {
+    /// Devuelve un nuevo cliente UdpConnection a partir de una dirección de servidor IP:PUERTO, luego de hacer el
+    /// handshake con el servidor. Devuelve error si el servidor no responde o rechaza la conexión.
+    pub fn new_client_connection(
+        server_addr: &String,
+        hello: &Hello,
+        config: UdpConfig,
+    ) -> Result<UdpConnection, CoffeeSystemError> {
+        let addr = server_addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or(CoffeeSystemError::ConnectionLost)?;
+        let local: SocketAddr = if addr.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let endpoint = Endpoint::bind(local, config, None)?;
+        let mut channel = endpoint.open_channel(addr)?;
+        let peer = task::block_on(client_handshake(&mut channel, hello, &addr.to_string()))?;
+        Ok(UdpConnection { channel, peer })
+    }
+
+    /// Devuelve un nuevo UdpConnection a modo de servidor, a partir de un canal aceptado por el listener.
+    pub async fn new_server_connection(
+        mut channel: Channel,
+        hello: &Hello,
+        accepted: NodeKind,
+    ) -> Result<UdpConnection, CoffeeSystemError> {
+        let addr = channel.0.addr;
+        let peer = server_handshake(&mut channel, hello, accepted, &addr.to_string()).await?;
+        Ok(UdpConnection { channel, peer })
+    }
+}
+
+#[async_trait]
+impl ConnectionProtocol for UdpConnection {
+    /// Envía el mensaje y espera que el otro extremo confirme todos sus paquetes.
+    async fn send(&mut self, data: &[u8]) -> Result<(), CoffeeSystemError> {
+        self.channel.send(data).await
+    }
+
+    /// Devuelve el próximo mensaje, en el orden en que se enviaron.
+    async fn recv(&mut self) -> Result<Vec<u8>, CoffeeSystemError> {
+        self.channel.recv().await
+    }
+
+    fn codec(&self) -> Codec {
+        self.peer.codec
+    }
+
+    fn peer(&self) -> Peer {
+        self.peer.clone()
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn ConnectionProtocol + Send + Sync>, CoffeeSystemError> {
+        Ok(Box::new(UdpConnection {
+            channel: self.channel.clone(),
+            peer: self.peer.clone(),
+        }))
+    }
+}
+
+/// Recibe conexiones UDP en una dirección. Todas las conexiones aceptadas comparten el socket.
+pub struct UdpListener {
+    endpoint: Arc<Endpoint>,
+    incoming: Receiver<Channel>,
+}
+
+impl UdpListener {
+    pub fn bind(address: &str, config: UdpConfig) -> Result<UdpListener, CoffeeSystemError> {
+        let address = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or(CoffeeSystemError::ConnectionLost)?;
+        let (incoming_sender, incoming) = channel::unbounded();
+        let endpoint = Endpoint::bind(address, config, Some(incoming_sender))?;
+        Ok(UdpListener { endpoint, incoming })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, CoffeeSystemError> {
+        self.endpoint.local_addr()
+    }
+
+    /// Espera el primer paquete de una dirección nueva y devuelve el canal con ella, junto a su dirección
+    pub async fn accept(&self) -> Result<(Channel, SocketAddr), CoffeeSystemError> {
+        let channel = self
+            .incoming
+            .recv()
+            .await
+            .map_err(|_| CoffeeSystemError::ConnectionClosed)?;
+        let addr = channel.0.addr;
+        Ok((channel, addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake::NodeId;
+
+    fn lossy_config(packet_loss: f64) -> UdpConfig {
+        UdpConfig {
+            retransmit_timeout: Duration::from_millis(20),
+            max_retransmissions: 50,
+            packet_loss,
+            ..UdpConfig::default()
+        }
+    }
+
+    /// Acepta una conexion de cafetera en el listener y la devuelve
+    fn accept(listener: UdpListener) -> std::thread::JoinHandle<UdpConnection> {
+        std::thread::spawn(move || {
+            task::block_on(async {
+                let (channel, _) = listener.accept().await.expect("Error accepting");
+                UdpConnection::new_server_connection(
+                    channel,
+                    &Hello::new(NodeId::Server(0)),
+                    NodeKind::CoffeeMaker,
+                )
+                .await
+                .expect("Error in handshake")
+            })
+        })
+    }
+
+    fn connect(config: UdpConfig) -> (UdpConnection, UdpConnection) {
+        let listener = UdpListener::bind("127.0.0.1:0", config).expect("Error binding");
+        let address = listener.local_addr().expect("No address").to_string();
+        let server = accept(listener);
+        let client = UdpConnection::new_client_connection(
+            &address,
+            &Hello::new(NodeId::CoffeeMaker(1)),
+            config,
+        )
+        .expect("Error connecting");
+        (client, server.join().expect("Server failed"))
+    }
+
+    #[test]
+    fn should_deliver_every_message_in_order_when_packets_are_lost() {
+        let (mut client, server) = connect(lossy_config(0.3));
+        let messages: Vec<Vec<u8>> = (0..20u8)
+            .map(|i| vec![i; i as usize * UDP_MAX_PAYLOAD_IN_BYTES / 4])
+            .collect();
+
+        let mut reader = server.try_clone().expect("Error cloning");
+        let expected = messages.clone();
+        let receiver = std::thread::spawn(move || {
+            for message in expected {
+                let received = task::block_on(reader.recv()).expect("Error receiving");
+                assert_eq!(message, received);
+            }
+        });
+        for message in messages.iter() {
+            task::block_on(client.send(message)).expect("Error sending");
+        }
+
+        receiver.join().expect("Messages out of order");
+    }
+
+    #[test]
+    fn should_rebuild_a_message_of_many_packets_when_packets_are_lost() {
+        let (mut client, server) = connect(UdpConfig {
+            send_window: 4,
+            ..lossy_config(0.3)
+        });
+        let long: Vec<u8> = (0..UDP_MAX_PAYLOAD_IN_BYTES * 10 + 7)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let messages = vec![long.clone(), b"after".to_vec(), long];
+
+        let mut reader = server.try_clone().expect("Error cloning");
+        let expected = messages.clone();
+        let receiver = std::thread::spawn(move || {
+            for message in expected {
+                let received = task::block_on(reader.recv()).expect("Error receiving");
+                assert_eq!(message, received);
+            }
+        });
+        for message in messages.iter() {
+            task::block_on(client.send(message)).expect("Error sending");
+        }
+
+        receiver.join().expect("Message rebuilt wrong");
+    }
+
+    #[test]
+    fn should_close_the_connection_when_the_other_side_drops_it() {
+        let (mut client, server) = connect(lossy_config(0.0));
+
+        drop(server);
+
+        assert_eq!(
+            Err(CoffeeSystemError::ConnectionClosed),
+            task::block_on(client.recv())
+        );
+        assert_eq!(
+            Err(CoffeeSystemError::ConnectionLost),
+            task::block_on(client.send(b"hello"))
+        );
+    }
+}
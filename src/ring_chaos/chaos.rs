@@ -0,0 +1,273 @@
+use std::{collections::HashMap, net::Shutdown, time::Duration};
+
+use async_std::net::TcpStream;
+use log::{info, warn};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::script::{Command, Fault, LinkSpec};
+
+/// Conexion entre dos servidores que pasa por el proxy
+struct OpenConnection {
+    client: usize,
+    server: usize,
+    streams: Vec<TcpStream>,
+}
+
+/// Que hacer con un mensaje que pasa por un enlace
+#[derive(Debug, PartialEq)]
+pub struct Verdict {
+    /// Cuantas veces reenviarlo, 0 si se descarta
+    pub copies: usize,
+    /// Cuanto esperar antes de reenviarlo
+    pub delay: Duration,
+    /// Comandos a ejecutar luego de reenviarlo
+    pub triggered: Vec<Command>,
+}
+
+/// Estado de las fallas inyectadas en los enlaces. Lo comparten todas las conexiones del proxy,
+/// y se modifica con los comandos del script o de la entrada estandar
+pub struct Chaos {
+    faults: Vec<(LinkSpec, Fault)>,
+    triggers: Vec<(LinkSpec, String, Command)>,
+    connections: HashMap<u64, OpenConnection>,
+    next_connection_id: u64,
+    rng: StdRng,
+}
+
+impl Chaos {
+    /// Crea el estado sin fallas. Con la misma semilla se toman las mismas decisiones al azar
+    pub fn new(seed: u64) -> Chaos {
+        Chaos {
+            faults: vec![],
+            triggers: vec![],
+            connections: HashMap::new(),
+            next_connection_id: 0,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// Ejecuta un comando. Las esperas las resuelve quien ejecuta el script
+    pub fn apply(&mut self, command: Command) {
+        info!("[CHAOS] Applying {:?}", command);
+        match command {
+            Command::Wait(_) => warn!("[CHAOS] Wait commands are only valid in a script"),
+            Command::Inject(link, Fault::Cut) => {
+                self.close_connections(link);
+                self.faults.push((link, Fault::Cut));
+            }
+            Command::Inject(link, fault) => self.faults.push((link, fault)),
+            Command::Heal(Some(link)) => self.faults.retain(|(spec, _)| *spec != link),
+            Command::Heal(None) => self.faults.clear(),
+            Command::On {
+                link,
+                message,
+                command,
+            } => self.triggers.push((link, message, *command)),
+        }
+    }
+
+    /// Indica si el enlace de `from` a `to` esta cortado
+    pub fn is_cut(&self, from: usize, to: usize) -> bool {
+        self.faults
+            .iter()
+            .any(|(link, fault)| *fault == Fault::Cut && link.matches(from, to))
+    }
+
+    /// Decide que hacer con un mensaje que va de `from` a `to`. `message` es el tipo del mensaje, si se pudo leer
+    pub fn on_message(&mut self, from: usize, to: usize, message: Option<&str>) -> Verdict {
+        let mut verdict = Verdict {
+            copies: 1,
+            delay: Duration::ZERO,
+            triggered: vec![],
+        };
+        let is_type = |filter: &Option<String>| {
+            filter
+                .as_ref()
+                .is_none_or(|filter| Some(filter.as_str()) == message)
+        };
+        for (link, fault) in self
+            .faults
+            .iter()
+            .filter(|(link, _)| link.matches(from, to))
+        {
+            match fault {
+                Fault::Cut => verdict.copies = 0,
+                Fault::Drop {
+                    probability,
+                    message,
+                } => {
+                    if is_type(message) && self.rng.gen_bool(*probability) {
+                        info!("[CHAOS] Dropping {:?} from {} to {}", link, from, to);
+                        verdict.copies = 0;
+                    }
+                }
+                Fault::Delay(delay) => verdict.delay += *delay,
+                Fault::Duplicate {
+                    probability,
+                    message,
+                } => {
+                    if verdict.copies > 0 && is_type(message) && self.rng.gen_bool(*probability) {
+                        verdict.copies += 1;
+                    }
+                }
+            }
+        }
+        if verdict.copies > 0 {
+            let (triggered, pending) = self.triggers.drain(..).partition(|(link, trigger, _)| {
+                link.matches(from, to) && Some(trigger.as_str()) == message
+            });
+            self.triggers = pending;
+            verdict.triggered = triggered
+                .into_iter()
+                .map(|(_, _, command)| command)
+                .collect();
+        }
+        verdict
+    }
+
+    /// Indica si esta cortado alguno de los sentidos del enlace entre los dos servidores. Como una conexion
+    /// lleva mensajes en ambos sentidos, se rechaza si cualquiera de los dos esta cortado
+    pub fn is_connection_cut(&self, client: usize, server: usize) -> bool {
+        self.is_cut(client, server) || self.is_cut(server, client)
+    }
+
+    /// Registra una conexion de `client` a `server` para poder cerrarla si se corta el enlace. Devuelve su id,
+    /// o None si el enlace ya esta cortado
+    pub fn register(
+        &mut self,
+        client: usize,
+        server: usize,
+        streams: Vec<TcpStream>,
+    ) -> Option<u64> {
+        if self.is_connection_cut(client, server) {
+            return None;
+        }
+        let id = self.next_connection_id;
+        self.next_connection_id += 1;
+        self.connections.insert(
+            id,
+            OpenConnection {
+                client,
+                server,
+                streams,
+            },
+        );
+        Some(id)
+    }
+
+    /// Cierra la conexion indicada, si sigue abierta
+    pub fn close(&mut self, id: u64) {
+        if let Some(connection) = self.connections.remove(&id) {
+            for stream in connection.streams {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+    }
+
+    /// Cierra las conexiones que usan el enlace en alguno de sus sentidos
+    fn close_connections(&mut self, link: LinkSpec) {
+        let cut: Vec<u64> = self
+            .connections
+            .iter()
+            .filter(|(_, connection)| {
+                link.matches(connection.client, connection.server)
+                    || link.matches(connection.server, connection.client)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in cut {
+            info!("[CHAOS] Closing connection {}", id);
+            self.close(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(from: usize, to: usize) -> LinkSpec {
+        LinkSpec::Link {
+            from: Some(from),
+            to: Some(to),
+        }
+    }
+
+    #[test]
+    fn should_forward_the_messages_once_without_faults() {
+        let mut chaos = Chaos::new(1);
+        let verdict = chaos.on_message(1, 2, Some("Token"));
+        assert_eq!(1, verdict.copies);
+        assert_eq!(Duration::ZERO, verdict.delay);
+    }
+
+    #[test]
+    fn should_only_drop_the_messages_of_the_given_type() {
+        let mut chaos = Chaos::new(1);
+        chaos.apply(Command::Inject(
+            link(1, 2),
+            Fault::Drop {
+                probability: 1.0,
+                message: Some(String::from("Token")),
+            },
+        ));
+        assert_eq!(0, chaos.on_message(1, 2, Some("Token")).copies);
+        assert_eq!(1, chaos.on_message(1, 2, Some("Election")).copies);
+        assert_eq!(1, chaos.on_message(2, 1, Some("Token")).copies);
+    }
+
+    #[test]
+    fn should_add_the_delays_and_duplicates_of_the_link() {
+        let mut chaos = Chaos::new(1);
+        let duplicate = Fault::Duplicate {
+            probability: 1.0,
+            message: None,
+        };
+        chaos.apply(Command::Inject(link(1, 2), duplicate));
+        chaos.apply(Command::Inject(
+            LinkSpec::Server(2),
+            Fault::Delay(Duration::from_millis(100)),
+        ));
+        chaos.apply(Command::Inject(
+            link(1, 2),
+            Fault::Delay(Duration::from_millis(50)),
+        ));
+        let verdict = chaos.on_message(1, 2, None);
+        assert_eq!(2, verdict.copies);
+        assert_eq!(Duration::from_millis(150), verdict.delay);
+    }
+
+    #[test]
+    fn should_heal_the_faults_of_the_same_link() {
+        let mut chaos = Chaos::new(1);
+        chaos.apply(Command::Inject(LinkSpec::Server(2), Fault::Cut));
+        chaos.apply(Command::Inject(link(0, 1), Fault::Cut));
+        assert!(chaos.is_cut(1, 2));
+        assert!(chaos.is_cut(2, 3));
+
+        chaos.apply(Command::Heal(Some(LinkSpec::Server(2))));
+        assert!(!chaos.is_cut(1, 2));
+        assert!(chaos.is_cut(0, 1));
+
+        chaos.apply(Command::Heal(None));
+        assert!(!chaos.is_cut(0, 1));
+    }
+
+    #[test]
+    fn should_run_a_trigger_once_after_the_first_message_of_its_type() {
+        let mut chaos = Chaos::new(1);
+        let cut = Command::Inject(LinkSpec::Server(2), Fault::Cut);
+        chaos.apply(Command::On {
+            link: link(1, 2),
+            message: String::from("Token"),
+            command: Box::new(cut.clone()),
+        });
+        assert!(chaos
+            .on_message(1, 2, Some("Election"))
+            .triggered
+            .is_empty());
+        assert!(chaos.on_message(0, 1, Some("Token")).triggered.is_empty());
+        assert_eq!(vec![cut], chaos.on_message(1, 2, Some("Token")).triggered);
+        assert!(chaos.on_message(1, 2, Some("Token")).triggered.is_empty());
+    }
+}
@@ -0,0 +1,8 @@
+/// Los argumentos que acepta el proxy.
+/// El archivo de topologia con las direcciones de los servidores y sus proxies, el script de fallas a ejecutar
+/// y la semilla con la que se decide al azar que mensajes se descartan o duplican
+pub struct ChaosArgs {
+    pub topology_file: String,
+    pub script_file: Option<String>,
+    pub seed: Option<u64>,
+}
@@ -0,0 +1,26 @@
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChaosError {
+    /// Faltan argumentos al iniciar la aplicacion
+    ArgsMissing,
+
+    /// Alguno de los argumentos tiene un formato invalido
+    ArgsFormat,
+
+    /// No se pudo leer el archivo de topologia o el script
+    FileError,
+
+    /// El archivo de topologia no tiene el formato esperado o ningun servidor tiene direccion de proxy
+    TopologyFormat,
+
+    /// Un comando del script no es valido. Incluye el comando
+    CommandFormat(String),
+
+    /// No se pudo escuchar en la direccion de proxy de algun servidor
+    ListenerError,
+}
+
+impl From<std::io::Error> for ChaosError {
+    fn from(_: std::io::Error) -> Self {
+        ChaosError::FileError
+    }
+}
@@ -0,0 +1,127 @@
+/// Modulo que contiene el estado de las fallas inyectadas y decide que hacer con cada mensaje
+pub mod chaos;
+/// Modulo que representa los parametros que puede recibir el binario para su ejecucion
+pub mod chaos_args;
+/// Modulo de errores que utiliza unicamente el proxy
+pub mod errors;
+/// Modulo que reenvia las conexiones entre servidores aplicando las fallas de cada enlace
+pub mod proxy;
+/// Modulo que lee los comandos del script de fallas
+pub mod script;
+/// Modulo que lee del archivo de topologia los servidores que tienen un proxy delante
+pub mod topology;
+
+use std::{
+    env, io,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use async_std::task;
+use chaos::Chaos;
+use chaos_args::ChaosArgs;
+use errors::ChaosError;
+use lib::logger::set_logger_config;
+use log::{error, info};
+use script::{parse_command, read_script, Command};
+
+fn get_args() -> Result<ChaosArgs, ChaosError> {
+    let args: Vec<String> = env::args().collect();
+    let topology_file = args.get(1).ok_or(ChaosError::ArgsMissing)?.clone();
+
+    let mut next_arg = 2;
+    let mut script_file = None;
+    if let Some(arg) = args.get(2) {
+        if !arg.starts_with("--") {
+            script_file = Some(arg.clone());
+            next_arg = 3;
+        }
+    }
+
+    let mut seed = None;
+    let mut options = args[next_arg..].iter();
+    while let Some(option) = options.next() {
+        let value = options.next().ok_or(ChaosError::ArgsMissing)?;
+        match option.as_str() {
+            "--seed" => seed = Some(value.trim().parse().map_err(|_| ChaosError::ArgsFormat)?),
+            _ => return Err(ChaosError::ArgsFormat),
+        }
+    }
+    Ok(ChaosArgs {
+        topology_file,
+        script_file,
+        seed,
+    })
+}
+
+/// Ejecuta los comandos en orden, esperando donde lo indique el script
+fn run_commands(commands: Vec<Command>, chaos: &Mutex<Chaos>) {
+    for command in commands {
+        match command {
+            Command::Wait(duration) => thread::sleep(duration),
+            command => match chaos.lock() {
+                Ok(mut chaos) => chaos.apply(command),
+                Err(_) => error!("[CHAOS] Lock error applying {:?}", command),
+            },
+        }
+    }
+}
+
+/// Escucha la entrada estandar y ejecuta cada linea como un comando del script
+fn listen_commands(chaos: Arc<Mutex<Chaos>>) {
+    thread::spawn(move || {
+        for line in io::stdin().lines() {
+            let Ok(line) = line else { return };
+            if line.trim().is_empty() {
+                continue;
+            }
+            match parse_command(&line) {
+                Ok(command) => run_commands(vec![command], &chaos),
+                Err(e) => error!("[CHAOS] Invalid command, {:?}", e),
+            }
+        }
+    });
+}
+
+fn main() {
+    set_logger_config();
+    let args = get_args();
+    if args.is_err() {
+        error!("Error setting args. Use [TOPOLOGY FILE] [SCRIPT FILE - OPTIONAL] [--seed SEED - OPTIONAL]");
+        return;
+    }
+    let args = args.unwrap();
+
+    let commands = match &args.script_file {
+        Some(path) => read_script(path),
+        None => Ok(vec![]),
+    };
+    let servers = topology::proxied_servers(&args.topology_file);
+    let (commands, servers) = match (commands, servers) {
+        (Ok(commands), Ok(servers)) => (commands, servers),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Error loading the proxy configuration {:?}, stopping...", e);
+            return;
+        }
+    };
+
+    let seed = args.seed.unwrap_or_else(rand::random);
+    info!("[CHAOS] Using seed {}", seed);
+    let chaos = Arc::new(Mutex::new(Chaos::new(seed)));
+    let mut proxies = vec![];
+    for server in servers {
+        match proxy::start_proxy(server, chaos.clone()) {
+            Ok(proxy) => proxies.push(proxy),
+            Err(_) => {
+                error!("Error booting up the proxies, stopping...");
+                return;
+            }
+        }
+    }
+
+    listen_commands(chaos.clone());
+    run_commands(commands, &chaos);
+    for proxy in proxies {
+        task::block_on(proxy);
+    }
+}
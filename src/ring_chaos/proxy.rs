@@ -0,0 +1,275 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use async_std::{
+    io::BufReader,
+    net::{TcpListener, TcpStream},
+    task::{self, JoinHandle},
+};
+use lib::{
+    codec::Codec,
+    common_errors::CoffeeSystemError,
+    handshake::{Hello, HelloReply, NodeId},
+};
+use log::{error, info, warn};
+use serde::{
+    de::{self, IgnoredAny, MapAccess, Visitor},
+    Deserialize, Deserializer,
+};
+
+use crate::{chaos::Chaos, errors::ChaosError, topology::ProxiedServer};
+
+/// Tipo de los mensajes entre servidores (`ServerMessageType`). El proxy solo necesita el nombre para filtrar
+/// los mensajes, el contenido se reenvia sin modificar. Se lee solo la etiqueta de la variante, sin conocer la
+/// lista de tipos, asi un tipo que el proxy no conoce igual tiene nombre: ambos codecs escriben una variante sin
+/// datos como su nombre y una con datos como un mapa de su nombre al contenido
+struct MessageType(String);
+
+impl<'de> Deserialize<'de> for MessageType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(MessageTypeVisitor)
+    }
+}
+
+struct MessageTypeVisitor;
+
+impl<'de> Visitor<'de> for MessageTypeVisitor {
+    type Value = MessageType;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("the name of a variant or a map from its name to its content")
+    }
+
+    fn visit_str<E>(self, name: &str) -> Result<MessageType, E>
+    where
+        E: de::Error,
+    {
+        Ok(MessageType(name.to_string()))
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<MessageType, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let (name, _content) = map
+            .next_entry::<String, IgnoredAny>()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        Ok(MessageType(name))
+    }
+}
+
+#[derive(Deserialize)]
+struct ProxiedMessage {
+    message_type: MessageType,
+}
+
+/// Devuelve el tipo de un mensaje entre servidores, o None si no se puede leer
+fn message_type(codec: Codec, frame: &[u8]) -> Option<String> {
+    codec
+        .decode::<ProxiedMessage>(frame)
+        .ok()
+        .map(|message| message.message_type.0)
+}
+
+/// Escucha en la direccion de proxy del servidor y reenvia cada conexion a su direccion del anillo,
+/// aplicando las fallas inyectadas en el enlace
+pub fn start_proxy(
+    server: ProxiedServer,
+    chaos: Arc<Mutex<Chaos>>,
+) -> Result<JoinHandle<()>, ChaosError> {
+    let listener = task::block_on(TcpListener::bind(&server.proxy_address)).map_err(|e| {
+        error!(
+            "[PROXY {}] Error binding to address {}, {}",
+            server.id, server.proxy_address, e
+        );
+        ChaosError::ListenerError
+    })?;
+    info!(
+        "[PROXY {}] Forwarding {} to {}",
+        server.id, server.proxy_address, server.server_address
+    );
+    Ok(task::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let server = server.clone();
+                    let chaos = chaos.clone();
+                    task::spawn(async move {
+                        if let Err(e) = relay_connection(stream, &server, chaos).await {
+                            warn!("[PROXY {}] Connection ended, {:?}", server.id, e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    error!("[PROXY {}] Error accepting connection {}", server.id, e);
+                    return;
+                }
+            }
+        }
+    }))
+}
+
+/// Reenvia el handshake para conocer al cliente y el codec acordado, y luego los mensajes en ambos sentidos
+async fn relay_connection(
+    client: TcpStream,
+    server: &ProxiedServer,
+    chaos: Arc<Mutex<Chaos>>,
+) -> Result<(), CoffeeSystemError> {
+    let mut client_reader = BufReader::new(client.clone());
+    let mut client_writer = client.clone();
+    let hello_frame = Codec::JsonLine
+        .read_frame(&mut client_reader)
+        .await?
+        .ok_or(CoffeeSystemError::ConnectionClosed)?;
+    let hello: Hello = Codec::JsonLine.decode(&hello_frame)?;
+    let client_id = match hello.node_id {
+        NodeId::Server(id) => id,
+        node_id => {
+            warn!("[PROXY {}] Rejecting {:?}", server.id, node_id);
+            return Ok(());
+        }
+    };
+    if chaos.lock()?.is_connection_cut(client_id, server.id) {
+        info!(
+            "[PROXY {}] Link with {} is cut, closing its connection",
+            server.id, client_id
+        );
+        return Ok(());
+    }
+
+    let upstream = TcpStream::connect(&server.server_address).await?;
+    let mut server_reader = BufReader::new(upstream.clone());
+    let mut server_writer = upstream.clone();
+    Codec::JsonLine
+        .write_frame(&mut server_writer, &hello_frame)
+        .await?;
+    let reply_frame = Codec::JsonLine
+        .read_frame(&mut server_reader)
+        .await?
+        .ok_or(CoffeeSystemError::ConnectionClosed)?;
+    Codec::JsonLine
+        .write_frame(&mut client_writer, &reply_frame)
+        .await?;
+    let codec = match Codec::JsonLine.decode(&reply_frame)? {
+        HelloReply::Accepted(reply) => reply.codecs.first().copied().unwrap_or_default(),
+        HelloReply::Rejected(_) => return Ok(()),
+    };
+
+    let id = match chaos
+        .lock()?
+        .register(client_id, server.id, vec![client, upstream])
+    {
+        Some(id) => id,
+        None => return Ok(()),
+    };
+    info!(
+        "[PROXY {}] Relaying connection {} from {} with codec {:?}",
+        server.id, id, client_id, codec
+    );
+    let to_server = task::spawn(relay(
+        codec,
+        client_reader,
+        server_writer,
+        (client_id, server.id),
+        chaos.clone(),
+    ));
+    relay(
+        codec,
+        server_reader,
+        client_writer,
+        (server.id, client_id),
+        chaos.clone(),
+    )
+    .await;
+    // Si se cierra un sentido se cierra la conexion entera, asi termina tambien el otro
+    chaos.lock()?.close(id);
+    to_server.await;
+    chaos.lock()?.close(id);
+    Ok(())
+}
+
+/// Reenvia los mensajes de un sentido de la conexion hasta que se cierre, segun lo que decida `Chaos` para el enlace
+async fn relay(
+    codec: Codec,
+    mut reader: BufReader<TcpStream>,
+    mut writer: TcpStream,
+    (from, to): (usize, usize),
+    chaos: Arc<Mutex<Chaos>>,
+) {
+    while let Ok(Some(frame)) = codec.read_frame(&mut reader).await {
+        let verdict = match chaos.lock() {
+            Ok(mut chaos) => chaos.on_message(from, to, message_type(codec, &frame).as_deref()),
+            Err(_) => return,
+        };
+        if !verdict.delay.is_zero() {
+            task::sleep(verdict.delay).await;
+        }
+        for _ in 0..verdict.copies {
+            if codec.write_frame(&mut writer, &frame).await.is_err() {
+                return;
+            }
+        }
+        for command in verdict.triggered {
+            match chaos.lock() {
+                Ok(mut chaos) => chaos.apply(command),
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lib::codec::SUPPORTED_CODECS;
+    use serde::Serialize;
+
+    use super::*;
+
+    /// Mensaje con la forma de `ServerMessage`, el proxy no depende del servidor
+    #[derive(Serialize)]
+    struct ServerMessage {
+        message_type: ServerMessageType,
+        sender_id: usize,
+    }
+
+    #[derive(Serialize)]
+    enum ServerMessageType {
+        Token(Vec<usize>),
+        CloseConnection,
+        // Tipo que no existe en esta version del servidor
+        Unknown { generation: u64 },
+    }
+
+    #[test]
+    fn should_read_the_type_of_the_server_messages_with_both_codecs() {
+        for codec in SUPPORTED_CODECS {
+            let token = ServerMessage {
+                message_type: ServerMessageType::Token(vec![1, 2]),
+                sender_id: 1,
+            };
+            let close = ServerMessage {
+                message_type: ServerMessageType::CloseConnection,
+                sender_id: 1,
+            };
+            let unknown = ServerMessage {
+                message_type: ServerMessageType::Unknown { generation: 3 },
+                sender_id: 1,
+            };
+            let token = codec.encode(&token).expect("Error encoding");
+            let close = codec.encode(&close).expect("Error encoding");
+            let unknown = codec.encode(&unknown).expect("Error encoding");
+            assert_eq!(Some("Token"), message_type(codec, &token).as_deref());
+            assert_eq!(
+                Some("CloseConnection"),
+                message_type(codec, &close).as_deref()
+            );
+            assert_eq!(Some("Unknown"), message_type(codec, &unknown).as_deref());
+            assert_eq!(None, message_type(codec, b"garbage"));
+        }
+    }
+}
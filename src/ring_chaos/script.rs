@@ -0,0 +1,259 @@
+use std::{fs, time::Duration};
+
+use crate::errors::ChaosError;
+
+/// Enlaces a los que se aplica una falla. Un enlace va de un servidor a otro en un solo sentido
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkSpec {
+    /// `FROM>TO`, cualquiera de los dos puede ser `*` para indicar todos los servidores
+    Link {
+        from: Option<usize>,
+        to: Option<usize>,
+    },
+    /// `ID`, todos los enlaces que salen o llegan al servidor
+    Server(usize),
+}
+
+impl LinkSpec {
+    /// Indica si el enlace de `from` a `to` es uno de los indicados
+    pub fn matches(&self, from: usize, to: usize) -> bool {
+        match self {
+            LinkSpec::Link {
+                from: from_spec,
+                to: to_spec,
+            } => from_spec.is_none_or(|id| id == from) && to_spec.is_none_or(|id| id == to),
+            LinkSpec::Server(id) => *id == from || *id == to,
+        }
+    }
+}
+
+/// Falla que se inyecta en los enlaces
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    /// Cierra las conexiones del enlace y rechaza las nuevas
+    Cut,
+    /// Descarta los mensajes con la probabilidad indicada, solo los del tipo indicado si lo hay
+    Drop {
+        probability: f64,
+        message: Option<String>,
+    },
+    /// Demora cada mensaje el tiempo indicado. Se mantiene el orden, asi que tambien demora a los siguientes
+    Delay(Duration),
+    /// Envia dos veces los mensajes con la probabilidad indicada, solo los del tipo indicado si lo hay
+    Duplicate {
+        probability: f64,
+        message: Option<String>,
+    },
+}
+
+/// Comando del script de fallas. Se leen de a uno por linea, del archivo de script o de la entrada estandar
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// `wait MS`, espera antes de ejecutar el siguiente comando
+    Wait(Duration),
+    /// `cut LINK`, `drop LINK [PROBABILIDAD] [MENSAJE]`, `delay LINK MS`, `duplicate LINK [PROBABILIDAD] [MENSAJE]`
+    Inject(LinkSpec, Fault),
+    /// `heal [LINK]`, quita las fallas inyectadas con el mismo enlace, o todas si no se indica
+    Heal(Option<LinkSpec>),
+    /// `on LINK MENSAJE COMANDO`, ejecuta el comando una vez, luego de reenviar el primer mensaje del tipo indicado
+    /// por el enlace
+    On {
+        link: LinkSpec,
+        message: String,
+        command: Box<Command>,
+    },
+}
+
+/// Lee los comandos de un archivo de script
+pub fn read_script(path: &str) -> Result<Vec<Command>, ChaosError> {
+    parse_script(&fs::read_to_string(path)?)
+}
+
+/// Devuelve los comandos del contenido de un script. Se ignoran las lineas vacias y lo que sigue a un `#`
+pub fn parse_script(content: &str) -> Result<Vec<Command>, ChaosError> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            (!line.is_empty()).then_some(line)
+        })
+        .map(parse_command)
+        .collect()
+}
+
+/// Devuelve el comando de una linea del script
+pub fn parse_command(line: &str) -> Result<Command, ChaosError> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    parse_words(&words).ok_or_else(|| ChaosError::CommandFormat(line.to_string()))
+}
+
+fn parse_words(words: &[&str]) -> Option<Command> {
+    let (name, args) = words.split_first()?;
+    match (*name, args) {
+        ("wait", [ms]) => Some(Command::Wait(parse_millis(ms)?)),
+        ("cut", [link]) => Some(Command::Inject(parse_link(link)?, Fault::Cut)),
+        ("delay", [link, ms]) => Some(Command::Inject(
+            parse_link(link)?,
+            Fault::Delay(parse_millis(ms)?),
+        )),
+        ("drop", [link, filter @ ..]) => {
+            let (probability, message) = parse_filter(filter)?;
+            Some(Command::Inject(
+                parse_link(link)?,
+                Fault::Drop {
+                    probability,
+                    message,
+                },
+            ))
+        }
+        ("duplicate", [link, filter @ ..]) => {
+            let (probability, message) = parse_filter(filter)?;
+            Some(Command::Inject(
+                parse_link(link)?,
+                Fault::Duplicate {
+                    probability,
+                    message,
+                },
+            ))
+        }
+        ("heal", []) => Some(Command::Heal(None)),
+        ("heal", [link]) => Some(Command::Heal(Some(parse_link(link)?))),
+        ("on", [link, message, command @ ..]) => match parse_words(command)? {
+            // El comando se ejecuta desde la conexion que reenvia el mensaje, no puede esperar
+            Command::Wait(_) => None,
+            command => Some(Command::On {
+                link: parse_link(link)?,
+                message: message.to_string(),
+                command: Box::new(command),
+            }),
+        },
+        _ => None,
+    }
+}
+
+fn parse_millis(ms: &str) -> Option<Duration> {
+    ms.parse().ok().map(Duration::from_millis)
+}
+
+fn parse_link(link: &str) -> Option<LinkSpec> {
+    let endpoint = |id: &str| match id {
+        "*" => Some(None),
+        id => id.parse().ok().map(Some),
+    };
+    match link.split_once('>') {
+        Some((from, to)) => Some(LinkSpec::Link {
+            from: endpoint(from)?,
+            to: endpoint(to)?,
+        }),
+        None => link.parse().ok().map(LinkSpec::Server),
+    }
+}
+
+/// `[PROBABILIDAD] [MENSAJE]`, la probabilidad por defecto es 1
+fn parse_filter(filter: &[&str]) -> Option<(f64, Option<String>)> {
+    let (probability, message) = match filter {
+        [] => (1.0, None),
+        [word] => match word.parse::<f64>() {
+            Ok(probability) => (probability, None),
+            Err(_) => (1.0, Some(word.to_string())),
+        },
+        [probability, message] => (probability.parse().ok()?, Some(message.to_string())),
+        _ => return None,
+    };
+    (0.0..=1.0)
+        .contains(&probability)
+        .then_some((probability, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_the_commands_of_a_script() {
+        let script = "
+            # aisla al servidor 2 cuando recibe el token
+            on 1>2 Token cut 2
+            wait 1500
+            drop *>3 0.5 Election
+            duplicate 0>1
+            delay 1>* 200
+            heal 2
+            heal
+        ";
+        let commands = parse_script(script).expect("Error parsing the script");
+        assert_eq!(
+            vec![
+                Command::On {
+                    link: LinkSpec::Link {
+                        from: Some(1),
+                        to: Some(2)
+                    },
+                    message: String::from("Token"),
+                    command: Box::new(Command::Inject(LinkSpec::Server(2), Fault::Cut)),
+                },
+                Command::Wait(Duration::from_millis(1500)),
+                Command::Inject(
+                    LinkSpec::Link {
+                        from: None,
+                        to: Some(3)
+                    },
+                    Fault::Drop {
+                        probability: 0.5,
+                        message: Some(String::from("Election"))
+                    }
+                ),
+                Command::Inject(
+                    LinkSpec::Link {
+                        from: Some(0),
+                        to: Some(1)
+                    },
+                    Fault::Duplicate {
+                        probability: 1.0,
+                        message: None
+                    }
+                ),
+                Command::Inject(
+                    LinkSpec::Link {
+                        from: Some(1),
+                        to: None
+                    },
+                    Fault::Delay(Duration::from_millis(200))
+                ),
+                Command::Heal(Some(LinkSpec::Server(2))),
+                Command::Heal(None),
+            ],
+            commands
+        );
+    }
+
+    #[test]
+    fn should_return_command_format_error_for_invalid_commands() {
+        for line in [
+            "cut",
+            "cut 1>",
+            "drop 1>2 1.5",
+            "delay 1>2",
+            "on 1>2 Token wait 100",
+            "explode 1",
+        ] {
+            assert_eq!(
+                Err(ChaosError::CommandFormat(line.to_string())),
+                parse_command(line)
+            );
+        }
+    }
+
+    #[test]
+    fn should_match_the_links_of_the_spec() {
+        let link = LinkSpec::Link {
+            from: Some(1),
+            to: None,
+        };
+        assert!(link.matches(1, 2));
+        assert!(!link.matches(2, 1));
+        assert!(LinkSpec::Server(2).matches(1, 2));
+        assert!(LinkSpec::Server(2).matches(2, 3));
+        assert!(!LinkSpec::Server(2).matches(1, 3));
+    }
+}
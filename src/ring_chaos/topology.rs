@@ -0,0 +1,93 @@
+use std::fs;
+
+use log::error;
+use serde::Deserialize;
+
+use crate::errors::ChaosError;
+
+/// Servidor de la topologia que tiene un proxy delante. Los demas servidores se conectan a `proxy_address`
+/// y el proxy reenvia el trafico a `server_address`
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct ProxiedServer {
+    pub id: usize,
+    pub server_address: String,
+    pub proxy_address: String,
+}
+
+/// Entrada del archivo de topologia, el mismo que usan los servidores. Se ignoran las direcciones de las cafeteras
+#[derive(Debug, Deserialize)]
+struct ServerEntry {
+    id: usize,
+    server_address: String,
+    #[serde(default)]
+    proxy_address: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Topology {
+    servers: Vec<ServerEntry>,
+}
+
+/// Lee del archivo de topologia los servidores que tienen una direccion de proxy
+pub fn proxied_servers(path: &str) -> Result<Vec<ProxiedServer>, ChaosError> {
+    let content = fs::read_to_string(path).map_err(|e| {
+        error!("[TOPOLOGY] Error reading topology file {}, {}", path, e);
+        ChaosError::FileError
+    })?;
+    parse_proxied_servers(&content)
+}
+
+/// Devuelve los servidores con direccion de proxy del contenido de un archivo de topologia.
+/// Es un error que ninguno la tenga, el proxy no tendria nada que hacer
+pub fn parse_proxied_servers(content: &str) -> Result<Vec<ProxiedServer>, ChaosError> {
+    let topology: Topology = serde_json::from_str(content).map_err(|e| {
+        error!("[TOPOLOGY] Malformed topology file, {}", e);
+        ChaosError::TopologyFormat
+    })?;
+    let servers: Vec<ProxiedServer> = topology
+        .servers
+        .into_iter()
+        .filter_map(|server| {
+            server.proxy_address.map(|proxy_address| ProxiedServer {
+                id: server.id,
+                server_address: server.server_address,
+                proxy_address,
+            })
+        })
+        .collect();
+    if servers.is_empty() {
+        error!("[TOPOLOGY] No server in the topology has a proxy_address");
+        return Err(ChaosError::TopologyFormat);
+    }
+    Ok(servers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_only_return_the_servers_with_a_proxy_address() {
+        let content = r#"{"servers": [
+            {"id": 0, "server_address": "127.0.0.1:10000", "coffee_address": "127.0.0.1:20000", "proxy_address": "127.0.0.1:30000"},
+            {"id": 1, "server_address": "127.0.0.1:10001", "coffee_address": "127.0.0.1:20001"}
+        ]}"#;
+        let servers = parse_proxied_servers(content).expect("Error in topology");
+        assert_eq!(
+            vec![ProxiedServer {
+                id: 0,
+                server_address: String::from("127.0.0.1:10000"),
+                proxy_address: String::from("127.0.0.1:30000"),
+            }],
+            servers
+        );
+    }
+
+    #[test]
+    fn should_return_format_error_if_no_server_has_a_proxy() {
+        let result = parse_proxied_servers(
+            r#"{"servers": [{"id": 0, "server_address": "127.0.0.1:10000", "coffee_address": "127.0.0.1:20000"}]}"#,
+        );
+        assert_eq!(Err(ChaosError::TopologyFormat), result);
+    }
+}
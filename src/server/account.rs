@@ -1,35 +1,45 @@
+use std::time::Instant;
+
 use crate::errors::ServerError;
-use std::time::{SystemTime, UNIX_EPOCH};
+use crate::hybrid_clock::HybridTimestamp;
+
+/// Reserva de puntos de una cuenta hecha por un dispenser de una cafetera. Vence en el instante indicado,
+/// si el dispenser no confirmo ni cancelo el pedido hasta entonces los puntos se liberan.
+/// Una cuenta puede tener varias reservas a la vez mientras la suma de estas no supere sus puntos,
+/// pero a lo sumo una por dispenser ya que cada uno prepara un cafe por vez
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reservation {
+    pub owner: u64,
+    pub points: usize,
+    pub expires_at: Instant,
+}
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Account {
     pub id: usize,
     points: usize,
-    last_updated_on: u128,
-    is_reserved: bool,
+    last_updated_on: HybridTimestamp,
+    reservations: Vec<Reservation>,
 }
 
 impl Account {
-    /// Constructor que crea una cuenta con fecha de ultima actualizacion en el momento de su invocacion
-    pub fn new(id: usize, points: usize) -> Option<Self> {
-        if let Ok(current_timestamp) = SystemTime::now().duration_since(UNIX_EPOCH) {
-            Some(Account {
-                id,
-                points,
-                last_updated_on: current_timestamp.as_nanos(),
-                is_reserved: false,
-            })
-        } else {
-            None
+    /// Constructor que crea una cuenta sin actualizaciones previas, cualquier operacion con marca de tiempo
+    /// es posterior a su creacion
+    pub fn new(id: usize, points: usize) -> Self {
+        Account {
+            id,
+            points,
+            last_updated_on: HybridTimestamp::default(),
+            reservations: Vec::new(),
         }
     }
     /// Constructor que crea una cuenta con fecha de ultima actualizacion
-    pub fn new_from_update(id: usize, points: usize, last_updated_on: u128) -> Self {
+    pub fn new_from_update(id: usize, points: usize, last_updated_on: HybridTimestamp) -> Self {
         Account {
             id,
             points,
             last_updated_on,
-            is_reserved: false,
+            reservations: Vec::new(),
         }
     }
 
@@ -37,19 +47,29 @@ impl Account {
         self.points
     }
 
-    pub fn last_updated_on(&self) -> u128 {
+    pub fn last_updated_on(&self) -> HybridTimestamp {
         self.last_updated_on
     }
 
-    pub fn is_reserved(&mut self) -> bool {
-        self.is_reserved
+    /// Indica si la cuenta tiene alguna reserva que todavia no vencio
+    pub fn is_reserved(&self, now: Instant) -> bool {
+        self.active_reservations(now).next().is_some()
+    }
+
+    /// Puntos de la cuenta que no estan comprometidos en reservas vigentes
+    pub fn available_points(&self, now: Instant) -> usize {
+        let reserved: usize = self
+            .active_reservations(now)
+            .map(|reservation| reservation.points)
+            .sum();
+        self.points.saturating_sub(reserved)
     }
     /// Metodo que suma puntos a una cuenta. En caso de recibirse un timestamp, ser verifica antes de sumar que
     /// el timestamp sea posterior al que posee la cuenta. Caso contrario no realiza la operacion
     pub fn add_points(
         &mut self,
         points: usize,
-        operation_time: Option<u128>,
+        operation_time: Option<HybridTimestamp>,
     ) -> Result<(), ServerError> {
         match operation_time {
             Some(timestamp) => {
@@ -70,12 +90,11 @@ impl Account {
 
     /// Metodo que resta puntos a una cuenta. En caso de recibirse un timestamp, ser verifica antes de restar que
     /// el timestamp sea posterior al que posee la cuenta. Caso contrario no realiza la operacion.
-    /// Ademas, si la cuenta esta reservada, retornara error, asi como tambien sucede si los puntos a restar son mas de los que
-    /// dispone la cuenta
+    /// Retorna error si los puntos a restar son mas de los que dispone la cuenta. No modifica la reserva de la cuenta
     pub fn substract_points(
         &mut self,
         points: usize,
-        operation_time: Option<u128>,
+        operation_time: Option<HybridTimestamp>,
     ) -> Result<(), ServerError> {
         if points > self.points {
             return Err(ServerError::NotEnoughPointsInAccount);
@@ -86,116 +105,282 @@ impl Account {
                 if self.last_updated_on < timestamp {
                     self.points -= points;
                     self.last_updated_on = timestamp;
-                    self.is_reserved = false;
                     return Ok(());
                 }
                 Err(ServerError::OperationIsOutdated)
             }
             None => {
                 self.points -= points;
-                self.is_reserved = false;
                 Ok(())
             }
         }
     }
     /// Actualiza una cuenta con los valores recibidos para puntos y timestamp
-    pub fn update(&mut self, points: usize, operation_time: u128) {
+    pub fn update(&mut self, points: usize, operation_time: HybridTimestamp) {
         self.points = points;
         self.last_updated_on = operation_time;
     }
-    /// Metodo que elimina la reserva de una cuenta
-    pub fn cancel_reservation(&mut self) {
-        self.is_reserved = false;
+    /// Metodo que resta los puntos de la reserva del dispenser y la libera.
+    /// Retorna error si el dispenser no tiene una reserva vigente por al menos esos puntos
+    pub fn take_reserved_points(
+        &mut self,
+        owner: u64,
+        points: usize,
+        operation_time: Option<HybridTimestamp>,
+        now: Instant,
+    ) -> Result<(), ServerError> {
+        let position = self.reservation_position(owner, now)?;
+        if points > self.reservations[position].points {
+            return Err(ServerError::PointsNotReserved);
+        }
+        self.substract_points(points, operation_time)?;
+        self.reservations.remove(position);
+        Ok(())
+    }
+    /// Metodo que elimina la reserva del dispenser. Retorna error si el dispenser no tiene una reserva vigente
+    pub fn cancel_reservation(&mut self, owner: u64, now: Instant) -> Result<(), ServerError> {
+        let position = self.reservation_position(owner, now)?;
+        self.reservations.remove(position);
+        Ok(())
     }
-    /// Metodo que reserva una cuenta. Retorna error en caso de que esta ya este reservada
-    pub fn reserve(&mut self) -> Result<(), ServerError> {
-        if self.is_reserved {
+    /// Metodo que reserva puntos de una cuenta para un dispenser hasta el instante indicado.
+    /// Si el dispenser ya tenia una reserva en la cuenta, esta es de un pedido que abandono y se reemplaza.
+    /// Retorna error si los puntos no estan disponibles porque estan reservados por otros pedidos,
+    /// o si la cuenta no tiene esa cantidad de puntos
+    pub fn reserve(
+        &mut self,
+        owner: u64,
+        points: usize,
+        expires_at: Instant,
+        now: Instant,
+    ) -> Result<(), ServerError> {
+        self.reservations
+            .retain(|reservation| reservation.owner != owner);
+        if points > self.points {
+            return Err(ServerError::NotEnoughPointsInAccount);
+        }
+        if points > self.available_points(now) {
             return Err(ServerError::AccountIsReserved);
         }
-        self.is_reserved = true;
+        self.reservations.push(Reservation {
+            owner,
+            points,
+            expires_at,
+        });
         Ok(())
     }
+    /// Metodo que libera las reservas de la cuenta que ya vencieron. Retorna cuantas libero
+    pub fn release_expired(&mut self, now: Instant) -> usize {
+        let before = self.reservations.len();
+        self.reservations
+            .retain(|reservation| now < reservation.expires_at);
+        before - self.reservations.len()
+    }
+
+    fn active_reservations(&self, now: Instant) -> impl Iterator<Item = &Reservation> {
+        self.reservations
+            .iter()
+            .filter(move |reservation| now < reservation.expires_at)
+    }
+
+    /// Posicion de la reserva vigente del dispenser
+    fn reservation_position(&self, owner: u64, now: Instant) -> Result<usize, ServerError> {
+        if !self.is_reserved(now) {
+            return Err(ServerError::ReservationNotFound);
+        }
+        self.reservations
+            .iter()
+            .position(|reservation| reservation.owner == owner && now < reservation.expires_at)
+            .ok_or(ServerError::NotReservationOwner)
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use super::*;
+    use crate::hybrid_clock::HybridClock;
+
+    fn in_secs(now: Instant, secs: u64) -> Instant {
+        now + Duration::from_secs(secs)
+    }
 
     #[test]
     fn account_points_for_empty_account_after_adding_5_points_should_return_5() {
-        if let Some(mut account) = Account::new(1, 0) {
-            let _result = account.add_points(5, None);
-            assert_eq!(account.points(), 5)
-        } else {
-            panic!("[Error] System time is somehow older than UNIX EPOCH")
-        }
+        let mut account = Account::new(1, 0);
+        let _result = account.add_points(5, None);
+        assert_eq!(account.points(), 5)
     }
 
     #[test]
     fn accounts_point_for_account_with_points_after_adding_100_points_should_return_correct_amount()
     {
-        if let Some(mut account) = Account::new(1, 200) {
-            let correct_amount = account.points() + 100;
-            let _result = account.add_points(100, None);
-            assert_eq!(account.points(), correct_amount)
-        } else {
-            panic!("[Error] System time is somehow older than UNIX EPOCH")
-        }
+        let mut account = Account::new(1, 200);
+        let correct_amount = account.points() + 100;
+        let _result = account.add_points(100, None);
+        assert_eq!(account.points(), correct_amount)
     }
 
     #[test]
     fn account_points_after_substracting_10_points_to_account_with_20_points_should_return_10() {
-        if let Some(mut account) = Account::new(1, 20) {
-            let correct_amount = account.points() - 10;
-            account
-                .substract_points(10, None)
-                .expect("[Error]Failed to substract points");
-            assert_eq!(account.points(), correct_amount)
-        } else {
-            panic!("[Error] System time is somehow older than UNIX EPOCH")
-        }
+        let mut account = Account::new(1, 20);
+        let correct_amount = account.points() - 10;
+        account
+            .substract_points(10, None)
+            .expect("[Error]Failed to substract points");
+        assert_eq!(account.points(), correct_amount)
     }
 
     #[test]
-    fn reserved_account_should_be_unreserved_after_substracting() {
-        if let Some(mut account) = Account::new(1, 20) {
-            let _result = account.reserve();
-            account
-                .substract_points(10, None)
-                .expect("[Error]Failed to substract points");
-            assert!(!account.is_reserved())
-        } else {
-            panic!("[Error] System time is somehow older than UNIX EPOCH")
-        }
+    fn reserved_account_should_be_unreserved_after_taking_the_reserved_points() {
+        let now = Instant::now();
+        let mut account = Account::new(1, 20);
+        let _result = account.reserve(3, 10, in_secs(now, 30), now);
+        account
+            .take_reserved_points(3, 10, None, now)
+            .expect("[Error]Failed to substract points");
+        assert!(!account.is_reserved(now));
+        assert_eq!(account.points(), 10)
     }
 
     #[test]
     fn substracting_more_points_than_available_should_return_error_() {
-        if let Some(mut account) = Account::new(1, 50) {
-            assert!(account.substract_points(100, None).is_err())
-        } else {
-            panic!("[Error] System time is somehow older than UNIX EPOCH")
-        }
+        let mut account = Account::new(1, 50);
+        assert!(account.substract_points(100, None).is_err())
     }
 
     #[test]
+    #[allow(clippy::nonminimal_bool)]
     fn trying_to_reserve_unreserved_account_should_return_ok() {
-        if let Some(mut account) = Account::new(1, 50) {
-            assert!(!account.reserve().is_err());
-        } else {
-            panic!("[Error] System time is somehow older than UNIX EPOCH")
-        }
+        let now = Instant::now();
+        let mut account = Account::new(1, 50);
+        assert!(!account.reserve(3, 10, in_secs(now, 30), now).is_err());
     }
 
     #[test]
-    fn trying_to_reserve_reserved_account_should_return_error() {
-        if let Some(mut account) = Account::new(1, 50) {
-            account
-                .reserve()
-                .expect("[Err] Account was already reserved");
-            assert!(account.reserve().is_err());
-        } else {
-            panic!("[Error] System time is somehow older than UNIX EPOCH")
-        }
+    fn trying_to_reserve_points_held_by_other_reservations_should_return_error() {
+        let now = Instant::now();
+        let mut account = Account::new(1, 50);
+        account
+            .reserve(3, 40, in_secs(now, 30), now)
+            .expect("[Err] Account was already reserved");
+        assert!(matches!(
+            account.reserve(4, 20, in_secs(now, 30), now),
+            Err(ServerError::AccountIsReserved)
+        ));
+        assert!(matches!(
+            account.reserve(4, 60, in_secs(now, 30), now),
+            Err(ServerError::NotEnoughPointsInAccount)
+        ));
+    }
+
+    #[test]
+    fn concurrent_reservations_should_be_accepted_while_the_points_are_enough() {
+        let now = Instant::now();
+        let mut account = Account::new(1, 50);
+        assert!(account.reserve(3, 20, in_secs(now, 30), now).is_ok());
+        assert!(account.reserve(4, 20, in_secs(now, 30), now).is_ok());
+        assert_eq!(10, account.available_points(now));
+
+        account
+            .take_reserved_points(4, 20, None, now)
+            .expect("[Error]Failed to take reserved points");
+        assert_eq!(30, account.points());
+        assert_eq!(10, account.available_points(now));
+        assert!(account.reserve(5, 10, in_secs(now, 30), now).is_ok());
+        assert_eq!(0, account.available_points(now));
+    }
+
+    #[test]
+    fn expired_reservation_should_be_released_and_the_account_reserved_again() {
+        let now = Instant::now();
+        let mut account = Account::new(1, 50);
+        account
+            .reserve(3, 50, in_secs(now, 30), now)
+            .expect("[Err] Account was already reserved");
+        let later = in_secs(now, 31);
+        assert!(account.reserve(4, 50, in_secs(later, 30), later).is_ok());
+        assert_eq!(1, account.release_expired(later));
+        assert_eq!(1, account.release_expired(in_secs(later, 31)));
+        assert!(!account.is_reserved(later));
+    }
+
+    #[test]
+    fn only_the_owner_should_take_or_cancel_the_reserved_points() {
+        let now = Instant::now();
+        let mut account = Account::new(1, 50);
+        account
+            .reserve(3, 10, in_secs(now, 30), now)
+            .expect("[Err] Account was already reserved");
+        assert!(matches!(
+            account.take_reserved_points(4, 10, None, now),
+            Err(ServerError::NotReservationOwner)
+        ));
+        assert!(matches!(
+            account.cancel_reservation(4, now),
+            Err(ServerError::NotReservationOwner)
+        ));
+        assert!(matches!(
+            account.take_reserved_points(3, 20, None, now),
+            Err(ServerError::PointsNotReserved)
+        ));
+        assert!(account.cancel_reservation(3, now).is_ok());
+        assert!(matches!(
+            account.take_reserved_points(3, 10, None, now),
+            Err(ServerError::ReservationNotFound)
+        ));
+        assert_eq!(account.points(), 50);
+    }
+
+    #[test]
+    fn each_dispenser_should_take_or_cancel_only_its_own_reservation() {
+        let now = Instant::now();
+        let mut account = Account::new(1, 50);
+        account
+            .reserve(10, 30, in_secs(now, 30), now)
+            .expect("[Err] Account was already reserved");
+        account
+            .reserve(20, 20, in_secs(now, 30), now)
+            .expect("[Err] Account was already reserved");
+        assert!(matches!(
+            account.take_reserved_points(20, 30, None, now),
+            Err(ServerError::PointsNotReserved)
+        ));
+
+        assert!(account.cancel_reservation(20, now).is_ok());
+        assert_eq!(20, account.available_points(now));
+        account
+            .take_reserved_points(10, 30, None, now)
+            .expect("[Error]Failed to take reserved points");
+        assert_eq!(20, account.points());
+        assert!(!account.is_reserved(now));
+    }
+
+    #[test]
+    fn new_reservation_of_a_dispenser_should_replace_its_abandoned_one() {
+        let now = Instant::now();
+        let mut account = Account::new(1, 50);
+        account
+            .reserve(10, 30, in_secs(now, 30), now)
+            .expect("[Err] Account was already reserved");
+        assert!(account.reserve(10, 40, in_secs(now, 30), now).is_ok());
+        assert_eq!(10, account.available_points(now));
+        assert!(account.cancel_reservation(10, now).is_ok());
+        assert!(!account.is_reserved(now));
+    }
+
+    #[test]
+    fn operation_stamped_after_an_update_from_a_skewed_server_should_be_applied() {
+        let mut account = Account::new(1, 50);
+        // Otro servidor tiene el reloj muy adelantado
+        let remote_update = HybridTimestamp::new(u64::MAX / 2, 0, 2);
+        account.update(80, remote_update);
+
+        let mut clock = HybridClock::new(1);
+        clock.update(remote_update);
+        assert!(account.add_points(10, Some(clock.now())).is_ok());
+        assert_eq!(account.points(), 90);
+        assert!(account.add_points(10, Some(remote_update)).is_err());
     }
 }
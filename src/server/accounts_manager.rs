@@ -1,22 +1,67 @@
-use crate::{errors::ServerError, server_messages::UpdatedAccount};
+use crate::{
+    errors::ServerError,
+    hybrid_clock::HybridTimestamp,
+    server_messages::{AccountAction, UpdatedAccount},
+};
 
-pub trait AccountsManager {
+/// Interfaz hacia la base de datos de los puntos de las cuentas
+pub trait AccountsManager: Send {
     fn add_points(
         &mut self,
         account_id: usize,
         points: usize,
-        operation_time: Option<u128>,
+        operation_time: Option<HybridTimestamp>,
     ) -> Result<(), ServerError>;
     fn substract_points(
         &mut self,
         account_id: usize,
         points: usize,
-        operation_time: Option<u128>,
+        operation_time: Option<HybridTimestamp>,
     ) -> Result<(), ServerError>;
-    fn update(&mut self, account_id: usize, points: usize, operation_time: u128);
-    fn request_points(&mut self, account_id: usize, points: usize) -> Result<(), ServerError>;
-    fn cancel_requested_points(&mut self, account_id: usize) -> Result<(), ServerError>;
-    fn get_most_recent_update(&self) -> u128;
-    fn get_accounts_updated_after(&self, timestamp: u128) -> Vec<UpdatedAccount>;
-    fn clear_reservations(&mut self);
+    /// Reemplaza los puntos y la marca de tiempo de la cuenta, creandola si no existe
+    fn update(
+        &mut self,
+        account_id: usize,
+        points: usize,
+        operation_time: HybridTimestamp,
+    ) -> Result<(), ServerError>;
+    /// Reserva los puntos de la cuenta para el dispenser indicado. La reserva vence luego de un tiempo
+    fn request_points(
+        &mut self,
+        account_id: usize,
+        points: usize,
+        owner: u64,
+    ) -> Result<(), ServerError>;
+    /// Resta los puntos reservados por el dispenser indicado y libera la reserva
+    fn take_reserved_points(
+        &mut self,
+        account_id: usize,
+        owner: u64,
+        points: usize,
+        operation_time: Option<HybridTimestamp>,
+    ) -> Result<(), ServerError>;
+    fn cancel_requested_points(&mut self, account_id: usize, owner: u64)
+        -> Result<(), ServerError>;
+    /// Devuelve el estado actual de una cuenta segun la base local, si existe
+    fn get_account(&self, account_id: usize) -> Option<UpdatedAccount>;
+    /// Devuelve el estado actual de todas las cuentas segun la base local
+    fn get_all_accounts(&self) -> Vec<UpdatedAccount>;
+    fn get_most_recent_update(&self) -> HybridTimestamp;
+    fn get_accounts_updated_after(&self, timestamp: HybridTimestamp) -> Vec<UpdatedAccount>;
+    /// Libera las reservas que vencieron sin que la cafetera confirme o cancele el pedido
+    fn release_expired_reservations(&mut self);
+    /// Registra una operacion aplicada en la historia de su cuenta. Devuelve false si ya estaba registrada
+    fn record_action(&mut self, action: AccountAction) -> bool;
+    /// Registra varias operaciones en la historia de sus cuentas. Devuelve cuantas no estaban registradas
+    fn record_actions(&mut self, actions: &[AccountAction]) -> usize;
+    /// Aplica la operacion sobre su cuenta y la registra en la historia en un solo paso. Si se indica el dispenser
+    /// los puntos se toman de su reserva. Si la operacion falla no se registra
+    fn apply_action(
+        &mut self,
+        action: &AccountAction,
+        owner: Option<u64>,
+    ) -> Result<(), ServerError>;
+    /// Devuelve una pagina de la historia de la cuenta, de la operacion mas reciente a la mas antigua
+    fn get_history(&self, account_id: usize, page: usize, page_size: usize) -> Vec<AccountAction>;
+    fn get_actions_after(&self, timestamp: HybridTimestamp) -> Vec<AccountAction>;
 }
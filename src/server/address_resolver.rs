@@ -1,14 +1,387 @@
-pub fn id_to_coffee_port(id: usize) -> String {
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    net::IpAddr,
+};
+
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::errors::ServerError;
+
+pub fn id_to_coffee_address(id: usize) -> String {
     let port = id + 20000;
-    port.to_string()
+    "127.0.0.1:".to_owned() + &*port.to_string()
 }
 
-pub fn id_to_server_port(id: usize) -> String {
+pub fn id_to_address(id: usize) -> String {
     let port = id + 10000;
-    port.to_string()
+    "127.0.0.1:".to_owned() + &*port.to_string()
 }
 
-pub fn id_to_address(id: usize) -> String {
-    let port = id + 10000;
+pub fn id_to_admin_address(id: usize) -> String {
+    let port = id + 30000;
+    "127.0.0.1:".to_owned() + &*port.to_string()
+}
+
+pub fn id_to_metrics_address(id: usize) -> String {
+    let port = id + 9000;
     "127.0.0.1:".to_owned() + &*port.to_string()
 }
+
+/// Direcciones de un servidor de la red, la del anillo de servidores y la de las cafeteras.
+/// Si se indica una direccion de proxy los demas servidores se conectan a esa en lugar de a la del anillo,
+/// por ejemplo para pasar por `ring_chaos`. La consola de administracion y las metricas solo se escuchan si tienen
+/// direccion, y deben ser de loopback ya que no piden credenciales
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ServerAddresses {
+    pub id: usize,
+    pub server_address: String,
+    pub coffee_address: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy_address: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub admin_address: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metrics_address: Option<String>,
+}
+
+/// Formato del archivo de topologia
+#[derive(Debug, Serialize, Deserialize)]
+struct Topology {
+    servers: Vec<ServerAddresses>,
+}
+
+/// Resuelve las direcciones HOST:PUERTO de los servidores a partir de su id.
+/// Puede armarse desde un archivo de topologia o derivarse de los puertos por defecto en la maquina local
+#[derive(Debug)]
+pub struct AddressResolver {
+    servers: HashMap<usize, ServerAddresses>,
+}
+
+impl AddressResolver {
+    /// Crea el resolver con las direcciones por defecto, todos los servidores en 127.0.0.1
+    /// con los puertos a partir de 10000 para el anillo, 20000 para las cafeteras, 30000 para la administracion
+    /// y 9000 para las metricas.
+    /// Incluye a los servidores de 0 a `peer_count` y al servidor propio
+    pub fn new_local(my_id: usize, peer_count: usize) -> AddressResolver {
+        let servers = (0..peer_count)
+            .chain(std::iter::once(my_id))
+            .map(|id| {
+                (
+                    id,
+                    ServerAddresses {
+                        id,
+                        server_address: id_to_address(id),
+                        coffee_address: id_to_coffee_address(id),
+                        proxy_address: None,
+                        admin_address: Some(id_to_admin_address(id)),
+                        metrics_address: Some(id_to_metrics_address(id)),
+                    },
+                )
+            })
+            .collect();
+        AddressResolver { servers }
+    }
+
+    /// Crea el resolver a partir de un archivo JSON de topologia
+    pub fn from_file(path: &str, my_id: usize) -> Result<AddressResolver, ServerError> {
+        let content = fs::read_to_string(path).map_err(|e| {
+            error!("[TOPOLOGY] Error reading topology file {}, {}", path, e);
+            ServerError::TopologyFileError
+        })?;
+        AddressResolver::from_json(&content, my_id)
+    }
+
+    /// Crea el resolver a partir del contenido de un archivo de topologia. Valida que el servidor propio este presente,
+    /// que no haya ids repetidos, que no se repitan las direcciones y que la administracion y las metricas solo se
+    /// expongan en loopback
+    pub fn from_json(content: &str, my_id: usize) -> Result<AddressResolver, ServerError> {
+        let topology: Topology = serde_json::from_str(content).map_err(|e| {
+            error!("[TOPOLOGY] Malformed topology file, {}", e);
+            ServerError::TopologyFormat
+        })?;
+
+        let mut servers = HashMap::new();
+        let mut used_addresses = HashSet::new();
+        for server in topology.servers {
+            let addresses = [&server.server_address, &server.coffee_address]
+                .into_iter()
+                .chain(server.proxy_address.as_ref())
+                .chain(server.admin_address.as_ref())
+                .chain(server.metrics_address.as_ref());
+            for address in addresses {
+                if !is_valid_address(address) {
+                    error!(
+                        "[TOPOLOGY] Invalid address {} for server {}, use HOST:PORT",
+                        address, server.id
+                    );
+                    return Err(ServerError::TopologyFormat);
+                }
+                if !used_addresses.insert(address.clone()) {
+                    error!(
+                        "[TOPOLOGY] Address {} of server {} is already in use",
+                        address, server.id
+                    );
+                    return Err(ServerError::DuplicatedAddress);
+                }
+            }
+            let local_only = [
+                ("Admin", &server.admin_address),
+                ("Metrics", &server.metrics_address),
+            ];
+            for (name, address) in local_only {
+                if let Some(address) = address {
+                    if !is_loopback_address(address) {
+                        error!(
+                            "[TOPOLOGY] {} address {} of server {} is not a loopback address",
+                            name, address, server.id
+                        );
+                        return Err(ServerError::TopologyFormat);
+                    }
+                }
+            }
+            if servers.contains_key(&server.id) {
+                error!("[TOPOLOGY] Server {} is defined more than once", server.id);
+                return Err(ServerError::DuplicatedServerId);
+            }
+            servers.insert(server.id, server);
+        }
+
+        if !servers.contains_key(&my_id) {
+            error!("[TOPOLOGY] Server {} is not in the topology", my_id);
+            return Err(ServerError::UnknownServerId);
+        }
+        Ok(AddressResolver { servers })
+    }
+
+    /// Devuelve los ids de los servidores conocidos
+    pub fn server_ids(&self) -> Vec<usize> {
+        self.servers.keys().copied().collect()
+    }
+
+    /// Devuelve la direccion en la que el servidor escucha las conexiones del anillo
+    pub fn server_address(&self, id: usize) -> Option<&String> {
+        self.servers.get(&id).map(|server| &server.server_address)
+    }
+
+    /// Devuelve la direccion a la que se conectan los demas servidores del anillo. Es la del proxy
+    /// si el servidor tiene uno delante, o la del anillo en otro caso
+    pub fn ring_address(&self, id: usize) -> Option<&String> {
+        self.servers.get(&id).map(|server| {
+            server
+                .proxy_address
+                .as_ref()
+                .unwrap_or(&server.server_address)
+        })
+    }
+
+    /// Devuelve la direccion donde el servidor escucha a las cafeteras
+    pub fn coffee_address(&self, id: usize) -> Option<&String> {
+        self.servers.get(&id).map(|server| &server.coffee_address)
+    }
+
+    /// Devuelve la direccion donde el servidor escucha a la consola de administracion, si tiene una
+    pub fn admin_address(&self, id: usize) -> Option<&String> {
+        self.servers
+            .get(&id)
+            .and_then(|server| server.admin_address.as_ref())
+    }
+
+    /// Devuelve la direccion donde el servidor expone sus metricas, si tiene una
+    pub fn metrics_address(&self, id: usize) -> Option<&String> {
+        self.servers
+            .get(&id)
+            .and_then(|server| server.metrics_address.as_ref())
+    }
+}
+
+/// Una direccion es valida si tiene el formato HOST:PUERTO
+fn is_valid_address(address: &str) -> bool {
+    match address.rsplit_once(':') {
+        Some((host, port)) => !host.is_empty() && port.parse::<u16>().is_ok(),
+        None => false,
+    }
+}
+
+/// Una direccion es de loopback si su host es `localhost` o una IP de loopback
+fn is_loopback_address(address: &str) -> bool {
+    match address.rsplit_once(':') {
+        Some(("localhost", _)) => true,
+        Some((host, _)) => host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback()),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_resolve_the_addresses_of_the_topology_file() {
+        let resolver =
+            AddressResolver::from_file("tests/topology.json", 0).expect("Error in topology");
+        assert_eq!(
+            Some(&String::from("127.0.0.1:10001")),
+            resolver.server_address(1)
+        );
+        assert_eq!(
+            Some(&String::from("127.0.0.1:20002")),
+            resolver.coffee_address(2)
+        );
+        assert_eq!(
+            Some(&String::from("127.0.0.1:30000")),
+            resolver.admin_address(0)
+        );
+        assert_eq!(None, resolver.server_address(3));
+    }
+
+    #[test]
+    fn should_resolve_the_default_local_addresses() {
+        let resolver = AddressResolver::new_local(4, 2);
+        assert_eq!(
+            Some(&String::from("127.0.0.1:10001")),
+            resolver.server_address(1)
+        );
+        assert_eq!(
+            Some(&String::from("127.0.0.1:20000")),
+            resolver.coffee_address(0)
+        );
+        assert_eq!(None, resolver.coffee_address(2));
+        assert_eq!(
+            Some(&String::from("127.0.0.1:10004")),
+            resolver.server_address(4)
+        );
+        assert_eq!(
+            Some(&String::from("127.0.0.1:30001")),
+            resolver.admin_address(1)
+        );
+        assert_eq!(
+            Some(&String::from("127.0.0.1:9001")),
+            resolver.metrics_address(1)
+        );
+    }
+
+    #[test]
+    fn should_listen_the_admin_console_only_if_the_topology_has_its_address() {
+        let content = r#"{"servers": [
+            {"id": 0, "server_address": "127.0.0.1:10000", "coffee_address": "127.0.0.1:20000", "admin_address": "127.0.0.1:30000"},
+            {"id": 1, "server_address": "127.0.0.1:10001", "coffee_address": "127.0.0.1:20001"}
+        ]}"#;
+        let resolver = AddressResolver::from_json(content, 0).expect("Error in topology");
+        assert_eq!(
+            Some(&String::from("127.0.0.1:30000")),
+            resolver.admin_address(0)
+        );
+        assert_eq!(None, resolver.admin_address(1));
+    }
+
+    #[test]
+    fn should_expose_the_metrics_only_on_loopback() {
+        let content = r#"{"servers": [
+            {"id": 0, "server_address": "10.0.0.1:10000", "coffee_address": "10.0.0.1:20000", "metrics_address": "127.0.0.1:9000"},
+            {"id": 1, "server_address": "10.0.0.2:10000", "coffee_address": "10.0.0.2:20000", "metrics_address": "[::1]:9001"}
+        ]}"#;
+        let resolver = AddressResolver::from_json(content, 0).expect("Error in topology");
+        assert_eq!(
+            Some(&String::from("127.0.0.1:9000")),
+            resolver.metrics_address(0)
+        );
+
+        let content = r#"{"servers": [
+            {"id": 0, "server_address": "10.0.0.1:10000", "coffee_address": "10.0.0.1:20000", "metrics_address": "10.0.0.1:9000"}
+        ]}"#;
+        let result = AddressResolver::from_json(content, 0);
+        assert!(matches!(result, Err(ServerError::TopologyFormat)));
+    }
+
+    #[test]
+    fn should_listen_the_admin_console_only_on_loopback() {
+        let content = r#"{"servers": [
+            {"id": 0, "server_address": "10.0.0.1:10000", "coffee_address": "10.0.0.1:20000", "admin_address": "localhost:30000"}
+        ]}"#;
+        let resolver = AddressResolver::from_json(content, 0).expect("Error in topology");
+        assert_eq!(
+            Some(&String::from("localhost:30000")),
+            resolver.admin_address(0)
+        );
+
+        let content = r#"{"servers": [
+            {"id": 0, "server_address": "10.0.0.1:10000", "coffee_address": "10.0.0.1:20000", "admin_address": "0.0.0.0:30000"}
+        ]}"#;
+        let result = AddressResolver::from_json(content, 0);
+        assert!(matches!(result, Err(ServerError::TopologyFormat)));
+    }
+
+    #[test]
+    fn should_route_the_ring_through_the_proxy_address_if_there_is_one() {
+        let content = r#"{"servers": [
+            {"id": 0, "server_address": "127.0.0.1:10000", "coffee_address": "127.0.0.1:20000", "proxy_address": "127.0.0.1:30000"},
+            {"id": 1, "server_address": "127.0.0.1:10001", "coffee_address": "127.0.0.1:20001"}
+        ]}"#;
+        let resolver = AddressResolver::from_json(content, 0).expect("Error in topology");
+        assert_eq!(
+            Some(&String::from("127.0.0.1:10000")),
+            resolver.server_address(0)
+        );
+        assert_eq!(
+            Some(&String::from("127.0.0.1:30000")),
+            resolver.ring_address(0)
+        );
+        assert_eq!(
+            Some(&String::from("127.0.0.1:10001")),
+            resolver.ring_address(1)
+        );
+    }
+
+    #[test]
+    fn should_return_unknown_id_if_the_server_is_not_in_the_topology() {
+        let content = r#"{"servers": [{"id": 0, "server_address": "10.0.0.1:10000", "coffee_address": "10.0.0.1:20000"}]}"#;
+        let result = AddressResolver::from_json(content, 1);
+        assert!(matches!(result, Err(ServerError::UnknownServerId)));
+    }
+
+    #[test]
+    fn should_return_duplicated_address_if_two_listeners_share_host_and_port() {
+        let content = r#"{"servers": [
+            {"id": 0, "server_address": "10.0.0.1:10000", "coffee_address": "10.0.0.1:20000"},
+            {"id": 1, "server_address": "10.0.0.1:20000", "coffee_address": "10.0.0.2:20000"}
+        ]}"#;
+        let result = AddressResolver::from_json(content, 0);
+        assert!(matches!(result, Err(ServerError::DuplicatedAddress)));
+
+        let content = r#"{"servers": [
+            {"id": 0, "server_address": "10.0.0.1:10000", "coffee_address": "10.0.0.1:20000", "proxy_address": "10.0.0.1:10000"}
+        ]}"#;
+        let result = AddressResolver::from_json(content, 0);
+        assert!(matches!(result, Err(ServerError::DuplicatedAddress)));
+    }
+
+    #[test]
+    fn should_return_duplicated_id_if_a_server_is_defined_twice() {
+        let content = r#"{"servers": [
+            {"id": 0, "server_address": "10.0.0.1:10000", "coffee_address": "10.0.0.1:20000"},
+            {"id": 0, "server_address": "10.0.0.2:10000", "coffee_address": "10.0.0.2:20000"}
+        ]}"#;
+        let result = AddressResolver::from_json(content, 0);
+        assert!(matches!(result, Err(ServerError::DuplicatedServerId)));
+    }
+
+    #[test]
+    fn should_return_format_error_for_malformed_entries() {
+        let result = AddressResolver::from_json(r#"{"servers": [{"id": 0}]}"#, 0);
+        assert!(matches!(result, Err(ServerError::TopologyFormat)));
+
+        let content = r#"{"servers": [{"id": 0, "server_address": "10.0.0.1", "coffee_address": "10.0.0.1:20000"}]}"#;
+        let result = AddressResolver::from_json(content, 0);
+        assert!(matches!(result, Err(ServerError::TopologyFormat)));
+
+        let content = r#"{"servers": [{"id": 0, "server_address": "10.0.0.1:99999", "coffee_address": "10.0.0.1:20000"}]}"#;
+        let result = AddressResolver::from_json(content, 0);
+        assert!(matches!(result, Err(ServerError::TopologyFormat)));
+    }
+}
@@ -0,0 +1,262 @@
+use std::thread;
+
+use async_std::task;
+use lib::{
+    admin_messages::{AccountSummary, AdminRequest, AdminResponse, QueuedOrder, ServerStatus},
+    common_errors::CoffeeSystemError,
+    connection_protocol::{recv_message, send_message, ConnectionProtocol},
+    handshake::NodeKind,
+};
+use log::{debug, error, info};
+
+use crate::{
+    connection_server::{ConnectionServer, Network},
+    errors::ServerError,
+    ring_node::NodeState,
+    server_messages::UpdatedAccount,
+    shutdown::shut_down,
+};
+
+/// Servidor que escucha a la consola de administracion. Responde cada consulta con el estado del servidor en
+/// ejecucion, el mismo que comparten sus componentes
+pub struct AdminServer {
+    listener: Box<dyn ConnectionServer + Send>,
+    state: NodeState,
+}
+
+impl AdminServer {
+    /// Devuelve un nuevo AdminServer escuchando en la direccion indicada, o error si no se puede abrir el listener
+    pub fn new(
+        address: &str,
+        state: NodeState,
+        network: &dyn Network,
+    ) -> Result<AdminServer, ServerError> {
+        let listener = network.bind(address, state.id, NodeKind::Admin)?;
+        Ok(AdminServer { listener, state })
+    }
+
+    /// Escucha nuevas conexiones de la consola y responde los pedidos de cada una en su hilo
+    pub fn listen(&mut self) -> Result<(), ServerError> {
+        loop {
+            let connection = task::block_on(self.listener.listen())?;
+            let state = self.state.clone();
+            thread::spawn(move || {
+                if let Err(e) = answer_requests(connection, &state) {
+                    debug!("[ADMIN] Console connection closed, {:?}", e);
+                }
+            });
+        }
+    }
+}
+
+/// Responde los pedidos de una conexion de la consola hasta que se cierre
+fn answer_requests(
+    mut connection: Box<dyn ConnectionProtocol + Send>,
+    state: &NodeState,
+) -> Result<(), CoffeeSystemError> {
+    loop {
+        let request: AdminRequest = task::block_on(recv_message(connection.as_mut()))?;
+        debug!("[ADMIN] Received {:?} request", request);
+        let response = match respond(state, request) {
+            Ok(response) => response,
+            Err(e) => {
+                error!("[ADMIN] Error answering {:?}, {:?}", request, e);
+                return Err(CoffeeSystemError::UnexpectedError);
+            }
+        };
+        task::block_on(send_message(connection.as_mut(), &response))?;
+    }
+}
+
+/// Arma la respuesta a un pedido de la consola a partir del estado del servidor
+pub fn respond(state: &NodeState, request: AdminRequest) -> Result<AdminResponse, ServerError> {
+    let response = match request {
+        AdminRequest::Status => AdminResponse::Status(status(state)?),
+        AdminRequest::ListAccounts => {
+            let mut accounts: Vec<AccountSummary> = state
+                .accounts_manager
+                .lock()?
+                .get_all_accounts()
+                .into_iter()
+                .map(to_summary)
+                .collect();
+            accounts.sort_by_key(|account| account.id);
+            AdminResponse::Accounts(accounts)
+        }
+        AdminRequest::GetAccount(account_id) => AdminResponse::Account(
+            state
+                .accounts_manager
+                .lock()?
+                .get_account(account_id)
+                .map(to_summary),
+        ),
+        AdminRequest::Queue => AdminResponse::Queue(
+            state
+                .orders
+                .lock()?
+                .pending_orders()
+                .into_iter()
+                .map(|(request, coffee_maker_id)| QueuedOrder {
+                    coffee_maker_id,
+                    request,
+                })
+                .collect(),
+        ),
+        AdminRequest::Shutdown => {
+            // Se sigue el mismo camino que el comando `leave` y SIGINT/SIGTERM, el servidor termina cuando termina de irse.
+            // Se espera a los pedidos pendientes en otro hilo para responder enseguida
+            info!("[ADMIN] Shutdown requested");
+            let state = state.clone();
+            thread::spawn(move || {
+                if let Err(e) = shut_down(&state) {
+                    error!("[ADMIN] Error shutting down, {:?}", e);
+                }
+            });
+            AdminResponse::ShuttingDown
+        }
+    };
+    Ok(response)
+}
+
+fn status(state: &NodeState) -> Result<ServerStatus, ServerError> {
+    let (prev_online, next_online, listening_to_id, next_id) = {
+        let connection_status = state.connection_status.lock()?;
+        (
+            connection_status.is_prev_online(),
+            connection_status.is_next_online(),
+            connection_status.prev_id(),
+            connection_status.next_id(),
+        )
+    };
+    let mut members = state.membership.lock()?.active_members();
+    members.sort();
+    Ok(ServerStatus {
+        id: state.id,
+        prev_online,
+        next_online,
+        listening_to_id,
+        next_id,
+        have_token: *state.have_token.lock()?,
+        token_generation: state.token_generation.lock()?.counter,
+        leader: *state.leader.lock()?,
+        members,
+    })
+}
+
+fn to_summary(account: UpdatedAccount) -> AccountSummary {
+    AccountSummary {
+        id: account.id,
+        points: account.amount,
+        last_updated_on: account.last_updated_on,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use lib::local_connection_messages::{CoffeeMakerRequest, MessageType, RequestId};
+
+    use super::*;
+    use crate::{
+        accounts_manager::AccountsManager,
+        hybrid_clock::HybridTimestamp,
+        memory_accounts_manager::MemoryAccountsManager,
+        ring_node::RingNode,
+        server_messages::{Generation, ServerMessageType},
+    };
+
+    fn ring_node() -> RingNode {
+        let mut accounts = MemoryAccountsManager::new();
+        accounts
+            .update(2, 30, HybridTimestamp::new(2, 0, 0))
+            .expect("Error updating account");
+        accounts
+            .update(1, 10, HybridTimestamp::new(1, 0, 0))
+            .expect("Error updating account");
+        RingNode::new_for_test(3, Box::new(accounts))
+    }
+
+    #[test]
+    fn should_report_the_status_of_the_server_in_the_ring() {
+        let node = ring_node();
+        {
+            let mut connection_status = node.state.connection_status.lock().expect("Lock error");
+            connection_status.set_next_online();
+            connection_status.set_next_id(1);
+        }
+        *node.state.have_token.lock().expect("Lock error") = true;
+        *node.state.token_generation.lock().expect("Lock error") = Generation::new(3, 0);
+
+        let response = respond(&node.state, AdminRequest::Status).expect("Error responding");
+        let AdminResponse::Status(status) = response else {
+            panic!("Unexpected response {:?}", response);
+        };
+        assert_eq!(
+            ServerStatus {
+                id: 0,
+                prev_online: false,
+                next_online: true,
+                listening_to_id: None,
+                next_id: Some(1),
+                have_token: true,
+                token_generation: 3,
+                leader: None,
+                members: vec![0, 1, 2],
+            },
+            status
+        );
+    }
+
+    #[test]
+    fn should_list_the_accounts_and_the_pending_orders() {
+        let node = ring_node();
+        let request = CoffeeMakerRequest {
+            message_type: MessageType::AddPoints,
+            account_id: 1,
+            points: 5,
+            request_id: RequestId::default(),
+        };
+        node.state
+            .orders
+            .lock()
+            .expect("Lock error")
+            .add(request, 4);
+
+        let response = respond(&node.state, AdminRequest::ListAccounts).expect("Error responding");
+        let AdminResponse::Accounts(accounts) = response else {
+            panic!("Unexpected response {:?}", response);
+        };
+        assert_eq!(
+            vec![(1, 10), (2, 30)],
+            accounts
+                .iter()
+                .map(|account| (account.id, account.points))
+                .collect::<Vec<_>>()
+        );
+
+        let response = respond(&node.state, AdminRequest::GetAccount(7)).expect("Error responding");
+        assert!(matches!(response, AdminResponse::Account(None)));
+
+        let response = respond(&node.state, AdminRequest::Queue).expect("Error responding");
+        let AdminResponse::Queue(orders) = response else {
+            panic!("Unexpected response {:?}", response);
+        };
+        assert_eq!(1, orders.len());
+        assert_eq!(4, orders[0].coffee_maker_id);
+        assert_eq!(5, orders[0].request.points);
+    }
+
+    #[test]
+    fn should_leave_the_ring_on_shutdown() {
+        let node = ring_node();
+
+        let response = respond(&node.state, AdminRequest::Shutdown).expect("Error responding");
+        assert!(matches!(response, AdminResponse::ShuttingDown));
+        let message = node
+            .next_connection
+            .next_message(Duration::from_secs(5))
+            .expect("Missing leave message");
+        assert_eq!(ServerMessageType::Leave, message.message_type);
+    }
+}
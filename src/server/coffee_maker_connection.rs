@@ -1,26 +1,31 @@
-use std::sync::mpsc::{Receiver, Sender};
+use std::{
+    sync::mpsc::{Receiver, Sender},
+    thread,
+};
 
 use async_std::task;
 use lib::{
     common_errors::CoffeeSystemError,
-    connection_protocol::ConnectionProtocol,
+    connection_protocol::{recv_message, send_message, ConnectionProtocol},
     local_connection_messages::{CoffeeMakerRequest, CoffeeMakerResponse},
-    serializer::{deserialize, serialize},
 };
-use log::{debug, error};
+use log::{debug, error, info};
 
 /// Recibe mensajes de la conexión con la cafetera y los deserializa, para luego enviarlos por un channel
 /// que escucharán CoffeeMessageDispatcher y el Order/Account managers posteriores. A su vez, esas entidades responderán por
-/// otro channel que esta función estará escuchando para poder responderle a la cafetera como corresponda.
+/// otro channel que escucha un hilo aparte para responderle a la cafetera como corresponda.
+/// No se espera la respuesta de un pedido para leer el siguiente, la cafetera reconoce cada respuesta por el id del pedido
 pub fn receive_messages_from_coffee_maker(
     connection: &mut Box<dyn ConnectionProtocol + Send>,
     machine_id: usize,
     request_sender: Sender<(CoffeeMakerRequest, usize)>,
     response_receiver: Receiver<CoffeeMakerResponse>,
 ) -> Result<(), CoffeeSystemError> {
+    let writer = connection.try_clone()?;
+    thread::spawn(move || send_responses_to_coffee_maker(writer, machine_id, response_receiver));
+
     loop {
-        let mut encoded = task::block_on(connection.recv())?;
-        let decoded: CoffeeMakerRequest = deserialize(&mut encoded)?;
+        let decoded: CoffeeMakerRequest = task::block_on(recv_message(connection.as_mut()))?;
         debug!(
             "[COFFEE MAKER {}] Received {:?} message",
             machine_id, decoded
@@ -32,21 +37,118 @@ pub fn receive_messages_from_coffee_maker(
             );
             return Err(CoffeeSystemError::ConnectionClosed);
         }
+    }
+}
+
+/// Envia a la cafetera las respuestas a medida que llegan por el channel, en el orden en que se resuelven los pedidos
+fn send_responses_to_coffee_maker(
+    mut connection: Box<dyn ConnectionProtocol + Send + Sync>,
+    machine_id: usize,
+    response_receiver: Receiver<CoffeeMakerResponse>,
+) {
+    for response in response_receiver {
+        let sent = task::block_on(send_message(connection.as_mut(), &response));
+        if let Err(e) = sent {
+            error!(
+                "[COFFEE MAKER {}] Error sending response {:?}, {:?}",
+                machine_id, response, e
+            );
+            return;
+        }
+    }
+    info!(
+        "[COFFEE MAKER {}] Connection closed, no more responses",
+        machine_id
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc, Arc, Mutex};
+
+    use lib::{
+        codec::Codec,
+        connection_protocol::MockConnectionProtocol,
+        local_connection_messages::{MessageType, RequestId, ResponseStatus},
+    };
 
-        let response = response_receiver.recv();
+    use super::*;
+
+    fn request(sequence: u64) -> CoffeeMakerRequest {
+        CoffeeMakerRequest {
+            message_type: MessageType::RequestPoints,
+            account_id: 1,
+            points: 10,
+            request_id: RequestId {
+                dispenser_id: 1,
+                sequence,
+            },
+        }
+    }
 
-        match response {
-            Err(_) => {
-                error!(
-                    "[COFFEE MAKER {}] Trying to receive on a channel without sender",
-                    machine_id
-                );
+    #[test]
+    fn should_read_the_next_request_without_waiting_for_the_previous_response() {
+        let sent = Arc::new(Mutex::new(vec![]));
+        let sent_clone = sent.clone();
+        let (done_sender, done_receiver) = mpsc::channel();
+        let mut connection = MockConnectionProtocol::new();
+        connection.expect_try_clone().times(1).returning(move || {
+            let mut writer = MockConnectionProtocol::new();
+            let sent = sent_clone.clone();
+            let done_sender = done_sender.clone();
+            writer.expect_codec().return_const(Codec::LengthPrefixed);
+            writer.expect_send().returning(move |data| {
+                let response: CoffeeMakerResponse = Codec::LengthPrefixed
+                    .decode(data)
+                    .expect("Error deserializing");
+                sent.lock()
+                    .expect("Lock error")
+                    .push(response.request_id.sequence);
+                done_sender.send(()).expect("Error notifying");
+                Ok(())
+            });
+            Ok(Box::new(writer))
+        });
+        connection
+            .expect_codec()
+            .return_const(Codec::LengthPrefixed);
+        let mut sequence = 0;
+        connection.expect_recv().times(3).returning(move || {
+            sequence += 1;
+            if sequence > 2 {
                 return Err(CoffeeSystemError::ConnectionClosed);
             }
-            Ok(res) => {
-                let serialized = serialize(&res)?;
-                task::block_on(connection.send(&serialized))?;
-            }
+            Ok(Codec::LengthPrefixed
+                .encode(&request(sequence))
+                .expect("Error serializing"))
+        });
+        let mut connection: Box<dyn ConnectionProtocol + Send> = Box::new(connection);
+
+        let (request_sender, request_receiver) = mpsc::channel();
+        let (response_sender, response_receiver) = mpsc::channel();
+        let result = receive_messages_from_coffee_maker(
+            &mut connection,
+            0,
+            request_sender,
+            response_receiver,
+        );
+
+        assert_eq!(Err(CoffeeSystemError::ConnectionClosed), result);
+        let received: Vec<CoffeeMakerRequest> = request_receiver.try_iter().map(|r| r.0).collect();
+        assert_eq!(2, received.len());
+
+        // Se responde primero el segundo pedido
+        for request in received.iter().rev() {
+            response_sender
+                .send(CoffeeMakerResponse {
+                    message_type: request.message_type,
+                    status: ResponseStatus::Ok,
+                    request_id: request.request_id,
+                })
+                .expect("Error sending response");
         }
+        done_receiver.recv().expect("Response not sent");
+        done_receiver.recv().expect("Response not sent");
+        assert_eq!(vec![2, 1], *sent.lock().expect("Lock error"));
     }
 }
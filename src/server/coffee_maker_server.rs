@@ -1,5 +1,6 @@
 use async_std::task;
 use lib::common_errors::CoffeeSystemError;
+use lib::handshake::NodeKind;
 use lib::local_connection_messages::{CoffeeMakerRequest, CoffeeMakerResponse};
 use std::collections::HashMap;
 use std::sync::mpsc::Sender;
@@ -7,11 +8,14 @@ use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use std::thread::JoinHandle;
 
-use crate::address_resolver::id_to_coffee_port;
+use log::info;
+
+use crate::address_resolver::AddressResolver;
 use crate::{
     coffee_maker_connection::receive_messages_from_coffee_maker,
-    connection_server::{ConnectionServer, TcpConnectionServer},
+    connection_server::{ConnectionServer, Network},
     errors::ServerError,
+    metrics::Metrics,
 };
 
 /// Representa un servidor que escucha nuevas conexiones de cafeteras. Además de su listener,
@@ -19,30 +23,43 @@ use crate::{
 /// y un mutex de un diccionario donde nos guardaremos el Sender channel de CoffeeMakerResponse
 /// para cada id de cafetera.
 pub struct CoffeeMakerServer {
-    listener: TcpConnectionServer,
+    listener: Box<dyn ConnectionServer + Send>,
     coffee_machines_connections: Vec<JoinHandle<Result<(), CoffeeSystemError>>>,
     coffee_request_sender: Sender<(CoffeeMakerRequest, usize)>,
     machine_response_senders: Arc<Mutex<HashMap<usize, Sender<CoffeeMakerResponse>>>>,
+    shutting_down: Arc<Mutex<bool>>,
+    metrics: Arc<Mutex<Metrics>>,
 }
 
 impl CoffeeMakerServer {
     /// Devuelve un nuevo CoffeeMakerServer, o error en caso de no poder abrir un nuevo listener.
     pub fn new(
         id: usize,
+        address_resolver: &AddressResolver,
         coffee_request_sender: Sender<(CoffeeMakerRequest, usize)>,
         machine_response_senders: Arc<Mutex<HashMap<usize, Sender<CoffeeMakerResponse>>>>,
+        shutting_down: Arc<Mutex<bool>>,
+        metrics: Arc<Mutex<Metrics>>,
+        network: &dyn Network,
     ) -> Result<CoffeeMakerServer, ServerError> {
-        let listener = TcpConnectionServer::new(&id_to_coffee_port(id))?;
+        let address = address_resolver
+            .coffee_address(id)
+            .ok_or(ServerError::UnknownServerId)?;
+        let listener = network.bind(address, id, NodeKind::CoffeeMaker)?;
         Ok(CoffeeMakerServer {
             listener,
             coffee_machines_connections: Vec::new(),
             coffee_request_sender,
             machine_response_senders,
+            shutting_down,
+            metrics,
         })
     }
 
     /// Escucha por nuevas conexiones entrantes de cafeteras, y para cada una de ellas las registra en el diccionario interno.
     /// Además levanta un hilo donde se llama a receive_messages_from_coffee_maker() para esa cafetera en específico.
+    /// Cuando la conexión se cierra se quita su Sender del diccionario.
+    /// Si el servidor se está retirando del anillo las conexiones nuevas se cierran, así la cafetera busca otro servidor.
     pub fn listen(&mut self) -> Result<(), ServerError> {
         let mut curr_machine_id = 0;
         loop {
@@ -54,14 +71,32 @@ impl CoffeeMakerServer {
             }
 
             let curr_machine_request_sender = self.coffee_request_sender.clone();
+            let machine_senders = self.machine_response_senders.clone();
             let mut new_conn_result = task::block_on(self.listener.listen())?;
+            if *self.shutting_down.lock()? {
+                info!("Shutting down, closing new coffee maker connection");
+                continue;
+            }
+            let metrics = self.metrics.clone();
+            metrics.lock()?.coffee_maker_connected();
             let handle = thread::spawn(move || {
-                receive_messages_from_coffee_maker(
+                let result = receive_messages_from_coffee_maker(
                     &mut new_conn_result,
                     curr_machine_id,
                     curr_machine_request_sender,
                     curr_machine_response_receiver,
-                )
+                );
+                // Al cerrarse la conexion se descarta su channel de respuestas, asi termina el hilo que las envia
+                if let Ok(mut machine_senders) = machine_senders.lock() {
+                    machine_senders.remove(&curr_machine_id);
+                }
+                if let Ok(mut metrics) = metrics.lock() {
+                    metrics.coffee_maker_disconnected();
+                    if let Err(e) = &result {
+                        metrics.coffee_system_error(e);
+                    }
+                }
+                result
             });
             self.coffee_machines_connections.push(handle);
             curr_machine_id += 1;
@@ -1,11 +1,13 @@
+use crate::accounts_manager::AccountsManager;
 use crate::connection_status::ConnectionStatus;
+use crate::dedup_cache::DedupCache;
 use crate::errors::ServerError;
 use crate::orders_queue::OrdersQueue;
 use lib::common_errors::CoffeeSystemError;
 use lib::local_connection_messages::{
-    CoffeeMakerRequest, CoffeeMakerResponse, MessageType, ResponseStatus,
+    AccountBalance, CoffeeMakerRequest, CoffeeMakerResponse, MessageType, ResponseStatus,
 };
-use log::{error, info};
+use log::{error, info, warn};
 use std::collections::HashMap;
 use std::sync::mpsc::{Receiver, Sender};
 use std::sync::{Arc, Mutex};
@@ -15,109 +17,276 @@ use std::thread;
 /// reenvía estos mensajes al OrdersManager
 pub struct CoffeeMessageDispatcher {
     is_connected: Arc<Mutex<ConnectionStatus>>,
+    shutting_down: Arc<Mutex<bool>>,
     orders: Arc<Mutex<OrdersQueue>>,
     machine_request_receiver: Receiver<(CoffeeMakerRequest, usize)>,
     machine_response_senders: Arc<Mutex<HashMap<usize, Sender<CoffeeMakerResponse>>>>,
+    accounts_manager: Arc<Mutex<Box<dyn AccountsManager>>>,
+    dedup_cache: Arc<Mutex<DedupCache>>,
 }
 
 impl CoffeeMessageDispatcher {
     /// Retorna un nuevo CoffeeMessageDispatcher
     pub fn new(
         is_connected: Arc<Mutex<ConnectionStatus>>,
+        shutting_down: Arc<Mutex<bool>>,
         orders: Arc<Mutex<OrdersQueue>>,
         machine_request_receiver: Receiver<(CoffeeMakerRequest, usize)>,
         machine_response_senders: Arc<Mutex<HashMap<usize, Sender<CoffeeMakerResponse>>>>,
+        accounts_manager: Arc<Mutex<Box<dyn AccountsManager>>>,
+        dedup_cache: Arc<Mutex<DedupCache>>,
     ) -> Self {
         Self {
             is_connected,
+            shutting_down,
             orders,
             machine_request_receiver,
             machine_response_senders,
+            accounts_manager,
+            dedup_cache,
         }
     }
     /// Recibe CoffeeMakerRequests y las reenvía al OrdersManager, ya sea a través de la OrdersQueue o un channel aparte,
     /// dependiendo del tipo de request. Además puede responder tempranamente a estas requests sin mandarlas al OrdersManager,
     /// de saber que el estado de la conexión y el tipo de request lo ameriten.
+    /// Los reintentos de pedidos ya respondidos se contestan con la respuesta original, sin volver a procesarlos.
     pub fn dispatch_coffee_requests(
         &mut self,
-        orders_request_sender: Sender<CoffeeMakerRequest>,
+        orders_request_sender: Sender<(CoffeeMakerRequest, usize)>,
         orders_response_sender: Sender<(CoffeeMakerResponse, usize)>,
         orders_response_receiver: Receiver<(CoffeeMakerResponse, usize)>,
     ) -> Result<(), ServerError> {
         let senders_clone = self.machine_response_senders.clone();
+        let dedup_cache_clone = self.dedup_cache.clone();
         let _handle = thread::spawn(move || {
-            Self::send_coffee_responses(senders_clone, orders_response_receiver);
+            Self::send_coffee_responses(senders_clone, orders_response_receiver, dedup_cache_clone);
         });
 
         loop {
             let new_request = self.machine_request_receiver.recv()?;
+            self.dispatch(new_request, &orders_request_sender, &orders_response_sender)?;
+        }
+    }
 
-            match new_request.0.message_type {
-                MessageType::AddPoints => {
-                    {
-                        let orders = self.orders.lock();
-                        if orders.is_err() {
-                            return Err(ServerError::LockError);
-                        }
-                        let mut orders = orders.unwrap();
-
-                        orders.add(new_request.0, new_request.1);
-                    }
-
-                    orders_response_sender.send((
-                        CoffeeMakerResponse {
-                            message_type: new_request.0.message_type,
-                            status: ResponseStatus::Ok,
-                        },
-                        new_request.1,
-                    ))?;
+    /// Reenvia un pedido de una cafetera al OrdersManager, o lo responde directamente si corresponde
+    pub fn dispatch(
+        &mut self,
+        new_request: (CoffeeMakerRequest, usize),
+        orders_request_sender: &Sender<(CoffeeMakerRequest, usize)>,
+        orders_response_sender: &Sender<(CoffeeMakerResponse, usize)>,
+    ) -> Result<(), ServerError> {
+        if new_request.0.message_type != MessageType::QueryBalance {
+            let mut dedup_cache = self.dedup_cache.lock()?;
+            if let Some(response) = dedup_cache.get(&new_request.0.request_id) {
+                info!(
+                    "Replaying response of repeated request {:?}",
+                    new_request.0.request_id
+                );
+                // Una resta que ya se aplico en otro servidor no usa la reserva que la cafetera volvio a pedir en este
+                if new_request.0.message_type == MessageType::TakePoints
+                    && self.orders.lock()?.is_awaiting_result(
+                        new_request.0.account_id,
+                        new_request.0.request_id.dispenser_id,
+                    )
+                {
+                    let cancel = CoffeeMakerRequest {
+                        message_type: MessageType::CancelPointsRequest,
+                        ..new_request.0
+                    };
+                    orders_request_sender.send((cancel, new_request.1))?;
                 }
+                orders_response_sender.send((response, new_request.1))?;
+                return Ok(());
+            }
+            if dedup_cache.redirect_pending(new_request.0.request_id, new_request.1) {
+                warn!(
+                    "Request {:?} is already waiting for the token, answering the retry when it is processed",
+                    new_request.0.request_id
+                );
+                return Ok(());
+            }
+        }
 
-                MessageType::RequestPoints => {
-                    let is_now_connected = self.is_connected.lock();
-                    if is_now_connected.is_err() {
-                        return Err(ServerError::LockError);
-                    }
-                    let is_now_connected = is_now_connected.unwrap().is_online();
-
-                    if !is_now_connected {
-                        orders_response_sender.send((
-                            CoffeeMakerResponse {
-                                message_type: new_request.0.message_type,
-                                status: ResponseStatus::Err(CoffeeSystemError::ConnectionLost),
-                            },
-                            new_request.1,
-                        ))?;
-                    }
+        // Si el servidor se esta yendo, los pedidos nuevos no llegarian a aplicarse. La cafetera guarda las sumas
+        // para enviarlas a otro servidor
+        if matches!(
+            new_request.0.message_type,
+            MessageType::AddPoints | MessageType::RequestPoints
+        ) && *self.shutting_down.lock()?
+        {
+            warn!(
+                "Shutting down, rejecting request {:?}",
+                new_request.0.request_id
+            );
+            orders_response_sender.send((
+                CoffeeMakerResponse {
+                    message_type: new_request.0.message_type,
+                    status: ResponseStatus::Err(CoffeeSystemError::ConnectionLost),
+                    request_id: new_request.0.request_id,
+                },
+                new_request.1,
+            ))?;
+            return Ok(());
+        }
 
+        match new_request.0.message_type {
+            MessageType::AddPoints => {
+                {
                     let orders = self.orders.lock();
                     if orders.is_err() {
                         return Err(ServerError::LockError);
                     }
                     let mut orders = orders.unwrap();
+
                     orders.add(new_request.0, new_request.1);
-                    // OrdersManager will be the one that sends the CoffeeMakerResponse through orders_request_sender channel in this case
                 }
 
-                _ => {
-                    orders_request_sender.send(new_request.0)?;
+                self.answer_now(
+                    CoffeeMakerResponse {
+                        message_type: new_request.0.message_type,
+                        status: ResponseStatus::Ok,
+                        request_id: new_request.0.request_id,
+                    },
+                    new_request.1,
+                    orders_response_sender,
+                )?;
+            }
+
+            MessageType::RequestPoints => {
+                let is_now_connected = self.is_connected.lock();
+                if is_now_connected.is_err() {
+                    return Err(ServerError::LockError);
+                }
+                let is_now_connected = is_now_connected.unwrap().is_online();
+
+                if !is_now_connected {
                     orders_response_sender.send((
                         CoffeeMakerResponse {
                             message_type: new_request.0.message_type,
-                            status: ResponseStatus::Ok,
+                            status: ResponseStatus::Err(CoffeeSystemError::ConnectionLost),
+                            request_id: new_request.0.request_id,
                         },
                         new_request.1,
                     ))?;
+                    return Ok(());
                 }
+
+                self.dedup_cache
+                    .lock()?
+                    .set_pending(new_request.0.request_id, new_request.1);
+                let orders = self.orders.lock();
+                if orders.is_err() {
+                    return Err(ServerError::LockError);
+                }
+                let mut orders = orders.unwrap();
+                orders.add(new_request.0, new_request.1);
+                // OrdersManager will be the one that sends the CoffeeMakerResponse through orders_request_sender channel in this case
+            }
+
+            MessageType::QueryBalance => {
+                // Se responde en otro hilo porque OrdersManager mantiene tomadas las cuentas mientras procesa el token
+                let accounts_manager = self.accounts_manager.clone();
+                let response_sender = orders_response_sender.clone();
+                thread::spawn(move || {
+                    Self::answer_balance_query(
+                        accounts_manager,
+                        new_request.0,
+                        new_request.1,
+                        response_sender,
+                    );
+                });
+            }
+
+            // Sin una reserva hecha con el token actual la resta no se aplicaria. Pasa si la cafetera se reconecto
+            // a otro servidor despues de reservar: asi sabe que la resta no se aplico y vuelve a pedir los puntos
+            MessageType::TakePoints
+                if !self.orders.lock()?.is_awaiting_result(
+                    new_request.0.account_id,
+                    new_request.0.request_id.dispenser_id,
+                ) =>
+            {
+                warn!(
+                    "Request {:?} takes points without a reservation",
+                    new_request.0.request_id
+                );
+                self.answer_now(
+                    CoffeeMakerResponse {
+                        message_type: new_request.0.message_type,
+                        status: ResponseStatus::Err(CoffeeSystemError::ReservationNotFound),
+                        request_id: new_request.0.request_id,
+                    },
+                    new_request.1,
+                    orders_response_sender,
+                )?;
+            }
+
+            _ => {
+                orders_request_sender.send(new_request)?;
+                self.answer_now(
+                    CoffeeMakerResponse {
+                        message_type: new_request.0.message_type,
+                        status: ResponseStatus::Ok,
+                        request_id: new_request.0.request_id,
+                    },
+                    new_request.1,
+                    orders_response_sender,
+                )?;
             }
         }
+        Ok(())
+    }
+
+    /// Responde un pedido sin pasar por el OrdersManager. La respuesta se guarda en la cache antes de enviarla,
+    /// asi un reintento que llegue enseguida ya la encuentra
+    fn answer_now(
+        &self,
+        response: CoffeeMakerResponse,
+        machine_id: usize,
+        orders_response_sender: &Sender<(CoffeeMakerResponse, usize)>,
+    ) -> Result<(), ServerError> {
+        self.dedup_cache.lock()?.insert(response);
+        orders_response_sender.send((response, machine_id))?;
+        Ok(())
+    }
+
+    /// Responde el saldo de una cuenta con la base local, sin esperar al token. El saldo puede no incluir
+    /// operaciones de otros servidores que todavia no llegaron, por eso se envia junto a la marca de su ultima actualizacion
+    fn answer_balance_query(
+        accounts_manager: Arc<Mutex<Box<dyn AccountsManager>>>,
+        request: CoffeeMakerRequest,
+        machine_id: usize,
+        response_sender: Sender<(CoffeeMakerResponse, usize)>,
+    ) {
+        let status = match accounts_manager.lock() {
+            Ok(accounts) => match accounts.get_account(request.account_id) {
+                Some(account) => ResponseStatus::Balance(AccountBalance {
+                    points: account.amount,
+                    last_updated_on: account.last_updated_on,
+                }),
+                None => ResponseStatus::Err(CoffeeSystemError::AccountNotFound),
+            },
+            Err(_) => {
+                error!("Unable to lock accounts for answering balance query");
+                ResponseStatus::Err(CoffeeSystemError::UnexpectedError)
+            }
+        };
+        let response = CoffeeMakerResponse {
+            message_type: MessageType::QueryBalance,
+            status,
+            request_id: request.request_id,
+        };
+        if response_sender.send((response, machine_id)).is_err() {
+            error!("Unable to send balance query response");
+        }
     }
 
     /// Escucha CoffeeMakerResponses por un Receiver, y las reenvía por el Sender correspondiente
-    /// a esa cafetera.
+    /// a esa cafetera. Cada respuesta se guarda en la cache para responder los reintentos del pedido.
+    /// Si la cafetera reintento el pedido desde otra conexion, la respuesta se envia por la del reintento.
     fn send_coffee_responses(
         machine_response_senders: Arc<Mutex<HashMap<usize, Sender<CoffeeMakerResponse>>>>,
         orders_response_receiver: Receiver<(CoffeeMakerResponse, usize)>,
+        dedup_cache: Arc<Mutex<DedupCache>>,
     ) {
         loop {
             let next_response = orders_response_receiver.recv();
@@ -125,17 +294,275 @@ impl CoffeeMessageDispatcher {
                 return; // the sender has disconnected, no more responses.
             }
             let (response, machine_id) = next_response.unwrap();
+            Self::forward_response(
+                &machine_response_senders,
+                &dedup_cache,
+                response,
+                machine_id,
+            );
+        }
+    }
 
-            let machine_senders_guard = machine_response_senders.lock();
-            if machine_senders_guard.is_err() {
-                error!("Unable to lock senders for sending response");
-            }
-            let machine_senders = machine_senders_guard.unwrap();
-            if let Some(sender) = machine_senders.get(&machine_id) {
-                if sender.send(response).is_err() {
-                    info!("Trying to send response through closed coffee maker channel");
+    /// Guarda la respuesta en la cache y la envia a la cafetera. Si la cafetera reintento el pedido desde otra
+    /// conexion, la respuesta se envia por la del reintento
+    pub fn forward_response(
+        machine_response_senders: &Mutex<HashMap<usize, Sender<CoffeeMakerResponse>>>,
+        dedup_cache: &Mutex<DedupCache>,
+        response: CoffeeMakerResponse,
+        mut machine_id: usize,
+    ) {
+        match dedup_cache.lock() {
+            Ok(mut dedup_cache) => {
+                if let Some(retry_machine_id) = dedup_cache.insert(response) {
+                    machine_id = retry_machine_id;
                 }
             }
+            Err(_) => error!("Unable to lock dedup cache for saving response"),
+        }
+
+        let machine_senders_guard = machine_response_senders.lock();
+        if machine_senders_guard.is_err() {
+            error!("Unable to lock senders for sending response");
+        }
+        let machine_senders = machine_senders_guard.unwrap();
+        if let Some(sender) = machine_senders.get(&machine_id) {
+            if sender.send(response).is_err() {
+                info!("Trying to send response through closed coffee maker channel");
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, time::Duration};
+
+    use lib::{hybrid_timestamp::HybridTimestamp, local_connection_messages::RequestId};
+
+    use super::*;
+    use crate::memory_accounts_manager::MemoryAccountsManager;
+
+    #[test]
+    fn should_answer_the_balance_query_without_the_token() {
+        let mut accounts = MemoryAccountsManager::new();
+        accounts
+            .update(3, 40, HybridTimestamp::new(100, 2, 1))
+            .expect("Error updating account");
+        let accounts: Arc<Mutex<Box<dyn AccountsManager>>> =
+            Arc::new(Mutex::new(Box::new(accounts)));
+        let orders = Arc::new(Mutex::new(OrdersQueue::new()));
+        let (machine_request_sender, machine_request_receiver) = mpsc::channel();
+        let (machine_response_sender, machine_response_receiver) = mpsc::channel();
+        let machine_response_senders =
+            Arc::new(Mutex::new(HashMap::from([(5, machine_response_sender)])));
+        let mut dispatcher = CoffeeMessageDispatcher::new(
+            Arc::new(Mutex::new(ConnectionStatus::new())),
+            Arc::new(Mutex::new(false)),
+            orders.clone(),
+            machine_request_receiver,
+            machine_response_senders,
+            accounts,
+            Arc::new(Mutex::new(DedupCache::new(10))),
+        );
+        let (orders_request_sender, _) = mpsc::channel();
+        let (orders_response_sender, orders_response_receiver) = mpsc::channel();
+
+        machine_request_sender
+            .send((
+                CoffeeMakerRequest {
+                    message_type: MessageType::QueryBalance,
+                    account_id: 3,
+                    points: 0,
+                    request_id: RequestId::default(),
+                },
+                5,
+            ))
+            .expect("Error sending request");
+        drop(machine_request_sender);
+        let _ = dispatcher.dispatch_coffee_requests(
+            orders_request_sender,
+            orders_response_sender,
+            orders_response_receiver,
+        );
+
+        let response = machine_response_receiver
+            .recv_timeout(Duration::from_secs(5))
+            .expect("No response received");
+        assert_eq!(MessageType::QueryBalance, response.message_type);
+        match response.status {
+            ResponseStatus::Balance(balance) => {
+                assert_eq!(40, balance.points);
+                assert_eq!(HybridTimestamp::new(100, 2, 1), balance.last_updated_on);
+            }
+            _ => panic!("Expected the balance"),
+        }
+        assert!(orders.lock().expect("Lock error").is_empty());
+    }
+
+    #[test]
+    fn should_replay_the_response_of_a_repeated_request_without_adding_it_again() {
+        let orders = Arc::new(Mutex::new(OrdersQueue::new()));
+        let (machine_request_sender, machine_request_receiver) = mpsc::channel();
+        let (machine_response_sender, machine_response_receiver) = mpsc::channel();
+        let machine_response_senders = Arc::new(Mutex::new(HashMap::from([
+            (1, machine_response_sender.clone()),
+            (2, machine_response_sender),
+        ])));
+        let mut dispatcher = CoffeeMessageDispatcher::new(
+            Arc::new(Mutex::new(ConnectionStatus::new())),
+            Arc::new(Mutex::new(false)),
+            orders.clone(),
+            machine_request_receiver,
+            machine_response_senders,
+            Arc::new(Mutex::new(Box::new(MemoryAccountsManager::new()))),
+            Arc::new(Mutex::new(DedupCache::new(10))),
+        );
+        let (orders_request_sender, _) = mpsc::channel();
+        let (orders_response_sender, orders_response_receiver) = mpsc::channel();
+
+        let request = CoffeeMakerRequest {
+            message_type: MessageType::AddPoints,
+            account_id: 3,
+            points: 10,
+            request_id: RequestId {
+                dispenser_id: 7,
+                sequence: 1,
+            },
+        };
+        // La cafetera reintenta el pedido luego de reconectarse
+        machine_request_sender
+            .send((request, 1))
+            .expect("Error sending request");
+        machine_request_sender
+            .send((request, 2))
+            .expect("Error sending request");
+        drop(machine_request_sender);
+        let _ = dispatcher.dispatch_coffee_requests(
+            orders_request_sender,
+            orders_response_sender,
+            orders_response_receiver,
+        );
+
+        for _ in 0..2 {
+            let response = machine_response_receiver
+                .recv_timeout(Duration::from_secs(5))
+                .expect("No response received");
+            assert!(matches!(response.status, ResponseStatus::Ok));
+            assert_eq!(request.request_id, response.request_id);
+        }
+        let adding_orders = orders
+            .lock()
+            .expect("Lock error")
+            .get_and_clear_adding_orders();
+        assert_eq!(1, adding_orders.len());
+        assert_eq!(10, adding_orders[0].order.points);
+    }
+
+    #[test]
+    fn should_reject_the_new_orders_while_shutting_down() {
+        let orders = Arc::new(Mutex::new(OrdersQueue::new()));
+        let (_, machine_request_receiver) = mpsc::channel();
+        let mut dispatcher = CoffeeMessageDispatcher::new(
+            Arc::new(Mutex::new(ConnectionStatus::new())),
+            Arc::new(Mutex::new(true)),
+            orders.clone(),
+            machine_request_receiver,
+            Arc::new(Mutex::new(HashMap::new())),
+            Arc::new(Mutex::new(Box::new(MemoryAccountsManager::new()))),
+            Arc::new(Mutex::new(DedupCache::new(10))),
+        );
+        let (orders_request_sender, _) = mpsc::channel();
+        let (orders_response_sender, orders_response_receiver) = mpsc::channel();
+
+        for message_type in [MessageType::AddPoints, MessageType::RequestPoints] {
+            let request = CoffeeMakerRequest {
+                message_type,
+                account_id: 3,
+                points: 10,
+                request_id: RequestId::default(),
+            };
+            dispatcher
+                .dispatch(
+                    (request, 1),
+                    &orders_request_sender,
+                    &orders_response_sender,
+                )
+                .expect("Error dispatching");
+            let (response, machine_id) = orders_response_receiver
+                .try_recv()
+                .expect("No response received");
+            assert_eq!(1, machine_id);
+            assert!(matches!(
+                response.status,
+                ResponseStatus::Err(CoffeeSystemError::ConnectionLost)
+            ));
+        }
+        assert!(orders.lock().expect("Lock error").is_empty());
+    }
+
+    #[test]
+    fn should_tell_a_take_without_reservation_apart_from_one_already_applied() {
+        let orders = Arc::new(Mutex::new(OrdersQueue::new()));
+        let dedup_cache = Arc::new(Mutex::new(DedupCache::new(10)));
+        let (_, machine_request_receiver) = mpsc::channel();
+        let mut dispatcher = CoffeeMessageDispatcher::new(
+            Arc::new(Mutex::new(ConnectionStatus::new())),
+            Arc::new(Mutex::new(false)),
+            orders.clone(),
+            machine_request_receiver,
+            Arc::new(Mutex::new(HashMap::new())),
+            Arc::new(Mutex::new(Box::new(MemoryAccountsManager::new()))),
+            dedup_cache.clone(),
+        );
+        let (orders_request_sender, orders_request_receiver) = mpsc::channel();
+        let (orders_response_sender, orders_response_receiver) = mpsc::channel();
+        let take = |sequence| CoffeeMakerRequest {
+            message_type: MessageType::TakePoints,
+            account_id: 3,
+            points: 10,
+            request_id: RequestId {
+                dispenser_id: 7,
+                sequence,
+            },
+        };
+
+        // La reserva se hizo en otro servidor, la resta no se aplicaria
+        dispatcher
+            .dispatch(
+                (take(1), 1),
+                &orders_request_sender,
+                &orders_response_sender,
+            )
+            .expect("Error dispatching");
+        let (response, _) = orders_response_receiver
+            .try_recv()
+            .expect("No response received");
+        assert!(matches!(
+            response.status,
+            ResponseStatus::Err(CoffeeSystemError::ReservationNotFound)
+        ));
+        assert!(orders_request_receiver.try_recv().is_err());
+
+        // La resta se aplico en otro servidor y la cafetera volvio a reservar, la nueva reserva se cancela
+        dedup_cache
+            .lock()
+            .expect("Lock error")
+            .insert_applied(take(2).request_id, MessageType::TakePoints);
+        orders.lock().expect("Lock error").await_result(3, 7);
+        dispatcher
+            .dispatch(
+                (take(2), 1),
+                &orders_request_sender,
+                &orders_response_sender,
+            )
+            .expect("Error dispatching");
+        let (response, _) = orders_response_receiver
+            .try_recv()
+            .expect("No response received");
+        assert!(matches!(response.status, ResponseStatus::Ok));
+        let (forwarded, _) = orders_request_receiver
+            .try_recv()
+            .expect("No request forwarded");
+        assert_eq!(MessageType::CancelPointsRequest, forwarded.message_type);
+    }
+}
@@ -1,7 +1,14 @@
 use async_std::{net::TcpListener, task};
 use async_trait::async_trait;
 
-use lib::connection_protocol::{ConnectionProtocol, TcpConnection};
+use lib::{
+    common_errors::CoffeeSystemError,
+    connection_protocol::{
+        ConnectionProtocol, MemoryConnection, MemoryListener, MemoryNetwork, TcpConnection,
+        Transport, UdpConfig, UdpConnection, UdpListener,
+    },
+    handshake::{Hello, NodeId, NodeKind},
+};
 use log::{error, info};
 
 use crate::errors::ServerError;
@@ -12,41 +19,224 @@ pub trait ConnectionServer {
     async fn listen(&self) -> Result<Box<dyn ConnectionProtocol + Send>, ServerError>;
 }
 
+/// Red sobre la que se comunica el servidor: crea sus listeners y abre las conexiones con otros nodos.
+/// Se inyecta en el servidor para poder reemplazar la red real, por ejemplo por una en memoria en los tests
+pub trait Network: Send + Sync {
+    /// Crea el servidor de conexiones que escucha en la direccion HOST:PUERTO indicada, y solo acepta
+    /// las conexiones del tipo de nodo indicado
+    fn bind(
+        &self,
+        address: &str,
+        id: usize,
+        accepts: NodeKind,
+    ) -> Result<Box<dyn ConnectionServer + Send>, ServerError>;
+
+    /// Abre una conexion con el nodo que escucha en la direccion indicada y hace el handshake
+    fn connect(
+        &self,
+        address: &str,
+        hello: &Hello,
+    ) -> Result<Box<dyn ConnectionProtocol + Send + Sync>, CoffeeSystemError>;
+}
+
+impl Network for Transport {
+    fn bind(
+        &self,
+        address: &str,
+        id: usize,
+        accepts: NodeKind,
+    ) -> Result<Box<dyn ConnectionServer + Send>, ServerError> {
+        Ok(match self {
+            Transport::Tcp => Box::new(TcpConnectionServer::new(address, id, accepts)?),
+            Transport::Udp(config) => {
+                Box::new(UdpConnectionServer::new(address, id, accepts, *config)?)
+            }
+        })
+    }
+
+    fn connect(
+        &self,
+        address: &str,
+        hello: &Hello,
+    ) -> Result<Box<dyn ConnectionProtocol + Send + Sync>, CoffeeSystemError> {
+        Transport::connect(self, &address.to_string(), hello)
+    }
+}
+
+impl Network for MemoryNetwork {
+    fn bind(
+        &self,
+        address: &str,
+        id: usize,
+        accepts: NodeKind,
+    ) -> Result<Box<dyn ConnectionServer + Send>, ServerError> {
+        let listener = self.listen(address, NodeId::Server(id)).map_err(|e| {
+            error!("[SERVER] Error binding to address {}, {:?}", address, e);
+            ServerError::ListenerError
+        })?;
+        Ok(Box::new(MemoryConnectionServer {
+            listener,
+            hello: Hello::new(NodeId::Server(id)),
+            accepts,
+        }))
+    }
+
+    fn connect(
+        &self,
+        address: &str,
+        hello: &Hello,
+    ) -> Result<Box<dyn ConnectionProtocol + Send + Sync>, CoffeeSystemError> {
+        Ok(Box::new(MemoryConnection::new_client_connection(
+            self, address, hello,
+        )?))
+    }
+}
+
 /// Implementacion de la abstraccion de conexion que utiliza el protocolo TCP.
 pub struct TcpConnectionServer {
     listener: TcpListener,
+    hello: Hello,
+    accepts: NodeKind,
 }
 
 impl TcpConnectionServer {
-    pub fn new(port: &str) -> Result<TcpConnectionServer, ServerError> {
-        let listener = task::block_on(TcpListener::bind("127.0.0.1:".to_owned() + port));
+    /// Crea el servidor escuchando en la direccion HOST:PUERTO indicada. Solo acepta las conexiones
+    /// del tipo de nodo indicado, y se presenta en el handshake con el id del servidor
+    pub fn new(
+        address: &str,
+        id: usize,
+        accepts: NodeKind,
+    ) -> Result<TcpConnectionServer, ServerError> {
+        let listener = task::block_on(TcpListener::bind(address));
         if let Err(e) = listener {
-            error!("[SERVER] Error binding to port {}, {}", port, e);
+            error!("[SERVER] Error binding to address {}, {}", address, e);
             return Err(ServerError::ListenerError);
         }
-        info!("[SERVER] Bind to port successful {}", port);
+        info!("[SERVER] Bind to address successful {}", address);
         let listener = listener.unwrap();
-        Ok(TcpConnectionServer { listener })
+        Ok(TcpConnectionServer {
+            listener,
+            hello: Hello::new(NodeId::Server(id)),
+            accepts,
+        })
     }
 }
 
 #[async_trait]
 impl ConnectionServer for TcpConnectionServer {
     async fn listen(&self) -> Result<Box<dyn ConnectionProtocol + Send>, ServerError> {
-        let result = self.listener.accept().await;
-        match result {
-            Ok((tcp_stream, addr)) => {
+        loop {
+            let result = self.listener.accept().await;
+            match result {
+                Ok((tcp_stream, addr)) => {
+                    info!(
+                        "[SERVER] Accepted connection from {} {}",
+                        addr.ip(),
+                        addr.port()
+                    );
+                    // Si el handshake falla se descarta la conexion y se sigue escuchando
+                    let result = TcpConnection::new_server_connection(
+                        tcp_stream,
+                        addr,
+                        &self.hello,
+                        self.accepts,
+                    )
+                    .await;
+                    if let Ok(conn) = result {
+                        let peer = conn.peer();
+                        info!(
+                            "[SERVER] Handshake with {:?}, protocol version {}, features {:?}, codec {:?}",
+                            peer.node_id, peer.protocol_version, peer.features, peer.codec
+                        );
+                        return Ok(Box::new(conn));
+                    }
+                }
+                Err(e) => {
+                    error!("[COFFEE MAKER SERVER] Error accepting connection {}", e);
+                    return Err(ServerError::AcceptError);
+                }
+            }
+        }
+    }
+}
+
+/// Implementacion de la abstraccion de conexion sobre UDP, con entrega confiable y ordenada.
+pub struct UdpConnectionServer {
+    listener: UdpListener,
+    hello: Hello,
+    accepts: NodeKind,
+}
+
+impl UdpConnectionServer {
+    /// Crea el servidor escuchando en la direccion HOST:PUERTO indicada. Solo acepta las conexiones
+    /// del tipo de nodo indicado, y se presenta en el handshake con el id del servidor
+    pub fn new(
+        address: &str,
+        id: usize,
+        accepts: NodeKind,
+        config: UdpConfig,
+    ) -> Result<UdpConnectionServer, ServerError> {
+        let listener = UdpListener::bind(address, config).map_err(|e| {
+            error!("[SERVER] Error binding to address {}, {:?}", address, e);
+            ServerError::ListenerError
+        })?;
+        info!("[SERVER] Bind to UDP address successful {}", address);
+        Ok(UdpConnectionServer {
+            listener,
+            hello: Hello::new(NodeId::Server(id)),
+            accepts,
+        })
+    }
+}
+
+#[async_trait]
+impl ConnectionServer for UdpConnectionServer {
+    async fn listen(&self) -> Result<Box<dyn ConnectionProtocol + Send>, ServerError> {
+        loop {
+            let (channel, addr) = self.listener.accept().await.map_err(|e| {
+                error!("[SERVER] Error accepting UDP connection {:?}", e);
+                ServerError::AcceptError
+            })?;
+            info!(
+                "[SERVER] Accepted UDP connection from {} {}",
+                addr.ip(),
+                addr.port()
+            );
+            // Si el handshake falla se descarta la conexion y se sigue escuchando
+            let result =
+                UdpConnection::new_server_connection(channel, &self.hello, self.accepts).await;
+            if let Ok(conn) = result {
+                let peer = conn.peer();
                 info!(
-                    "[SERVER] Accepted connection from {} {}",
-                    addr.ip(),
-                    addr.port()
+                    "[SERVER] Handshake with {:?}, protocol version {}, features {:?}, codec {:?}",
+                    peer.node_id, peer.protocol_version, peer.features, peer.codec
                 );
-                let conn = TcpConnection::new_server_connection(tcp_stream, addr);
-                Ok(Box::new(conn))
+                return Ok(Box::new(conn));
             }
-            Err(e) => {
-                error!("[COFFEE MAKER SERVER] Error accepting connection {}", e);
-                Err(ServerError::AcceptError)
+        }
+    }
+}
+
+/// Implementacion de la abstraccion de conexion sobre una red en memoria, para levantar varios servidores
+/// en un mismo proceso
+pub struct MemoryConnectionServer {
+    listener: MemoryListener,
+    hello: Hello,
+    accepts: NodeKind,
+}
+
+#[async_trait]
+impl ConnectionServer for MemoryConnectionServer {
+    async fn listen(&self) -> Result<Box<dyn ConnectionProtocol + Send>, ServerError> {
+        loop {
+            let channel = self.listener.accept().await.map_err(|e| {
+                error!("[SERVER] Error accepting connection {:?}", e);
+                ServerError::AcceptError
+            })?;
+            let result =
+                MemoryConnection::new_server_connection(channel, &self.hello, self.accepts).await;
+            if let Ok(conn) = result {
+                return Ok(Box::new(conn));
             }
         }
     }
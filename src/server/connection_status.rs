@@ -1,3 +1,5 @@
+use lib::handshake::Feature;
+
 #[derive(Debug, PartialEq, Eq)]
 /// Posibles estados para los peers
 enum Status {
@@ -5,10 +7,14 @@ enum Status {
     Disconnected,
 }
 
-/// Estado que contiene los estados para el vecino anterior y siguiente del ring
+/// Estado que contiene los estados para el vecino anterior y siguiente del ring, y sus ids si se conocen
 pub struct ConnectionStatus {
     next: Status,
     prev: Status,
+    next_id: Option<usize>,
+    prev_id: Option<usize>,
+    /// Funcionalidades acordadas con el siguiente en el handshake
+    next_features: Vec<Feature>,
 }
 
 impl ConnectionStatus {
@@ -16,6 +22,9 @@ impl ConnectionStatus {
         ConnectionStatus {
             next: Status::Disconnected,
             prev: Status::Disconnected,
+            next_id: None,
+            prev_id: None,
+            next_features: vec![],
         }
     }
 
@@ -46,6 +55,33 @@ impl ConnectionStatus {
     pub fn set_next_offline(&mut self) {
         self.next = Status::Disconnected;
     }
+
+    /// Id del servidor al que se envian los mensajes, el ultimo con el que se conecto `NextConnection`
+    pub fn next_id(&self) -> Option<usize> {
+        self.next_id
+    }
+
+    /// Id del servidor del que se reciben los mensajes, el que registro la ultima `PrevConnection`
+    pub fn prev_id(&self) -> Option<usize> {
+        self.prev_id
+    }
+
+    pub fn set_next_id(&mut self, id: usize) {
+        self.next_id = Some(id);
+    }
+
+    pub fn set_prev_id(&mut self, id: usize) {
+        self.prev_id = Some(id);
+    }
+
+    pub fn set_next_features(&mut self, features: Vec<Feature>) {
+        self.next_features = features;
+    }
+
+    /// Indica si el siguiente puede leer los mensajes que usan la funcionalidad. Sin siguiente no hay a quien enviarlos
+    pub fn next_supports(&self, feature: &Feature) -> bool {
+        !self.is_next_online() || self.next_features.contains(feature)
+    }
 }
 
 impl Default for ConnectionStatus {
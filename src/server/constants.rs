@@ -5,7 +5,8 @@ pub const INITIAL_WAIT_IN_MS_FOR_CONNECTION_ATTEMPT: u64 = 500;
 pub const MAX_WAIT_IN_MS_FOR_CONNECTION_ATTEMPT: u64 = 3600000;
 
 /// Es el tiempo de timeout que tiene el sender hacia la next connection.
-/// Si no recibe nada en este tiempo revisa si esta conectado. Si lo esta se toma como que hay demora.
+/// Si no recibe nada en este tiempo revisa si esta conectado. Si lo esta se toma como que hay demora, y si no tiene
+/// el token hace circular el aviso de token perdido para confirmar que nadie lo tiene antes de regenerarlo.
 pub const TO_NEXT_CONN_CHANNEL_TIMEOUT_IN_MS: u64 = 22000;
 
 /// Indica el tiempo que se espera a recibr el resultado de un cafe con puntos. Debe de ser por lo menos algo mas que lo que toma hacer un cafe.
@@ -19,3 +20,33 @@ pub const POST_INITIAL_TIMEOUT_COFFEE_RESULT_IN_MS: u64 = 500;
 /// Indica el tiempo de espera antes de limpiar las ordenes que son de resta si se esta offline.
 /// Se tiene una espera antes de limpiarlas para dar tiempo en caso de una perdida muy temporal de conexion
 pub const CLEAN_ORDERS_TIME_IN_MS: u64 = 4000;
+
+/// Indica cada cuantas operaciones registradas en el log de cuentas se compacta el mismo en un snapshot.
+/// Solo aplica si el servidor persiste las cuentas en disco
+pub const OPERATIONS_BETWEEN_SNAPSHOTS: usize = 1000;
+
+/// Indica el tiempo que se espera el primer mensaje de una conexion entrante de otro servidor.
+/// Con ese mensaje se distingue un pedido para unirse a la red de una conexion del anillo
+pub const FIRST_MESSAGE_TIMEOUT_IN_MS: u64 = 3000;
+
+/// Indica el tiempo que dura la reserva de puntos de una cuenta. Debe de ser mayor al tiempo de espera del resultado
+/// del cafe (`COFFEE_RESULT_TIMEOUT_IN_MS`), pasado este tiempo los puntos reservados se liberan
+pub const RESERVATION_TTL_IN_MS: u64 = 30000;
+
+/// Indica cuantas respuestas a pedidos de las cafeteras se recuerdan para responder sus reintentos sin volver a aplicarlos
+pub const DEDUP_CACHE_CAPACITY: usize = 10000;
+
+/// Indica el tiempo maximo que un servidor que se retira espera a que el token aplique sus pedidos pendientes.
+/// Incluye una vuelta del token y la espera del resultado de los cafes con puntos (`COFFEE_RESULT_TIMEOUT_IN_MS`)
+pub const SHUTDOWN_DRAIN_TIMEOUT_IN_MS: u64 = 60000;
+
+/// Indica cada cuanto se revisa si quedan pedidos pendientes mientras el servidor se retira
+pub const SHUTDOWN_POLL_INTERVAL_IN_MS: u64 = 100;
+
+/// Indica cuantas operaciones de cada cuenta se guardan en su historia, las mas antiguas se descartan.
+/// Solo las que quedan se vuelven a escribir en disco al compactar el log en un snapshot
+pub const LEDGER_ACTIONS_PER_ACCOUNT: usize = 1000;
+
+/// Indica cuantos bytes de historia se envian como maximo a un servidor que se reconecta. Tiene que ser bastante
+/// menor al largo maximo de un frame (`MAX_FRAME_LENGTH`), el mensaje de nueva conexion tambien lleva las cuentas
+pub const MAX_DIFF_HISTORY_BYTES: usize = 16 * 1024 * 1024;
@@ -0,0 +1,153 @@
+use std::collections::{HashMap, VecDeque};
+
+use lib::{
+    common_errors::CoffeeSystemError,
+    local_connection_messages::{CoffeeMakerResponse, MessageType, RequestId, ResponseStatus},
+};
+
+/// Cache acotada de las respuestas dadas a los pedidos de las cafeteras, por id de pedido.
+/// Si una cafetera reintenta un pedido se le devuelve la respuesta original en lugar de aplicarlo de nuevo.
+/// Tambien guarda los pedidos aplicados en otros servidores que llegan en el token, asi se reconoce el reintento
+/// de un pedido enviado a otra sucursal. Al superar la capacidad se descartan las respuestas mas viejas
+#[derive(Debug)]
+pub struct DedupCache {
+    responses: HashMap<RequestId, CoffeeMakerResponse>,
+    order: VecDeque<RequestId>,
+    pending: HashMap<RequestId, usize>,
+    capacity: usize,
+}
+
+impl DedupCache {
+    pub fn new(capacity: usize) -> DedupCache {
+        DedupCache {
+            responses: HashMap::new(),
+            order: VecDeque::new(),
+            pending: HashMap::new(),
+            capacity,
+        }
+    }
+
+    /// Devuelve la respuesta que se dio al pedido, si ya fue respondido
+    pub fn get(&self, request_id: &RequestId) -> Option<CoffeeMakerResponse> {
+        self.responses.get(request_id).copied()
+    }
+
+    /// Registra que el pedido espera al token para ser respondido, y la conexion de la cafetera que lo envio
+    pub fn set_pending(&mut self, request_id: RequestId, machine_id: usize) {
+        self.pending.insert(request_id, machine_id);
+    }
+
+    /// Si el pedido esta esperando al token, su respuesta se envia a la conexion del reintento
+    /// (la cafetera pudo haberse reconectado). Devuelve si el pedido estaba esperando
+    pub fn redirect_pending(&mut self, request_id: RequestId, machine_id: usize) -> bool {
+        match self.pending.get_mut(&request_id) {
+            Some(target) => {
+                *target = machine_id;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Guarda la respuesta de un pedido. Las respuestas de errores transitorios no se guardan, un reintento de ese
+    /// pedido se vuelve a procesar (ej. una resta sin reserva, que la cafetera reenvia despues de volver a reservar).
+    /// Si el pedido estaba esperando al token devuelve la conexion a la que se debe enviar la respuesta
+    pub fn insert(&mut self, response: CoffeeMakerResponse) -> Option<usize> {
+        let target = self.pending.remove(&response.request_id);
+        if response.message_type == MessageType::QueryBalance || is_transient(&response.status) {
+            return target;
+        }
+        if self
+            .responses
+            .insert(response.request_id, response)
+            .is_none()
+        {
+            self.order.push_back(response.request_id);
+        }
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.responses.remove(&oldest);
+            }
+        }
+        target
+    }
+
+    /// Registra un pedido que fue aplicado en otro servidor, su reintento se responde como exitoso
+    pub fn insert_applied(&mut self, request_id: RequestId, message_type: MessageType) {
+        self.insert(CoffeeMakerResponse {
+            message_type,
+            status: ResponseStatus::Ok,
+            request_id,
+        });
+    }
+}
+
+fn is_transient(status: &ResponseStatus) -> bool {
+    matches!(
+        status,
+        ResponseStatus::Err(CoffeeSystemError::ConnectionLost)
+            | ResponseStatus::Err(CoffeeSystemError::UnexpectedError)
+            | ResponseStatus::Err(CoffeeSystemError::ReservationNotFound)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(sequence: u64) -> RequestId {
+        RequestId {
+            dispenser_id: 1,
+            sequence,
+        }
+    }
+
+    fn response(sequence: u64, status: ResponseStatus) -> CoffeeMakerResponse {
+        CoffeeMakerResponse {
+            message_type: MessageType::AddPoints,
+            status,
+            request_id: id(sequence),
+        }
+    }
+
+    #[test]
+    fn should_return_the_original_response_and_forget_the_oldest_ones() {
+        let mut cache = DedupCache::new(2);
+        cache.insert(response(1, ResponseStatus::Ok));
+        cache.insert(response(
+            2,
+            ResponseStatus::Err(CoffeeSystemError::NotEnoughPoints),
+        ));
+        assert!(cache.get(&id(1)).is_some());
+        assert!(matches!(
+            cache.get(&id(2)).map(|response| response.status),
+            Some(ResponseStatus::Err(CoffeeSystemError::NotEnoughPoints))
+        ));
+
+        cache.insert(response(3, ResponseStatus::Ok));
+        assert!(cache.get(&id(1)).is_none());
+        assert!(cache.get(&id(3)).is_some());
+    }
+
+    #[test]
+    fn should_not_keep_transient_errors_and_clear_the_pending_request() {
+        let mut cache = DedupCache::new(10);
+        cache.set_pending(id(1), 0);
+        assert!(cache.redirect_pending(id(1), 0));
+        cache.insert(response(
+            1,
+            ResponseStatus::Err(CoffeeSystemError::ConnectionLost),
+        ));
+        assert!(!cache.redirect_pending(id(1), 0));
+        assert!(cache.get(&id(1)).is_none());
+    }
+
+    #[test]
+    fn should_return_the_connection_of_the_last_retry_of_a_pending_request() {
+        let mut cache = DedupCache::new(10);
+        cache.set_pending(id(1), 0);
+        assert!(cache.redirect_pending(id(1), 3));
+        assert_eq!(Some(3), cache.insert(response(1, ResponseStatus::Ok)));
+        assert_eq!(None, cache.insert(response(2, ResponseStatus::Ok)));
+    }
+}
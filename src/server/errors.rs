@@ -1,5 +1,7 @@
 use std::time::SystemTimeError;
 
+use lib::common_errors::CoffeeSystemError;
+
 #[derive(Debug)]
 pub enum ServerError {
     ListenerError,
@@ -14,8 +16,19 @@ pub enum ServerError {
     SerializationError,
     OperationIsOutdated,
     AccountIsReserved,
+    ReservationNotFound,
+    NotReservationOwner,
+    PointsNotReserved,
     CoffeeServerStartError,
+    AdminServerStartError,
     TimestampError,
+    StorageError,
+    TopologyFileError,
+    TopologyFormat,
+    UnknownServerId,
+    DuplicatedServerId,
+    DuplicatedAddress,
+    IncompatibleProtocol,
 }
 
 impl<T> From<std::sync::PoisonError<T>> for ServerError {
@@ -47,3 +60,19 @@ impl From<SystemTimeError> for ServerError {
         ServerError::TimestampError
     }
 }
+
+impl From<std::io::Error> for ServerError {
+    fn from(_: std::io::Error) -> Self {
+        ServerError::StorageError
+    }
+}
+
+impl From<CoffeeSystemError> for ServerError {
+    fn from(error: CoffeeSystemError) -> Self {
+        match error {
+            CoffeeSystemError::SerializationError => ServerError::SerializationError,
+            CoffeeSystemError::IncompatibleProtocol => ServerError::IncompatibleProtocol,
+            _ => ServerError::ConnectionLost,
+        }
+    }
+}
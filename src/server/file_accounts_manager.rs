@@ -0,0 +1,628 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use lib::serializer::serialize;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    accounts_manager::AccountsManager,
+    constants::OPERATIONS_BETWEEN_SNAPSHOTS,
+    errors::ServerError,
+    hybrid_clock::HybridTimestamp,
+    memory_accounts_manager::MemoryAccountsManager,
+    server_messages::{AccountAction, UpdatedAccount},
+};
+
+/// Tipos de operaciones que quedan registradas en el log
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+enum LoggedOperation {
+    AddPoints,
+    SubstractPoints,
+    Update,
+    Record,
+}
+
+/// Entrada del log de operaciones. Ademas de la operacion guarda el estado en el que quedo la cuenta,
+/// de esta forma reproducir el log da el mismo resultado sin importar cuando se reproduzca.
+/// Si la operacion se registra en la historia, la accion viaja en la misma entrada. Las entradas `Record`
+/// solo registran una accion en la historia y no tienen cuenta
+#[derive(Debug, Serialize, Deserialize)]
+struct LogEntry {
+    operation: LoggedOperation,
+    points: usize,
+    account: Option<UpdatedAccount>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    action: Option<AccountAction>,
+}
+
+/// Implementacion de manejador de cuentas persistida en disco.
+/// Mantiene las cuentas en memoria y registra cada cambio en un log (write-ahead log) antes de aplicarlo en memoria.
+/// Cada `OPERATIONS_BETWEEN_SNAPSHOTS` operaciones compacta el log en un snapshot de las cuentas y otro de la
+/// historia. Al iniciarse reconstruye las cuentas y su historia a partir de los snapshots y del log
+pub struct FileAccountsManager {
+    accounts: MemoryAccountsManager,
+    log: File,
+    snapshot_path: PathBuf,
+    ledger_path: PathBuf,
+    operations_since_snapshot: usize,
+}
+
+impl FileAccountsManager {
+    /// Abre (o crea) los archivos del servidor dentro del directorio de datos y recupera las cuentas guardadas
+    pub fn new(data_dir: &str, server_id: usize) -> Result<FileAccountsManager, ServerError> {
+        let dir = Path::new(data_dir);
+        fs::create_dir_all(dir)?;
+        let log_path = dir.join(format!("server_{}.log", server_id));
+        let snapshot_path = dir.join(format!("server_{}.snapshot", server_id));
+        let ledger_path = dir.join(format!("server_{}.ledger", server_id));
+
+        let mut accounts = MemoryAccountsManager::new();
+        load_snapshot(&snapshot_path, &mut accounts)?;
+        load_ledger(&ledger_path, &mut accounts)?;
+        let replayed = replay_log(&log_path, &mut accounts)?;
+        info!(
+            "[ACCOUNTS STORAGE] Recovered accounts from {:?}, replayed {} operations",
+            dir, replayed
+        );
+
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)?;
+        let mut manager = FileAccountsManager {
+            accounts,
+            log,
+            snapshot_path,
+            ledger_path,
+            operations_since_snapshot: replayed,
+        };
+        // Se compacta al iniciar para descartar una posible entrada incompleta al final del log
+        if replayed > 0 {
+            manager.take_snapshot()?;
+        }
+        Ok(manager)
+    }
+
+    /// Valida la operacion sobre una copia de la cuenta, guarda en el log como queda la cuenta y recien
+    /// entonces la aplica en memoria. Si la operacion no es valida no se guarda nada, y si no se puede guardar
+    /// no se aplica: las cuentas en memoria nunca tienen cambios que no esten en disco.
+    /// Si se indica una accion se guarda en la misma entrada y se registra en la historia junto con el cambio
+    fn write_ahead<F>(
+        &mut self,
+        operation: LoggedOperation,
+        account_id: usize,
+        points: usize,
+        action: Option<&AccountAction>,
+        change: F,
+    ) -> Result<(), ServerError>
+    where
+        F: FnOnce(&mut MemoryAccountsManager) -> Result<(), ServerError>,
+    {
+        let account = self.accounts.prepare(account_id, change)?;
+        let entry = LogEntry {
+            operation,
+            points,
+            account: Some(UpdatedAccount {
+                id: account.id,
+                amount: account.points(),
+                last_updated_on: account.last_updated_on(),
+            }),
+            action: action.cloned(),
+        };
+        self.log.write_all(&serialize(&entry)?)?;
+        self.log.sync_data()?;
+        self.accounts.commit(account);
+        if let Some(action) = action {
+            self.accounts.record_action(action.clone());
+        }
+        self.logged_operations(1);
+        Ok(())
+    }
+
+    /// Guarda en el log las acciones que no estaban en la historia y recien entonces las registra en memoria.
+    /// Todas se guardan con una sola escritura en disco
+    fn write_ahead_actions(&mut self, actions: &[AccountAction]) -> Result<usize, ServerError> {
+        let mut new_actions: Vec<&AccountAction> = vec![];
+        let mut written = HashSet::new();
+        let mut entries = vec![];
+        for action in actions {
+            if !self.accounts.is_new_action(action)
+                || !written.insert((action.account_id, action.last_updated_on))
+            {
+                continue;
+            }
+            entries.extend(serialize(&LogEntry {
+                operation: LoggedOperation::Record,
+                points: action.points,
+                account: None,
+                action: Some(action.clone()),
+            })?);
+            new_actions.push(action);
+        }
+        if new_actions.is_empty() {
+            return Ok(0);
+        }
+        self.log.write_all(&entries)?;
+        self.log.sync_data()?;
+        let recorded = new_actions
+            .iter()
+            .filter(|action| self.accounts.record_action((**action).clone()))
+            .count();
+        self.logged_operations(new_actions.len());
+        Ok(recorded)
+    }
+
+    /// Cuenta las entradas escritas en el log y lo compacta si paso la cantidad de operaciones entre snapshots
+    fn logged_operations(&mut self, entries: usize) {
+        self.operations_since_snapshot += entries;
+        if self.operations_since_snapshot >= OPERATIONS_BETWEEN_SNAPSHOTS
+            && self.take_snapshot().is_err()
+        {
+            error!("[ACCOUNTS STORAGE] Error compacting the log, it will be retried later");
+        }
+    }
+
+    /// Abre el log solo para lectura, asi fallan las escrituras siguientes como si el disco fallara
+    #[cfg(test)]
+    pub fn fail_log_writes(&mut self) -> Result<(), ServerError> {
+        self.log = File::open(self.snapshot_path.with_extension("log"))?;
+        Ok(())
+    }
+
+    /// Escribe el estado de todas las cuentas en el snapshot, la historia que se guarda en su archivo y vacia el log.
+    /// Los archivos se escriben primero en un archivo temporal para no perder el anterior si se corta la escritura
+    fn take_snapshot(&mut self) -> Result<(), ServerError> {
+        let accounts = serialize(&self.accounts.get_all_accounts())?;
+        replace_file(&self.snapshot_path, &accounts)?;
+        let mut ledger = vec![];
+        for action in self.accounts.recorded_actions() {
+            ledger.extend(serialize(&action)?);
+        }
+        replace_file(&self.ledger_path, &ledger)?;
+        self.log.set_len(0)?;
+        self.log.sync_all()?;
+        self.operations_since_snapshot = 0;
+        info!("[ACCOUNTS STORAGE] Log compacted into snapshot");
+        Ok(())
+    }
+}
+
+/// Reemplaza el contenido del archivo pasando por un archivo temporal
+fn replace_file(path: &Path, content: &[u8]) -> Result<(), ServerError> {
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+    {
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(content)?;
+        tmp.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Carga las cuentas del snapshot si existe
+fn load_snapshot(path: &Path, accounts: &mut MemoryAccountsManager) -> Result<(), ServerError> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let content = fs::read_to_string(path)?;
+    let saved_accounts: Vec<UpdatedAccount> = serde_json::from_str(&content)?;
+    for account in saved_accounts {
+        accounts.update(account.id, account.amount, account.last_updated_on)?;
+    }
+    Ok(())
+}
+
+/// Reproduce las entradas del log sobre las cuentas. Devuelve la cantidad de entradas aplicadas.
+/// Las entradas que no se pueden leer (ej. escritura cortada por una caida) se descartan
+fn replay_log(path: &Path, accounts: &mut MemoryAccountsManager) -> Result<usize, ServerError> {
+    if !path.exists() {
+        return Ok(0);
+    }
+    let reader = BufReader::new(File::open(path)?);
+    let mut replayed = 0;
+    for line in reader.lines() {
+        let line = line?;
+        match serde_json::from_str::<LogEntry>(&line) {
+            Ok(entry) => {
+                if let Some(account) = entry.account {
+                    accounts.update(account.id, account.amount, account.last_updated_on)?;
+                }
+                if let Some(action) = entry.action {
+                    accounts.record_action(action);
+                }
+                replayed += 1;
+            }
+            Err(_) => {
+                warn!(
+                    "[ACCOUNTS STORAGE] Discarding unreadable log entry {}",
+                    line
+                );
+            }
+        }
+    }
+    Ok(replayed)
+}
+
+/// Carga la historia de las cuentas guardada en el ultimo snapshot. Las entradas que no se pueden leer se descartan
+fn load_ledger(path: &Path, accounts: &mut MemoryAccountsManager) -> Result<(), ServerError> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let reader = BufReader::new(File::open(path)?);
+    for line in reader.lines() {
+        let line = line?;
+        match serde_json::from_str::<AccountAction>(&line) {
+            Ok(action) => {
+                accounts.record_action(action);
+            }
+            Err(_) => {
+                warn!(
+                    "[ACCOUNTS STORAGE] Discarding unreadable ledger entry {}",
+                    line
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+impl AccountsManager for FileAccountsManager {
+    fn add_points(
+        &mut self,
+        account_id: usize,
+        points: usize,
+        operation_time: Option<HybridTimestamp>,
+    ) -> Result<(), ServerError> {
+        self.write_ahead(
+            LoggedOperation::AddPoints,
+            account_id,
+            points,
+            None,
+            |accounts| accounts.add_points(account_id, points, operation_time),
+        )
+    }
+
+    fn substract_points(
+        &mut self,
+        account_id: usize,
+        points: usize,
+        operation_time: Option<HybridTimestamp>,
+    ) -> Result<(), ServerError> {
+        self.write_ahead(
+            LoggedOperation::SubstractPoints,
+            account_id,
+            points,
+            None,
+            |accounts| accounts.substract_points(account_id, points, operation_time),
+        )
+    }
+
+    fn update(
+        &mut self,
+        account_id: usize,
+        points: usize,
+        operation_time: HybridTimestamp,
+    ) -> Result<(), ServerError> {
+        self.write_ahead(
+            LoggedOperation::Update,
+            account_id,
+            points,
+            None,
+            |accounts| accounts.update(account_id, points, operation_time),
+        )
+    }
+
+    fn request_points(
+        &mut self,
+        account_id: usize,
+        points: usize,
+        owner: u64,
+    ) -> Result<(), ServerError> {
+        self.accounts.request_points(account_id, points, owner)
+    }
+
+    fn take_reserved_points(
+        &mut self,
+        account_id: usize,
+        owner: u64,
+        points: usize,
+        operation_time: Option<HybridTimestamp>,
+    ) -> Result<(), ServerError> {
+        self.write_ahead(
+            LoggedOperation::SubstractPoints,
+            account_id,
+            points,
+            None,
+            |accounts| accounts.take_reserved_points(account_id, owner, points, operation_time),
+        )
+    }
+
+    fn cancel_requested_points(
+        &mut self,
+        account_id: usize,
+        owner: u64,
+    ) -> Result<(), ServerError> {
+        self.accounts.cancel_requested_points(account_id, owner)
+    }
+
+    fn get_account(&self, account_id: usize) -> Option<UpdatedAccount> {
+        self.accounts.get_account(account_id)
+    }
+
+    fn get_all_accounts(&self) -> Vec<UpdatedAccount> {
+        self.accounts.get_all_accounts()
+    }
+
+    fn get_most_recent_update(&self) -> HybridTimestamp {
+        self.accounts.get_most_recent_update()
+    }
+
+    fn get_accounts_updated_after(&self, timestamp: HybridTimestamp) -> Vec<UpdatedAccount> {
+        self.accounts.get_accounts_updated_after(timestamp)
+    }
+
+    fn release_expired_reservations(&mut self) {
+        self.accounts.release_expired_reservations()
+    }
+
+    fn record_action(&mut self, action: AccountAction) -> bool {
+        self.record_actions(&[action]) > 0
+    }
+
+    fn record_actions(&mut self, actions: &[AccountAction]) -> usize {
+        match self.write_ahead_actions(actions) {
+            Ok(recorded) => recorded,
+            Err(e) => {
+                error!(
+                    "[ACCOUNTS STORAGE] Error saving history of {} actions, {:?}",
+                    actions.len(),
+                    e
+                );
+                0
+            }
+        }
+    }
+
+    fn apply_action(
+        &mut self,
+        action: &AccountAction,
+        owner: Option<u64>,
+    ) -> Result<(), ServerError> {
+        let operation = match action.message_type {
+            lib::local_connection_messages::MessageType::AddPoints => LoggedOperation::AddPoints,
+            _ => LoggedOperation::SubstractPoints,
+        };
+        self.write_ahead(
+            operation,
+            action.account_id,
+            action.points,
+            Some(action),
+            |accounts| accounts.apply_operation(action, owner),
+        )
+    }
+
+    fn get_history(&self, account_id: usize, page: usize, page_size: usize) -> Vec<AccountAction> {
+        self.accounts.get_history(account_id, page, page_size)
+    }
+
+    fn get_actions_after(&self, timestamp: HybridTimestamp) -> Vec<AccountAction> {
+        self.accounts.get_actions_after(timestamp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::LEDGER_ACTIONS_PER_ACCOUNT;
+
+    fn at(physical: u64) -> HybridTimestamp {
+        HybridTimestamp::new(physical, 0, 0)
+    }
+
+    fn test_dir(name: &str) -> String {
+        let dir =
+            std::env::temp_dir().join(format!("coffee_accounts_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn should_recover_the_accounts_after_a_restart() {
+        let dir = test_dir("restart");
+        {
+            let mut manager = FileAccountsManager::new(&dir, 0).expect("Error opening storage");
+            manager
+                .update(1, 100, at(10))
+                .expect("Error updating account");
+            manager
+                .add_points(1, 50, Some(at(11)))
+                .expect("Error adding points");
+            manager
+                .substract_points(1, 30, Some(at(12)))
+                .expect("Error substracting points");
+        }
+        let manager = FileAccountsManager::new(&dir, 0).expect("Error opening storage");
+        let account = manager.accounts.get_account(1).expect("Account not found");
+        assert_eq!(120, account.amount);
+        assert_eq!(at(12), manager.get_most_recent_update());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn should_compact_the_log_into_a_snapshot() {
+        let dir = test_dir("snapshot");
+        {
+            let mut manager = FileAccountsManager::new(&dir, 0).expect("Error opening storage");
+            for i in 0..OPERATIONS_BETWEEN_SNAPSHOTS {
+                manager
+                    .update(i % 10, i, at(i as u64))
+                    .expect("Error updating account");
+            }
+            assert_eq!(0, manager.operations_since_snapshot);
+        }
+        let log_len = fs::metadata(Path::new(&dir).join("server_0.log"))
+            .expect("Log not found")
+            .len();
+        assert_eq!(0, log_len);
+
+        let manager = FileAccountsManager::new(&dir, 0).expect("Error opening storage");
+        assert_eq!(10, manager.accounts.get_all_accounts().len());
+        assert_eq!(
+            at((OPERATIONS_BETWEEN_SNAPSHOTS - 1) as u64),
+            manager.get_most_recent_update()
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn should_discard_an_incomplete_entry_at_the_end_of_the_log() {
+        let dir = test_dir("incomplete");
+        {
+            let mut manager = FileAccountsManager::new(&dir, 0).expect("Error opening storage");
+            manager
+                .update(1, 100, at(10))
+                .expect("Error updating account");
+            manager
+                .log
+                .write_all(b"{\"operation\":\"AddPoints\",\"poi")
+                .expect("Error writing test data");
+        }
+        {
+            let mut manager = FileAccountsManager::new(&dir, 0).expect("Error opening storage");
+            let account = manager.accounts.get_account(1).expect("Account not found");
+            assert_eq!(100, account.amount);
+            manager
+                .add_points(1, 5, Some(at(11)))
+                .expect("Error adding points");
+        }
+        let manager = FileAccountsManager::new(&dir, 0).expect("Error opening storage");
+        let account = manager.accounts.get_account(1).expect("Account not found");
+        assert_eq!(105, account.amount);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn should_not_log_an_operation_that_is_not_valid() {
+        let dir = test_dir("invalid");
+        {
+            let mut manager = FileAccountsManager::new(&dir, 0).expect("Error opening storage");
+            manager
+                .update(1, 10, at(10))
+                .expect("Error updating account");
+            assert!(manager.substract_points(1, 20, Some(at(11))).is_err());
+            assert!(manager.add_points(1, 5, Some(at(9))).is_err());
+            assert_eq!(1, manager.operations_since_snapshot);
+        }
+        let manager = FileAccountsManager::new(&dir, 0).expect("Error opening storage");
+        let account = manager.accounts.get_account(1).expect("Account not found");
+        assert_eq!(10, account.amount);
+        assert_eq!(at(10), account.last_updated_on);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn should_not_apply_an_operation_that_could_not_be_logged() {
+        let dir = test_dir("unlogged");
+        let mut manager = FileAccountsManager::new(&dir, 0).expect("Error opening storage");
+        manager
+            .update(1, 10, at(10))
+            .expect("Error updating account");
+        manager.fail_log_writes().expect("Log not found");
+
+        assert!(manager.add_points(1, 5, Some(at(11))).is_err());
+        assert!(manager.update(2, 30, at(12)).is_err());
+
+        let account = manager.accounts.get_account(1).expect("Account not found");
+        assert_eq!(10, account.amount);
+        assert_eq!(at(10), account.last_updated_on);
+        assert!(manager.accounts.get_account(2).is_none());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn should_recover_the_history_of_the_accounts_after_a_restart() {
+        let dir = test_dir("ledger");
+        let action = AccountAction {
+            message_type: lib::local_connection_messages::MessageType::AddPoints,
+            account_id: 1,
+            points: 50,
+            last_updated_on: at(11),
+            origin_server_id: 2,
+            coffee_maker_id: 3,
+            request_ids: vec![],
+        };
+        {
+            let mut manager = FileAccountsManager::new(&dir, 0).expect("Error opening storage");
+            assert!(manager.record_action(action.clone()));
+            assert!(!manager.record_action(action.clone()));
+        }
+        let manager = FileAccountsManager::new(&dir, 0).expect("Error opening storage");
+        assert_eq!(vec![action], manager.get_history(1, 0, 10));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    fn add_action(account_id: usize, points: usize, physical: u64) -> AccountAction {
+        AccountAction {
+            message_type: lib::local_connection_messages::MessageType::AddPoints,
+            account_id,
+            points,
+            last_updated_on: at(physical),
+            origin_server_id: 0,
+            coffee_maker_id: 1,
+            request_ids: vec![],
+        }
+    }
+
+    #[test]
+    fn should_save_the_operation_and_its_history_in_the_same_log_entry() {
+        let dir = test_dir("action");
+        {
+            let mut manager = FileAccountsManager::new(&dir, 0).expect("Error opening storage");
+            manager
+                .apply_action(&add_action(1, 50, 11), None)
+                .expect("Error applying action");
+            assert!(manager.apply_action(&add_action(1, 5, 10), None).is_err());
+        }
+        let log = fs::read_to_string(Path::new(&dir).join("server_0.log")).expect("Log not found");
+        assert_eq!(1, log.lines().count());
+
+        let manager = FileAccountsManager::new(&dir, 0).expect("Error opening storage");
+        let account = manager.get_account(1).expect("Account not found");
+        assert_eq!(50, account.amount);
+        assert_eq!(vec![add_action(1, 50, 11)], manager.get_history(1, 0, 10));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn should_compact_the_history_together_with_the_snapshot() {
+        let dir = test_dir("ledger_snapshot");
+        let operations = OPERATIONS_BETWEEN_SNAPSHOTS + 5;
+        {
+            let mut manager = FileAccountsManager::new(&dir, 0).expect("Error opening storage");
+            for i in 1..=operations {
+                manager
+                    .apply_action(&add_action(1, 1, i as u64), None)
+                    .expect("Error applying action");
+            }
+        }
+        let manager = FileAccountsManager::new(&dir, 0).expect("Error opening storage");
+        let ledger =
+            fs::read_to_string(Path::new(&dir).join("server_0.ledger")).expect("Ledger not found");
+        assert_eq!(LEDGER_ACTIONS_PER_ACCOUNT, ledger.lines().count());
+        assert_eq!(
+            operations,
+            manager.get_account(1).expect("Account not found").amount
+        );
+        let history = manager.get_history(1, 0, usize::MAX);
+        assert_eq!(LEDGER_ACTIONS_PER_ACCOUNT, history.len());
+        assert_eq!(at(operations as u64), history[0].last_updated_on);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,136 @@
+use std::{
+    cmp::max,
+    fmt,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub use lib::hybrid_timestamp::HybridTimestamp;
+
+/// Reloj logico hibrido (HLC). Genera marcas que siguen al reloj fisico pero que siempre avanzan,
+/// aun si el reloj del servidor esta atrasado respecto a los de los demas.
+/// Se debe avanzar con cada marca recibida de otro servidor
+pub struct HybridClock {
+    node_id: usize,
+    last: HybridTimestamp,
+    physical_time: PhysicalTime,
+}
+
+/// Fuente del tiempo fisico en milisegundos
+pub type PhysicalTime = Arc<dyn Fn() -> u64 + Send + Sync>;
+
+impl HybridClock {
+    pub fn new(node_id: usize) -> HybridClock {
+        HybridClock::with_physical_time(node_id, Arc::new(physical_now))
+    }
+
+    /// Crea un reloj que toma el tiempo fisico de la fuente indicada en lugar del reloj del sistema,
+    /// por ejemplo del reloj virtual de la simulacion
+    pub fn with_physical_time(node_id: usize, physical_time: PhysicalTime) -> HybridClock {
+        HybridClock {
+            node_id,
+            last: HybridTimestamp::new(0, 0, node_id),
+            physical_time,
+        }
+    }
+
+    /// Devuelve una marca para un evento local, posterior a todas las generadas y recibidas hasta el momento
+    pub fn now(&mut self) -> HybridTimestamp {
+        let physical = (self.physical_time)();
+        self.now_with_physical(physical)
+    }
+
+    /// Avanza el reloj con una marca recibida de otro servidor
+    pub fn update(&mut self, received: HybridTimestamp) {
+        let physical = (self.physical_time)();
+        self.update_with_physical(received, physical);
+    }
+
+    fn now_with_physical(&mut self, physical: u64) -> HybridTimestamp {
+        if physical > self.last.physical {
+            self.last = HybridTimestamp::new(physical, 0, self.node_id);
+        } else {
+            self.last =
+                HybridTimestamp::new(self.last.physical, self.last.logical + 1, self.node_id);
+        }
+        self.last
+    }
+
+    fn update_with_physical(&mut self, received: HybridTimestamp, physical: u64) {
+        let max_physical = max(physical, max(self.last.physical, received.physical));
+        let logical = if max_physical == self.last.physical && max_physical == received.physical {
+            max(self.last.logical, received.logical) + 1
+        } else if max_physical == self.last.physical {
+            self.last.logical + 1
+        } else if max_physical == received.physical {
+            received.logical + 1
+        } else {
+            0
+        };
+        self.last = HybridTimestamp::new(max_physical, logical, self.node_id);
+    }
+}
+
+impl fmt::Debug for HybridClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HybridClock")
+            .field("node_id", &self.node_id)
+            .field("last", &self.last)
+            .finish()
+    }
+}
+
+/// Tiempo fisico del servidor en milisegundos
+fn physical_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_generate_increasing_timestamps_even_if_the_physical_clock_goes_back() {
+        let mut clock = HybridClock::new(1);
+        let first = clock.now_with_physical(100);
+        let second = clock.now_with_physical(90);
+        let third = clock.now_with_physical(100);
+        assert!(first < second);
+        assert!(second < third);
+        assert_eq!(HybridTimestamp::new(100, 2, 1), third);
+    }
+
+    #[test]
+    fn should_order_local_events_after_a_received_timestamp_from_a_skewed_clock() {
+        // El servidor 2 tiene el reloj adelantado
+        let received = HybridTimestamp::new(5000, 3, 2);
+        let mut clock = HybridClock::new(1);
+        clock.update_with_physical(received, 1000);
+        let local = clock.now_with_physical(1001);
+        assert!(received < local);
+        assert_eq!(HybridTimestamp::new(5000, 5, 1), local);
+    }
+
+    #[test]
+    fn should_follow_the_physical_clock_when_it_is_ahead() {
+        let mut clock = HybridClock::new(1);
+        clock.update_with_physical(HybridTimestamp::new(50, 7, 2), 100);
+        assert_eq!(
+            HybridTimestamp::new(100, 1, 1),
+            clock.now_with_physical(100)
+        );
+        assert_eq!(
+            HybridTimestamp::new(200, 0, 1),
+            clock.now_with_physical(200)
+        );
+    }
+
+    #[test]
+    fn should_break_ties_with_the_node_id() {
+        assert!(HybridTimestamp::new(10, 1, 1) < HybridTimestamp::new(10, 1, 2));
+        assert!(HybridTimestamp::new(10, 1, 2) < HybridTimestamp::new(10, 2, 0));
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+
+use crate::{hybrid_clock::HybridTimestamp, server_messages::AccountAction};
+
+/// Registro de las operaciones aplicadas sobre cada cuenta, ordenadas por su marca de tiempo.
+/// Como las marcas del reloj hibrido son unicas, una operacion que se recibe dos veces se registra una sola vez.
+/// De cada cuenta se guardan las `actions_per_account` operaciones mas recientes
+#[derive(Debug)]
+pub struct Ledger {
+    entries: HashMap<usize, Vec<AccountAction>>,
+    actions_per_account: usize,
+}
+
+impl Ledger {
+    pub fn new(actions_per_account: usize) -> Ledger {
+        Ledger {
+            entries: HashMap::new(),
+            actions_per_account,
+        }
+    }
+
+    /// Indica si la operacion se registraria, es decir si no esta registrada y no es mas antigua que todas
+    /// las que se guardan de una historia llena
+    pub fn is_new(&self, action: &AccountAction) -> bool {
+        match self.entries.get(&action.account_id) {
+            Some(history) => match history
+                .binary_search_by_key(&action.last_updated_on, |entry| entry.last_updated_on)
+            {
+                Ok(_) => false,
+                Err(position) => position > 0 || history.len() < self.actions_per_account,
+            },
+            None => true,
+        }
+    }
+
+    /// Registra una operacion en la historia de su cuenta, descartando la mas antigua si la historia esta llena.
+    /// Devuelve false si ya estaba registrada o si es mas antigua que todas las que se guardan
+    pub fn record(&mut self, action: AccountAction) -> bool {
+        let history = self.entries.entry(action.account_id).or_default();
+        match history.binary_search_by_key(&action.last_updated_on, |entry| entry.last_updated_on) {
+            Ok(_) => false,
+            Err(0) if history.len() >= self.actions_per_account => false,
+            Err(position) => {
+                history.insert(position, action);
+                if history.len() > self.actions_per_account {
+                    history.remove(0);
+                }
+                true
+            }
+        }
+    }
+
+    /// Devuelve todas las operaciones guardadas
+    pub fn actions(&self) -> Vec<AccountAction> {
+        self.entries.values().flatten().cloned().collect()
+    }
+
+    /// Devuelve una pagina de la historia de la cuenta, de la operacion mas reciente a la mas antigua.
+    /// La pagina 0 contiene las `page_size` operaciones mas recientes
+    pub fn page(&self, account_id: usize, page: usize, page_size: usize) -> Vec<AccountAction> {
+        match self.entries.get(&account_id) {
+            Some(history) => history
+                .iter()
+                .rev()
+                .skip(page.saturating_mul(page_size))
+                .take(page_size)
+                .cloned()
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Devuelve las operaciones de todas las cuentas posteriores a cierta marca de tiempo
+    pub fn actions_after(&self, timestamp: HybridTimestamp) -> Vec<AccountAction> {
+        let mut actions: Vec<AccountAction> = self
+            .entries
+            .values()
+            .flatten()
+            .filter(|action| timestamp < action.last_updated_on)
+            .cloned()
+            .collect();
+        actions.sort_by_key(|action| action.last_updated_on);
+        actions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lib::local_connection_messages::MessageType;
+
+    use super::*;
+
+    fn action(account_id: usize, points: usize, physical: u64) -> AccountAction {
+        AccountAction {
+            message_type: MessageType::AddPoints,
+            account_id,
+            points,
+            last_updated_on: HybridTimestamp::new(physical, 0, 1),
+            origin_server_id: 1,
+            coffee_maker_id: 0,
+            request_ids: vec![],
+        }
+    }
+
+    #[test]
+    fn should_return_the_history_of_an_account_by_pages_from_the_most_recent() {
+        let mut ledger = Ledger::new(10);
+        for physical in 1..=5 {
+            ledger.record(action(7, physical as usize, physical));
+        }
+        ledger.record(action(8, 100, 3));
+
+        let first_page = ledger.page(7, 0, 2);
+        let second_page = ledger.page(7, 1, 2);
+        let last_page = ledger.page(7, 2, 2);
+
+        assert_eq!(
+            vec![5, 4],
+            first_page.iter().map(|a| a.points).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![3, 2],
+            second_page.iter().map(|a| a.points).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![1],
+            last_page.iter().map(|a| a.points).collect::<Vec<_>>()
+        );
+        assert!(ledger.page(7, 3, 2).is_empty());
+        assert!(ledger.page(9, 0, 2).is_empty());
+    }
+
+    #[test]
+    fn should_keep_the_order_of_the_clock_and_ignore_repeated_actions() {
+        let mut ledger = Ledger::new(10);
+        assert!(ledger.record(action(7, 2, 20)));
+        assert!(ledger.record(action(7, 1, 10)));
+        assert!(!ledger.record(action(7, 2, 20)));
+
+        let history = ledger.page(7, 0, 10);
+        assert_eq!(2, history.len());
+        assert_eq!(HybridTimestamp::new(20, 0, 1), history[0].last_updated_on);
+        assert_eq!(HybridTimestamp::new(10, 0, 1), history[1].last_updated_on);
+    }
+
+    #[test]
+    fn should_return_the_actions_after_a_timestamp() {
+        let mut ledger = Ledger::new(10);
+        ledger.record(action(7, 1, 10));
+        ledger.record(action(8, 2, 30));
+        ledger.record(action(7, 3, 20));
+
+        let actions = ledger.actions_after(HybridTimestamp::new(10, 0, 1));
+
+        assert_eq!(
+            vec![3, 2],
+            actions.iter().map(|a| a.points).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn should_keep_only_the_most_recent_actions_of_each_account() {
+        let mut ledger = Ledger::new(2);
+        assert!(ledger.record(action(7, 1, 10)));
+        assert!(ledger.record(action(7, 3, 30)));
+        assert!(ledger.record(action(7, 2, 20)));
+        assert!(!ledger.is_new(&action(7, 0, 5)));
+        assert!(!ledger.record(action(7, 0, 5)));
+        assert!(ledger.record(action(8, 4, 1)));
+
+        assert_eq!(
+            vec![3, 2],
+            ledger
+                .page(7, 0, 10)
+                .iter()
+                .map(|a| a.points)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(3, ledger.actions().len());
+    }
+}
@@ -1,170 +1,458 @@
 use std::{
-    collections::HashMap,
-    sync::{
-        mpsc::{self, Sender},
-        Arc, Mutex,
-    },
+    sync::Arc,
     thread::{self, JoinHandle},
+    time::Duration,
 };
 
-use async_std::task;
-use lib::common_errors::CoffeeSystemError;
-use log::error;
+use async_std::{future, task};
+use lib::{
+    common_errors::CoffeeSystemError,
+    connection_protocol::{recv_message, send_message, ConnectionProtocol},
+    handshake::{Feature, Hello, NodeId, NodeKind},
+};
+use log::{error, info, warn};
 
 use crate::{
-    address_resolver::id_to_server_port,
+    accounts_manager::AccountsManager,
+    address_resolver::AddressResolver,
+    admin_server::AdminServer,
     coffee_maker_server::CoffeeMakerServer,
-    coffee_message_dispatcher::CoffeeMessageDispatcher,
-    connection_server::{ConnectionServer, TcpConnectionServer},
-    connection_status::ConnectionStatus,
+    connection_server::{ConnectionServer, Network},
+    constants::FIRST_MESSAGE_TIMEOUT_IN_MS,
     errors::ServerError,
+    file_accounts_manager::FileAccountsManager,
+    hybrid_clock::HybridClock,
+    membership::Membership,
     memory_accounts_manager::MemoryAccountsManager,
-    next_connection::NextConnection,
-    offline_substract_orders_cleaner::SubstractOrdersCleaner,
-    orders_manager::OrdersManager,
-    orders_queue::OrdersQueue,
-    previous_connection::PrevConnection,
-    server_messages::{ServerMessage, TokenData},
+    metrics_server::MetricsServer,
+    ring_node::{NodeState, RingNode},
+    server_messages::{
+        create_join_accepted_message, create_join_message, ServerMessage, ServerMessageType,
+    },
 };
 
 /// Es la entidad que inicializa la aplicacion.
 /// Una vez hecho esto se pone a escuchar para conexiones entrantes de otros servidores locales
 pub struct LocalServer {
-    id: usize,
-    listener: Box<dyn ConnectionServer>,
-    connection_status: Arc<Mutex<ConnectionStatus>>,
-    to_next_conn_sender: Sender<ServerMessage>,
-    to_orders_manager_sender: Sender<TokenData>,
-    have_token: Arc<Mutex<bool>>,
-    accounts_manager: Arc<Mutex<MemoryAccountsManager>>,
-    next_conn_handle: Option<JoinHandle<Result<(), ServerError>>>,
-    orders_manager_handle: Option<JoinHandle<Result<(), ServerError>>>,
-    dispatcher_handle: Option<JoinHandle<Result<(), ServerError>>>,
-    coffee_handle: Option<JoinHandle<Result<(), ServerError>>>,
+    listener: Box<dyn ConnectionServer + Send>,
+    state: NodeState,
+    next_conn_handle: JoinHandle<Result<(), ServerError>>,
 }
 
 impl LocalServer {
-    pub fn new(id: usize, peer_count: usize) -> Result<LocalServer, ServerError> {
-        let listener: Box<dyn ConnectionServer> =
-            Box::new(TcpConnectionServer::new(&id_to_server_port(id))?);
-        let (to_next_conn_sender, next_conn_receiver) = mpsc::channel();
-        let (to_orders_manager_sender, orders_manager_receiver) = mpsc::channel();
-
-        let (request_points_result_sender, request_points_result_receiver) = mpsc::channel();
-        let (result_points_sender, result_points_receiver) = mpsc::channel();
-        let (orders_from_coffee_sender, orders_from_coffee_receiver) = mpsc::channel();
-
-        let connection_status = Arc::new(Mutex::new(ConnectionStatus::new()));
-        let have_token = Arc::new(Mutex::new(false));
-
-        let orders = Arc::new(Mutex::new(OrdersQueue::new()));
-        let orders_clone = orders.clone();
-        let request_points_channel_clone = request_points_result_sender.clone();
-
-        let accounts_manager = Arc::new(Mutex::new(MemoryAccountsManager::new()));
-
-        let mut orders_manager = OrdersManager::new(
+    /// Inicializa el servidor. Si se indica la direccion de un miembro de la red, antes de iniciarse
+    /// se le pide unirse al anillo para obtener la vista de miembros actual
+    pub fn new(
+        id: usize,
+        data_dir: Option<String>,
+        address_resolver: AddressResolver,
+        join_address: Option<String>,
+        network: Arc<dyn Network>,
+    ) -> Result<LocalServer, ServerError> {
+        let server_address = address_resolver
+            .server_address(id)
+            .ok_or(ServerError::UnknownServerId)?
+            .clone();
+        let listener = network.bind(&server_address, id, NodeKind::Server)?;
+        let mut membership = Membership::from_resolver(&address_resolver);
+        if let Some(join_address) = join_address {
+            request_join(
+                id,
+                address_resolver
+                    .ring_address(id)
+                    .unwrap_or(&server_address)
+                    .clone(),
+                &join_address,
+                &mut membership,
+                network.as_ref(),
+            )?;
+        }
+        let accounts_manager = create_accounts_manager(id, data_dir)?;
+        let RingNode {
+            state,
+            mut next_connection,
+            mut orders_manager,
+            dispatcher: mut coffee_message_dispatcher,
+            orders_from_coffee_sender,
+            machine_response_senders,
+            result_points_sender,
+            request_points_result_sender,
+            request_points_result_receiver,
+        } = RingNode::new(
             id,
-            orders.clone(),
-            orders_manager_receiver,
-            to_next_conn_sender.clone(),
-            request_points_result_sender.clone(),
-            result_points_receiver,
-            accounts_manager.clone(),
-        );
-
-        let machine_response_senders = Arc::new(Mutex::new(HashMap::new()));
-        let mut coffee_message_dispatcher = CoffeeMessageDispatcher::new(
-            connection_status.clone(),
-            orders,
-            orders_from_coffee_receiver,
-            machine_response_senders.clone(),
+            membership,
+            accounts_manager,
+            HybridClock::new(id),
+            network.clone(),
         );
 
-        let offline_cleaner =
-            SubstractOrdersCleaner::new(orders_clone, request_points_channel_clone);
-
-        let mut next_connection = NextConnection::new(
+        let coffee_server = CoffeeMakerServer::new(
             id,
-            peer_count,
-            next_conn_receiver,
-            connection_status.clone(),
-            have_token.clone(),
-            accounts_manager.clone(),
-            offline_cleaner,
+            &address_resolver,
+            orders_from_coffee_sender,
+            machine_response_senders,
+            state.shutting_down.clone(),
+            state.metrics.clone(),
+            network.as_ref(),
         );
-
-        let coffee_server =
-            CoffeeMakerServer::new(id, orders_from_coffee_sender, machine_response_senders);
         if coffee_server.is_err() {
             error!("Error booting up coffee maker server, stopping...");
             return Err(ServerError::CoffeeServerStartError);
         }
         let mut coffee_server = coffee_server.unwrap();
-        let coffee_handle = thread::spawn(move || coffee_server.listen());
-        let dispatcher_handle = thread::spawn(move || {
+        if let Some(admin_address) = address_resolver.admin_address(id) {
+            let admin_server = AdminServer::new(admin_address, state.clone(), network.as_ref());
+            if admin_server.is_err() {
+                error!("Error booting up admin server, stopping...");
+                return Err(ServerError::AdminServerStartError);
+            }
+            let mut admin_server = admin_server.unwrap();
+            spawn_component(&state, move || admin_server.listen());
+        }
+        // Las metricas no son necesarias para atender pedidos, si no se pueden exponer el servidor sigue igual
+        if let Some(metrics_address) = address_resolver.metrics_address(id) {
+            match MetricsServer::new(metrics_address, state.clone()) {
+                Ok(metrics_server) => {
+                    spawn_component(&state, move || metrics_server.listen());
+                }
+                Err(_) => error!("Error booting up metrics server, continuing without metrics..."),
+            }
+        }
+        spawn_component(&state, move || coffee_server.listen());
+        spawn_component(&state, move || {
             coffee_message_dispatcher.dispatch_coffee_requests(
                 result_points_sender,
                 request_points_result_sender,
                 request_points_result_receiver,
             )
         });
-        let orders_manager_handle = thread::spawn(move || orders_manager.handle_orders());
-        let next_conn_handle = thread::spawn(move || next_connection.handle_message_to_next());
+        spawn_component(&state, move || orders_manager.handle_orders());
+        let next_conn_handle =
+            spawn_component(&state, move || next_connection.handle_message_to_next());
 
         Ok(LocalServer {
             listener,
-            id,
-            connection_status,
-            to_next_conn_sender,
-            to_orders_manager_sender,
-            have_token,
-            accounts_manager,
-            next_conn_handle: Some(next_conn_handle),
-            dispatcher_handle: Some(dispatcher_handle),
-            orders_manager_handle: Some(orders_manager_handle),
-            coffee_handle: Some(coffee_handle),
+            state,
+            next_conn_handle,
         })
     }
 
-    pub fn start_server(&mut self) {
-        if self.listen().is_err() {
-            error!("Error on local server listener");
+    /// Devuelve el estado compartido del servidor, por ejemplo para retirarlo del anillo
+    pub fn state(&self) -> NodeState {
+        self.state.clone()
+    }
+
+    /// Escucha las conexiones de los demas servidores hasta que el servidor se retira del anillo, con el comando
+    /// `leave`, SIGINT/SIGTERM o desde la consola de administracion. Los demas hilos no terminan solos, se detienen con el proceso
+    pub fn start_server(self) {
+        let LocalServer {
+            listener,
+            state,
+            next_conn_handle,
+        } = self;
+        thread::spawn(move || {
+            if listen(listener, state).is_err() {
+                error!("Error on local server listener");
+            }
+        });
+        match next_conn_handle.join() {
+            Ok(Ok(())) => info!("Left the ring, stopping..."),
+            _ => error!("Error on the connection with the next server, stopping..."),
         }
-        self.coffee_handle.take().map(JoinHandle::join);
-        self.dispatcher_handle.take().map(JoinHandle::join);
-        self.next_conn_handle.take().map(JoinHandle::join);
-        self.orders_manager_handle.take().map(JoinHandle::join);
     }
+}
+
+/// Corre un componente del servidor en su hilo. Si termina con error, se registra en las metricas
+fn spawn_component<F>(state: &NodeState, run: F) -> JoinHandle<Result<(), ServerError>>
+where
+    F: FnOnce() -> Result<(), ServerError> + Send + 'static,
+{
+    let metrics = state.metrics.clone();
+    thread::spawn(move || {
+        let result = run();
+        if let Err(e) = &result {
+            if let Ok(mut metrics) = metrics.lock() {
+                metrics.server_error(e);
+            }
+        }
+        result
+    })
+}
 
-    fn listen(&mut self) -> Result<(), ServerError> {
-        let mut curr_prev_handle: Option<JoinHandle<Result<(), CoffeeSystemError>>> = None;
-        loop {
-            let new_connection = task::block_on(self.listener.listen())?;
-            let to_next_channel = self.to_next_conn_sender.clone();
-            let to_orders_manager_channel = self.to_orders_manager_sender.clone();
-            let mut previous = PrevConnection::new(
-                new_connection,
-                to_next_channel,
-                to_orders_manager_channel,
-                self.connection_status.clone(),
-                self.id,
-                self.have_token.clone(),
-                self.accounts_manager.clone(),
-            );
-
-            let new_prev_handle = thread::spawn(move || previous.listen());
-            if self.connection_status.lock()?.is_prev_online() {
-                if let Some(handle) = curr_prev_handle {
-                    if handle.join().is_err() {
-                        error!("[LOCAL SERVER LISTENER] Error joining old previous connection");
-                    }
+/// Escucha las conexiones entrantes de los demas servidores. Cada una pasa a ser la conexion con el anterior,
+/// salvo los pedidos para unirse a la red
+fn listen(listener: Box<dyn ConnectionServer + Send>, state: NodeState) -> Result<(), ServerError> {
+    let mut curr_prev_handle: Option<JoinHandle<Result<(), CoffeeSystemError>>> = None;
+    loop {
+        let mut new_connection = task::block_on(listener.listen())?;
+        let first_message = match handle_first_message(&state, &mut new_connection) {
+            Ok(Some(encoded)) => encoded,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!(
+                    "[LOCAL SERVER LISTENER] Dropping new connection, error reading its first message {:?}",
+                    e
+                );
+                state.metrics.lock()?.server_error(&e);
+                continue;
+            }
+        };
+        let mut previous = state
+            .prev_connection(new_connection)
+            .with_first_message(first_message);
+
+        let metrics = state.metrics.clone();
+        let new_prev_handle = thread::spawn(move || {
+            let result = previous.listen();
+            if let Err(e) = &result {
+                if let Ok(mut metrics) = metrics.lock() {
+                    metrics.coffee_system_error(e);
+                }
+            }
+            result
+        });
+        if state.connection_status.lock()?.is_prev_online() {
+            if let Some(handle) = curr_prev_handle {
+                if handle.join().is_err() {
+                    error!("[LOCAL SERVER LISTENER] Error joining old previous connection");
                 }
             }
+        }
+
+        curr_prev_handle = Some(new_prev_handle);
+
+        state.connection_status.lock()?.set_prev_online();
+    }
+}
+
+/// Lee el primer mensaje de una conexion entrante. Si es un pedido para unirse a la red se agrega al servidor
+/// a la vista de miembros, se le responde con la misma y se devuelve None. En otro caso se devuelve el mensaje
+/// para que lo procese la conexion con el anterior
+fn handle_first_message(
+    state: &NodeState,
+    connection: &mut Box<dyn ConnectionProtocol + Send>,
+) -> Result<Option<Vec<u8>>, ServerError> {
+    let timeout = Duration::from_millis(FIRST_MESSAGE_TIMEOUT_IN_MS);
+    let encoded = match task::block_on(future::timeout(timeout, connection.recv())) {
+        Ok(Ok(encoded)) => encoded,
+        _ => return Err(ServerError::ConnectionLost),
+    };
+
+    let message: ServerMessage = match connection.codec().decode(&encoded) {
+        Ok(message) => message,
+        Err(_) => return Ok(Some(encoded)),
+    };
+    let address = match message.message_type {
+        ServerMessageType::Join(address) => address,
+        _ => return Ok(Some(encoded)),
+    };
+
+    info!(
+        "[LOCAL SERVER LISTENER] Server {} at {} is joining the ring",
+        message.sender_id, address
+    );
+    let view = {
+        let mut membership = state.membership.lock()?;
+        membership.merge(&message.membership, state.id);
+        membership.join(message.sender_id, address);
+        membership.clone()
+    };
+    let response = create_join_accepted_message(state.id, view);
+    task::block_on(send_message(connection.as_mut(), &response))?;
+    Ok(None)
+}
+
+/// Pide unirse a la red al servidor indicado, y actualiza la vista de miembros con la que este responde.
+/// Se anuncia con la direccion a la que deben conectarse los demas servidores del anillo
+fn request_join(
+    id: usize,
+    ring_address: String,
+    join_address: &String,
+    membership: &mut Membership,
+    network: &dyn Network,
+) -> Result<(), ServerError> {
+    info!("Requesting to join the ring through {}", join_address);
+    let hello = Hello::new(NodeId::Server(id));
+    let mut connection = network.connect(join_address, &hello).map_err(|e| {
+        error!(
+            "Error connecting to {} to join the ring, {:?}",
+            join_address, e
+        );
+        ServerError::from(e)
+    })?;
+    if !connection.peer().supports(&Feature::DynamicMembership) {
+        error!(
+            "Server at {} does not support joining the ring while it is running",
+            join_address
+        );
+        return Err(ServerError::IncompatibleProtocol);
+    }
+    let mut request = create_join_message(id, ring_address);
+    request.membership = membership.clone();
+    task::block_on(send_message(connection.as_mut(), &request))?;
+
+    let response: ServerMessage = task::block_on(recv_message(connection.as_mut()))?;
+    if response.message_type != ServerMessageType::JoinAccepted {
+        error!("Join request to {} was not accepted", join_address);
+        return Err(ServerError::ConnectionLost);
+    }
+    membership.merge(&response.membership, id);
+    info!(
+        "Joined the ring, active members {:?}",
+        membership.active_members()
+    );
+    Ok(())
+}
+
+/// Crea el manejador de cuentas. Si se indico un directorio de datos las cuentas se persisten en disco,
+/// caso contrario se mantienen solo en memoria
+fn create_accounts_manager(
+    id: usize,
+    data_dir: Option<String>,
+) -> Result<Box<dyn AccountsManager>, ServerError> {
+    match data_dir {
+        Some(dir) => match FileAccountsManager::new(&dir, id) {
+            Ok(manager) => Ok(Box::new(manager)),
+            Err(e) => {
+                error!("Error opening accounts storage in {}, {:?}", dir, e);
+                Err(e)
+            }
+        },
+        None => Ok(Box::new(MemoryAccountsManager::new())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Mutex, RwLock},
+        time::Instant,
+    };
 
-            curr_prev_handle = Some(new_prev_handle);
+    use lib::{
+        connection_protocol::{MemoryConnection, MemoryNetwork},
+        local_connection_messages::{
+            CoffeeMakerRequest, CoffeeMakerResponse, MessageType, RequestId, ResponseStatus,
+        },
+    };
 
-            self.connection_status.lock()?.set_prev_online();
+    use super::*;
+    use crate::address_resolver::id_to_coffee_address;
+
+    const RING_SIZE: usize = 5;
+    const CONVERGENCE_TIMEOUT_IN_MS: u64 = 60000;
+
+    /// Red en memoria que demora las conexiones hasta que todos los servidores del anillo estan escuchando,
+    /// asi el anillo se forma con una sola eleccion
+    struct StartGate {
+        network: MemoryNetwork,
+        starting: Arc<RwLock<()>>,
+    }
+
+    impl Network for StartGate {
+        fn bind(
+            &self,
+            address: &str,
+            id: usize,
+            accepts: NodeKind,
+        ) -> Result<Box<dyn ConnectionServer + Send>, ServerError> {
+            self.network.bind(address, id, accepts)
+        }
+
+        fn connect(
+            &self,
+            address: &str,
+            hello: &Hello,
+        ) -> Result<Box<dyn ConnectionProtocol + Send + Sync>, CoffeeSystemError> {
+            let _started = self.starting.read()?;
+            Network::connect(&self.network, address, hello)
+        }
+    }
+
+    /// Levanta un anillo de servidores sobre la red en memoria y devuelve las cuentas de cada uno
+    fn start_ring(network: &MemoryNetwork) -> Vec<Arc<Mutex<Box<dyn AccountsManager>>>> {
+        let gate = Arc::new(StartGate {
+            network: network.clone(),
+            starting: Arc::new(RwLock::new(())),
+        });
+        let _starting = gate.starting.write().expect("Lock error");
+        (0..RING_SIZE)
+            .map(|id| {
+                let server = LocalServer::new(
+                    id,
+                    None,
+                    AddressResolver::new_local(id, RING_SIZE),
+                    None,
+                    gate.clone(),
+                )
+                .expect("Error starting server");
+                let accounts = server.state.accounts_manager.clone();
+                thread::spawn(move || server.start_server());
+                accounts
+            })
+            .collect()
+    }
+
+    /// Suma puntos a la cuenta conectandose como cafetera al servidor indicado
+    fn add_points(network: &MemoryNetwork, server_id: usize, points: usize, sequence: u64) {
+        let mut connection = MemoryConnection::new_client_connection(
+            network,
+            &id_to_coffee_address(server_id),
+            &Hello::new(NodeId::CoffeeMaker(1)),
+        )
+        .expect("Error connecting to the coffee maker server");
+        let request = CoffeeMakerRequest {
+            message_type: MessageType::AddPoints,
+            account_id: 1,
+            points,
+            request_id: RequestId {
+                dispenser_id: 1,
+                sequence,
+            },
+        };
+        task::block_on(send_message(&mut connection, &request)).expect("Error sending");
+        let response: CoffeeMakerResponse =
+            task::block_on(recv_message(&mut connection)).expect("Error receiving");
+        assert!(matches!(response.status, ResponseStatus::Ok));
+    }
+
+    /// Espera a que todos los servidores indicados tengan el saldo esperado en la cuenta
+    fn converges(
+        accounts: &[Arc<Mutex<Box<dyn AccountsManager>>>],
+        servers: &[usize],
+        expected: usize,
+    ) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(CONVERGENCE_TIMEOUT_IN_MS) {
+            let balances: Vec<Option<usize>> = servers
+                .iter()
+                .map(|id| {
+                    let accounts = accounts[*id].lock().expect("Lock error");
+                    accounts.get_account(1).map(|account| account.amount)
+                })
+                .collect();
+            if balances.iter().all(|balance| *balance == Some(expected)) {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(100));
         }
+        false
+    }
+
+    #[test]
+    fn should_converge_the_balances_of_a_ring_after_killing_nodes() {
+        let network = MemoryNetwork::new();
+        let accounts = start_ring(&network);
+
+        add_points(&network, 0, 10, 1);
+        assert!(converges(&accounts, &[0, 1, 2, 3, 4], 10));
+
+        network.kill(NodeId::Server(2)).expect("Error killing");
+        add_points(&network, 1, 5, 2);
+        assert!(converges(&accounts, &[0, 1, 3, 4], 15));
+
+        network.kill(NodeId::Server(4)).expect("Error killing");
+        add_points(&network, 3, 7, 3);
+        assert!(converges(&accounts, &[0, 1, 3], 22));
     }
 }
@@ -1,16 +1,21 @@
-use std::env;
+use std::{env, io, process, sync::Arc, thread};
 
+use address_resolver::AddressResolver;
 use errors::ServerError;
-use lib::logger::set_logger_config;
+use lib::{connection_protocol::Transport, logger::set_logger_config};
 use local_server::LocalServer;
-use log::error;
+use log::{error, info, warn};
+use ring_node::NodeState;
 use server_args::ServerArgs;
+use shutdown::shut_down;
 /// Modulo utilizado para representar una cuenta de un cliente de la cafeteria
 pub mod account;
 /// Abstraccion utilizada para representar una manejador de cuentas de un cliente de la cafeteria
 pub mod accounts_manager;
-/// Modulo de funciones tipo helper para mapear id de servidores a conexiones IPs
+/// Modulo que resuelve las direcciones de los servidores a partir de su id, por defecto o segun un archivo de topologia
 pub mod address_resolver;
+/// Modulo que responde las consultas de la consola de administracion
+pub mod admin_server;
 /// Modulo que realiza la comunicacion con la cafetera
 pub mod coffee_maker_connection;
 /// Modulo que crea hilos para las conexiones con cada cafetera
@@ -23,12 +28,26 @@ pub mod connection_server;
 pub mod connection_status;
 /// Modulo donde se encuentran las constantes definidas para el funcionamiento correcto del servidor
 pub mod constants;
+/// Modulo que recuerda las respuestas a los pedidos de las cafeteras para reconocer sus reintentos
+pub mod dedup_cache;
 /// Modulo de errores que utiliza unicamente el servidor
 pub mod errors;
+/// Modulo que contiene una implementacion de manejador de cuentas persistida en disco
+pub mod file_accounts_manager;
+/// Modulo que contiene el reloj logico hibrido con el que se ordenan las operaciones sobre las cuentas
+pub mod hybrid_clock;
+/// Modulo que guarda la historia de operaciones de cada cuenta
+pub mod ledger;
 /// Modulo que representa al servidor
 pub mod local_server;
+/// Modulo que mantiene la vista de los servidores que forman parte del anillo
+pub mod membership;
 /// Modulo que contiene una implementacion implementacion de manejador de cuentas en memoria
 pub mod memory_accounts_manager;
+/// Modulo que mantiene las metricas del servidor, compartidas entre sus hilos
+pub mod metrics;
+/// Modulo que expone las metricas del servidor por HTTP
+pub mod metrics_server;
 /// Modulo que representa la conexion de un servidor con el peer siguiente del token ring
 pub mod next_connection;
 /// Modulo que representa un limpiador de ordenes de resta de puntos en caso de que un servidor este offline
@@ -39,42 +58,150 @@ pub mod orders_manager;
 pub mod orders_queue;
 /// Modulo que representa la conexion de un servidor con el peer anterior del token ring
 pub mod previous_connection;
+/// Modulo que arma los componentes de un servidor conectados entre si
+pub mod ring_node;
 /// Modulo que representa los parametros que recibe el servidor al ejecutarse
 pub mod server_args;
 /// Modulo que contiene los posibles mensajes que pueden intercambiar los servidores pares
 pub mod server_messages;
+/// Modulo que retira al servidor del anillo de forma ordenada
+pub mod shutdown;
+/// Modulo que simula un anillo de servidores con un reloj virtual y fallas inyectadas segun una semilla
+#[cfg(test)]
+pub mod simulation;
 
 fn get_args() -> Result<ServerArgs, ServerError> {
     let args: Vec<String> = env::args().collect();
-    if args.len() == 3 {
-        let parsed_id: Result<usize, _> = args[1].clone().trim().parse();
-        let parsed_peer_count: Result<usize, _> = args[2].clone().trim().parse();
+    if args.len() < 2 {
+        return Err(ServerError::ArgsMissing);
+    }
+    let id: usize = args[1]
+        .trim()
+        .parse()
+        .map_err(|_| ServerError::ArgsFormat)?;
+
+    let mut next_arg = 2;
+    let mut peer_server_count = None;
+    if let Some(arg) = args.get(2) {
+        if !arg.starts_with("--") {
+            let count: usize = arg.trim().parse().map_err(|_| ServerError::ArgsFormat)?;
+            peer_server_count = Some(count);
+            next_arg = 3;
+        }
+    }
 
-        return match (parsed_id, parsed_peer_count) {
-            (Ok(id), Ok(peer_server_count)) => Ok(ServerArgs {
-                id,
-                peer_server_count,
-            }),
-            (_, _) => Err(ServerError::ArgsFormat),
-        };
+    let mut data_dir = None;
+    let mut topology_file = None;
+    let mut join_address = None;
+    let mut transport = Transport::default();
+    let mut options = args[next_arg..].iter();
+    while let Some(option) = options.next() {
+        let value = options.next().ok_or(ServerError::ArgsMissing)?.clone();
+        match option.as_str() {
+            "--data-dir" => data_dir = Some(value),
+            "--topology" => topology_file = Some(value),
+            "--join" => join_address = Some(value),
+            "--transport" => {
+                transport = Transport::from_name(&value).ok_or(ServerError::ArgsFormat)?
+            }
+            _ => return Err(ServerError::ArgsFormat),
+        }
     }
-    Err(ServerError::ArgsMissing)
+    Ok(ServerArgs {
+        id,
+        peer_server_count,
+        data_dir,
+        topology_file,
+        join_address,
+        transport,
+    })
 }
 
 fn main() {
     set_logger_config();
     let server_args_res = get_args();
     if server_args_res.is_err() {
-        error!("Error setting args. Use [ID] [PEER_COUNT]");
+        error!(
+            "Error setting args. Use [ID] [PEER_COUNT - OPTIONAL] [--data-dir DIR - OPTIONAL] [--topology FILE - OPTIONAL] [--join HOST:PORT - OPTIONAL] [--transport tcp|udp - OPTIONAL]"
+        );
         return;
     }
     let server_args = server_args_res.unwrap();
 
-    let result = LocalServer::new(server_args.id, server_args.peer_server_count);
+    let address_resolver = match &server_args.topology_file {
+        Some(path) => AddressResolver::from_file(path, server_args.id),
+        None => Ok(AddressResolver::new_local(
+            server_args.id,
+            server_args.peer_server_count.unwrap_or(0),
+        )),
+    };
+    if let Err(e) = address_resolver {
+        error!("Error loading the network topology {:?}, stopping...", e);
+        return;
+    }
+
+    let result = LocalServer::new(
+        server_args.id,
+        server_args.data_dir,
+        address_resolver.unwrap(),
+        server_args.join_address,
+        Arc::new(server_args.transport),
+    );
     if result.is_err() {
         error!("Error booting up local server, stopping...");
         return;
     }
-    let mut server = result.unwrap();
+    let server = result.unwrap();
+    listen_leave_command(server.state());
+    listen_shutdown_signals(server.state());
     server.start_server();
 }
+
+/// Escucha la entrada estandar. Al ingresar `leave` el servidor se retira de la red de forma ordenada
+fn listen_leave_command(state: NodeState) {
+    thread::spawn(move || {
+        for line in io::stdin().lines() {
+            match line {
+                Ok(command) if command.trim() == "leave" => {
+                    info!("Leaving the ring...");
+                    leave(&state);
+                    return;
+                }
+                Ok(_) => {}
+                Err(_) => return,
+            }
+        }
+    });
+}
+
+/// Al recibir SIGINT o SIGTERM el servidor se retira de la red de forma ordenada, como con `leave`.
+/// Si llega otra senal mientras se retira, o no se puede retirar, se detiene sin esperar
+fn listen_shutdown_signals(state: NodeState) {
+    let result = ctrlc::set_handler(move || {
+        if *state
+            .shutting_down
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+        {
+            warn!("Signal received again, stopping without leaving the ring");
+            process::exit(1);
+        }
+        info!("Signal received, leaving the ring...");
+        let state = state.clone();
+        thread::spawn(move || {
+            if let Err(e) = shut_down(&state) {
+                error!("Error leaving the ring, {:?}, stopping without leaving", e);
+                process::exit(1);
+            }
+        });
+    });
+    if let Err(e) = result {
+        error!("Error setting the signal handler, {:?}", e);
+    }
+}
+
+fn leave(state: &NodeState) {
+    if let Err(e) = shut_down(state) {
+        error!("Error leaving the ring, {:?}", e);
+    }
+}
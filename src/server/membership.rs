@@ -0,0 +1,218 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::address_resolver::AddressResolver;
+
+/// Representa a un servidor conocido de la red. Un miembro que se fue de la red se mantiene
+/// marcado como inactivo para que el cambio se propague con su version
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub address: String,
+    pub active: bool,
+    pub version: u64,
+}
+
+/// Vista de los miembros del anillo. Cada cambio aumenta la version de la vista y la del miembro afectado,
+/// de esta forma al recibir la vista de otro servidor nos quedamos con la informacion mas reciente de cada miembro.
+/// El anillo se ordena por id de servidor
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+pub struct Membership {
+    version: u64,
+    members: BTreeMap<usize, Member>,
+}
+
+impl Membership {
+    /// Crea la vista inicial con los servidores configurados en el resolver
+    pub fn from_resolver(address_resolver: &AddressResolver) -> Membership {
+        let members = address_resolver
+            .server_ids()
+            .into_iter()
+            .filter_map(|id| {
+                address_resolver.ring_address(id).map(|address| {
+                    (
+                        id,
+                        Member {
+                            address: address.clone(),
+                            active: true,
+                            version: 0,
+                        },
+                    )
+                })
+            })
+            .collect();
+        Membership {
+            version: 0,
+            members,
+        }
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Agrega (o reactiva) un miembro a la vista
+    pub fn join(&mut self, id: usize, address: String) {
+        self.version += 1;
+        self.members.insert(
+            id,
+            Member {
+                address,
+                active: true,
+                version: self.version,
+            },
+        );
+    }
+
+    /// Marca que un miembro se fue de la red
+    pub fn leave(&mut self, id: usize) {
+        if let Some(member) = self.members.get_mut(&id) {
+            self.version += 1;
+            member.active = false;
+            member.version = self.version;
+        }
+    }
+
+    /// Actualiza la vista con la de otro servidor, quedandose con la version mas reciente de cada miembro.
+    /// La informacion sobre uno mismo solo la cambia uno mismo, si llega una version mas nueva se vuelve a anunciar
+    /// el estado propio con una version mayor. Devuelve si la vista cambio
+    pub fn merge(&mut self, other: &Membership, my_id: usize) -> bool {
+        let mut changed = false;
+        for (id, member) in other.members.iter() {
+            match self.members.get_mut(id) {
+                Some(local) if local.version >= member.version => {}
+                Some(local) if *id == my_id => {
+                    local.version = member.version.max(other.version) + 1;
+                    changed = true;
+                }
+                _ => {
+                    self.members.insert(*id, member.clone());
+                    changed = true;
+                }
+            }
+        }
+        let max_member_version = self
+            .members
+            .values()
+            .map(|member| member.version)
+            .max()
+            .unwrap_or(0);
+        self.version = self.version.max(other.version).max(max_member_version);
+        changed
+    }
+
+    /// Devuelve si el servidor es un miembro activo de la red
+    pub fn is_active(&self, id: usize) -> bool {
+        self.members.get(&id).is_some_and(|member| member.active)
+    }
+
+    /// Devuelve si el servidor formaba parte de la red y se fue
+    pub fn has_left(&self, id: usize) -> bool {
+        self.members.get(&id).is_some_and(|member| !member.active)
+    }
+
+    /// Devuelve la direccion de un servidor de la vista
+    pub fn address_of(&self, id: usize) -> Option<&String> {
+        self.members.get(&id).map(|member| &member.address)
+    }
+
+    /// Devuelve los miembros activos que siguen al id en el anillo, en orden y dando la vuelta
+    pub fn ring_after(&self, id: usize) -> Vec<usize> {
+        let active = self.active_members();
+        let after = active.iter().filter(|member| **member > id);
+        let before = active.iter().filter(|member| **member < id);
+        after.chain(before).copied().collect()
+    }
+
+    /// Devuelve los miembros activos en el tramo del anillo que va desde `from` (incluido) hasta `to` (excluido)
+    pub fn ring_between(&self, from: usize, to: usize) -> Vec<usize> {
+        let active = self.active_members();
+        if from <= to {
+            return active
+                .into_iter()
+                .filter(|member| from <= *member && *member < to)
+                .collect();
+        }
+        let until_end = active.iter().filter(|member| from <= **member);
+        let from_start = active.iter().filter(|member| **member < to);
+        until_end.chain(from_start).copied().collect()
+    }
+
+    /// Devuelve los ids de los miembros activos ordenados
+    pub fn active_members(&self) -> Vec<usize> {
+        self.members
+            .iter()
+            .filter(|(_, member)| member.active)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn membership_with(ids: &[usize]) -> Membership {
+        let mut membership = Membership::default();
+        for id in ids {
+            membership.join(*id, format!("127.0.0.1:{}", 10000 + id));
+        }
+        membership
+    }
+
+    #[test]
+    fn should_return_the_members_after_an_id_in_ring_order() {
+        let membership = membership_with(&[0, 2, 5, 7]);
+        assert_eq!(vec![5, 7, 0], membership.ring_after(2));
+        assert_eq!(vec![0, 2, 5], membership.ring_after(7));
+        assert_eq!(vec![5, 7, 0, 2], membership.ring_after(3));
+    }
+
+    #[test]
+    fn should_return_the_members_in_between_two_ids() {
+        let membership = membership_with(&[0, 1, 2, 3, 4]);
+        assert_eq!(vec![1, 2], membership.ring_between(1, 3));
+        assert_eq!(vec![3, 4, 0], membership.ring_between(3, 1));
+        assert!(membership.ring_between(2, 2).is_empty());
+    }
+
+    #[test]
+    fn should_not_include_members_that_left_the_ring() {
+        let mut membership = membership_with(&[0, 1, 2]);
+        membership.leave(1);
+        assert!(!membership.is_active(1));
+        assert!(membership.has_left(1));
+        assert!(!membership.has_left(3));
+        assert_eq!(vec![2], membership.ring_after(0));
+        assert_eq!(vec![2, 0], membership.ring_after(1));
+        assert_eq!(4, membership.version());
+    }
+
+    #[test]
+    fn should_keep_the_most_recent_version_of_each_member_when_merging() {
+        let mut mine = membership_with(&[0, 1]);
+        let mut other = mine.clone();
+        other.join(6, String::from("10.0.0.6:10000"));
+        mine.leave(1);
+
+        assert!(mine.merge(&other, 0));
+        assert!(mine.is_active(6));
+        assert!(!mine.is_active(1));
+        assert_eq!(3, mine.version());
+
+        assert!(!mine.merge(&other, 0));
+    }
+
+    #[test]
+    fn should_announce_itself_again_if_a_newer_view_says_it_left() {
+        let mut mine = membership_with(&[0, 1]);
+        let mut other = mine.clone();
+        other.leave(1);
+
+        assert!(mine.merge(&other, 1));
+        assert!(mine.is_active(1));
+
+        assert!(other.merge(&mine, 0));
+        assert!(other.is_active(1));
+    }
+}
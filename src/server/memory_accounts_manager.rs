@@ -1,22 +1,83 @@
 use crate::account::Account;
 use crate::accounts_manager::AccountsManager;
+use crate::constants::{LEDGER_ACTIONS_PER_ACCOUNT, RESERVATION_TTL_IN_MS};
 use crate::errors::ServerError;
-use crate::server_messages::UpdatedAccount;
+use crate::hybrid_clock::HybridTimestamp;
+use crate::ledger::Ledger;
+use crate::server_messages::{AccountAction, UpdatedAccount};
+use lib::local_connection_messages::MessageType;
+use log::info;
 use std::collections::hash_map::Entry::Vacant;
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 /// Implementacion en memoria de manejador de cuentas
 pub struct MemoryAccountsManager {
     accounts: HashMap<usize, Account>,
+    ledger: Ledger,
 }
 
 impl MemoryAccountsManager {
     pub fn new() -> Self {
         MemoryAccountsManager {
             accounts: HashMap::new(),
+            ledger: Ledger::new(LEDGER_ACTIONS_PER_ACCOUNT),
         }
     }
+
+    /// Aplica la operacion sobre una copia de la cuenta y devuelve como quedaria, sin modificar la original.
+    /// Permite guardar el cambio antes de aplicarlo con `commit`
+    pub fn prepare<F>(&self, account_id: usize, operation: F) -> Result<Account, ServerError>
+    where
+        F: FnOnce(&mut MemoryAccountsManager) -> Result<(), ServerError>,
+    {
+        let mut copy = MemoryAccountsManager::new();
+        if let Some(account) = self.accounts.get(&account_id) {
+            copy.accounts.insert(account_id, account.clone());
+        }
+        operation(&mut copy)?;
+        copy.accounts
+            .remove(&account_id)
+            .ok_or(ServerError::AccountNotFound)
+    }
+
+    /// Reemplaza la cuenta por la version devuelta por `prepare`
+    pub fn commit(&mut self, account: Account) {
+        self.accounts.insert(account.id, account);
+    }
+
+    /// Aplica sobre la cuenta la operacion de una accion de la historia, sin registrarla
+    pub fn apply_operation(
+        &mut self,
+        action: &AccountAction,
+        owner: Option<u64>,
+    ) -> Result<(), ServerError> {
+        let operation_time = Some(action.last_updated_on);
+        match (action.message_type, owner) {
+            (MessageType::AddPoints, _) => {
+                self.add_points(action.account_id, action.points, operation_time)
+            }
+            (MessageType::TakePoints, Some(owner)) => {
+                self.take_reserved_points(action.account_id, owner, action.points, operation_time)
+            }
+            (MessageType::TakePoints, None) => {
+                self.substract_points(action.account_id, action.points, operation_time)
+            }
+            // La historia solo registra operaciones que suman o restan puntos
+            _ => Err(ServerError::SerializationError),
+        }
+    }
+
+    /// Indica si la accion todavia no esta en la historia de su cuenta y se registraria
+    pub fn is_new_action(&self, action: &AccountAction) -> bool {
+        self.ledger.is_new(action)
+    }
+
+    /// Devuelve todas las operaciones de la historia de las cuentas
+    pub fn recorded_actions(&self) -> Vec<AccountAction> {
+        self.ledger.actions()
+    }
 }
 
 impl AccountsManager for MemoryAccountsManager {
@@ -25,12 +86,14 @@ impl AccountsManager for MemoryAccountsManager {
         &mut self,
         account_id: usize,
         points: usize,
-        operation_time: Option<u128>,
+        operation_time: Option<HybridTimestamp>,
     ) -> Result<(), ServerError> {
         if let Vacant(e) = self.accounts.entry(account_id) {
-            if let Some(new_account) = Account::new(account_id, points) {
-                e.insert(new_account);
-            }
+            let new_account = match operation_time {
+                Some(timestamp) => Account::new_from_update(account_id, points, timestamp),
+                None => Account::new(account_id, points),
+            };
+            e.insert(new_account);
         } else if let Some(account) = self.accounts.get_mut(&account_id) {
             account.add_points(points, operation_time)?;
         }
@@ -41,7 +104,7 @@ impl AccountsManager for MemoryAccountsManager {
         &mut self,
         account_id: usize,
         points: usize,
-        operation_time: Option<u128>,
+        operation_time: Option<HybridTimestamp>,
     ) -> Result<(), ServerError> {
         if let Some(account) = self.accounts.get_mut(&account_id) {
             account.substract_points(points, operation_time)?;
@@ -51,39 +114,87 @@ impl AccountsManager for MemoryAccountsManager {
         Err(ServerError::AccountNotFound)
     }
     /// Metodo que toma el lock de una cuenta e invoca su metodo de actualizar puntos
-    fn update(&mut self, account_id: usize, points: usize, operation_time: u128) {
+    fn update(
+        &mut self,
+        account_id: usize,
+        points: usize,
+        operation_time: HybridTimestamp,
+    ) -> Result<(), ServerError> {
         if let Some(account) = self.accounts.get_mut(&account_id) {
             account.update(points, operation_time);
-            return;
+            return Ok(());
         }
         self.accounts.insert(
             account_id,
             Account::new_from_update(account_id, points, operation_time),
         );
+        Ok(())
     }
-    /// Metodo que toma el lock de una cuenta y la reserva para que nadie pueda operar sobre ella
-    fn request_points(&mut self, account_id: usize, points: usize) -> Result<(), ServerError> {
+    /// Metodo que toma el lock de una cuenta y reserva los puntos para el dispenser indicado, si no estan reservados por otros pedidos
+    fn request_points(
+        &mut self,
+        account_id: usize,
+        points: usize,
+        owner: u64,
+    ) -> Result<(), ServerError> {
         if let Some(account) = self.accounts.get_mut(&account_id) {
-            if account.points() >= points {
-                return account.reserve();
-            }
-            return Err(ServerError::NotEnoughPointsInAccount);
+            let now = Instant::now();
+            let expires_at = now + Duration::from_millis(RESERVATION_TTL_IN_MS);
+            return account.reserve(owner, points, expires_at, now);
         }
 
         Err(ServerError::AccountNotFound)
     }
-    /// Metodo que toma el lock de una cuenta e invalida la reserva que realizo sobre esta.
-    fn cancel_requested_points(&mut self, account_id: usize) -> Result<(), ServerError> {
+    /// Metodo que toma el lock de una cuenta y resta los puntos que reservo el dispenser indicado
+    fn take_reserved_points(
+        &mut self,
+        account_id: usize,
+        owner: u64,
+        points: usize,
+        operation_time: Option<HybridTimestamp>,
+    ) -> Result<(), ServerError> {
         if let Some(account) = self.accounts.get_mut(&account_id) {
-            account.cancel_reservation();
-            return Ok(());
+            return account.take_reserved_points(owner, points, operation_time, Instant::now());
         }
 
         Err(ServerError::AccountNotFound)
     }
+    /// Metodo que toma el lock de una cuenta e invalida la reserva que realizo el dispenser indicado sobre esta.
+    fn cancel_requested_points(
+        &mut self,
+        account_id: usize,
+        owner: u64,
+    ) -> Result<(), ServerError> {
+        if let Some(account) = self.accounts.get_mut(&account_id) {
+            return account.cancel_reservation(owner, Instant::now());
+        }
+
+        Err(ServerError::AccountNotFound)
+    }
+    /// Metodo que devuelve el estado actual de una cuenta, si existe
+    fn get_account(&self, account_id: usize) -> Option<UpdatedAccount> {
+        self.accounts
+            .get(&account_id)
+            .map(|account| UpdatedAccount {
+                id: account.id,
+                amount: account.points(),
+                last_updated_on: account.last_updated_on(),
+            })
+    }
+    /// Metodo que devuelve el estado actual de todas las cuentas
+    fn get_all_accounts(&self) -> Vec<UpdatedAccount> {
+        self.accounts
+            .values()
+            .map(|account| UpdatedAccount {
+                id: account.id,
+                amount: account.points(),
+                last_updated_on: account.last_updated_on(),
+            })
+            .collect()
+    }
     /// Metodo que devuelve el timestamp de la cuenta que fue actualizada por ultima vez entre todas las existentes
-    fn get_most_recent_update(&self) -> u128 {
-        let mut latest_update: u128 = 0;
+    fn get_most_recent_update(&self) -> HybridTimestamp {
+        let mut latest_update = HybridTimestamp::default();
         for account in self.accounts.values() {
             let account_last_update = account.last_updated_on();
             if latest_update < account_last_update {
@@ -93,7 +204,7 @@ impl AccountsManager for MemoryAccountsManager {
         latest_update
     }
     /// Metodo que devuelve las cuentas que fueron actualizadas luego de cierto timestamp
-    fn get_accounts_updated_after(&self, timestamp: u128) -> Vec<UpdatedAccount> {
+    fn get_accounts_updated_after(&self, timestamp: HybridTimestamp) -> Vec<UpdatedAccount> {
         let mut updated_accounts = vec![];
         for (id, account) in self.accounts.iter() {
             let last_updated_on = account.last_updated_on();
@@ -108,12 +219,53 @@ impl AccountsManager for MemoryAccountsManager {
         updated_accounts
     }
 
-    /// Metodo que elimina las reservas realizadas sobre todas las cuentas
-    fn clear_reservations(&mut self) {
+    /// Metodo que elimina las reservas vencidas de todas las cuentas
+    fn release_expired_reservations(&mut self) {
+        let now = Instant::now();
         for account in self.accounts.values_mut() {
-            account.cancel_reservation();
+            let released = account.release_expired(now);
+            if released > 0 {
+                info!(
+                    "{} reservations of account {} expired, releasing points",
+                    released, account.id
+                );
+            }
         }
     }
+
+    /// Metodo que registra una operacion en la historia de la cuenta, ignorandola si ya estaba registrada
+    fn record_action(&mut self, action: AccountAction) -> bool {
+        self.ledger.record(action)
+    }
+
+    /// Metodo que registra varias operaciones en la historia, devuelve cuantas no estaban registradas
+    fn record_actions(&mut self, actions: &[AccountAction]) -> usize {
+        actions
+            .iter()
+            .filter(|action| self.ledger.record((*action).clone()))
+            .count()
+    }
+
+    /// Metodo que aplica la operacion sobre la cuenta y, si se pudo aplicar, la registra en la historia
+    fn apply_action(
+        &mut self,
+        action: &AccountAction,
+        owner: Option<u64>,
+    ) -> Result<(), ServerError> {
+        self.apply_operation(action, owner)?;
+        self.ledger.record(action.clone());
+        Ok(())
+    }
+
+    /// Metodo que devuelve una pagina de la historia de una cuenta
+    fn get_history(&self, account_id: usize, page: usize, page_size: usize) -> Vec<AccountAction> {
+        self.ledger.page(account_id, page, page_size)
+    }
+
+    /// Metodo que devuelve las operaciones de la historia posteriores a cierto timestamp
+    fn get_actions_after(&self, timestamp: HybridTimestamp) -> Vec<AccountAction> {
+        self.ledger.actions_after(timestamp)
+    }
 }
 
 impl Default for MemoryAccountsManager {
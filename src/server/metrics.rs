@@ -0,0 +1,255 @@
+use std::{collections::BTreeMap, fmt::Debug, fmt::Write, time::Duration, time::Instant};
+
+use lib::common_errors::CoffeeSystemError;
+
+use crate::errors::ServerError;
+
+/// Limites superiores en segundos de los buckets de los histogramas de tiempos del token. Con la demora de cada
+/// envio entre servidores una vuelta del anillo tarda algunos segundos
+const TOKEN_TIME_BUCKETS_IN_SECONDS: [f64; 12] = [
+    0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 25.0, 60.0, 120.0,
+];
+
+/// Histograma acumulado, cada bucket cuenta las observaciones menores o iguales a su limite
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    bounds: Vec<f64>,
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &[f64]) -> Histogram {
+        Histogram {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, text: &mut String, name: &str, help: &str) {
+        let _ = writeln!(text, "# HELP {} {}", name, help);
+        let _ = writeln!(text, "# TYPE {} histogram", name);
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            let _ = writeln!(text, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(text, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count);
+        let _ = writeln!(text, "{}_sum {}", name, self.sum);
+        let _ = writeln!(text, "{}_count {}", name, self.count);
+    }
+}
+
+/// Metricas del servidor, las actualizan sus componentes desde sus hilos y se exponen en formato de texto
+/// de Prometheus
+pub struct Metrics {
+    token_round_trip: Histogram,
+    token_hold: Histogram,
+    /// Ultima vez que pasamos el token, para medir cuanto tarda en volver
+    token_sent_at: Option<Instant>,
+    connected_coffee_makers: u64,
+    reconnection_attempts: u64,
+    token_regenerations: u64,
+    /// Cantidad de errores por tipo y variante
+    errors: BTreeMap<(&'static str, String), u64>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            token_round_trip: Histogram::new(&TOKEN_TIME_BUCKETS_IN_SECONDS),
+            token_hold: Histogram::new(&TOKEN_TIME_BUCKETS_IN_SECONDS),
+            token_sent_at: None,
+            connected_coffee_makers: 0,
+            reconnection_attempts: 0,
+            token_regenerations: 0,
+            errors: BTreeMap::new(),
+        }
+    }
+
+    /// Registra que pasamos el token al siguiente
+    pub fn token_sent(&mut self) {
+        self.token_sent_at = Some(Instant::now());
+    }
+
+    /// Registra que nos llego el token, midiendo cuanto tardo en dar la vuelta desde que lo pasamos
+    pub fn token_received(&mut self) {
+        if let Some(sent_at) = self.token_sent_at.take() {
+            self.token_round_trip
+                .observe(sent_at.elapsed().as_secs_f64());
+        }
+    }
+
+    /// Registra cuanto tiempo retuvo el token el OrdersManager para aplicar los pedidos
+    pub fn token_held(&mut self, duration: Duration) {
+        self.token_hold.observe(duration.as_secs_f64());
+    }
+
+    pub fn coffee_maker_connected(&mut self) {
+        self.connected_coffee_makers += 1;
+    }
+
+    pub fn coffee_maker_disconnected(&mut self) {
+        self.connected_coffee_makers = self.connected_coffee_makers.saturating_sub(1);
+    }
+
+    pub fn reconnection_attempt(&mut self) {
+        self.reconnection_attempts += 1;
+    }
+
+    pub fn token_regenerated(&mut self) {
+        self.token_regenerations += 1;
+    }
+
+    pub fn server_error(&mut self, error: &ServerError) {
+        self.error("ServerError", error);
+    }
+
+    pub fn coffee_system_error(&mut self, error: &CoffeeSystemError) {
+        self.error("CoffeeSystemError", error);
+    }
+
+    fn error(&mut self, error_type: &'static str, error: &impl Debug) {
+        *self
+            .errors
+            .entry((error_type, format!("{:?}", error)))
+            .or_insert(0) += 1;
+    }
+
+    /// Devuelve las metricas en formato de texto de Prometheus. La profundidad de la cola se consulta al momento
+    pub fn render(&self, queue_depth: usize) -> String {
+        let mut text = String::new();
+        self.token_round_trip.render(
+            &mut text,
+            "token_round_trip_seconds",
+            "Time for the token to go around the ring and come back to this server.",
+        );
+        self.token_hold.render(
+            &mut text,
+            "token_hold_seconds",
+            "Time the orders manager holds the token applying the pending orders.",
+        );
+        render_value(
+            &mut text,
+            "orders_queue_depth",
+            "gauge",
+            "Coffee maker orders waiting for the token.",
+            queue_depth as u64,
+        );
+        render_value(
+            &mut text,
+            "connected_coffee_makers",
+            "gauge",
+            "Coffee makers connected to this server.",
+            self.connected_coffee_makers,
+        );
+        render_value(
+            &mut text,
+            "next_connection_reconnection_attempts_total",
+            "counter",
+            "Attempts to connect to a next server of the ring.",
+            self.reconnection_attempts,
+        );
+        render_value(
+            &mut text,
+            "token_regenerations_total",
+            "counter",
+            "Tokens minted by this server after winning an election.",
+            self.token_regenerations,
+        );
+        let _ = writeln!(
+            text,
+            "# HELP errors_total Errors by type and variant.\n# TYPE errors_total counter"
+        );
+        for ((error_type, variant), count) in &self.errors {
+            let _ = writeln!(
+                text,
+                "errors_total{{type=\"{}\",variant=\"{}\"}} {}",
+                error_type, variant, count
+            );
+        }
+        text
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn render_value(text: &mut String, name: &str, metric_type: &str, help: &str, value: u64) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} {}", name, metric_type);
+    let _ = writeln!(text, "{} {}", name, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_count_the_observations_in_every_bucket_above_them() {
+        let mut histogram = Histogram::new(&[1.0, 5.0]);
+        histogram.observe(0.5);
+        histogram.observe(3.0);
+        histogram.observe(7.0);
+
+        let mut text = String::new();
+        histogram.render(&mut text, "test_seconds", "Test.");
+        assert_eq!(
+            "# HELP test_seconds Test.\n# TYPE test_seconds histogram\n\
+             test_seconds_bucket{le=\"1\"} 1\ntest_seconds_bucket{le=\"5\"} 2\n\
+             test_seconds_bucket{le=\"+Inf\"} 3\ntest_seconds_sum 10.5\ntest_seconds_count 3\n",
+            text
+        );
+    }
+
+    #[test]
+    fn should_render_the_counters_and_the_errors_by_variant() {
+        let mut metrics = Metrics::new();
+        metrics.coffee_maker_connected();
+        metrics.coffee_maker_connected();
+        metrics.coffee_maker_disconnected();
+        metrics.reconnection_attempt();
+        metrics.token_regenerated();
+        metrics.server_error(&ServerError::ConnectionLost);
+        metrics.server_error(&ServerError::ConnectionLost);
+        metrics.coffee_system_error(&CoffeeSystemError::ConnectionClosed);
+
+        let text = metrics.render(4);
+        assert!(text.contains("\norders_queue_depth 4\n"));
+        assert!(text.contains("\nconnected_coffee_makers 1\n"));
+        assert!(text.contains("\nnext_connection_reconnection_attempts_total 1\n"));
+        assert!(text.contains("\ntoken_regenerations_total 1\n"));
+        assert!(
+            text.contains("\nerrors_total{type=\"ServerError\",variant=\"ConnectionLost\"} 2\n")
+        );
+        assert!(text.contains(
+            "\nerrors_total{type=\"CoffeeSystemError\",variant=\"ConnectionClosed\"} 1\n"
+        ));
+    }
+
+    #[test]
+    fn should_measure_the_round_trip_only_after_passing_the_token() {
+        let mut metrics = Metrics::new();
+        metrics.token_received();
+        assert_eq!(0, metrics.token_round_trip.count);
+
+        metrics.token_sent();
+        metrics.token_received();
+        metrics.token_received();
+        assert_eq!(1, metrics.token_round_trip.count);
+    }
+}
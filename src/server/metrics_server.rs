@@ -0,0 +1,138 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    thread,
+};
+
+use log::{debug, error, info};
+
+use crate::{errors::ServerError, ring_node::NodeState};
+
+/// Servidor HTTP de las metricas. Responde `GET /metrics` en texto plano con el formato de Prometheus.
+/// No forma parte de la red de servidores, por eso escucha directamente sobre TCP y no sobre la `Network` del servidor
+pub struct MetricsServer {
+    listener: TcpListener,
+    state: NodeState,
+}
+
+impl MetricsServer {
+    /// Devuelve un nuevo MetricsServer escuchando en la direccion indicada, o error si no se puede abrir el listener
+    pub fn new(address: &str, state: NodeState) -> Result<MetricsServer, ServerError> {
+        let listener = TcpListener::bind(address).map_err(|e| {
+            error!("[METRICS] Error binding to address {}, {}", address, e);
+            ServerError::ListenerError
+        })?;
+        info!("[METRICS] Serving metrics on http://{}/metrics", address);
+        Ok(MetricsServer { listener, state })
+    }
+
+    /// Escucha nuevas conexiones y responde cada una en su hilo
+    pub fn listen(&self) -> Result<(), ServerError> {
+        for stream in self.listener.incoming() {
+            let stream = stream.map_err(|_| ServerError::AcceptError)?;
+            let state = self.state.clone();
+            thread::spawn(move || {
+                if let Err(e) = answer(stream, &state) {
+                    debug!("[METRICS] Error answering request, {:?}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Lee el pedido HTTP y responde con las metricas, o con 404 si no es `GET /metrics`
+fn answer(mut stream: TcpStream, state: &NodeState) -> Result<(), ServerError> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Se descartan los encabezados, no se usan
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim() != "" {
+        header.clear();
+    }
+
+    let mut words = request_line.split_whitespace();
+    let response = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => http_response("200 OK", &render(state)?),
+        _ => http_response(
+            "404 Not Found",
+            "Not found, metrics are served on /metrics\n",
+        ),
+    };
+    stream.write_all(response.as_bytes())?;
+    Ok(())
+}
+
+/// Devuelve las metricas del servidor en el formato de Prometheus
+pub fn render(state: &NodeState) -> Result<String, ServerError> {
+    let queue_depth = state.orders.lock()?.pending_orders().len();
+    Ok(state.metrics.lock()?.render(queue_depth))
+}
+
+fn http_response(status: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use lib::local_connection_messages::{CoffeeMakerRequest, MessageType, RequestId};
+
+    use super::*;
+    use crate::{memory_accounts_manager::MemoryAccountsManager, ring_node::RingNode};
+
+    fn get(address: &str, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).expect("Error connecting");
+        write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, address)
+            .expect("Error sending request");
+        let mut response = String::new();
+        stream
+            .read_to_string(&mut response)
+            .expect("Error reading response");
+        response
+    }
+
+    #[test]
+    fn should_serve_the_metrics_of_the_server_over_http() {
+        let node = RingNode::new_for_test(1, Box::new(MemoryAccountsManager::new()));
+        let request = CoffeeMakerRequest {
+            message_type: MessageType::AddPoints,
+            account_id: 1,
+            points: 5,
+            request_id: RequestId::default(),
+        };
+        node.state
+            .orders
+            .lock()
+            .expect("Lock error")
+            .add(request, 0);
+        node.state
+            .metrics
+            .lock()
+            .expect("Lock error")
+            .token_regenerated();
+
+        let server = MetricsServer::new("127.0.0.1:0", node.state.clone()).expect("Error binding");
+        let address = server
+            .listener
+            .local_addr()
+            .expect("Missing address")
+            .to_string();
+        thread::spawn(move || server.listen());
+
+        let response = get(&address, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\norders_queue_depth 1\n"));
+        assert!(response.contains("\ntoken_regenerations_total 1\n"));
+
+        let response = get(&address, "/");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
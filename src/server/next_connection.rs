@@ -1,11 +1,12 @@
 use async_std::task;
 use lib::{
-    connection_protocol::{ConnectionProtocol, TcpConnection},
+    connection_protocol::ConnectionProtocol,
+    handshake::{Feature, Hello, NodeId},
     local_connection_messages::MessageType,
-    serializer::serialize,
 };
 use log::{debug, error, info, warn};
 use std::{
+    collections::HashMap,
     sync::{
         mpsc::{Receiver, RecvTimeoutError},
         Arc, Mutex,
@@ -15,18 +16,20 @@ use std::{
 
 use crate::{
     accounts_manager::AccountsManager,
-    address_resolver::id_to_address,
+    connection_server::Network,
     connection_status::ConnectionStatus,
     constants::{
-        CLEAN_ORDERS_TIME_IN_MS, INITIAL_WAIT_IN_MS_FOR_CONNECTION_ATTEMPT,
+        CLEAN_ORDERS_TIME_IN_MS, INITIAL_WAIT_IN_MS_FOR_CONNECTION_ATTEMPT, MAX_DIFF_HISTORY_BYTES,
         MAX_WAIT_IN_MS_FOR_CONNECTION_ATTEMPT, TO_NEXT_CONN_CHANNEL_TIMEOUT_IN_MS,
     },
     errors::ServerError,
-    memory_accounts_manager::MemoryAccountsManager,
+    membership::Membership,
+    metrics::Metrics,
     offline_substract_orders_cleaner::SubstractOrdersCleaner,
     server_messages::{
-        create_close_connection_message, create_new_connection_message, create_token_message, Diff,
-        ServerMessage, ServerMessageType,
+        create_close_connection_message, create_elected_message, create_election_message,
+        create_maybe_we_lost_the_token_message, create_new_connection_message, recreate_token,
+        AccountAction, Candidate, Diff, Generation, ServerMessage, ServerMessageType, Token,
     },
 };
 
@@ -50,59 +53,81 @@ mod sync {
 /// Maneja y envia la comunicacion hacia la siguiente conexion en el anillo de servidores locales
 pub struct NextConnection {
     id: usize,
-    peer_count: usize,
+    membership: Arc<Mutex<Membership>>,
     next_conn_receiver: Receiver<ServerMessage>,
     connection_status: Arc<Mutex<ConnectionStatus>>,
-    connection: Option<TcpConnection>,
+    connection: Option<Box<dyn ConnectionProtocol + Send + Sync>>,
+    network: Arc<dyn Network>,
     initial_connection: bool,
     next_id: usize,
     last_token: Option<ServerMessage>,
     have_token: Arc<Mutex<bool>>,
-    accounts_manager: Arc<Mutex<MemoryAccountsManager>>,
+    accounts_manager: Arc<Mutex<Box<dyn AccountsManager>>>,
     offline_cleaner: SubstractOrdersCleaner,
+    token_generation: Arc<Mutex<Generation>>,
+    leader: Arc<Mutex<Option<usize>>>,
+    metrics: Arc<Mutex<Metrics>>,
+    participant: bool,
+    leaving: bool,
+    close_after_token: bool,
+    /// Sumas de un token que no se pudo pasar, se agregan al proximo que recibamos
+    pending_sums: Vec<AccountAction>,
+    reconnect_wait_in_ms: u64,
 }
 
 impl NextConnection {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: usize,
-        peer_count: usize,
+        membership: Arc<Mutex<Membership>>,
         next_conn_receiver: Receiver<ServerMessage>,
         connection_status: Arc<Mutex<ConnectionStatus>>,
         have_token: Arc<Mutex<bool>>,
-        accounts_manager: Arc<Mutex<MemoryAccountsManager>>,
+        accounts_manager: Arc<Mutex<Box<dyn AccountsManager>>>,
         offline_cleaner: SubstractOrdersCleaner,
+        token_generation: Arc<Mutex<Generation>>,
+        leader: Arc<Mutex<Option<usize>>>,
+        metrics: Arc<Mutex<Metrics>>,
+        network: Arc<dyn Network>,
     ) -> NextConnection {
-        let mut initial_connection = false;
-        if id == 0 {
-            initial_connection = true;
-        }
         NextConnection {
             id,
-            peer_count,
+            membership,
             next_conn_receiver,
             connection_status,
             connection: None,
-            initial_connection,
+            network,
+            initial_connection: true,
             next_id: id,
             last_token: None,
             have_token,
             accounts_manager,
             offline_cleaner,
+            token_generation,
+            leader,
+            metrics,
+            participant: false,
+            leaving: false,
+            close_after_token: false,
+            pending_sums: vec![],
+            reconnect_wait_in_ms: INITIAL_WAIT_IN_MS_FOR_CONNECTION_ATTEMPT,
         }
     }
 
     fn attempt_connections(
         &mut self,
-        start: usize,
-        stop: usize,
+        ids: Vec<usize>,
         message: ServerMessage,
     ) -> Result<(), ServerError> {
-        for id in start..stop {
-            let result = TcpConnection::new_client_connection(&id_to_address(id));
+        for id in ids {
+            self.metrics.lock()?.reconnection_attempt();
+            let result = self.connect_to(id);
+            if let Err(e) = &result {
+                self.metrics.lock()?.server_error(e);
+            }
             if let Ok(connection) = result {
-                self.next_id = id;
-                self.connection = Some(connection);
-                self.connection_status.lock()?.set_next_online();
+                self.set_next_id(id)?;
+                self.use_connection(connection)?;
                 if self.send_message(message.clone()).is_err() {
                     continue;
                 }
@@ -113,271 +138,652 @@ impl NextConnection {
     }
 
     fn connect_to_next(&mut self, message: ServerMessage) -> Result<(), ServerError> {
-        let peer_count = self.peer_count;
         let my_id = self.id;
+        let ring_after_me = self.membership.lock()?.ring_after(my_id);
         if self
-            .attempt_connections(my_id + 1, peer_count, message.clone())
+            .attempt_connections(ring_after_me, message.clone())
             .is_ok()
         {
             return Ok(());
         }
-        if self.attempt_connections(0, my_id, message.clone()).is_ok() {
-            return Ok(());
-        }
-        if my_id == 0 && self.initial_connection {
+        // Al iniciar, si no hay nadie mas en la red nos conectamos con nosotros mismos para formarla
+        if self.initial_connection {
             self.initial_connection = false;
-            return self.attempt_connections(0, 1, message);
+            return self.attempt_connections(vec![my_id], message);
         }
         self.connection_status.lock()?.set_next_offline();
         Err(ServerError::ConnectionLost)
     }
 
+    /// Espera hasta conectarse con el siguiente miembro activo del anillo
     fn try_to_connect_wait_if_offline(&mut self) -> Result<(), ServerError> {
-        let mut cleaned_orders = false;
-        let mut wait = INITIAL_WAIT_IN_MS_FOR_CONNECTION_ATTEMPT;
+        while let Some(wait) = self.try_to_connect()? {
+            sleep(wait);
+        }
+        Ok(())
+    }
+
+    /// Intenta una vez conectarse con el siguiente miembro activo del anillo. Si no lo logra devuelve cuanto
+    /// esperar antes del proximo intento, la espera se duplica con cada intento fallido
+    pub fn try_to_connect(&mut self) -> Result<Option<Duration>, ServerError> {
         let most_recent_update = self.accounts_manager.lock()?.get_most_recent_update();
         let message = create_new_connection_message(self.id, most_recent_update);
-        loop {
-            if self.connect_to_next(message.clone()).is_ok() {
-                return Ok(());
-            }
-            sleep(Duration::from_millis(wait));
-            wait *= 2;
-            if wait >= MAX_WAIT_IN_MS_FOR_CONNECTION_ATTEMPT {
-                wait = INITIAL_WAIT_IN_MS_FOR_CONNECTION_ATTEMPT;
-            }
-            if wait >= CLEAN_ORDERS_TIME_IN_MS && !cleaned_orders {
-                self.offline_cleaner.clean_substract_orders_if_offline()?;
-                cleaned_orders = false;
-            }
+        if self.connect_to_next(message).is_ok() {
+            self.reconnect_wait_in_ms = INITIAL_WAIT_IN_MS_FOR_CONNECTION_ATTEMPT;
+            return Ok(None);
+        }
+        let wait = self.reconnect_wait_in_ms;
+        self.reconnect_wait_in_ms *= 2;
+        if self.reconnect_wait_in_ms >= MAX_WAIT_IN_MS_FOR_CONNECTION_ATTEMPT {
+            self.reconnect_wait_in_ms = INITIAL_WAIT_IN_MS_FOR_CONNECTION_ATTEMPT;
         }
+        if self.reconnect_wait_in_ms >= CLEAN_ORDERS_TIME_IN_MS {
+            self.offline_cleaner.clean_substract_orders_if_offline()?;
+        }
+        Ok(Some(Duration::from_millis(wait)))
     }
 
     pub fn handle_message_to_next(&mut self) -> Result<(), ServerError> {
         let timeout = Duration::from_millis(TO_NEXT_CONN_CHANNEL_TIMEOUT_IN_MS);
-        let mut pending_sums = vec![];
-        if self.id == 0 {
-            self.try_to_connect_wait_if_offline()?;
-            if self.send_message(create_token_message(self.id)).is_err() {
-                error!("Failed to send initial token");
-                return Err(ServerError::ConnectionLost);
-            }
-            info!("Sent initial token to {}", self.next_id);
-        }
+        self.try_to_connect_wait_if_offline()?;
+        self.start_election()?;
         loop {
             if !self.connection_status.lock()?.is_next_online() {
+                if self.leaving {
+                    info!("[SENDER {}] Left the ring", self.id);
+                    return Ok(());
+                }
                 self.try_to_connect_wait_if_offline()?;
             }
-            let result = self.next_conn_receiver.recv_timeout(timeout);
-            if let Err(e) = result {
-                match e {
-                    RecvTimeoutError::Timeout => {
-                        debug!(
-                            "[SENDER {}] Channel timeout, checking if previous is offline to restart network",
-                            self.id
-                        );
-                        // Cubre el caso en que la red no quedo propiamente formada.
-                        // Nos damos cuenta cuando no estamos escuchando mensajes de nadie (prev offline)
-                        // pero nosotros nos creemos conectados.
-                        // Ej. Red con nodos 0, 1, 2, 3. 2 esta offline y se logra conectar con 0 (justo con el 3 no pudo)
-                        // 1 cierra la conexion con 3. La red quedo con un nodo apuntando al equivocado.
-                        // Al detectar que no recibimos mensajes y no tenemos prev conn intenamos unirnos nuevamente.
-                        let mut connected = self.connection_status.lock()?;
-                        if !connected.is_prev_online() {
-                            debug!(
-                                "[SENDER {}] Previous is offline, restarting search",
-                                self.id
-                            );
-                            connected.set_next_offline();
-                        }
-                        continue;
-                    }
-                    RecvTimeoutError::Disconnected => {
-                        error!(
-                            "[SENDER {}] Channel error on next conn, stopping...",
-                            self.id
-                        );
-                        return Err(ServerError::ChannelError);
+            let message = match self.next_message(timeout) {
+                Ok(message) => message,
+                Err(RecvTimeoutError::Timeout) => {
+                    if self.handle_channel_timeout()? {
+                        return Ok(());
                     }
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    error!(
+                        "[SENDER {}] Channel error on next conn, stopping...",
+                        self.id
+                    );
+                    return Err(ServerError::ChannelError);
                 }
+            };
+            if self.handle_message(message)? {
+                return Ok(());
             }
-            let mut message = result.unwrap();
-            match &mut message.message_type {
-                ServerMessageType::NewConnection(diff) => {
-                    if is_in_between(self.id, message.sender_id, self.next_id) {
-                        self.add_data_to_diff(diff);
-                        let result = self.connect_to_new_conn(message.sender_id);
-                        if result.is_err() {
-                            error!(
-                                "[SENDER {}] New connection {} went offline again, ignoring...",
-                                self.id, message.sender_id
-                            );
-                            continue;
-                        }
-                        let new_conn = result.unwrap();
-                        if self
-                            .send_message(create_close_connection_message(self.id))
-                            .is_err()
-                        {
-                            error!(
-                                "[SENDER {}] Failed to notify {} of close connection",
-                                self.id, self.next_id
-                            );
-                        }
-                        self.next_id = message.sender_id;
-                        self.connection = Some(new_conn);
-                        info!(
-                            "[SENDER {}] Next connection is now {}",
-                            self.id, self.next_id
+        }
+    }
+
+    /// Inicia la eleccion una vez conectados al anillo. Si ya hay un token circulando la eleccion se descarta,
+    /// sino se elige quien lo genera
+    pub fn start_election(&mut self) -> Result<(), ServerError> {
+        let election = self.election_message()?;
+        self.send_or_reconnect(election);
+        Ok(())
+    }
+
+    /// Espera el proximo mensaje para el siguiente hasta el tiempo indicado
+    pub fn next_message(&self, timeout: Duration) -> Result<ServerMessage, RecvTimeoutError> {
+        self.next_conn_receiver.recv_timeout(timeout)
+    }
+
+    /// Maneja el vencimiento de la espera de mensajes. Devuelve true si terminamos de irnos de la red
+    pub fn handle_channel_timeout(&mut self) -> Result<bool, ServerError> {
+        debug!(
+            "[SENDER {}] Channel timeout, checking if previous is offline to restart network",
+            self.id
+        );
+        // Cubre el caso en que la red no quedo propiamente formada.
+        // Nos damos cuenta cuando no estamos escuchando mensajes de nadie (prev offline)
+        // pero nosotros nos creemos conectados.
+        // Ej. Red con nodos 0, 1, 2, 3. 2 esta offline y se logra conectar con 0 (justo con el 3 no pudo)
+        // 1 cierra la conexion con 3. La red quedo con un nodo apuntando al equivocado.
+        // Al detectar que no recibimos mensajes y no tenemos prev conn intenamos unirnos nuevamente.
+        let mut connected = self.connection_status.lock()?;
+        if self.leaving && !connected.is_prev_online() {
+            info!("[SENDER {}] Left the ring", self.id);
+            connected.set_next_offline();
+            return Ok(true);
+        }
+        if !connected.is_prev_online() {
+            debug!(
+                "[SENDER {}] Previous is offline, restarting search",
+                self.id
+            );
+            connected.set_next_offline();
+            return Ok(false);
+        }
+        drop(connected);
+        // Con el anillo formado el token pasa seguido por aca, si no llega nada puede haberse perdido sin que nadie
+        // lo note. Pasa cuando se escribe en una conexion TCP que el otro lado ya cerro: el primer envio no falla.
+        // Tambien cubre una eleccion que se perdio. Como otro servidor puede tener el token mientras espera el
+        // resultado de los cafes, no se inicia la eleccion: se hace circular el aviso de token perdido y solo si
+        // vuelve sin que nadie tenga el token el anterior a nosotros la inicia
+        if !*self.have_token.lock()? {
+            warn!(
+                "[SENDER {}] No messages in {} ms, checking if the token was lost",
+                self.id, TO_NEXT_CONN_CHANNEL_TIMEOUT_IN_MS
+            );
+            return self.handle_message(create_maybe_we_lost_the_token_message(self.id, self.id));
+        }
+        Ok(false)
+    }
+
+    /// Maneja un mensaje para el siguiente. Devuelve true si terminamos de irnos de la red
+    pub fn handle_message(&mut self, mut message: ServerMessage) -> Result<bool, ServerError> {
+        match &mut message.message_type {
+            ServerMessageType::NewConnection(diff) => {
+                if is_in_between(self.id, message.sender_id, self.next_id) {
+                    self.add_data_to_diff(diff);
+                    let result = self.connect_to_new_conn(message.sender_id);
+                    if result.is_err() {
+                        error!(
+                            "[SENDER {}] New connection {} went offline again, ignoring...",
+                            self.id, message.sender_id
                         );
+                        return Ok(false);
                     }
-                    message.passed_by.insert(self.id);
-                    if self.send_message(message).is_err() {
+                    let new_conn = result.unwrap();
+                    if self
+                        .send_message(create_close_connection_message(self.id))
+                        .is_err()
+                    {
                         error!(
-                            "[SENDER {}] Failed to send to {} new connection message",
+                            "[SENDER {}] Failed to notify {} of close connection",
                             self.id, self.next_id
                         );
                     }
+                    self.set_next_id(message.sender_id)?;
+                    self.use_connection(new_conn)?;
+                    info!(
+                        "[SENDER {}] Next connection is now {}",
+                        self.id, self.next_id
+                    );
                 }
-                ServerMessageType::Token(token_data) => {
-                    let mut token_data_copy = token_data.clone();
-
-                    if !pending_sums.is_empty() {
-                        token_data
-                            .entry(self.id)
-                            .or_insert(vec![])
-                            .append(&mut pending_sums);
-                    }
+                message.passed_by.insert(self.id);
+                if self.send_message(message).is_err() {
+                    error!(
+                        "[SENDER {}] Failed to send to {} new connection message",
+                        self.id, self.next_id
+                    );
+                }
+            }
+            ServerMessageType::Token(token) => {
+                // Si hay un token circulando no hay una eleccion en curso
+                self.participant = false;
+                let mut lost_actions = self.lost_actions(token.generation);
+                if !lost_actions.is_empty() {
+                    warn!(
+                        "[SENDER {}] The last token we sent was lost, adding {} of our operations again",
+                        self.id,
+                        lost_actions.len()
+                    );
+                    let actions = token.data.entry(self.id).or_default();
+                    lost_actions.retain(|action| !actions.contains(action));
+                    actions.splice(0..0, lost_actions);
+                }
+                let mut token_data_copy = token.data.clone();
 
-                    let token_backup = Some(message.clone());
-                    // enviar el token al siguiente
-                    if self.send_message(message.clone()).is_err() {
-                        // si tenemos cambios de una perdida anterior donde justo teniamos el token agregarlos y limpiarlo
-                        // si falla reintentar conectarnos con el/los siguiente/s
-                        if self.connect_to_next(message).is_err() {
-                            // si fallan todas las reconexiones, perdimos la conexion y el token no es valido
-                            // guardar los cambios hechos en otro lugar (solo las sumas) para appendearlos al proximo token cuando recuperemos la conexion
-                            // hacemos continue, reintentamos hasta poder
-                            if let Some(requests) = token_data_copy.remove(&self.id) {
-                                let mut sums = requests
-                                    .into_iter()
-                                    .filter(|req| req.message_type == MessageType::AddPoints)
-                                    .collect::<Vec<_>>();
-                                pending_sums.append(&mut sums);
-                            }
-                            // marcamos en un mutex que ya no tenemos el token, estamos sin conexion
-                            *self.have_token.lock()? = false;
-                            continue;
+                if !self.pending_sums.is_empty() {
+                    token
+                        .data
+                        .entry(self.id)
+                        .or_insert(vec![])
+                        .append(&mut self.pending_sums);
+                }
+
+                let token_backup = Some(message.clone());
+                // enviar el token al siguiente
+                if self.send_message(message.clone()).is_err() {
+                    // si tenemos cambios de una perdida anterior donde justo teniamos el token agregarlos y limpiarlo
+                    // si falla reintentar conectarnos con el/los siguiente/s
+                    if self.connect_to_next(message).is_err() {
+                        // si fallan todas las reconexiones, perdimos la conexion y el token no es valido
+                        // guardar los cambios hechos en otro lugar (solo las sumas) para appendearlos al proximo token cuando recuperemos la conexion
+                        // hacemos continue, reintentamos hasta poder
+                        if let Some(requests) = token_data_copy.remove(&self.id) {
+                            let mut sums = requests
+                                .into_iter()
+                                .filter(|req| req.message_type == MessageType::AddPoints)
+                                .collect::<Vec<_>>();
+                            self.pending_sums.append(&mut sums);
                         }
+                        // marcamos en un mutex que ya no tenemos el token, estamos sin conexion
+                        *self.have_token.lock()? = false;
+                        return Ok(false);
                     }
-                    // marcamos en un mutex que ya no tenemos el token
-                    *self.have_token.lock()? = false;
-                    // si no fallan todas las reconexiones (ej logramos conectarnos al siguiente del siguiente)
-                    // le mandamos el token, no se perdio
-                    self.last_token = token_backup;
-                    pending_sums.clear();
                 }
-                ServerMessageType::MaybeWeLostTheTokenTo(lost_id) => {
-                    let lost_id = *lost_id;
-                    // si el que perdio la conexion es al que apuntamos
-                    // SOLO si es al que apuntamos, que nos llegue este mensaje es que se perdio el token
-                    // (llego al final de la carrera - no estaba el token circulando porque se perdio)
-                    // nos conectamos con el siguiente y mandarle mensaje token guardado
-                    if *self.have_token.lock()? {
-                        info!("[SENDER {}] I have the token, we did't lost it", self.id);
-                        continue;
-                    }
+                // marcamos en un mutex que ya no tenemos el token
+                *self.have_token.lock()? = false;
+                // si no fallan todas las reconexiones (ej logramos conectarnos al siguiente del siguiente)
+                // le mandamos el token, no se perdio
+                self.last_token = token_backup;
+                self.pending_sums.clear();
+                self.metrics.lock()?.token_sent();
+                if self.close_after_token {
+                    self.close_and_leave()?;
+                    return Ok(true);
+                }
+            }
+            ServerMessageType::MaybeWeLostTheTokenTo(lost_id) => {
+                let lost_id = *lost_id;
+                // si el que perdio la conexion es al que apuntamos
+                // SOLO si es al que apuntamos, que nos llegue este mensaje es que se perdio el token
+                // (llego al final de la carrera - no estaba el token circulando porque se perdio)
+                // nos conectamos con el siguiente y mandarle mensaje token guardado
+                if *self.have_token.lock()? {
+                    info!("[SENDER {}] I have the token, we did't lost it", self.id);
+                    return Ok(false);
+                }
 
-                    if self.next_id == lost_id {
-                        warn!(
-                            "[SENDER {}] We lost the token, sending copy to next possible connection",
+                if self.next_id == lost_id {
+                    warn!(
+                        "[SENDER {}] We lost the token, sending copy to next possible connection",
+                        self.id
+                    );
+                    // La conexion con el siguiente sigue en pie, reemplazarla cerraria la que el escucha y
+                    // dispararia otro aviso de token perdido
+                    let election = self.election_message()?;
+                    if self.send_message(election.clone()).is_err()
+                        && self.connect_to_next(election).is_err()
+                    {
+                        error!(
+                            "[SENDER {}] Error starting the election with the next, we lost connection",
                             self.id
                         );
-                        if let Some(token) = self.last_token.as_ref() {
-                            if self.connect_to_next(token.clone()).is_err() {
-                                error!(
-                                    "[SENDER {}] Error passing the token to the next, we lost connection",
-                                    self.id
-                                );
-                                continue;
+                        return Ok(false);
+                    }
+                    info!(
+                        "[SENDER {}] Started an election to mint a new token through {}",
+                        self.id, self.next_id
+                    );
+                    return Ok(false);
+                }
+                message.passed_by.insert(self.id);
+                // Al fallar el envio se pierde el id del siguiente, se guarda para buscar a partir de el
+                let next_id = self.next_id;
+                if self.send_message(message.clone()).is_err() {
+                    error!(
+                        "[SENDER {}] Next is offline, trying to contact nodes after me and initial lost server",
+                        self.id
+                    );
+                    let in_order = self.membership.lock()?.ring_between(next_id, lost_id);
+
+                    for id in in_order {
+                        let result = self.connect_to(id);
+                        if let Ok(connection) = result {
+                            self.set_next_id(id)?;
+                            self.use_connection(connection)?;
+                            if self.send_message(message.clone()).is_ok() {
+                                break;
                             }
-                            info!(
-                                "[SENDER {}] We managed to send the copy of the token to {}",
-                                self.id, self.next_id
-                            );
-                            continue;
                         }
                     }
-                    message.passed_by.insert(self.id);
-                    if self.send_message(message.clone()).is_err() {
+
+                    if self.connection.is_some() {
+                        info!(
+                            "[SENDER {}] Sent Maybe We Lost The token to {} in between me and lost node",
+                            self.id,
+                            self.next_id
+                        );
+                        return Ok(false);
+                    }
+
+                    // Yo perdi la conexion
+                    if !self.connection_status.lock()?.is_prev_online() {
+                        error!("[SENDER {}] I lost connection", self.id);
+                        return Ok(false);
+                    }
+
+                    warn!(
+                        "[SENDER {}] The token was lost between {} and {}, starting an election to mint a new one",
+                        self.id,
+                        self.id,
+                        lost_id
+                    );
+                    let election = self.election_message()?;
+                    if self.connect_to_next(election).is_err() {
                         error!(
-                            "[SENDER {}] Next is offline, trying to contact nodes after me and initial lost server",
+                            "[SENDER {}] Error starting the election with the next, we lost connection",
                             self.id
                         );
-                        let mut in_order = (self.next_id..lost_id).collect::<Vec<_>>();
-                        if lost_id < self.next_id {
-                            let ring_return = (0..lost_id).collect::<Vec<_>>();
-                            in_order = (self.next_id..self.peer_count).collect::<Vec<_>>();
-                            in_order.extend(ring_return);
-                        }
+                        return Ok(false);
+                    }
+                    info!(
+                        "[SENDER {}] Started an election through next connection {}",
+                        self.id, self.next_id
+                    );
+                }
+            }
+            ServerMessageType::Leave => {
+                self.handle_leave(message)?;
+            }
+            ServerMessageType::Election(candidate) => {
+                let candidate = *candidate;
+                self.handle_election(message, candidate)?;
+            }
+            ServerMessageType::Elected(leader_id) => {
+                let leader_id = *leader_id;
+                self.handle_elected(message, leader_id)?;
+            }
+            ServerMessageType::CloseConnection => {
+                // Solo llega por el channel si nos estamos yendo y el anterior ya no nos envia mensajes
+                if *self.have_token.lock()? {
+                    info!(
+                        "[SENDER {}] Waiting to pass the token before leaving",
+                        self.id
+                    );
+                    self.close_after_token = true;
+                    return Ok(false);
+                }
+                self.close_and_leave()?;
+                return Ok(true);
+            }
+            _ => {}
+        }
+        Ok(false)
+    }
 
-                        for id in in_order {
-                            let result = TcpConnection::new_client_connection(&id_to_address(id));
-                            if let Ok(connection) = result {
-                                self.next_id = id;
-                                self.connection = Some(connection);
-                                self.connection_status.lock()?.set_next_online();
-                                if self.send_message(message.clone()).is_ok() {
-                                    break;
-                                }
-                            }
-                        }
+    /// Arma el mensaje para iniciar una eleccion con nosotros como candidato. Se usa al iniciar
+    /// y cuando se confirma que se perdio el token
+    fn election_message(&mut self) -> Result<ServerMessage, ServerError> {
+        let known_generation = *self.token_generation.lock()?;
+        info!(
+            "[SENDER {}] Starting election, last known token generation {}",
+            self.id, known_generation
+        );
+        self.participant = true;
+        *self.leader.lock()? = None;
+        Ok(create_election_message(self.id, self.id, known_generation))
+    }
 
-                        if self.connection.is_some() {
-                            info!(
-                                "[SENDER {}] Sent Maybe We Lost The token to {} in between me and lost node",
-                                self.id,
-                                self.next_id
-                            );
-                            continue;
-                        }
+    /// Maneja un mensaje de eleccion segun Chang-Roberts. Se reenvia si el candidato es mayor, se reemplaza
+    /// por nosotros si es menor y todavia no participamos, y si vuelve nuestra candidatura ganamos la eleccion
+    fn handle_election(
+        &mut self,
+        mut message: ServerMessage,
+        candidate: Candidate,
+    ) -> Result<(), ServerError> {
+        {
+            let mut generation = self.token_generation.lock()?;
+            if *generation > candidate.known_generation {
+                debug!(
+                    "[SENDER {}] A newer token is circulating, dropping election of {}",
+                    self.id, candidate.id
+                );
+                return Ok(());
+            }
+            // Si no nos llego el ultimo token adoptamos su generacion, asi nuestra candidatura no se descarta
+            // en los servidores que si lo vieron
+            *generation = candidate.known_generation;
+        }
+        if candidate.id == self.id {
+            if !self.participant {
+                // Ya anunciamos que ganamos, o mientras tanto llego el token o el resultado de otra eleccion
+                debug!(
+                    "[SENDER {}] Our election already finished, dropping duplicate",
+                    self.id
+                );
+                return Ok(());
+            }
+            info!(
+                "[SENDER {}] Won the election, announcing it to the ring",
+                self.id
+            );
+            self.participant = false;
+            self.send_or_reconnect(create_elected_message(self.id, self.id));
+            return Ok(());
+        }
+        if message.passed_by.contains(&self.id) {
+            // El candidato ya no esta en la red, la eleccion dio la vuelta sin terminar
+            warn!(
+                "[SENDER {}] Election of {} went around the ring without its candidate",
+                self.id, candidate.id
+            );
+            let election = self.election_message()?;
+            self.send_or_reconnect(election);
+            return Ok(());
+        }
+        if candidate.id > self.id {
+            self.participant = true;
+            message.passed_by.insert(self.id);
+            self.send_or_reconnect(message);
+        } else if !self.participant {
+            let election = self.election_message()?;
+            self.send_or_reconnect(election);
+        } else {
+            debug!(
+                "[SENDER {}] Already participating, dropping election of {}",
+                self.id, candidate.id
+            );
+        }
+        Ok(())
+    }
 
-                        // Yo perdi la conexion
-                        if !self.connection_status.lock()?.is_prev_online() {
-                            error!("[SENDER {}] I lost connection", self.id);
-                            continue;
-                        }
+    /// Maneja el resultado de la eleccion. Si el elegido somos nosotros y el aviso dio la vuelta generamos el token
+    fn handle_elected(
+        &mut self,
+        mut message: ServerMessage,
+        leader_id: usize,
+    ) -> Result<(), ServerError> {
+        self.participant = false;
+        {
+            let mut leader = self.leader.lock()?;
+            // Si el anillo cambio durante la eleccion pueden terminar dos. Gana el mayor, asi no se generan
+            // dos tokens de la misma generacion. Si ya somos el elegido el aviso esta repetido y ya generamos el token
+            if leader_id == self.id && leader.is_some_and(|leader| leader >= self.id) {
+                info!(
+                    "[SENDER {}] Server {:?} was already elected, not minting the token",
+                    self.id, *leader
+                );
+                return Ok(());
+            }
+            *leader = Some(leader_id);
+        }
+        if leader_id == self.id {
+            return self.mint_token();
+        }
+        if message.passed_by.contains(&self.id) {
+            debug!(
+                "[SENDER {}] Elected message of {} already passed, dropping...",
+                self.id, leader_id
+            );
+            return Ok(());
+        }
+        info!(
+            "[SENDER {}] Server {} was elected to mint the token",
+            self.id, leader_id
+        );
+        message.passed_by.insert(self.id);
+        self.send_or_reconnect(message);
+        Ok(())
+    }
 
-                        if let Some(token) = self.last_token.as_ref() {
-                            warn!(
-                                "[SENDER {}] The token was lost between {} and {}, sending copy to next possible connection",
-                                self.id,
-                                self.id,
-                                lost_id
-                            );
-                            if self.connect_to_next(token.clone()).is_err() {
-                                error!(
-                                    "[SENDER {}] Error passing the token to the next, we lost connection",
-                                    self.id
-                                );
-                                continue;
-                            }
-                            info!(
-                                "[SENDER {}] Managed to send a copy of the token to next connection {}",
-                                self.id,
-                                self.next_id
-                            );
-                        }
-                    }
-                }
-                _ => {}
+    /// Genera un token con una nueva generacion. Si se tiene una copia del ultimo token enviado se reutilizan
+    /// sus datos, las operaciones repetidas se descartan por su fecha de actualizacion.
+    /// Cualquier token de una generacion anterior que siga circulando (ej. el original estaba demorado)
+    /// se descarta al llegar a un servidor que vio la nueva
+    fn mint_token(&mut self) -> Result<(), ServerError> {
+        let data = match &self.last_token {
+            Some(ServerMessage {
+                message_type: ServerMessageType::Token(token),
+                ..
+            }) => token.data.clone(),
+            _ => HashMap::new(),
+        };
+        let generation = {
+            let mut generation = self.token_generation.lock()?;
+            *generation = generation.next(self.id);
+            *generation
+        };
+        let token = recreate_token(self.id, Token { generation, data });
+        if self.send_message(token.clone()).is_err() && self.connect_to_next(token.clone()).is_err()
+        {
+            error!(
+                "[SENDER {}] Failed to send the minted token, we lost connection",
+                self.id
+            );
+            return Ok(());
+        }
+        info!(
+            "[SENDER {}] Elected as leader, minted the token with generation {} and sent it to {}",
+            self.id, generation, self.next_id
+        );
+        self.last_token = Some(token);
+        let mut metrics = self.metrics.lock()?;
+        metrics.token_regenerated();
+        metrics.token_sent();
+        Ok(())
+    }
+
+    /// Pasa a enviar los mensajes por la conexion. Se registra lo acordado con el siguiente en el estado de las
+    /// conexiones, asi antes de irse de la red se puede saber si el siguiente puede leer el aviso
+    fn use_connection(
+        &mut self,
+        connection: Box<dyn ConnectionProtocol + Send + Sync>,
+    ) -> Result<(), ServerError> {
+        let features = connection.peer().features;
+        self.connection = Some(connection);
+        let mut connection_status = self.connection_status.lock()?;
+        connection_status.set_next_online();
+        connection_status.set_next_features(features);
+        Ok(())
+    }
+
+    /// Registra a quien se envian los mensajes, tambien en el estado de las conexiones para que se pueda consultar
+    fn set_next_id(&mut self, id: usize) -> Result<(), ServerError> {
+        self.next_id = id;
+        self.connection_status.lock()?.set_next_id(id);
+        Ok(())
+    }
+
+    /// Devuelve nuestras operaciones del ultimo token que enviamos si llega uno de una generacion posterior. En ese caso
+    /// el token se perdio antes de dar la vuelta, y con el las operaciones que los demas no llegaron a recibir
+    /// (ej. se envio por una conexion que el otro lado ya habia cerrado). Las cuentas descartan las que ya aplicaron
+    /// por su fecha de actualizacion
+    fn lost_actions(&self, generation: Generation) -> Vec<AccountAction> {
+        match &self.last_token {
+            Some(ServerMessage {
+                message_type: ServerMessageType::Token(last_token),
+                ..
+            }) if last_token.generation < generation => {
+                last_token.data.get(&self.id).cloned().unwrap_or_default()
             }
+            _ => vec![],
         }
     }
 
-    fn send_message(&mut self, message: ServerMessage) -> Result<(), ServerError> {
-        let message_bytes = serialize(&message)?;
+    /// Envia el mensaje al siguiente, si falla intenta con los siguientes miembros del anillo
+    fn send_or_reconnect(&mut self, message: ServerMessage) {
+        if self.send_message(message.clone()).is_err() && self.connect_to_next(message).is_err() {
+            error!(
+                "[SENDER {}] Failed to send message to the next, we lost connection",
+                self.id
+            );
+        }
+    }
+
+    /// Maneja el aviso de que un servidor se va de la red.
+    /// Si es propio se marca en la vista y se avisa al siguiente. Si es al que apuntamos, le cerramos la conexion
+    /// y nos conectamos con el siguiente miembro activo. En otro caso se reenvia el aviso
+    fn handle_leave(&mut self, mut message: ServerMessage) -> Result<(), ServerError> {
+        let leaving_id = message.sender_id;
+        if leaving_id == self.id {
+            // Si el siguiente no puede leer el aviso nadie se enteraria de que nos fuimos, nos quedamos en la red
+            if !self
+                .connection_status
+                .lock()?
+                .next_supports(&Feature::DynamicMembership)
+            {
+                error!(
+                    "[SENDER {}] Next {} does not support leaving the ring, staying",
+                    self.id, self.next_id
+                );
+                return Ok(());
+            }
+            info!("[SENDER {}] Announcing that I'm leaving the ring", self.id);
+            self.membership.lock()?.leave(self.id);
+            self.leaving = true;
+            if let Err(e) = self.send_message(message) {
+                error!("[SENDER {}] Failed to announce leave, {:?}", self.id, e);
+            }
+            return Ok(());
+        }
+
+        message.passed_by.insert(self.id);
+        if self.next_id != leaving_id {
+            if let Err(e) = self.send_message(message) {
+                error!(
+                    "[SENDER {}] Failed to pass leave of {} to {}, {:?}",
+                    self.id, leaving_id, self.next_id, e
+                );
+            }
+            return Ok(());
+        }
+
+        info!(
+            "[SENDER {}] Next {} is leaving, connecting to the following member",
+            self.id, leaving_id
+        );
+        if self
+            .send_message(create_close_connection_message(self.id))
+            .is_err()
+        {
+            error!(
+                "[SENDER {}] Failed to notify {} of close connection",
+                self.id, leaving_id
+            );
+        }
+        self.connection = None;
+        let most_recent_update = self.accounts_manager.lock()?.get_most_recent_update();
+        let new_connection_message = create_new_connection_message(self.id, most_recent_update);
+        if self.connect_to_next(new_connection_message).is_err() {
+            error!(
+                "[SENDER {}] Failed to connect to a member after {}",
+                self.id, leaving_id
+            );
+            return Ok(());
+        }
+        info!(
+            "[SENDER {}] Next connection is now {}",
+            self.id, self.next_id
+        );
+        Ok(())
+    }
+
+    /// Cierra la conexion con el siguiente luego de habernos ido de la red
+    fn close_and_leave(&mut self) -> Result<(), ServerError> {
+        if self
+            .send_message(create_close_connection_message(self.id))
+            .is_err()
+        {
+            error!("[SENDER {}] Failed to close connection with next", self.id);
+        }
+        self.connection = None;
+        self.connection_status.lock()?.set_next_offline();
+        info!("[SENDER {}] Left the ring", self.id);
+        Ok(())
+    }
+
+    fn send_message(&mut self, mut message: ServerMessage) -> Result<(), ServerError> {
+        message.membership = self.membership.lock()?.clone();
         if let Some(connection) = self.connection.as_mut() {
+            // Con un siguiente de una version anterior no se envian los mensajes que no puede leer, y se avisa
+            // a quien lo envia porque el mensaje no llega
+            if let Some(feature) = message.message_type.required_feature() {
+                if !connection.peer().supports(&feature) {
+                    warn!(
+                        "[SENDER {}] Next {} does not support {:?}, unable to send {:?}",
+                        self.id, self.next_id, feature, message.message_type
+                    );
+                    return Err(ServerError::IncompatibleProtocol);
+                }
+            }
+            let message_bytes =
+                message.encode(connection.codec(), connection.peer().protocol_version)?;
             sleep(Duration::from_millis(1000));
             debug!("[SENDER {}] Sending message {:?}", self.id, message);
             if task::block_on(connection.send(&message_bytes[..])).is_err() {
@@ -387,7 +793,10 @@ impl NextConnection {
                 );
                 self.connection = None;
                 self.connection_status.lock()?.set_next_offline();
-                self.next_id = self.id;
+                self.set_next_id(self.id)?;
+                self.metrics
+                    .lock()?
+                    .server_error(&ServerError::ConnectionLost);
                 return Err(ServerError::ConnectionLost);
             }
             return Ok(());
@@ -403,6 +812,10 @@ impl NextConnection {
         if let Ok(accounts) = self.accounts_manager.lock() {
             let update = accounts.get_accounts_updated_after(diff.last_update);
             diff.changes = update;
+            diff.history = most_recent_within(
+                accounts.get_actions_after(diff.last_update),
+                MAX_DIFF_HISTORY_BYTES,
+            );
             return;
         }
         error!(
@@ -411,16 +824,64 @@ impl NextConnection {
         );
     }
 
-    fn connect_to_new_conn(&mut self, sender_id: usize) -> Result<TcpConnection, ServerError> {
-        let result = TcpConnection::new_client_connection(&id_to_address(sender_id));
-        if let Ok(connection) = result {
-            return Ok(connection);
+    fn connect_to_new_conn(
+        &mut self,
+        sender_id: usize,
+    ) -> Result<Box<dyn ConnectionProtocol + Send + Sync>, ServerError> {
+        self.connect_to(sender_id)
+    }
+
+    /// Abre una conexion con el servidor de la red indicado, usando la direccion de la vista de miembros
+    fn connect_to(
+        &self,
+        id: usize,
+    ) -> Result<Box<dyn ConnectionProtocol + Send + Sync>, ServerError> {
+        let address = match self.membership.lock()?.address_of(id) {
+            Some(address) => address.clone(),
+            None => return Err(ServerError::UnknownServerId),
+        };
+        let hello = Hello::new(NodeId::Server(self.id));
+        let connection = self.network.connect(&address, &hello)?;
+        let peer_id = connection.peer().node_id;
+        if peer_id != NodeId::Server(id) {
+            error!(
+                "[SENDER {}] Expected server {} at {}, but {:?} answered",
+                self.id, id, address, peer_id
+            );
+            return Err(ServerError::UnknownServerId);
         }
-        Err(ServerError::ConnectionLost)
+        Ok(connection)
+    }
+}
+
+/// Se queda con las operaciones mas recientes de la historia que entran en la cantidad de bytes indicada, asi el
+/// mensaje de nueva conexion no supera el largo maximo de un frame. Las operaciones deben estar ordenadas por su
+/// marca de tiempo. La historia mas antigua no se envia, los saldos de las cuentas viajan igual en el diff
+fn most_recent_within(mut actions: Vec<AccountAction>, max_bytes: usize) -> Vec<AccountAction> {
+    let mut bytes = 0;
+    let mut kept = 0;
+    for action in actions.iter().rev() {
+        bytes += serde_json::to_vec(action).map_or(0, |encoded| encoded.len());
+        if bytes > max_bytes {
+            warn!(
+                "[SENDER] Not sending the {} oldest actions of the history, they don't fit in the message",
+                actions.len() - kept
+            );
+            break;
+        }
+        kept += 1;
     }
+    actions.split_off(actions.len() - kept)
 }
 
+/// Indica si el sender queda entre nosotros y nuestro siguiente. Como el anillo de la vista de miembros
+/// se ordena por id alcanza con comparar los ids, sin importar si hay ids que no forman parte de la red
 fn is_in_between(my_id: usize, sender_id: usize, next_id: usize) -> bool {
+    // estamos solos en la red, cualquier otro servidor queda en el medio
+    if next_id == my_id && sender_id != my_id {
+        return true;
+    }
+
     // caso "normal"
     if sender_id < next_id && my_id < sender_id {
         return true;
@@ -441,7 +902,235 @@ fn is_in_between(my_id: usize, sender_id: usize, next_id: usize) -> bool {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::mpsc;
+
     use super::*;
+    use lib::{
+        codec::Codec, connection_protocol::MemoryNetwork,
+        connection_protocol::MockConnectionProtocol,
+    };
+
+    use crate::{
+        memory_accounts_manager::MemoryAccountsManager,
+        orders_queue::OrdersQueue,
+        server_messages::{create_leave_message, create_token_message},
+    };
+
+    type Sent = Arc<Mutex<Vec<ServerMessage>>>;
+
+    /// Arma el sender de un servidor ya conectado al anillo, los mensajes que envia al siguiente quedan en la lista
+    fn connected_next(id: usize, next_id: usize, have_token: bool) -> (NextConnection, Sent) {
+        connected_next_with(id, Hello::new(NodeId::Server(next_id)), have_token)
+    }
+
+    /// Igual que `connected_next`, con el saludo que envia el siguiente en el handshake
+    fn connected_next_with(
+        id: usize,
+        next_hello: Hello,
+        have_token: bool,
+    ) -> (NextConnection, Sent) {
+        let NodeId::Server(next_id) = next_hello.node_id else {
+            panic!("The next must be a server");
+        };
+        let sent: Sent = Arc::new(Mutex::new(vec![]));
+        let sent_clone = sent.clone();
+        let mut connection = MockConnectionProtocol::new();
+        connection.expect_codec().return_const(Codec::JsonLine);
+        let peer = Hello::new(NodeId::Server(id))
+            .agree(&next_hello)
+            .expect("Error agreeing handshake");
+        connection.expect_peer().return_const(peer);
+        connection.expect_send().returning(move |data| {
+            let message = Codec::JsonLine.decode(data).expect("Error deserializing");
+            sent_clone.lock().expect("Lock error").push(message);
+            Ok(())
+        });
+
+        let connection_status = Arc::new(Mutex::new(ConnectionStatus::new()));
+        connection_status
+            .lock()
+            .expect("Lock error")
+            .set_prev_online();
+        let (_, next_conn_receiver) = mpsc::channel();
+        let (request_points_sender, _) = mpsc::channel();
+        let accounts_manager: Arc<Mutex<Box<dyn AccountsManager>>> =
+            Arc::new(Mutex::new(Box::new(MemoryAccountsManager::new())));
+        let mut next = NextConnection::new(
+            id,
+            Arc::new(Mutex::new(Membership::default())),
+            next_conn_receiver,
+            connection_status,
+            Arc::new(Mutex::new(have_token)),
+            accounts_manager,
+            SubstractOrdersCleaner::new(
+                Arc::new(Mutex::new(OrdersQueue::new())),
+                request_points_sender,
+            ),
+            Arc::new(Mutex::new(Generation::default())),
+            Arc::new(Mutex::new(None)),
+            Arc::new(Mutex::new(Metrics::new())),
+            Arc::new(MemoryNetwork::new()),
+        );
+        next.set_next_id(next_id).expect("Lock error");
+        next.use_connection(Box::new(connection))
+            .expect("Lock error");
+        (next, sent)
+    }
+
+    fn take_sent(sent: &Sent) -> Vec<ServerMessageType> {
+        sent.lock()
+            .expect("Lock error")
+            .drain(..)
+            .map(|message| message.message_type)
+            .collect()
+    }
+
+    fn election(sender_id: usize, candidate_id: usize, passed_by: &[usize]) -> ServerMessage {
+        let mut message = create_election_message(sender_id, candidate_id, Generation::default());
+        message.passed_by.extend(passed_by);
+        message
+    }
+
+    fn elected(leader_id: usize, passed_by: &[usize]) -> ServerMessage {
+        let mut message = create_elected_message(leader_id, leader_id);
+        message.passed_by.extend(passed_by);
+        message
+    }
+
+    fn candidate(id: usize) -> ServerMessageType {
+        ServerMessageType::Election(Candidate {
+            id,
+            known_generation: Generation::default(),
+        })
+    }
+
+    #[test]
+    fn should_pass_on_the_higher_candidate_and_drop_lower_ones_while_participating() {
+        let (mut next, sent) = connected_next(1, 2, false);
+        next.start_election().expect("Error starting election");
+        assert_eq!(vec![candidate(1)], take_sent(&sent));
+
+        next.handle_message(election(3, 3, &[3]))
+            .expect("Error handling election");
+        next.handle_message(election(0, 0, &[0]))
+            .expect("Error handling election");
+
+        assert_eq!(vec![candidate(3)], take_sent(&sent));
+    }
+
+    #[test]
+    fn should_replace_a_lower_candidate_when_not_participating() {
+        let (mut next, sent) = connected_next(1, 2, false);
+
+        next.handle_message(election(0, 0, &[0]))
+            .expect("Error handling election");
+
+        assert_eq!(vec![candidate(1)], take_sent(&sent));
+    }
+
+    #[test]
+    fn should_announce_its_victory_once_when_the_election_is_duplicated() {
+        let (mut next, sent) = connected_next(1, 2, false);
+        next.start_election().expect("Error starting election");
+        take_sent(&sent);
+
+        next.handle_message(election(1, 1, &[1, 2, 0]))
+            .expect("Error handling election");
+        next.handle_message(election(1, 1, &[1, 2, 0]))
+            .expect("Error handling election");
+
+        assert_eq!(vec![ServerMessageType::Elected(1)], take_sent(&sent));
+    }
+
+    #[test]
+    fn should_pass_the_elected_message_around_the_ring_once() {
+        let (mut next, sent) = connected_next(1, 2, false);
+
+        next.handle_message(elected(3, &[3]))
+            .expect("Error handling elected");
+        next.handle_message(elected(3, &[3, 1, 2]))
+            .expect("Error handling elected");
+
+        assert_eq!(vec![ServerMessageType::Elected(3)], take_sent(&sent));
+        assert_eq!(Some(3), *next.leader.lock().expect("Lock error"));
+    }
+
+    #[test]
+    fn should_mint_a_single_token_when_its_elected_message_comes_back() {
+        let (mut next, sent) = connected_next(3, 0, false);
+        next.start_election().expect("Error starting election");
+        next.handle_message(election(3, 3, &[3, 0, 1, 2]))
+            .expect("Error handling election");
+        take_sent(&sent);
+
+        next.handle_message(elected(3, &[3, 0, 1, 2]))
+            .expect("Error handling elected");
+        next.handle_message(elected(3, &[3, 0, 1, 2]))
+            .expect("Error handling elected");
+
+        let expected = create_token_message(3, Generation::new(1, 3)).message_type;
+        assert_eq!(vec![expected], take_sent(&sent));
+        assert_eq!(
+            Generation::new(1, 3),
+            *next.token_generation.lock().expect("Lock error")
+        );
+    }
+
+    #[test]
+    fn should_check_that_the_token_was_lost_before_starting_an_election_on_timeout() {
+        let (mut next, sent) = connected_next(1, 2, false);
+
+        assert!(!next.handle_channel_timeout().expect("Error on timeout"));
+
+        assert_eq!(
+            vec![ServerMessageType::MaybeWeLostTheTokenTo(1)],
+            take_sent(&sent)
+        );
+    }
+
+    #[test]
+    fn should_not_check_for_a_lost_token_on_timeout_while_holding_it() {
+        let (mut next, sent) = connected_next(1, 2, true);
+
+        assert!(!next.handle_channel_timeout().expect("Error on timeout"));
+
+        assert!(take_sent(&sent).is_empty());
+    }
+
+    #[test]
+    fn should_start_an_election_when_the_lost_token_check_reaches_the_previous_of_its_sender() {
+        let (mut next, sent) = connected_next(0, 1, false);
+        let mut check = create_maybe_we_lost_the_token_message(1, 1);
+        check.passed_by.extend([1, 2]);
+
+        next.handle_message(check).expect("Error handling check");
+
+        assert_eq!(vec![candidate(0)], take_sent(&sent));
+    }
+
+    #[test]
+    fn should_not_leave_through_a_next_that_does_not_support_it() {
+        let mut old_next = Hello::new(NodeId::Server(2));
+        old_next.protocol_version = 1;
+        old_next.features = vec![Feature::BalanceQuery];
+        let (mut next, sent) = connected_next_with(1, old_next, false);
+
+        assert!(matches!(
+            next.send_message(create_leave_message(3)),
+            Err(ServerError::IncompatibleProtocol)
+        ));
+        next.handle_message(create_leave_message(1))
+            .expect("Error handling leave");
+
+        assert!(take_sent(&sent).is_empty());
+        assert!(!next.leaving);
+        assert!(!next.membership.lock().expect("Lock error").has_left(1));
+        assert!(next
+            .connection_status
+            .lock()
+            .expect("Lock error")
+            .is_next_online());
+    }
 
     #[test]
     fn should_return_that_the_id_is_in_between() {
@@ -451,6 +1140,7 @@ mod tests {
         assert!(is_in_between(3, 1, 2));
         assert!(is_in_between(1, 3, 0));
         assert!(is_in_between(2, 3, 1));
+        assert!(is_in_between(2, 0, 2));
     }
 
     #[test]
@@ -458,5 +1148,30 @@ mod tests {
         assert!(!is_in_between(0, 3, 1));
         assert!(!is_in_between(5, 0, 7));
         assert!(!is_in_between(3, 2, 1));
+        assert!(!is_in_between(2, 2, 2));
+    }
+
+    #[test]
+    fn should_send_only_the_most_recent_history_that_fits_in_the_message() {
+        let actions: Vec<AccountAction> = (1..=5)
+            .map(|physical| AccountAction {
+                message_type: MessageType::AddPoints,
+                account_id: 1,
+                points: 10,
+                last_updated_on: crate::hybrid_clock::HybridTimestamp::new(physical, 0, 1),
+                origin_server_id: 1,
+                coffee_maker_id: 0,
+                request_ids: vec![],
+            })
+            .collect();
+        let action_bytes = serde_json::to_vec(&actions[0])
+            .expect("Error serializing action")
+            .len();
+
+        let kept = most_recent_within(actions.clone(), action_bytes * 2 + 1);
+
+        assert_eq!(actions[3..].to_vec(), kept);
+        assert_eq!(actions, most_recent_within(actions.clone(), usize::MAX));
+        assert!(most_recent_within(actions, 0).is_empty());
     }
 }
@@ -24,15 +24,16 @@ impl SubstractOrdersCleaner {
     }
     /// Metodo para eliminar las ordenes de resta de puntos cuando no se tiene conexion por un tiempo
     pub fn clean_substract_orders_if_offline(&self) -> Result<(), ServerError> {
-        let response = CoffeeMakerResponse {
-            message_type: MessageType::RequestPoints,
-            status: ResponseStatus::Err(CoffeeSystemError::UnexpectedError),
-        };
-
         let discarded_orders = self.orders.lock()?.get_and_clear_request_points_orders();
 
-        for order in discarded_orders.iter() {
-            self.request_points_channel.send((response, order.1))?;
+        for (order, coffee_maker_id) in discarded_orders.iter() {
+            let response = CoffeeMakerResponse {
+                message_type: MessageType::RequestPoints,
+                status: ResponseStatus::Err(CoffeeSystemError::UnexpectedError),
+                request_id: order.request_id,
+            };
+            self.request_points_channel
+                .send((response, *coffee_maker_id))?;
         }
         Ok(())
     }
@@ -42,7 +43,7 @@ impl SubstractOrdersCleaner {
 mod tests {
     use std::sync::mpsc;
 
-    use lib::local_connection_messages::CoffeeMakerRequest;
+    use lib::local_connection_messages::{CoffeeMakerRequest, RequestId};
 
     use super::*;
 
@@ -59,6 +60,7 @@ mod tests {
                     message_type: MessageType::RequestPoints,
                     account_id: 0,
                     points: 10,
+                    request_id: RequestId::default(),
                 },
                 0,
             );
@@ -67,6 +69,7 @@ mod tests {
                     message_type: MessageType::RequestPoints,
                     account_id: 0,
                     points: 10,
+                    request_id: RequestId::default(),
                 },
                 0,
             );
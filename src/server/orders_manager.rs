@@ -4,7 +4,7 @@ use lib::common_errors::CoffeeSystemError;
 use lib::local_connection_messages::{
     CoffeeMakerRequest, CoffeeMakerResponse, MessageType, ResponseStatus,
 };
-use log::{debug, error /*, info */};
+use log::{debug, error, warn /*, info */};
 use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
 use std::sync::Mutex;
 //use std::thread;
@@ -12,32 +12,42 @@ use std::sync::Mutex;
 use crate::accounts_manager::AccountsManager;
 use crate::constants::{COFFEE_RESULT_TIMEOUT_IN_MS, POST_INITIAL_TIMEOUT_COFFEE_RESULT_IN_MS};
 use crate::errors::ServerError;
-use crate::memory_accounts_manager::MemoryAccountsManager;
+use crate::hybrid_clock::HybridClock;
+use crate::metrics::Metrics;
 use crate::orders_queue::OrdersQueue;
-use crate::server_messages::{recreate_token, AccountAction, ServerMessage, TokenData};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::server_messages::{
+    recreate_token, AccountAction, Generation, ServerMessage, Token, TokenData,
+};
+use std::time::{Duration, Instant};
 
 /// Ejecuta los pedidos de las cafeteras, guarda en la base de datos y le responde al dispatcher en las restas
 /// Se ejecuta el algoritmo cada vez que recibe el token.
 pub struct OrdersManager {
     my_id: usize,
     orders: Arc<Mutex<OrdersQueue>>,
-    token_receiver: Receiver<TokenData>,
+    token_receiver: Receiver<Token>,
     to_next_sender: Sender<ServerMessage>,
     request_points_channel: Sender<(CoffeeMakerResponse, usize)>,
-    result_take_points_channel: Receiver<CoffeeMakerRequest>,
-    accounts_manager: Arc<Mutex<MemoryAccountsManager>>,
+    result_take_points_channel: Receiver<(CoffeeMakerRequest, usize)>,
+    accounts_manager: Arc<Mutex<Box<dyn AccountsManager>>>,
+    token_generation: Arc<Mutex<Generation>>,
+    clock: Arc<Mutex<HybridClock>>,
+    metrics: Arc<Mutex<Metrics>>,
 }
 
 impl OrdersManager {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         my_id: usize,
         orders: Arc<Mutex<OrdersQueue>>,
-        token_receiver: Receiver<TokenData>,
+        token_receiver: Receiver<Token>,
         to_next_sender: Sender<ServerMessage>,
         request_points_channel: Sender<(CoffeeMakerResponse, usize)>,
-        result_take_points_channel: Receiver<CoffeeMakerRequest>,
-        accounts_manager: Arc<Mutex<MemoryAccountsManager>>,
+        result_take_points_channel: Receiver<(CoffeeMakerRequest, usize)>,
+        accounts_manager: Arc<Mutex<Box<dyn AccountsManager>>>,
+        token_generation: Arc<Mutex<Generation>>,
+        clock: Arc<Mutex<HybridClock>>,
+        metrics: Arc<Mutex<Metrics>>,
     ) -> OrdersManager {
         OrdersManager {
             my_id,
@@ -47,6 +57,9 @@ impl OrdersManager {
             request_points_channel,
             result_take_points_channel,
             accounts_manager,
+            token_generation,
+            clock,
+            metrics,
         }
     }
 
@@ -60,116 +73,193 @@ impl OrdersManager {
         // });
 
         loop {
-            let mut timeout = Duration::from_millis(COFFEE_RESULT_TIMEOUT_IN_MS);
-            let mut token = self.token_receiver.recv()?;
-            debug!("[ORDERS MANAGER] I have the token");
-            let adding_orders;
-            let request_points_orders;
-            {
-                let mut orders = self.orders.lock()?;
-                if orders.is_empty() {
-                    self.to_next_sender
-                        .send(recreate_token(self.my_id, token))?;
-                    debug!("[ORDERS MANAGER] I don't need the token");
-                    continue;
-                }
-                adding_orders = orders.get_and_clear_adding_orders();
-                request_points_orders = orders.get_and_clear_request_points_orders();
+            let token = self.token_receiver.recv()?;
+            let received_at = Instant::now();
+            self.metrics.lock()?.token_received();
+            if let Some((token, total_request_orders)) = self.take_orders(token)? {
+                self.finish_orders(token, total_request_orders)?;
             }
-            let mut accounts = self.accounts_manager.lock()?;
-            for order in adding_orders {
-                let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
-                if accounts
-                    .add_points(order.account_id, order.points, Some(timestamp))
-                    .is_err()
-                {
+            self.metrics.lock()?.token_held(received_at.elapsed());
+        }
+    }
+
+    /// Devuelve el token que haya llegado, sin esperar
+    #[cfg(test)]
+    pub fn try_recv_token(&self) -> Option<Token> {
+        self.token_receiver.try_recv().ok()
+    }
+
+    /// Aplica los pedidos de suma y reserva los puntos de los pedidos de resta, respondiendo a las cafeteras.
+    /// Devuelve el token junto a la cantidad de resultados de cafes a esperar, o None si no habia pedidos
+    /// y el token ya se paso al siguiente
+    pub fn take_orders(&mut self, mut token: Token) -> Result<Option<(Token, usize)>, ServerError> {
+        // Si mientras tanto llego un token de una generacion mas nueva, este quedo viejo y no se aplica
+        if token.generation < *self.token_generation.lock()? {
+            warn!(
+                "[ORDERS MANAGER] Discarding token of stale generation {}",
+                token.generation
+            );
+            return Ok(None);
+        }
+        debug!("[ORDERS MANAGER] I have the token");
+        let adding_orders;
+        let request_points_orders;
+        {
+            let mut orders = self.orders.lock()?;
+            if orders.is_empty() {
+                self.to_next_sender
+                    .send(recreate_token(self.my_id, token))?;
+                debug!("[ORDERS MANAGER] I don't need the token");
+                return Ok(None);
+            }
+            adding_orders = orders.get_and_clear_adding_orders();
+            request_points_orders = orders.get_and_clear_request_points_orders();
+        }
+        let mut accounts = self.accounts_manager.lock()?;
+        for reduced in adding_orders {
+            let order = reduced.order;
+            let timestamp = self.clock.lock()?.now();
+            let action = AccountAction {
+                message_type: MessageType::AddPoints,
+                account_id: order.account_id,
+                points: order.points,
+                last_updated_on: timestamp,
+                origin_server_id: self.my_id,
+                coffee_maker_id: reduced.coffee_maker_id,
+                request_ids: reduced.request_ids.clone(),
+            };
+            // Una suma que no se aplico no se registra ni viaja en el token, si no los demas servidores
+            // aplicarian puntos que este no tiene. Si no se pudo guardar se reintenta con el proximo token
+            match accounts.apply_action(&action, None) {
+                Ok(()) => {}
+                Err(ServerError::StorageError) => {
                     error!(
-                        "Error adding {} points to account {}",
+                        "Error saving {} points of account {}, retrying with the next token",
                         order.points, order.account_id
                     );
+                    self.orders.lock()?.retry_adding_order(reduced);
+                    continue;
+                }
+                Err(e) => {
+                    error!(
+                        "Error adding {} points to account {}, {:?}",
+                        order.points, order.account_id, e
+                    );
+                    continue;
                 }
-                let action = AccountAction {
-                    message_type: MessageType::AddPoints,
-                    account_id: order.account_id,
-                    points: order.points,
-                    last_updated_on: timestamp,
-                };
-                token.entry(self.my_id).or_insert(vec![]).push(action);
             }
+            token.data.entry(self.my_id).or_insert(vec![]).push(action);
+        }
 
-            let mut total_request_orders = 0;
-            for (order, coffee_maker_id) in request_points_orders {
-                let result = accounts.request_points(order.account_id, order.points);
-
-                let status = match result {
-                    Ok(()) => {
-                        total_request_orders += 1;
-                        ResponseStatus::Ok
-                    }
-                    Err(ServerError::NotEnoughPointsInAccount) => {
-                        ResponseStatus::Err(CoffeeSystemError::NotEnoughPoints)
-                    }
-                    Err(ServerError::AccountNotFound) => {
-                        ResponseStatus::Err(CoffeeSystemError::AccountNotFound)
-                    }
-                    Err(ServerError::AccountIsReserved) => {
-                        ResponseStatus::Err(CoffeeSystemError::AccountIsReserved)
-                    }
-                    _ => ResponseStatus::Err(CoffeeSystemError::UnexpectedError),
-                };
-                self.request_points_channel.send((
-                    CoffeeMakerResponse {
-                        message_type: MessageType::RequestPoints,
-                        status,
-                    },
-                    coffee_maker_id,
-                ))?;
-            }
+        let mut total_request_orders = 0;
+        for (order, coffee_maker_id) in request_points_orders {
+            // La reserva queda a nombre del dispenser que la pidio, la conexion de la cafetera se comparte
+            // entre sus dispensers y su indice se reutiliza cuando se reconecta
+            let result = accounts.request_points(
+                order.account_id,
+                order.points,
+                order.request_id.dispenser_id,
+            );
 
-            let mut there_was_a_timeout = false;
-            for _ in 0..total_request_orders {
-                let result = self.result_take_points_channel.recv_timeout(timeout);
-                match result {
-                    Ok(result) => {
-                        self.handle_result_of_substract_order(result, &mut accounts, &mut token)?;
-                    }
-                    Err(RecvTimeoutError::Timeout) => {
-                        there_was_a_timeout = true;
-                        timeout = Duration::from_millis(POST_INITIAL_TIMEOUT_COFFEE_RESULT_IN_MS);
-                    }
-                    Err(RecvTimeoutError::Disconnected) => {
-                        return Err(ServerError::ChannelError);
-                    }
+            let status = match result {
+                Ok(()) => {
+                    total_request_orders += 1;
+                    self.orders
+                        .lock()?
+                        .await_result(order.account_id, order.request_id.dispenser_id);
+                    ResponseStatus::Ok
+                }
+                Err(ServerError::NotEnoughPointsInAccount) => {
+                    ResponseStatus::Err(CoffeeSystemError::NotEnoughPoints)
+                }
+                Err(ServerError::AccountNotFound) => {
+                    ResponseStatus::Err(CoffeeSystemError::AccountNotFound)
+                }
+                Err(ServerError::AccountIsReserved) => {
+                    ResponseStatus::Err(CoffeeSystemError::AccountIsReserved)
+                }
+                _ => ResponseStatus::Err(CoffeeSystemError::UnexpectedError),
+            };
+            self.request_points_channel.send((
+                CoffeeMakerResponse {
+                    message_type: MessageType::RequestPoints,
+                    status,
+                    request_id: order.request_id,
+                },
+                coffee_maker_id,
+            ))?;
+        }
+        Ok(Some((token, total_request_orders)))
+    }
+
+    /// Espera el resultado de los cafes con puntos reservados, libera las reservas vencidas y pasa el token al siguiente
+    pub fn finish_orders(
+        &mut self,
+        mut token: Token,
+        total_request_orders: usize,
+    ) -> Result<(), ServerError> {
+        let mut timeout = Duration::from_millis(COFFEE_RESULT_TIMEOUT_IN_MS);
+        let mut accounts = self.accounts_manager.lock()?;
+        for _ in 0..total_request_orders {
+            let result = self.result_take_points_channel.recv_timeout(timeout);
+            match result {
+                Ok((result, coffee_maker_id)) => {
+                    self.handle_result_of_substract_order(
+                        result,
+                        coffee_maker_id,
+                        &mut accounts,
+                        &mut token.data,
+                    )?;
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    timeout = Duration::from_millis(POST_INITIAL_TIMEOUT_COFFEE_RESULT_IN_MS);
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(ServerError::ChannelError);
                 }
             }
-            if there_was_a_timeout {
-                accounts.clear_reservations();
-            }
-            self.to_next_sender
-                .send(recreate_token(self.my_id, token))?;
-            debug!("[ORDERS MANAGER] Passed the token to next connection");
         }
+        // Las reservas de las cafeteras que no respondieron se liberan cuando vencen, sin afectar a las demas
+        accounts.release_expired_reservations();
+        self.orders.lock()?.stop_awaiting_results();
+        self.to_next_sender
+            .send(recreate_token(self.my_id, token))?;
+        debug!("[ORDERS MANAGER] Passed the token to next connection");
+        Ok(())
     }
 
     fn handle_result_of_substract_order(
         &self,
         result: CoffeeMakerRequest,
-        accounts: &mut std::sync::MutexGuard<'_, MemoryAccountsManager>,
+        coffee_maker_id: usize,
+        accounts: &mut std::sync::MutexGuard<'_, Box<dyn AccountsManager>>,
         token: &mut TokenData,
     ) -> Result<(), ServerError> {
+        self.orders
+            .lock()?
+            .result_received(result.account_id, result.request_id.dispenser_id);
         match result.message_type {
             MessageType::CancelPointsRequest => {
-                if accounts.cancel_requested_points(result.account_id).is_err() {
+                let cancel_result = accounts
+                    .cancel_requested_points(result.account_id, result.request_id.dispenser_id);
+                if let Err(e) = cancel_result {
                     error!(
-                        "Error canceling points request from account {}",
-                        result.account_id
+                        "Error canceling points request from account {}, {:?}",
+                        result.account_id, e
                     );
                 }
             }
             MessageType::TakePoints => {
-                let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
-                if let Err(e) =
-                    accounts.substract_points(result.account_id, result.points, Some(timestamp))
+                let action = AccountAction {
+                    message_type: MessageType::TakePoints,
+                    account_id: result.account_id,
+                    points: result.points,
+                    last_updated_on: self.clock.lock()?.now(),
+                    origin_server_id: self.my_id,
+                    coffee_maker_id,
+                    request_ids: vec![result.request_id],
+                };
+                if let Err(e) = accounts.apply_action(&action, Some(result.request_id.dispenser_id))
                 {
                     error!(
                         "Error taking {} points from account {}, {:?}",
@@ -177,12 +267,6 @@ impl OrdersManager {
                     );
                     return Ok(());
                 }
-                let action = AccountAction {
-                    message_type: MessageType::TakePoints,
-                    account_id: result.account_id,
-                    points: result.points,
-                    last_updated_on: timestamp,
-                };
                 token.entry(self.my_id).or_insert(vec![]).push(action);
             }
             _ => {}
@@ -190,3 +274,355 @@ impl OrdersManager {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::mpsc};
+
+    use super::*;
+    use crate::{
+        file_accounts_manager::FileAccountsManager, hybrid_clock::HybridTimestamp,
+        memory_accounts_manager::MemoryAccountsManager, server_messages::ServerMessageType,
+    };
+    use lib::local_connection_messages::RequestId;
+
+    #[test]
+    fn should_not_apply_a_delayed_token_of_a_stale_generation() {
+        let orders = Arc::new(Mutex::new(OrdersQueue::new()));
+        orders.lock().expect("Lock error").add(
+            CoffeeMakerRequest {
+                message_type: MessageType::AddPoints,
+                account_id: 1,
+                points: 10,
+                request_id: RequestId::default(),
+            },
+            0,
+        );
+        let (token_sender, token_receiver) = mpsc::channel();
+        let (to_next_sender, to_next_receiver) = mpsc::channel();
+        let (request_points_sender, _) = mpsc::channel();
+        let (_, result_take_points_receiver) = mpsc::channel();
+        let accounts_manager: Arc<Mutex<Box<dyn AccountsManager>>> =
+            Arc::new(Mutex::new(Box::new(MemoryAccountsManager::new())));
+        // Ya circula una copia regenerada del token con generacion 1
+        let token_generation = Arc::new(Mutex::new(Generation::new(1, 0)));
+
+        let mut orders_manager = OrdersManager::new(
+            0,
+            orders.clone(),
+            token_receiver,
+            to_next_sender,
+            request_points_sender,
+            result_take_points_receiver,
+            accounts_manager.clone(),
+            token_generation,
+            Arc::new(Mutex::new(HybridClock::new(0))),
+            Arc::new(Mutex::new(Metrics::new())),
+        );
+
+        token_sender
+            .send(Token {
+                generation: Generation::new(0, 0),
+                data: HashMap::new(),
+            })
+            .expect("Error sending token");
+        drop(token_sender);
+        let result = orders_manager.handle_orders();
+
+        assert!(matches!(result, Err(ServerError::ChannelError)));
+        assert!(to_next_receiver.try_recv().is_err());
+        assert!(!orders.lock().expect("Lock error").is_empty());
+        assert_eq!(
+            HybridTimestamp::default(),
+            accounts_manager
+                .lock()
+                .expect("Lock error")
+                .get_most_recent_update()
+        );
+    }
+
+    #[test]
+    fn should_apply_the_orders_and_pass_a_token_of_the_current_generation() {
+        let orders = Arc::new(Mutex::new(OrdersQueue::new()));
+        orders.lock().expect("Lock error").add(
+            CoffeeMakerRequest {
+                message_type: MessageType::AddPoints,
+                account_id: 1,
+                points: 10,
+                request_id: RequestId::default(),
+            },
+            4,
+        );
+        let (token_sender, token_receiver) = mpsc::channel();
+        let (to_next_sender, to_next_receiver) = mpsc::channel();
+        let (request_points_sender, _) = mpsc::channel();
+        let (_, result_take_points_receiver) = mpsc::channel();
+        let accounts_manager: Arc<Mutex<Box<dyn AccountsManager>>> =
+            Arc::new(Mutex::new(Box::new(MemoryAccountsManager::new())));
+
+        let mut orders_manager = OrdersManager::new(
+            0,
+            orders.clone(),
+            token_receiver,
+            to_next_sender,
+            request_points_sender,
+            result_take_points_receiver,
+            accounts_manager.clone(),
+            Arc::new(Mutex::new(Generation::new(1, 0))),
+            Arc::new(Mutex::new(HybridClock::new(0))),
+            Arc::new(Mutex::new(Metrics::new())),
+        );
+
+        token_sender
+            .send(Token {
+                generation: Generation::new(1, 0),
+                data: HashMap::new(),
+            })
+            .expect("Error sending token");
+        drop(token_sender);
+        let result = orders_manager.handle_orders();
+
+        assert!(matches!(result, Err(ServerError::ChannelError)));
+        let message = to_next_receiver.try_recv().expect("No message present");
+        match message.message_type {
+            ServerMessageType::Token(token) => {
+                assert_eq!(Generation::new(1, 0), token.generation);
+                assert_eq!(1, token.data[&0].len());
+            }
+            _ => panic!("Expected the token"),
+        }
+        let history = accounts_manager
+            .lock()
+            .expect("Lock error")
+            .get_history(1, 0, 10);
+        assert_eq!(1, history.len());
+        assert_eq!(0, history[0].origin_server_id);
+        assert_eq!(4, history[0].coffee_maker_id);
+    }
+
+    #[test]
+    fn should_keep_an_order_that_could_not_be_saved_out_of_the_token() {
+        let orders = Arc::new(Mutex::new(OrdersQueue::new()));
+        orders.lock().expect("Lock error").add(
+            CoffeeMakerRequest {
+                message_type: MessageType::AddPoints,
+                account_id: 1,
+                points: 10,
+                request_id: RequestId::default(),
+            },
+            4,
+        );
+        let dir =
+            std::env::temp_dir().join(format!("coffee_orders_storage_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut accounts =
+            FileAccountsManager::new(&dir.to_string_lossy(), 0).expect("Error opening storage");
+        accounts.fail_log_writes().expect("Log not found");
+        let accounts_manager: Arc<Mutex<Box<dyn AccountsManager>>> =
+            Arc::new(Mutex::new(Box::new(accounts)));
+        let (_, token_receiver) = mpsc::channel();
+        let (to_next_sender, _) = mpsc::channel();
+        let (request_points_sender, _) = mpsc::channel();
+        let (_, result_take_points_receiver) = mpsc::channel();
+
+        let mut orders_manager = OrdersManager::new(
+            0,
+            orders.clone(),
+            token_receiver,
+            to_next_sender,
+            request_points_sender,
+            result_take_points_receiver,
+            accounts_manager.clone(),
+            Arc::new(Mutex::new(Generation::new(1, 0))),
+            Arc::new(Mutex::new(HybridClock::new(0))),
+            Arc::new(Mutex::new(Metrics::new())),
+        );
+        let (token, _) = orders_manager
+            .take_orders(Token {
+                generation: Generation::new(1, 0),
+                data: HashMap::new(),
+            })
+            .expect("Error taking orders")
+            .expect("Expected the token to be kept");
+
+        assert!(token.data.is_empty());
+        let accounts = accounts_manager.lock().expect("Lock error");
+        assert!(accounts.get_account(1).is_none());
+        assert!(accounts.get_history(1, 0, 10).is_empty());
+        assert_eq!(1, orders.lock().expect("Lock error").pending_orders().len());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn should_only_take_the_points_reserved_by_the_same_dispenser() {
+        let orders = Arc::new(Mutex::new(OrdersQueue::new()));
+        // Los dos dispensers comparten la conexion de la cafetera
+        for account_id in [1, 2] {
+            orders.lock().expect("Lock error").add(
+                CoffeeMakerRequest {
+                    message_type: MessageType::RequestPoints,
+                    account_id,
+                    points: 10,
+                    request_id: RequestId {
+                        dispenser_id: account_id as u64,
+                        sequence: 0,
+                    },
+                },
+                0,
+            );
+        }
+        let mut accounts = MemoryAccountsManager::new();
+        accounts
+            .update(1, 50, HybridTimestamp::default())
+            .expect("Error updating account");
+        accounts
+            .update(2, 50, HybridTimestamp::default())
+            .expect("Error updating account");
+        let accounts_manager: Arc<Mutex<Box<dyn AccountsManager>>> =
+            Arc::new(Mutex::new(Box::new(accounts)));
+        let (token_sender, token_receiver) = mpsc::channel();
+        let (to_next_sender, to_next_receiver) = mpsc::channel();
+        let (request_points_sender, request_points_receiver) = mpsc::channel();
+        let (result_take_points_sender, result_take_points_receiver) = mpsc::channel();
+
+        let mut orders_manager = OrdersManager::new(
+            0,
+            orders,
+            token_receiver,
+            to_next_sender,
+            request_points_sender,
+            result_take_points_receiver,
+            accounts_manager.clone(),
+            Arc::new(Mutex::new(Generation::new(1, 0))),
+            Arc::new(Mutex::new(HybridClock::new(0))),
+            Arc::new(Mutex::new(Metrics::new())),
+        );
+
+        let take_points = |account_id, dispenser_id| {
+            (
+                CoffeeMakerRequest {
+                    message_type: MessageType::TakePoints,
+                    account_id,
+                    points: 10,
+                    request_id: RequestId {
+                        dispenser_id,
+                        sequence: 1,
+                    },
+                },
+                0,
+            )
+        };
+        // El dispenser 2 confirma la reserva del dispenser 1, que no responde
+        result_take_points_sender
+            .send(take_points(1, 2))
+            .expect("Error sending result");
+        result_take_points_sender
+            .send(take_points(2, 2))
+            .expect("Error sending result");
+        token_sender
+            .send(Token {
+                generation: Generation::new(1, 0),
+                data: HashMap::new(),
+            })
+            .expect("Error sending token");
+        drop(token_sender);
+        let result = orders_manager.handle_orders();
+
+        assert!(matches!(result, Err(ServerError::ChannelError)));
+        assert_eq!(2, request_points_receiver.try_iter().count());
+        match to_next_receiver
+            .try_recv()
+            .expect("No message present")
+            .message_type
+        {
+            ServerMessageType::Token(token) => {
+                assert_eq!(1, token.data[&0].len());
+                assert_eq!(2, token.data[&0][0].account_id);
+            }
+            _ => panic!("Expected the token"),
+        }
+        let accounts = accounts_manager.lock().expect("Lock error");
+        assert_eq!(50, accounts.get_account(1).expect("No account").amount);
+        assert_eq!(40, accounts.get_account(2).expect("No account").amount);
+    }
+
+    #[test]
+    fn should_settle_the_reservation_of_each_dispenser_sharing_a_connection() {
+        let orders = Arc::new(Mutex::new(OrdersQueue::new()));
+        let request = |message_type, dispenser_id, points| CoffeeMakerRequest {
+            message_type,
+            account_id: 1,
+            points,
+            request_id: RequestId {
+                dispenser_id,
+                sequence: 0,
+            },
+        };
+        // Dos dispensers de la misma cafetera reservan puntos de la misma cuenta
+        orders
+            .lock()
+            .expect("Lock error")
+            .add(request(MessageType::RequestPoints, 10, 30), 0);
+        orders
+            .lock()
+            .expect("Lock error")
+            .add(request(MessageType::RequestPoints, 20, 20), 0);
+        let mut accounts = MemoryAccountsManager::new();
+        accounts
+            .update(1, 50, HybridTimestamp::default())
+            .expect("Error updating account");
+        let accounts_manager: Arc<Mutex<Box<dyn AccountsManager>>> =
+            Arc::new(Mutex::new(Box::new(accounts)));
+        let (token_sender, token_receiver) = mpsc::channel();
+        let (to_next_sender, to_next_receiver) = mpsc::channel();
+        let (request_points_sender, request_points_receiver) = mpsc::channel();
+        let (result_take_points_sender, result_take_points_receiver) = mpsc::channel();
+
+        let mut orders_manager = OrdersManager::new(
+            0,
+            orders,
+            token_receiver,
+            to_next_sender,
+            request_points_sender,
+            result_take_points_receiver,
+            accounts_manager.clone(),
+            Arc::new(Mutex::new(Generation::new(1, 0))),
+            Arc::new(Mutex::new(HybridClock::new(0))),
+            Arc::new(Mutex::new(Metrics::new())),
+        );
+
+        // El segundo dispenser cancela antes de que el primero confirme
+        result_take_points_sender
+            .send((request(MessageType::CancelPointsRequest, 20, 20), 0))
+            .expect("Error sending result");
+        result_take_points_sender
+            .send((request(MessageType::TakePoints, 10, 30), 0))
+            .expect("Error sending result");
+        token_sender
+            .send(Token {
+                generation: Generation::new(1, 0),
+                data: HashMap::new(),
+            })
+            .expect("Error sending token");
+        drop(token_sender);
+        let result = orders_manager.handle_orders();
+
+        assert!(matches!(result, Err(ServerError::ChannelError)));
+        assert!(request_points_receiver
+            .try_iter()
+            .all(|(response, _)| matches!(response.status, ResponseStatus::Ok)));
+        match to_next_receiver
+            .try_recv()
+            .expect("No message present")
+            .message_type
+        {
+            ServerMessageType::Token(token) => {
+                assert_eq!(1, token.data[&0].len());
+                assert_eq!(30, token.data[&0][0].points);
+            }
+            _ => panic!("Expected the token"),
+        }
+        let accounts = accounts_manager.lock().expect("Lock error");
+        assert_eq!(20, accounts.get_account(1).expect("No account").amount);
+    }
+}
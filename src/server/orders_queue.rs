@@ -1,18 +1,33 @@
-use std::collections::HashMap;
+use std::collections::{hash_map::Entry, HashMap, HashSet};
 
-use lib::local_connection_messages::{CoffeeMakerRequest, MessageType};
+use lib::local_connection_messages::{CoffeeMakerRequest, MessageType, RequestId};
+
+/// Pedido de suma que resulta de reducir los pedidos de una misma cafetera sobre una cuenta.
+/// Guarda los ids de todos los pedidos que lo forman
+#[derive(Debug)]
+pub struct ReducedOrder {
+    pub order: CoffeeMakerRequest,
+    pub coffee_maker_id: usize,
+    pub request_ids: Vec<RequestId>,
+}
 
 /// Representa a la cola de pedidos de las cafeteras. Estas van a ser procesadas por el OrdersManager
 pub struct OrdersQueue {
     adding_orders: Vec<(CoffeeMakerRequest, usize)>,
+    /// Pedidos de suma ya reducidos que no se pudieron aplicar y esperan al proximo token
+    retried_adding_orders: Vec<ReducedOrder>,
     request_points_orders: Vec<(CoffeeMakerRequest, usize)>,
+    /// Reservas hechas con el token actual cuyo resultado todavia no llego, por cuenta y dispenser
+    awaiting_results: HashSet<(usize, u64)>,
 }
 
 impl OrdersQueue {
     pub fn new() -> OrdersQueue {
         OrdersQueue {
             adding_orders: Vec::new(),
+            retried_adding_orders: Vec::new(),
             request_points_orders: Vec::new(),
+            awaiting_results: HashSet::new(),
         }
     }
 
@@ -24,25 +39,80 @@ impl OrdersQueue {
         }
     }
 
+    /// Vuelve a encolar un pedido de suma que no se pudo aplicar, para aplicarlo con el proximo token
+    pub fn retry_adding_order(&mut self, order: ReducedOrder) {
+        self.retried_adding_orders.push(order);
+    }
+
+    /// Registra que se espera el resultado (resta o cancelacion) de la reserva del dispenser sobre la cuenta
+    pub fn await_result(&mut self, account_id: usize, dispenser_id: u64) {
+        self.awaiting_results.insert((account_id, dispenser_id));
+    }
+
+    /// Indica si el resultado de la reserva se puede aplicar, es decir si se hizo con el token actual y no llego todavia
+    pub fn is_awaiting_result(&self, account_id: usize, dispenser_id: u64) -> bool {
+        self.awaiting_results.contains(&(account_id, dispenser_id))
+    }
+
+    pub fn result_received(&mut self, account_id: usize, dispenser_id: u64) {
+        self.awaiting_results.remove(&(account_id, dispenser_id));
+    }
+
+    /// Al pasar el token ya no se esperan mas resultados
+    pub fn stop_awaiting_results(&mut self) {
+        self.awaiting_results.clear();
+    }
+
+    /// Devuelve los pedidos que esperan al token junto a la cafetera que los envio, sin sacarlos de la cola
+    pub fn pending_orders(&self) -> Vec<(CoffeeMakerRequest, usize)> {
+        self.retried_adding_orders
+            .iter()
+            .map(|reduced| (reduced.order, reduced.coffee_maker_id))
+            .chain(self.adding_orders.iter().copied())
+            .chain(self.request_points_orders.iter().copied())
+            .collect()
+    }
+
     pub fn is_empty(&self) -> bool {
-        self.adding_orders.is_empty() && self.request_points_orders.is_empty()
+        self.adding_orders.is_empty()
+            && self.retried_adding_orders.is_empty()
+            && self.request_points_orders.is_empty()
     }
 
-    /// Retorna los pedidos de suma reduciendolos en caso de que sean varios sobre la misma cuenta
-    pub fn get_and_clear_adding_orders(&mut self) -> Vec<CoffeeMakerRequest> {
-        let mut reduced = HashMap::new();
-        for req in &self.adding_orders {
-            *reduced.entry(req.0.account_id).or_insert(0) += req.0.points;
+    /// Retorna los pedidos de suma junto a la cafetera que los envio, reduciendolos en caso de que sean varios
+    /// sobre la misma cuenta desde la misma cafetera
+    pub fn get_and_clear_adding_orders(&mut self) -> Vec<ReducedOrder> {
+        let mut reduced: HashMap<(usize, usize), ReducedOrder> = HashMap::new();
+        for retried in self.retried_adding_orders.drain(..) {
+            match reduced.entry((retried.order.account_id, retried.coffee_maker_id)) {
+                Entry::Occupied(mut entry) => {
+                    let entry = entry.get_mut();
+                    entry.order.points += retried.order.points;
+                    entry.request_ids.extend(retried.request_ids);
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(retried);
+                }
+            }
+        }
+        for (req, coffee_maker_id) in &self.adding_orders {
+            let entry = reduced
+                .entry((req.account_id, *coffee_maker_id))
+                .or_insert_with(|| ReducedOrder {
+                    order: CoffeeMakerRequest {
+                        account_id: req.account_id,
+                        points: 0,
+                        message_type: MessageType::AddPoints,
+                        request_id: req.request_id,
+                    },
+                    coffee_maker_id: *coffee_maker_id,
+                    request_ids: vec![],
+                });
+            entry.order.points += req.points;
+            entry.request_ids.push(req.request_id);
         }
         self.adding_orders.clear();
-        reduced
-            .into_iter()
-            .map(|(account_id, points)| CoffeeMakerRequest {
-                account_id,
-                points,
-                message_type: MessageType::AddPoints,
-            })
-            .collect()
+        reduced.into_values().collect()
     }
 
     pub fn get_and_clear_request_points_orders(&mut self) -> Vec<(CoffeeMakerRequest, usize)> {
@@ -76,6 +146,7 @@ mod tests {
                 message_type: MessageType::RequestPoints,
                 account_id: 0,
                 points: 10,
+                request_id: RequestId::default(),
             },
             0,
         );
@@ -84,6 +155,7 @@ mod tests {
                 message_type: MessageType::AddPoints,
                 account_id: 0,
                 points: 10,
+                request_id: RequestId::default(),
             },
             0,
         );
@@ -102,6 +174,7 @@ mod tests {
                 message_type: MessageType::AddPoints,
                 account_id: 0,
                 points: 10,
+                request_id: RequestId::default(),
             },
             0,
         );
@@ -110,6 +183,7 @@ mod tests {
                 message_type: MessageType::AddPoints,
                 account_id: 0,
                 points: 10,
+                request_id: RequestId::default(),
             },
             0,
         );
@@ -118,7 +192,8 @@ mod tests {
         assert_eq!(2, orders.adding_orders.len());
         let adding_orders = orders.get_and_clear_adding_orders();
         assert_eq!(1, adding_orders.len());
-        assert_eq!(20, adding_orders[0].points);
+        assert_eq!(20, adding_orders[0].order.points);
+        assert_eq!(2, adding_orders[0].request_ids.len());
     }
 
     #[test]
@@ -130,6 +205,7 @@ mod tests {
                 message_type: MessageType::RequestPoints,
                 account_id: 0,
                 points: 10,
+                request_id: RequestId::default(),
             },
             0,
         );
@@ -138,6 +214,7 @@ mod tests {
                 message_type: MessageType::RequestPoints,
                 account_id: 0,
                 points: 10,
+                request_id: RequestId::default(),
             },
             0,
         );
@@ -148,4 +225,65 @@ mod tests {
         assert_eq!(2, substract_orders.len());
         assert!(orders.request_points_orders.is_empty());
     }
+
+    #[test]
+    fn should_return_the_pending_orders_without_removing_them() {
+        let mut orders = OrdersQueue::new();
+
+        orders.add(
+            CoffeeMakerRequest {
+                message_type: MessageType::AddPoints,
+                account_id: 0,
+                points: 10,
+                request_id: RequestId::default(),
+            },
+            0,
+        );
+        orders.add(
+            CoffeeMakerRequest {
+                message_type: MessageType::RequestPoints,
+                account_id: 1,
+                points: 5,
+                request_id: RequestId::default(),
+            },
+            2,
+        );
+
+        let pending = orders.pending_orders();
+        assert_eq!(2, pending.len());
+        assert_eq!(
+            (MessageType::AddPoints, 0),
+            (pending[0].0.message_type, pending[0].1)
+        );
+        assert_eq!(
+            (MessageType::RequestPoints, 2),
+            (pending[1].0.message_type, pending[1].1)
+        );
+        assert!(!orders.is_empty());
+    }
+
+    #[test]
+    fn should_merge_a_retried_adding_order_with_the_new_ones() {
+        let mut orders = OrdersQueue::new();
+        let request = |sequence| CoffeeMakerRequest {
+            message_type: MessageType::AddPoints,
+            account_id: 0,
+            points: 10,
+            request_id: RequestId {
+                dispenser_id: 1,
+                sequence,
+            },
+        };
+        orders.add(request(1), 0);
+        let retried = orders.get_and_clear_adding_orders().remove(0);
+        orders.retry_adding_order(retried);
+        assert!(!orders.is_empty());
+        orders.add(request(2), 0);
+
+        let reduced = orders.get_and_clear_adding_orders();
+        assert_eq!(1, reduced.len());
+        assert_eq!(20, reduced[0].order.points);
+        assert_eq!(2, reduced[0].request_ids.len());
+        assert!(orders.is_empty());
+    }
 }
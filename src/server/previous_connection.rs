@@ -6,17 +6,19 @@ use std::{
 use async_std::task;
 use lib::{
     common_errors::CoffeeSystemError, connection_protocol::ConnectionProtocol,
-    local_connection_messages::MessageType, serializer::deserialize,
+    local_connection_messages::MessageType,
 };
 use log::{debug, error, info, warn};
 
 use crate::{
     accounts_manager::AccountsManager,
     connection_status::ConnectionStatus,
-    memory_accounts_manager::MemoryAccountsManager,
+    dedup_cache::DedupCache,
+    hybrid_clock::HybridClock,
+    membership::Membership,
     server_messages::{
-        create_maybe_we_lost_the_token_message, AccountAction, Diff, ServerMessage,
-        ServerMessageType, TokenData,
+        create_close_connection_message, create_maybe_we_lost_the_token_message, AccountAction,
+        Diff, Generation, ServerMessage, ServerMessageType, Token, TokenData,
     },
 };
 
@@ -24,23 +26,33 @@ use crate::{
 pub struct PrevConnection {
     connection: Box<dyn ConnectionProtocol + Send>,
     to_next_sender: Sender<ServerMessage>,
-    to_orders_manager_sender: Sender<TokenData>,
+    to_orders_manager_sender: Sender<Token>,
     connection_status: Arc<Mutex<ConnectionStatus>>,
     listening_to_id: Option<usize>,
     my_id: usize,
     have_token: Arc<Mutex<bool>>,
-    accounts_manager: Arc<Mutex<MemoryAccountsManager>>,
+    accounts_manager: Arc<Mutex<Box<dyn AccountsManager>>>,
+    membership: Arc<Mutex<Membership>>,
+    token_generation: Arc<Mutex<Generation>>,
+    clock: Arc<Mutex<HybridClock>>,
+    dedup_cache: Arc<Mutex<DedupCache>>,
+    pending_message: Option<Vec<u8>>,
 }
 
 impl PrevConnection {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         connection: Box<dyn ConnectionProtocol + Send>,
         to_next_sender: Sender<ServerMessage>,
-        to_orders_manager_sender: Sender<TokenData>,
+        to_orders_manager_sender: Sender<Token>,
         connection_status: Arc<Mutex<ConnectionStatus>>,
         my_id: usize,
         have_token: Arc<Mutex<bool>>,
-        accounts_manager: Arc<Mutex<MemoryAccountsManager>>,
+        accounts_manager: Arc<Mutex<Box<dyn AccountsManager>>>,
+        membership: Arc<Mutex<Membership>>,
+        token_generation: Arc<Mutex<Generation>>,
+        clock: Arc<Mutex<HybridClock>>,
+        dedup_cache: Arc<Mutex<DedupCache>>,
     ) -> PrevConnection {
         PrevConnection {
             connection,
@@ -51,93 +63,237 @@ impl PrevConnection {
             my_id,
             have_token,
             accounts_manager,
+            membership,
+            token_generation,
+            clock,
+            dedup_cache,
+            pending_message: None,
         }
     }
 
+    /// Indica el primer mensaje de la conexion, que ya fue leido al aceptarla
+    pub fn with_first_message(mut self, encoded: Vec<u8>) -> PrevConnection {
+        self.pending_message = Some(encoded);
+        self
+    }
+
     pub fn listen(&mut self) -> Result<(), CoffeeSystemError> {
         loop {
-            let encoded = task::block_on(self.connection.recv());
-            if encoded.is_err() {
-                warn!("[PREVIOUS CONNECTION] Previous connection died");
-                self.connection_status.lock()?.set_prev_offline();
-                if !*self.have_token.lock()? {
-                    warn!(
-                        "[PREVIOUS CONNECTION] I don't have the token, maybe we lost the token, sending message"
-                    );
-                    let to_id = self.listening_to_id.unwrap_or(self.my_id);
-                    self.to_next_sender
-                        .send(create_maybe_we_lost_the_token_message(self.my_id, to_id))?;
-                } else {
-                    info!("[PREVIOUS CONNECTION] Previous connection died but i have the token");
+            let encoded = match self.pending_message.take() {
+                Some(encoded) => Ok(encoded),
+                None => task::block_on(self.connection.recv()),
+            };
+            match encoded {
+                Ok(encoded) => {
+                    if self.handle_message(&encoded)? {
+                        return Ok(());
+                    }
+                }
+                Err(_) => {
+                    self.handle_connection_lost()?;
+                    return Err(CoffeeSystemError::ConnectionLost);
                 }
-                return Err(CoffeeSystemError::ConnectionLost);
             }
+        }
+    }
 
-            let mut encoded = encoded.unwrap();
-            let result = deserialize(&mut encoded);
-            if result.is_err() {
-                error!("[PREVIOUS CONNECTION] Error deserializing the message");
-                continue;
-            }
-            let mut message: ServerMessage = result.unwrap();
+    /// Maneja la caida de la conexion con el anterior. Si no tenemos el token avisamos al siguiente
+    /// que puede haberse perdido
+    pub fn handle_connection_lost(&mut self) -> Result<(), CoffeeSystemError> {
+        warn!("[PREVIOUS CONNECTION] Previous connection died");
+        self.connection_status.lock()?.set_prev_offline();
+        if !*self.have_token.lock()? {
+            warn!(
+                "[PREVIOUS CONNECTION] I don't have the token, maybe we lost the token, sending message"
+            );
+            let to_id = self.listening_to_id.unwrap_or(self.my_id);
+            self.to_next_sender
+                .send(create_maybe_we_lost_the_token_message(self.my_id, to_id))?;
+        } else {
+            info!("[PREVIOUS CONNECTION] Previous connection died but i have the token");
+        }
+        Ok(())
+    }
 
-            match &mut message.message_type {
-                ServerMessageType::NewConnection(diff) => {
-                    info!(
-                        "[PREVIOUS CONNECTION] Received new connection message from {}",
-                        message.sender_id
-                    );
-                    self.set_listening_to_id(&message.passed_by, message.sender_id);
-                    if message.sender_id == self.my_id {
-                        self.update_myself_by_diff(diff);
-                        continue;
-                    }
-                    if message.passed_by.contains(&self.my_id) {
-                        debug!(
-                            "[PREVIOUS CONNECTION] I have already seen this message, dropping..."
-                        );
-                        continue;
-                    }
-                    self.to_next_sender.send(message)?;
+    /// Procesa un mensaje recibido del anterior. Devuelve true si el anterior cerro la conexion
+    /// y se debe dejar de escuchar
+    pub fn handle_message(&mut self, encoded: &[u8]) -> Result<bool, CoffeeSystemError> {
+        let result = self.connection.codec().decode(encoded);
+        if result.is_err() {
+            error!("[PREVIOUS CONNECTION] Error deserializing the message");
+            return Ok(false);
+        }
+        let mut message: ServerMessage = result.unwrap();
+        self.merge_membership(&message.membership)?;
+
+        match &mut message.message_type {
+            ServerMessageType::NewConnection(diff) => {
+                info!(
+                    "[PREVIOUS CONNECTION] Received new connection message from {}",
+                    message.sender_id
+                );
+                self.advance_clock_with_diff(diff);
+                self.set_listening_to_id(&message.passed_by, message.sender_id);
+                if message.sender_id == self.my_id {
+                    self.update_myself_by_diff(diff);
+                    return Ok(false);
                 }
-                ServerMessageType::CloseConnection => {
-                    info!(
-                        "[PREVIOUS CONNECTION] Received close connection from {}",
-                        message.sender_id
-                    );
+                if message.passed_by.contains(&self.my_id) {
+                    debug!("[PREVIOUS CONNECTION] I have already seen this message, dropping...");
+                    return Ok(false);
+                }
+                self.to_next_sender.send(message)?;
+            }
+            ServerMessageType::CloseConnection => {
+                info!(
+                    "[PREVIOUS CONNECTION] Received close connection from {}",
+                    message.sender_id
+                );
+                let membership = self.membership.lock()?;
+                if membership.has_left(self.my_id) {
+                    // Nos estamos yendo de la red, el anterior ya no nos envia mensajes
+                    self.to_next_sender
+                        .send(create_close_connection_message(self.my_id))?;
+                }
+                // Si el que cierra se fue de la red, el nuevo anterior ya se esta conectando
+                if !membership.has_left(message.sender_id) {
                     self.connection_status.lock()?.set_prev_offline();
-                    return Ok(());
                 }
-                ServerMessageType::Token(data) => {
-                    self.set_listening_to_id(&message.passed_by, message.sender_id);
-                    debug!(
-                        "[PREVIOUS CONNECTION] Received the token from {}",
-                        message.sender_id
-                    );
-                    *self.have_token.lock()? = true;
-                    self.receive_update_of_other_nodes_and_clean_my_updates(data);
-                    self.to_orders_manager_sender.send(data.to_owned())?;
+                return Ok(true);
+            }
+            ServerMessageType::Leave => {
+                self.set_listening_to_id(&message.passed_by, message.sender_id);
+                info!(
+                    "[PREVIOUS CONNECTION] Server {} is leaving the ring",
+                    message.sender_id
+                );
+                if message.sender_id == self.my_id || message.passed_by.contains(&self.my_id) {
+                    debug!("[PREVIOUS CONNECTION] I have already seen this message, dropping...");
+                    return Ok(false);
                 }
-                ServerMessageType::MaybeWeLostTheTokenTo(lost_id) => {
-                    self.set_listening_to_id(&message.passed_by, message.sender_id);
-                    debug!(
-                        "[PREVIOUS CONNECTION] Received maybe we lost the token to {} from {}",
-                        lost_id, message.sender_id
+                self.to_next_sender.send(message)?;
+            }
+            ServerMessageType::Election(candidate) => {
+                self.set_listening_to_id(&message.passed_by, message.sender_id);
+                debug!(
+                    "[PREVIOUS CONNECTION] Received election with candidate {} from {}",
+                    candidate.id, message.sender_id
+                );
+                self.to_next_sender.send(message)?;
+            }
+            ServerMessageType::Elected(leader_id) => {
+                self.set_listening_to_id(&message.passed_by, message.sender_id);
+                debug!(
+                    "[PREVIOUS CONNECTION] Received elected {} from {}",
+                    leader_id, message.sender_id
+                );
+                self.to_next_sender.send(message)?;
+            }
+            ServerMessageType::Join(_) | ServerMessageType::JoinAccepted => {
+                warn!(
+                    "[PREVIOUS CONNECTION] Unexpected join message from {}, ignoring",
+                    message.sender_id
+                );
+            }
+            ServerMessageType::Token(token) => {
+                self.set_listening_to_id(&message.passed_by, message.sender_id);
+                debug!(
+                    "[PREVIOUS CONNECTION] Received the token from {}",
+                    message.sender_id
+                );
+                if self.is_stale_token(token.generation)? {
+                    warn!(
+                        "[PREVIOUS CONNECTION] Dropping token of stale generation {} from {}",
+                        token.generation, message.sender_id
                     );
-                    if *self.have_token.lock()? {
-                        info!("[PREVIOUS CONNECTION] I have the token, we did't lost it");
-                        continue;
-                    }
-                    if message.sender_id == self.my_id || message.passed_by.contains(&self.my_id) {
-                        debug!(
-                            "[PREVIOUS CONNECTION] I have already seen this message, dropping..."
-                        );
-                        continue;
-                    }
-                    warn!("[PREVIOUS CONNECTION] I don't have the token, maybe we lost it");
-                    self.to_next_sender.send(message)?;
+                    return Ok(false);
+                }
+                *self.have_token.lock()? = true;
+                self.advance_clock_with_token(&token.data);
+                self.receive_update_of_other_nodes_and_clean_my_updates(&mut token.data);
+                self.remember_applied_requests(&token.data);
+                self.to_orders_manager_sender.send(token.to_owned())?;
+            }
+            ServerMessageType::MaybeWeLostTheTokenTo(lost_id) => {
+                self.set_listening_to_id(&message.passed_by, message.sender_id);
+                debug!(
+                    "[PREVIOUS CONNECTION] Received maybe we lost the token to {} from {}",
+                    lost_id, message.sender_id
+                );
+                if *self.have_token.lock()? {
+                    info!("[PREVIOUS CONNECTION] I have the token, we did't lost it");
+                    return Ok(false);
+                }
+                if message.sender_id == self.my_id || message.passed_by.contains(&self.my_id) {
+                    debug!("[PREVIOUS CONNECTION] I have already seen this message, dropping...");
+                    return Ok(false);
+                }
+                warn!("[PREVIOUS CONNECTION] I don't have the token, maybe we lost it");
+                self.to_next_sender.send(message)?;
+            }
+        }
+        Ok(false)
+    }
+
+    /// Actualiza la vista de miembros propia con la que trae el mensaje
+    fn merge_membership(&mut self, membership: &Membership) -> Result<(), CoffeeSystemError> {
+        let mut view = self.membership.lock()?;
+        if view.merge(membership, self.my_id) {
+            debug!(
+                "[PREVIOUS CONNECTION] Membership updated to version {}, active members {:?}",
+                view.version(),
+                view.active_members()
+            );
+        }
+        Ok(())
+    }
+
+    /// Indica si el token es de una generacion anterior a la mas nueva que conocemos.
+    /// Si no lo es, se guarda su generacion como la actual
+    fn is_stale_token(&mut self, generation: Generation) -> Result<bool, CoffeeSystemError> {
+        let mut current_generation = self.token_generation.lock()?;
+        if generation < *current_generation {
+            return Ok(true);
+        }
+        *current_generation = generation;
+        Ok(false)
+    }
+
+    /// Avanza el reloj con las marcas de las operaciones del token, asi las operaciones locales siguientes
+    /// quedan ordenadas despues de ellas aunque nuestro reloj este atrasado
+    fn advance_clock_with_token(&self, data: &TokenData) {
+        if let Ok(mut clock) = self.clock.lock() {
+            for action in data.values().flatten() {
+                clock.update(action.last_updated_on);
+            }
+        } else {
+            error!("[PREVIOUS CONNECTION] Error locking the clock to advance it with the token");
+        }
+    }
+
+    /// Avanza el reloj con las marcas del mensaje de nueva conexion
+    fn advance_clock_with_diff(&self, diff: &Diff) {
+        if let Ok(mut clock) = self.clock.lock() {
+            clock.update(diff.last_update);
+            for change in &diff.changes {
+                clock.update(change.last_updated_on);
+            }
+        } else {
+            error!("[PREVIOUS CONNECTION] Error locking the clock to advance it with the diff");
+        }
+    }
+
+    /// Guarda los ids de los pedidos aplicados en otros servidores, asi se reconoce si una cafetera
+    /// reintenta en esta sucursal un pedido que ya se aplico en otra
+    fn remember_applied_requests(&self, data: &TokenData) {
+        if let Ok(mut dedup_cache) = self.dedup_cache.lock() {
+            for action in data.values().flatten() {
+                for request_id in &action.request_ids {
+                    dedup_cache.insert_applied(*request_id, action.message_type);
                 }
             }
+        } else {
+            error!("[PREVIOUS CONNECTION] Error locking the dedup cache to save applied requests");
         }
     }
 
@@ -145,6 +301,9 @@ impl PrevConnection {
         if self.listening_to_id.is_none() && passed_by.is_empty() {
             info!("[PREVIOUS CONNECTION] My previous connection is {}", sender);
             self.listening_to_id = Some(sender);
+            if let Ok(mut connection_status) = self.connection_status.lock() {
+                connection_status.set_prev_id(sender);
+            }
         }
     }
 
@@ -156,8 +315,14 @@ impl PrevConnection {
         );
         if let Ok(mut guard) = self.accounts_manager.lock() {
             for update in &diff.changes {
-                guard.update(update.id, update.amount, update.last_updated_on);
+                if let Err(e) = guard.update(update.id, update.amount, update.last_updated_on) {
+                    error!(
+                        "[PREVIOUS CONNECTION] Error updating account {} with diff data, {:?}",
+                        update.id, e
+                    );
+                }
             }
+            guard.record_actions(&diff.history);
         } else {
             error!("[PREVIOUS CONNECTION] Error updating myself with diff data due to lock error");
         }
@@ -166,15 +331,19 @@ impl PrevConnection {
     fn receive_update_of_other_nodes_and_clean_my_updates(&mut self, data: &mut TokenData) {
         data.remove(&self.my_id);
         if let Ok(mut guard) = self.accounts_manager.lock() {
-            for (server_id, changes) in data {
+            for (server_id, changes) in data.iter() {
                 debug!(
                     "[PREVIOUS CONNECTION] There are updates from server {}",
                     server_id
                 );
                 debug!("[PREVIOUS CONNECTION] List of changes {:?}", changes);
-                for update in changes {
-                    update_account_with_change(update, &mut guard);
-                }
+            }
+            // Las cuentas descartan las operaciones anteriores a su ultima actualizacion, asi que se aplican
+            // en el orden de sus marcas de tiempo y no en el de los servidores que las hicieron
+            let mut changes: Vec<&mut AccountAction> = data.values_mut().flatten().collect();
+            changes.sort_by_key(|update| update.last_updated_on);
+            for update in changes {
+                update_account_with_change(update, &mut guard);
             }
         } else {
             error!("[PREVIOUS CONNECTION] Error locking accounts manager to receive changes")
@@ -184,34 +353,21 @@ impl PrevConnection {
 
 fn update_account_with_change(
     update: &mut AccountAction,
-    guard: &mut std::sync::MutexGuard<MemoryAccountsManager>,
+    guard: &mut std::sync::MutexGuard<Box<dyn AccountsManager>>,
 ) {
-    match update.message_type {
-        MessageType::AddPoints => {
-            if guard
-                .add_points(
-                    update.account_id,
-                    update.points,
-                    Some(update.last_updated_on),
-                )
-                .is_err()
-            {
-                warn!("[PREVIOUS CONNECTION] Unable to handle add points message");
-            }
-        }
-        MessageType::TakePoints => {
-            if guard
-                .substract_points(
-                    update.account_id,
-                    update.points,
-                    Some(update.last_updated_on),
-                )
-                .is_err()
-            {
-                warn!("[PREVIOUS CONNECTION] Unable to handle subtract points message");
-            }
-        }
-        _ => {}
+    if !matches!(
+        update.message_type,
+        MessageType::AddPoints | MessageType::TakePoints
+    ) {
+        return;
+    }
+    if let Err(e) = guard.apply_action(update, None) {
+        warn!(
+            "[PREVIOUS CONNECTION] Unable to apply {:?} of account {}, {:?}",
+            update.message_type, update.account_id, e
+        );
+        // Se registra aunque no se haya podido aplicar localmente, la operacion ya fue aplicada en su servidor de origen
+        guard.record_action(update.clone());
     }
 }
 
@@ -220,22 +376,28 @@ mod tests {
     use std::sync::mpsc;
 
     use super::*;
-    use lib::{connection_protocol::MockConnectionProtocol, serializer::serialize};
+    use lib::{
+        codec::Codec, connection_protocol::MockConnectionProtocol,
+        local_connection_messages::RequestId,
+    };
     use mockall::Sequence;
 
-    use crate::server_messages::{
-        create_close_connection_message, create_token_message, UpdatedAccount,
+    use crate::{
+        hybrid_clock::HybridTimestamp,
+        memory_accounts_manager::MemoryAccountsManager,
+        server_messages::{create_leave_message, create_token_message, UpdatedAccount},
     };
 
     #[test]
     fn should_receive_close_connection_and_terminate() {
         let mut connection = MockConnectionProtocol::new();
+        connection.expect_codec().return_const(Codec::JsonLine);
 
         connection.expect_recv().returning(|| {
-            let encoded =
-                serialize(&create_close_connection_message(1)).expect("Error serializing");
-            let recv_return = String::from_utf8(encoded);
-            Ok(recv_return.expect("Error converting message"))
+            let encoded = Codec::JsonLine
+                .encode(&create_close_connection_message(1))
+                .expect("Error serializing");
+            Ok(encoded)
         });
 
         let (to_next_channel, _) = mpsc::channel();
@@ -244,7 +406,8 @@ mod tests {
         let connection_status = Arc::new(Mutex::new(ConnectionStatus::new()));
         let have_token = Arc::new(Mutex::new(false));
 
-        let accounts_manager = Arc::new(Mutex::new(MemoryAccountsManager::new()));
+        let accounts_manager: Arc<Mutex<Box<dyn AccountsManager>>> =
+            Arc::new(Mutex::new(Box::new(MemoryAccountsManager::new())));
 
         let mut previous = PrevConnection::new(
             Box::new(connection),
@@ -254,6 +417,10 @@ mod tests {
             0,
             have_token.clone(),
             accounts_manager.clone(),
+            Arc::new(Mutex::new(Membership::default())),
+            Arc::new(Mutex::new(Generation::new(0, 0))),
+            Arc::new(Mutex::new(HybridClock::new(0))),
+            Arc::new(Mutex::new(DedupCache::new(10))),
         );
 
         let result = previous.listen();
@@ -268,6 +435,7 @@ mod tests {
     #[test]
     fn should_send_maybe_lost_token_if_it_loses_connection_without_the_token() {
         let mut connection = MockConnectionProtocol::new();
+        connection.expect_codec().return_const(Codec::JsonLine);
 
         connection
             .expect_recv()
@@ -279,7 +447,8 @@ mod tests {
         let connection_status = Arc::new(Mutex::new(ConnectionStatus::new()));
         let have_token = Arc::new(Mutex::new(false));
 
-        let accounts_manager = Arc::new(Mutex::new(MemoryAccountsManager::new()));
+        let accounts_manager: Arc<Mutex<Box<dyn AccountsManager>>> =
+            Arc::new(Mutex::new(Box::new(MemoryAccountsManager::new())));
 
         let mut previous = PrevConnection::new(
             Box::new(connection),
@@ -289,6 +458,10 @@ mod tests {
             0,
             have_token.clone(),
             accounts_manager.clone(),
+            Arc::new(Mutex::new(Membership::default())),
+            Arc::new(Mutex::new(Generation::new(0, 0))),
+            Arc::new(Mutex::new(HybridClock::new(0))),
+            Arc::new(Mutex::new(DedupCache::new(10))),
         );
         previous.listening_to_id = Some(1);
         let result = previous.listen();
@@ -308,6 +481,7 @@ mod tests {
     #[test]
     fn should_not_send_maybe_lost_token_if_it_loses_connection_with_the_token() {
         let mut connection = MockConnectionProtocol::new();
+        connection.expect_codec().return_const(Codec::JsonLine);
 
         connection
             .expect_recv()
@@ -319,7 +493,8 @@ mod tests {
         let connection_status = Arc::new(Mutex::new(ConnectionStatus::new()));
         let have_token = Arc::new(Mutex::new(true));
 
-        let accounts_manager = Arc::new(Mutex::new(MemoryAccountsManager::new()));
+        let accounts_manager: Arc<Mutex<Box<dyn AccountsManager>>> =
+            Arc::new(Mutex::new(Box::new(MemoryAccountsManager::new())));
 
         let mut previous = PrevConnection::new(
             Box::new(connection),
@@ -329,6 +504,10 @@ mod tests {
             0,
             have_token.clone(),
             accounts_manager.clone(),
+            Arc::new(Mutex::new(Membership::default())),
+            Arc::new(Mutex::new(Generation::new(0, 0))),
+            Arc::new(Mutex::new(HybridClock::new(0))),
+            Arc::new(Mutex::new(DedupCache::new(10))),
         );
         previous.listening_to_id = Some(1);
         let result = previous.listen();
@@ -345,16 +524,17 @@ mod tests {
     #[test]
     fn should_recv_maybe_lost_token_and_pass_it_to_the_next_if_it_does_not_have_it() {
         let mut connection = MockConnectionProtocol::new();
+        connection.expect_codec().return_const(Codec::JsonLine);
         let mut seq = Sequence::new();
 
         connection
             .expect_recv()
             .times(1)
             .returning(|| {
-                let encoded = serialize(&create_maybe_we_lost_the_token_message(3, 2))
+                let encoded = Codec::JsonLine
+                    .encode(&create_maybe_we_lost_the_token_message(3, 2))
                     .expect("Error serializing");
-                let recv_return = String::from_utf8(encoded);
-                Ok(recv_return.expect("Error converting message"))
+                Ok(encoded)
             })
             .in_sequence(&mut seq);
 
@@ -362,10 +542,10 @@ mod tests {
             .expect_recv()
             .times(1)
             .returning(|| {
-                let encoded =
-                    serialize(&create_close_connection_message(1)).expect("Error serializing");
-                let recv_return = String::from_utf8(encoded);
-                Ok(recv_return.expect("Error converting message"))
+                let encoded = Codec::JsonLine
+                    .encode(&create_close_connection_message(1))
+                    .expect("Error serializing");
+                Ok(encoded)
             })
             .in_sequence(&mut seq);
 
@@ -375,7 +555,8 @@ mod tests {
         let connection_status = Arc::new(Mutex::new(ConnectionStatus::new()));
         let have_token = Arc::new(Mutex::new(false));
 
-        let accounts_manager = Arc::new(Mutex::new(MemoryAccountsManager::new()));
+        let accounts_manager: Arc<Mutex<Box<dyn AccountsManager>>> =
+            Arc::new(Mutex::new(Box::new(MemoryAccountsManager::new())));
 
         let mut previous = PrevConnection::new(
             Box::new(connection),
@@ -385,6 +566,10 @@ mod tests {
             0,
             have_token.clone(),
             accounts_manager.clone(),
+            Arc::new(Mutex::new(Membership::default())),
+            Arc::new(Mutex::new(Generation::new(0, 0))),
+            Arc::new(Mutex::new(HybridClock::new(0))),
+            Arc::new(Mutex::new(DedupCache::new(10))),
         );
         previous.listening_to_id = Some(3);
         let result = previous.listen();
@@ -404,15 +589,17 @@ mod tests {
     #[test]
     fn should_pass_the_token_if_it_receives_the_message() {
         let mut connection = MockConnectionProtocol::new();
+        connection.expect_codec().return_const(Codec::JsonLine);
         let mut seq = Sequence::new();
 
         connection
             .expect_recv()
             .times(1)
             .returning(|| {
-                let encoded = serialize(&create_token_message(0)).expect("Error serializing");
-                let recv_return = String::from_utf8(encoded);
-                Ok(recv_return.expect("Error converting message"))
+                let encoded = Codec::JsonLine
+                    .encode(&create_token_message(0, Generation::new(0, 0)))
+                    .expect("Error serializing");
+                Ok(encoded)
             })
             .in_sequence(&mut seq);
 
@@ -420,10 +607,10 @@ mod tests {
             .expect_recv()
             .times(1)
             .returning(|| {
-                let encoded =
-                    serialize(&create_close_connection_message(1)).expect("Error serializing");
-                let recv_return = String::from_utf8(encoded);
-                Ok(recv_return.expect("Error converting message"))
+                let encoded = Codec::JsonLine
+                    .encode(&create_close_connection_message(1))
+                    .expect("Error serializing");
+                Ok(encoded)
             })
             .in_sequence(&mut seq);
 
@@ -433,7 +620,8 @@ mod tests {
         let connection_status = Arc::new(Mutex::new(ConnectionStatus::new()));
         let have_token = Arc::new(Mutex::new(false));
 
-        let accounts_manager = Arc::new(Mutex::new(MemoryAccountsManager::new()));
+        let accounts_manager: Arc<Mutex<Box<dyn AccountsManager>>> =
+            Arc::new(Mutex::new(Box::new(MemoryAccountsManager::new())));
 
         let mut previous = PrevConnection::new(
             Box::new(connection),
@@ -443,6 +631,10 @@ mod tests {
             0,
             have_token.clone(),
             accounts_manager.clone(),
+            Arc::new(Mutex::new(Membership::default())),
+            Arc::new(Mutex::new(Generation::new(0, 0))),
+            Arc::new(Mutex::new(HybridClock::new(0))),
+            Arc::new(Mutex::new(DedupCache::new(10))),
         );
         previous.listening_to_id = Some(3);
         let result = previous.listen();
@@ -459,6 +651,7 @@ mod tests {
     #[test]
     fn should_recv_the_new_connection_msg_and_update_itself() {
         let mut connection = MockConnectionProtocol::new();
+        connection.expect_codec().return_const(Codec::JsonLine);
         let mut seq = Sequence::new();
 
         connection
@@ -466,21 +659,30 @@ mod tests {
             .times(1)
             .returning(|| {
                 let diff = Diff {
-                    last_update: 0,
+                    last_update: HybridTimestamp::default(),
                     changes: vec![UpdatedAccount {
                         id: 1,
                         amount: 10,
-                        last_updated_on: 10,
+                        last_updated_on: HybridTimestamp::new(10, 0, 1),
+                    }],
+                    history: vec![AccountAction {
+                        message_type: MessageType::AddPoints,
+                        account_id: 1,
+                        points: 10,
+                        last_updated_on: HybridTimestamp::new(10, 0, 1),
+                        origin_server_id: 1,
+                        coffee_maker_id: 2,
+                        request_ids: vec![],
                     }],
                 };
                 let request = ServerMessage {
                     message_type: ServerMessageType::NewConnection(diff),
                     sender_id: 0,
                     passed_by: HashSet::new(),
+                    membership: Membership::default(),
                 };
-                let encoded = serialize(&request).expect("Error serializing");
-                let recv_return = String::from_utf8(encoded);
-                Ok(recv_return.expect("Error converting message"))
+                let encoded = Codec::JsonLine.encode(&request).expect("Error serializing");
+                Ok(encoded)
             })
             .in_sequence(&mut seq);
 
@@ -488,10 +690,10 @@ mod tests {
             .expect_recv()
             .times(1)
             .returning(|| {
-                let encoded =
-                    serialize(&create_close_connection_message(1)).expect("Error serializing");
-                let recv_return = String::from_utf8(encoded);
-                Ok(recv_return.expect("Error converting message"))
+                let encoded = Codec::JsonLine
+                    .encode(&create_close_connection_message(1))
+                    .expect("Error serializing");
+                Ok(encoded)
             })
             .in_sequence(&mut seq);
 
@@ -501,7 +703,8 @@ mod tests {
         let connection_status = Arc::new(Mutex::new(ConnectionStatus::new()));
         let have_token = Arc::new(Mutex::new(false));
 
-        let accounts_manager = Arc::new(Mutex::new(MemoryAccountsManager::new()));
+        let accounts_manager: Arc<Mutex<Box<dyn AccountsManager>>> =
+            Arc::new(Mutex::new(Box::new(MemoryAccountsManager::new())));
 
         let mut previous = PrevConnection::new(
             Box::new(connection),
@@ -511,6 +714,10 @@ mod tests {
             0,
             have_token.clone(),
             accounts_manager.clone(),
+            Arc::new(Mutex::new(Membership::default())),
+            Arc::new(Mutex::new(Generation::new(0, 0))),
+            Arc::new(Mutex::new(HybridClock::new(0))),
+            Arc::new(Mutex::new(DedupCache::new(10))),
         );
 
         let result = previous.listen();
@@ -521,11 +728,326 @@ mod tests {
             .expect("Lock error")
             .is_prev_online());
         assert_eq!(
-            10,
+            HybridTimestamp::new(10, 0, 1),
             accounts_manager
                 .lock()
                 .expect("Lock error")
                 .get_most_recent_update()
         );
+        let history = accounts_manager
+            .lock()
+            .expect("Lock error")
+            .get_history(1, 0, 10);
+        assert_eq!(1, history.len());
+        assert_eq!(2, history[0].coffee_maker_id);
+    }
+
+    #[test]
+    fn should_update_the_membership_and_pass_the_leave_message_to_the_next() {
+        let mut connection = MockConnectionProtocol::new();
+        connection.expect_codec().return_const(Codec::JsonLine);
+        let mut seq = Sequence::new();
+
+        connection
+            .expect_recv()
+            .times(1)
+            .returning(|| {
+                let mut membership = Membership::default();
+                membership.join(0, String::from("127.0.0.1:10000"));
+                membership.join(2, String::from("127.0.0.1:10002"));
+                membership.leave(2);
+                let mut message = create_leave_message(2);
+                message.membership = membership;
+                let encoded = Codec::JsonLine.encode(&message).expect("Error serializing");
+                Ok(encoded)
+            })
+            .in_sequence(&mut seq);
+
+        connection
+            .expect_recv()
+            .times(1)
+            .returning(|| Err(CoffeeSystemError::ConnectionLost))
+            .in_sequence(&mut seq);
+
+        let (to_next_channel, to_next_sender_msg) = mpsc::channel();
+        let (to_orders_manager_channel, _) = mpsc::channel();
+
+        let connection_status = Arc::new(Mutex::new(ConnectionStatus::new()));
+        let have_token = Arc::new(Mutex::new(true));
+
+        let accounts_manager: Arc<Mutex<Box<dyn AccountsManager>>> =
+            Arc::new(Mutex::new(Box::new(MemoryAccountsManager::new())));
+        let membership = Arc::new(Mutex::new(Membership::default()));
+
+        let mut previous = PrevConnection::new(
+            Box::new(connection),
+            to_next_channel,
+            to_orders_manager_channel,
+            connection_status.clone(),
+            0,
+            have_token.clone(),
+            accounts_manager.clone(),
+            membership.clone(),
+            Arc::new(Mutex::new(Generation::new(0, 0))),
+            Arc::new(Mutex::new(HybridClock::new(0))),
+            Arc::new(Mutex::new(DedupCache::new(10))),
+        );
+        let result = previous.listen();
+
+        assert!(result.is_err());
+        let msg = to_next_sender_msg.try_recv().expect("No message present");
+        assert_eq!(ServerMessageType::Leave, msg.message_type);
+        assert_eq!(2, msg.sender_id);
+        let membership = membership.lock().expect("Lock error");
+        assert!(membership.has_left(2));
+        assert_eq!(vec![0], membership.active_members());
+    }
+
+    #[test]
+    fn should_close_the_next_connection_if_it_is_leaving_and_the_previous_closes() {
+        let mut connection = MockConnectionProtocol::new();
+        connection.expect_codec().return_const(Codec::JsonLine);
+
+        connection.expect_recv().returning(|| {
+            let encoded = Codec::JsonLine
+                .encode(&create_close_connection_message(1))
+                .expect("Error serializing");
+            Ok(encoded)
+        });
+
+        let (to_next_channel, to_next_sender_msg) = mpsc::channel();
+        let (to_orders_manager_channel, _) = mpsc::channel();
+
+        let connection_status = Arc::new(Mutex::new(ConnectionStatus::new()));
+        let have_token = Arc::new(Mutex::new(false));
+
+        let accounts_manager: Arc<Mutex<Box<dyn AccountsManager>>> =
+            Arc::new(Mutex::new(Box::new(MemoryAccountsManager::new())));
+        let mut membership = Membership::default();
+        membership.join(0, String::from("127.0.0.1:10000"));
+        membership.join(1, String::from("127.0.0.1:10001"));
+        membership.leave(0);
+
+        let mut previous = PrevConnection::new(
+            Box::new(connection),
+            to_next_channel,
+            to_orders_manager_channel,
+            connection_status.clone(),
+            0,
+            have_token.clone(),
+            accounts_manager.clone(),
+            Arc::new(Mutex::new(membership)),
+            Arc::new(Mutex::new(Generation::new(0, 0))),
+            Arc::new(Mutex::new(HybridClock::new(0))),
+            Arc::new(Mutex::new(DedupCache::new(10))),
+        );
+        let result = previous.listen();
+
+        assert!(result.is_ok());
+        assert!(!connection_status
+            .lock()
+            .expect("Lock error")
+            .is_prev_online());
+        let msg = to_next_sender_msg.try_recv().expect("No message present");
+        assert_eq!(ServerMessageType::CloseConnection, msg.message_type);
+        assert_eq!(0, msg.sender_id);
+    }
+
+    #[test]
+    fn should_keep_a_single_copy_when_two_servers_regenerate_the_token_at_once() {
+        let mut connection = MockConnectionProtocol::new();
+        connection.expect_codec().return_const(Codec::JsonLine);
+        // 3 y 1 regeneraron el token a la vez, las dos copias llevan la misma cantidad de regeneraciones
+        let mut messages = vec![
+            create_close_connection_message(1),
+            create_token_message(1, Generation::new(1, 1)),
+            create_token_message(3, Generation::new(1, 3)),
+        ];
+        connection.expect_recv().times(3).returning(move || {
+            let message = messages.pop().expect("No more messages");
+            Ok(Codec::JsonLine.encode(&message).expect("Error serializing"))
+        });
+
+        let (to_next_channel, _) = mpsc::channel();
+        let (to_orders_manager_channel, to_orders_recv) = mpsc::channel();
+        let token_generation = Arc::new(Mutex::new(Generation::default()));
+        let accounts_manager: Arc<Mutex<Box<dyn AccountsManager>>> =
+            Arc::new(Mutex::new(Box::new(MemoryAccountsManager::new())));
+
+        let mut previous = PrevConnection::new(
+            Box::new(connection),
+            to_next_channel,
+            to_orders_manager_channel,
+            Arc::new(Mutex::new(ConnectionStatus::new())),
+            0,
+            Arc::new(Mutex::new(false)),
+            accounts_manager,
+            Arc::new(Mutex::new(Membership::default())),
+            token_generation.clone(),
+            Arc::new(Mutex::new(HybridClock::new(0))),
+            Arc::new(Mutex::new(DedupCache::new(10))),
+        );
+        let result = previous.listen();
+
+        assert!(result.is_ok());
+        let token = to_orders_recv.try_recv().expect("No token present");
+        assert_eq!(Generation::new(1, 3), token.generation);
+        assert!(to_orders_recv.try_recv().is_err());
+        assert_eq!(
+            Generation::new(1, 3),
+            *token_generation.lock().expect("Lock error")
+        );
+    }
+
+    #[test]
+    fn should_drop_a_delayed_token_that_arrives_after_a_regenerated_copy() {
+        let mut connection = MockConnectionProtocol::new();
+        connection.expect_codec().return_const(Codec::JsonLine);
+        let mut seq = Sequence::new();
+
+        connection
+            .expect_recv()
+            .times(1)
+            .returning(|| {
+                let encoded = Codec::JsonLine
+                    .encode(&create_token_message(3, Generation::new(1, 0)))
+                    .expect("Error serializing");
+                Ok(encoded)
+            })
+            .in_sequence(&mut seq);
+
+        connection
+            .expect_recv()
+            .times(1)
+            .returning(|| {
+                let encoded = Codec::JsonLine
+                    .encode(&create_token_message(3, Generation::new(0, 0)))
+                    .expect("Error serializing");
+                Ok(encoded)
+            })
+            .in_sequence(&mut seq);
+
+        connection
+            .expect_recv()
+            .times(1)
+            .returning(|| {
+                let encoded = Codec::JsonLine
+                    .encode(&create_close_connection_message(1))
+                    .expect("Error serializing");
+                Ok(encoded)
+            })
+            .in_sequence(&mut seq);
+
+        let (to_next_channel, _) = mpsc::channel();
+        let (to_orders_manager_channel, to_orders_recv) = mpsc::channel();
+
+        let connection_status = Arc::new(Mutex::new(ConnectionStatus::new()));
+        let have_token = Arc::new(Mutex::new(false));
+        let token_generation = Arc::new(Mutex::new(Generation::new(0, 0)));
+
+        let accounts_manager: Arc<Mutex<Box<dyn AccountsManager>>> =
+            Arc::new(Mutex::new(Box::new(MemoryAccountsManager::new())));
+
+        let mut previous = PrevConnection::new(
+            Box::new(connection),
+            to_next_channel,
+            to_orders_manager_channel,
+            connection_status.clone(),
+            0,
+            have_token.clone(),
+            accounts_manager.clone(),
+            Arc::new(Mutex::new(Membership::default())),
+            token_generation.clone(),
+            Arc::new(Mutex::new(HybridClock::new(0))),
+            Arc::new(Mutex::new(DedupCache::new(10))),
+        );
+        let result = previous.listen();
+
+        assert!(result.is_ok());
+        let token = to_orders_recv.try_recv().expect("No token present");
+        assert_eq!(Generation::new(1, 0), token.generation);
+        assert!(to_orders_recv.try_recv().is_err());
+        assert_eq!(
+            Generation::new(1, 0),
+            *token_generation.lock().expect("Lock error")
+        );
+    }
+
+    #[test]
+    fn should_remember_the_requests_applied_by_other_servers_in_the_token() {
+        let mut connection = MockConnectionProtocol::new();
+        connection
+            .expect_codec()
+            .return_const(Codec::LengthPrefixed);
+        let mut seq = Sequence::new();
+        let request_id = RequestId {
+            dispenser_id: 7,
+            sequence: 3,
+        };
+
+        connection
+            .expect_recv()
+            .times(1)
+            .returning(move || {
+                // El token llega con el codec binario negociado entre servidores
+                let mut message = create_token_message(1, Generation::new(0, 0));
+                if let ServerMessageType::Token(token) = &mut message.message_type {
+                    token.data.insert(
+                        1,
+                        vec![AccountAction {
+                            message_type: MessageType::AddPoints,
+                            account_id: 4,
+                            points: 10,
+                            last_updated_on: HybridTimestamp::new(10, 0, 1),
+                            origin_server_id: 1,
+                            coffee_maker_id: 0,
+                            request_ids: vec![request_id],
+                        }],
+                    );
+                }
+                let encoded = Codec::LengthPrefixed
+                    .encode(&message)
+                    .expect("Error serializing");
+                Ok(encoded)
+            })
+            .in_sequence(&mut seq);
+
+        connection
+            .expect_recv()
+            .times(1)
+            .returning(|| {
+                let encoded = Codec::LengthPrefixed
+                    .encode(&create_close_connection_message(1))
+                    .expect("Error serializing");
+                Ok(encoded)
+            })
+            .in_sequence(&mut seq);
+
+        let (to_next_channel, _) = mpsc::channel();
+        let (to_orders_manager_channel, _to_orders_recv) = mpsc::channel();
+        let dedup_cache = Arc::new(Mutex::new(DedupCache::new(10)));
+
+        let mut previous = PrevConnection::new(
+            Box::new(connection),
+            to_next_channel,
+            to_orders_manager_channel,
+            Arc::new(Mutex::new(ConnectionStatus::new())),
+            0,
+            Arc::new(Mutex::new(false)),
+            Arc::new(Mutex::new(Box::new(MemoryAccountsManager::new()))),
+            Arc::new(Mutex::new(Membership::default())),
+            Arc::new(Mutex::new(Generation::new(0, 0))),
+            Arc::new(Mutex::new(HybridClock::new(0))),
+            dedup_cache.clone(),
+        );
+        let result = previous.listen();
+
+        assert!(result.is_ok());
+        let replayed = dedup_cache
+            .lock()
+            .expect("Lock error")
+            .get(&request_id)
+            .expect("Request not remembered");
+        assert_eq!(MessageType::AddPoints, replayed.message_type);
     }
 }
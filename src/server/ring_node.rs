@@ -0,0 +1,191 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+};
+
+use lib::{
+    connection_protocol::ConnectionProtocol,
+    local_connection_messages::{CoffeeMakerRequest, CoffeeMakerResponse},
+};
+
+use crate::{
+    accounts_manager::AccountsManager,
+    coffee_message_dispatcher::CoffeeMessageDispatcher,
+    connection_server::Network,
+    connection_status::ConnectionStatus,
+    constants::DEDUP_CACHE_CAPACITY,
+    dedup_cache::DedupCache,
+    hybrid_clock::HybridClock,
+    membership::Membership,
+    metrics::Metrics,
+    next_connection::NextConnection,
+    offline_substract_orders_cleaner::SubstractOrdersCleaner,
+    orders_manager::OrdersManager,
+    orders_queue::OrdersQueue,
+    previous_connection::PrevConnection,
+    server_messages::{Generation, ServerMessage, Token},
+};
+
+/// Estado compartido entre los componentes de un servidor
+#[derive(Clone)]
+pub struct NodeState {
+    pub id: usize,
+    pub connection_status: Arc<Mutex<ConnectionStatus>>,
+    pub to_next_conn_sender: Sender<ServerMessage>,
+    pub to_orders_manager_sender: Sender<Token>,
+    pub have_token: Arc<Mutex<bool>>,
+    pub accounts_manager: Arc<Mutex<Box<dyn AccountsManager>>>,
+    pub membership: Arc<Mutex<Membership>>,
+    pub token_generation: Arc<Mutex<Generation>>,
+    pub leader: Arc<Mutex<Option<usize>>>,
+    pub clock: Arc<Mutex<HybridClock>>,
+    pub dedup_cache: Arc<Mutex<DedupCache>>,
+    pub orders: Arc<Mutex<OrdersQueue>>,
+    /// Indica que el servidor se esta retirando del anillo, no se aceptan cafeteras ni pedidos nuevos
+    pub shutting_down: Arc<Mutex<bool>>,
+    pub metrics: Arc<Mutex<Metrics>>,
+}
+
+impl NodeState {
+    /// Crea el receptor de los mensajes de una nueva conexion con el anterior
+    pub fn prev_connection(
+        &self,
+        connection: Box<dyn ConnectionProtocol + Send>,
+    ) -> PrevConnection {
+        PrevConnection::new(
+            connection,
+            self.to_next_conn_sender.clone(),
+            self.to_orders_manager_sender.clone(),
+            self.connection_status.clone(),
+            self.id,
+            self.have_token.clone(),
+            self.accounts_manager.clone(),
+            self.membership.clone(),
+            self.token_generation.clone(),
+            self.clock.clone(),
+            self.dedup_cache.clone(),
+        )
+    }
+}
+
+/// Componentes de un servidor ya conectados entre si por sus channels. El servidor corre cada uno en su hilo,
+/// la simulacion los ejecuta paso a paso
+pub struct RingNode {
+    pub state: NodeState,
+    pub next_connection: NextConnection,
+    pub orders_manager: OrdersManager,
+    pub dispatcher: CoffeeMessageDispatcher,
+    /// Canal por el que las conexiones con las cafeteras le envian sus pedidos al dispatcher
+    pub orders_from_coffee_sender: Sender<(CoffeeMakerRequest, usize)>,
+    pub machine_response_senders: Arc<Mutex<HashMap<usize, Sender<CoffeeMakerResponse>>>>,
+    /// Canal por el que el dispatcher le pasa al OrdersManager los resultados de los cafes con puntos
+    pub result_points_sender: Sender<(CoffeeMakerRequest, usize)>,
+    /// Canal por el que se responde a las cafeteras
+    pub request_points_result_sender: Sender<(CoffeeMakerResponse, usize)>,
+    pub request_points_result_receiver: Receiver<(CoffeeMakerResponse, usize)>,
+}
+
+impl RingNode {
+    pub fn new(
+        id: usize,
+        membership: Membership,
+        accounts_manager: Box<dyn AccountsManager>,
+        clock: HybridClock,
+        network: Arc<dyn Network>,
+    ) -> RingNode {
+        let membership = Arc::new(Mutex::new(membership));
+        let (to_next_conn_sender, next_conn_receiver) = mpsc::channel();
+        let (to_orders_manager_sender, orders_manager_receiver) = mpsc::channel();
+
+        let (request_points_result_sender, request_points_result_receiver) = mpsc::channel();
+        let (result_points_sender, result_points_receiver) = mpsc::channel();
+        let (orders_from_coffee_sender, orders_from_coffee_receiver) = mpsc::channel();
+
+        let state = NodeState {
+            id,
+            connection_status: Arc::new(Mutex::new(ConnectionStatus::new())),
+            to_next_conn_sender: to_next_conn_sender.clone(),
+            to_orders_manager_sender,
+            have_token: Arc::new(Mutex::new(false)),
+            accounts_manager: Arc::new(Mutex::new(accounts_manager)),
+            membership,
+            token_generation: Arc::new(Mutex::new(Generation::default())),
+            leader: Arc::new(Mutex::new(None)),
+            clock: Arc::new(Mutex::new(clock)),
+            dedup_cache: Arc::new(Mutex::new(DedupCache::new(DEDUP_CACHE_CAPACITY))),
+            orders: Arc::new(Mutex::new(OrdersQueue::new())),
+            shutting_down: Arc::new(Mutex::new(false)),
+            metrics: Arc::new(Mutex::new(Metrics::new())),
+        };
+
+        let orders = state.orders.clone();
+        let orders_manager = OrdersManager::new(
+            id,
+            orders.clone(),
+            orders_manager_receiver,
+            to_next_conn_sender,
+            request_points_result_sender.clone(),
+            result_points_receiver,
+            state.accounts_manager.clone(),
+            state.token_generation.clone(),
+            state.clock.clone(),
+            state.metrics.clone(),
+        );
+
+        let machine_response_senders = Arc::new(Mutex::new(HashMap::new()));
+        let dispatcher = CoffeeMessageDispatcher::new(
+            state.connection_status.clone(),
+            state.shutting_down.clone(),
+            orders.clone(),
+            orders_from_coffee_receiver,
+            machine_response_senders.clone(),
+            state.accounts_manager.clone(),
+            state.dedup_cache.clone(),
+        );
+
+        let offline_cleaner =
+            SubstractOrdersCleaner::new(orders, request_points_result_sender.clone());
+        let next_connection = NextConnection::new(
+            id,
+            state.membership.clone(),
+            next_conn_receiver,
+            state.connection_status.clone(),
+            state.have_token.clone(),
+            state.accounts_manager.clone(),
+            offline_cleaner,
+            state.token_generation.clone(),
+            state.leader.clone(),
+            state.metrics.clone(),
+            network,
+        );
+
+        RingNode {
+            state,
+            next_connection,
+            orders_manager,
+            dispatcher,
+            orders_from_coffee_sender,
+            machine_response_senders,
+            result_points_sender,
+            request_points_result_sender,
+            request_points_result_receiver,
+        }
+    }
+
+    /// Crea el servidor 0 de un anillo local de `peer_count` servidores, con la red en memoria. Para los tests
+    #[cfg(test)]
+    pub fn new_for_test(peer_count: usize, accounts_manager: Box<dyn AccountsManager>) -> RingNode {
+        RingNode::new(
+            0,
+            Membership::from_resolver(&crate::address_resolver::AddressResolver::new_local(
+                0, peer_count,
+            )),
+            accounts_manager,
+            HybridClock::new(0),
+            Arc::new(lib::connection_protocol::MemoryNetwork::new()),
+        )
+    }
+}
@@ -1,5 +1,16 @@
+use lib::connection_protocol::Transport;
+
 /// Argumentos que puede recibir la aplicacion de servidor local
 pub struct ServerArgs {
     pub id: usize,
-    pub peer_server_count: usize,
+    /// Cantidad de servidores con las direcciones por defecto. Si no se indica solo se conoce al servidor propio
+    pub peer_server_count: Option<usize>,
+    /// Directorio donde se persisten las cuentas. Si no se indica se guardan solo en memoria
+    pub data_dir: Option<String>,
+    /// Archivo con las direcciones de los servidores. Si no se indica se usan las direcciones locales por defecto
+    pub topology_file: Option<String>,
+    /// Direccion de un miembro de la red a traves del cual unirse al anillo
+    pub join_address: Option<String>,
+    /// Transporte de las conexiones con los demas servidores y las cafeteras, TCP por defecto
+    pub transport: Transport,
 }
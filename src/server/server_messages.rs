@@ -1,14 +1,27 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
 
-use lib::local_connection_messages::MessageType;
+use lib::{
+    codec::Codec,
+    common_errors::CoffeeSystemError,
+    handshake::Feature,
+    local_connection_messages::{MessageType, RequestId},
+};
 use serde::{Deserialize, Serialize};
 
-/// Representa al mensaje que se envian entre si los servidores locales
+use crate::{hybrid_clock::HybridTimestamp, membership::Membership};
+
+/// Representa al mensaje que se envian entre si los servidores locales.
+/// Cada mensaje lleva la vista de los miembros del anillo que tiene quien lo envia
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ServerMessage {
     pub message_type: ServerMessageType,
     pub sender_id: usize,
     pub passed_by: HashSet<usize>,
+    #[serde(default)]
+    pub membership: Membership,
 }
 
 /// Los diferentes tipos de mensajes que pueden enviarse los servidores locales
@@ -16,28 +29,185 @@ pub struct ServerMessage {
 pub enum ServerMessageType {
     NewConnection(Diff),
     CloseConnection,
-    Token(TokenData),
+    Token(Token),
     MaybeWeLostTheTokenTo(ServerId),
+    /// Pedido de un servidor para unirse a la red, incluye su direccion
+    Join(String),
+    /// Respuesta al pedido de union, en el mensaje viaja la vista de los miembros
+    JoinAccepted,
+    /// Aviso de que el servidor que envia el mensaje se va de la red
+    Leave,
+    /// Eleccion del servidor que genera el token (Chang-Roberts), circula con el mayor candidato visto
+    Election(Candidate),
+    /// Aviso del resultado de la eleccion, incluye el id del servidor elegido
+    Elected(ServerId),
+}
+
+impl ServerMessageType {
+    /// Funcionalidad opcional del protocolo que debe soportar quien recibe el mensaje para poder leerlo
+    pub fn required_feature(&self) -> Option<Feature> {
+        match self {
+            ServerMessageType::Join(_)
+            | ServerMessageType::JoinAccepted
+            | ServerMessageType::Leave => Some(Feature::DynamicMembership),
+            _ => None,
+        }
+    }
+}
+
+impl ServerMessage {
+    /// Codifica el mensaje para un servidor que usa la version del protocolo indicada.
+    /// En la version 1 la generacion del token es solo su contador
+    pub fn encode(
+        &self,
+        codec: Codec,
+        protocol_version: u32,
+    ) -> Result<Vec<u8>, CoffeeSystemError> {
+        if protocol_version >= 2 {
+            return codec.encode(self);
+        }
+        let message_type = match &self.message_type {
+            ServerMessageType::Token(token) => ServerMessageTypeV1::Token(TokenV1 {
+                generation: token.generation.counter,
+                data: &token.data,
+            }),
+            ServerMessageType::Election(candidate) => ServerMessageTypeV1::Election(CandidateV1 {
+                id: candidate.id,
+                known_generation: candidate.known_generation.counter,
+            }),
+            _ => return codec.encode(self),
+        };
+        codec.encode(&ServerMessageV1 {
+            message_type,
+            sender_id: self.sender_id,
+            passed_by: &self.passed_by,
+            membership: &self.membership,
+        })
+    }
+}
+
+/// Forma de la version 1 del protocolo de los mensajes que llevan una generacion, los demas no cambiaron
+#[derive(Serialize)]
+struct ServerMessageV1<'a> {
+    message_type: ServerMessageTypeV1<'a>,
+    sender_id: usize,
+    passed_by: &'a HashSet<usize>,
+    membership: &'a Membership,
+}
+
+#[derive(Serialize)]
+#[serde(rename = "ServerMessageType")]
+enum ServerMessageTypeV1<'a> {
+    Token(TokenV1<'a>),
+    Election(CandidateV1),
+}
+
+#[derive(Serialize)]
+#[serde(rename = "Token")]
+struct TokenV1<'a> {
+    generation: u64,
+    data: &'a TokenData,
+}
+
+#[derive(Serialize)]
+#[serde(rename = "Candidate")]
+struct CandidateV1 {
+    id: ServerId,
+    known_generation: u64,
 }
 
 type ServerId = usize;
 pub type TokenData = HashMap<usize, Vec<AccountAction>>;
 
-/// Representa un cambio a ejecutarse sobre una cuenta
+/// Token que circula por el anillo. La generacion aumenta cada vez que se regenera el token luego de una perdida,
+/// de esta forma se descartan las copias de generaciones anteriores que sigan circulando (ej. un token demorado)
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+pub struct Token {
+    pub generation: Generation,
+    pub data: TokenData,
+}
+
+/// Generacion de un token. Se compara primero por la cantidad de regeneraciones y luego por el servidor que lo genero,
+/// asi si dos servidores regeneran el token a la vez sus copias no quedan de la misma generacion y se descarta la del menor
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default,
+)]
+#[serde(from = "GenerationWire")]
+pub struct Generation {
+    pub counter: u64,
+    pub minted_by: ServerId,
+}
+
+/// Un servidor de la version 1 envia la generacion solo con su contador
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum GenerationWire {
+    Counter(u64),
+    Minted { counter: u64, minted_by: ServerId },
+}
+
+impl From<GenerationWire> for Generation {
+    /// Sin saber quien genero el token se lo toma como el mayor de su contador. Si no, el token que paso por un
+    /// servidor de la version 1 quedaria por debajo de la generacion que ya conocen los demas y se descartaria
+    fn from(wire: GenerationWire) -> Self {
+        match wire {
+            GenerationWire::Counter(counter) => Generation::new(counter, ServerId::MAX),
+            GenerationWire::Minted { counter, minted_by } => Generation::new(counter, minted_by),
+        }
+    }
+}
+
+impl Generation {
+    pub fn new(counter: u64, minted_by: ServerId) -> Generation {
+        Generation { counter, minted_by }
+    }
+
+    /// Devuelve la generacion siguiente, generada por el servidor indicado
+    pub fn next(&self, minted_by: ServerId) -> Generation {
+        Generation::new(self.counter + 1, minted_by)
+    }
+}
+
+impl fmt::Display for Generation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.minted_by == ServerId::MAX {
+            return write!(f, "{} (minted by a version 1 server)", self.counter);
+        }
+        write!(f, "{} (minted by {})", self.counter, self.minted_by)
+    }
+}
+
+/// Candidato de una eleccion. Incluye la generacion de token mas nueva que conocia quien inicio la eleccion,
+/// si un servidor ya vio un token de una generacion posterior la eleccion no es necesaria y se descarta
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct Candidate {
+    pub id: ServerId,
+    pub known_generation: Generation,
+}
+
+/// Representa un cambio a ejecutarse sobre una cuenta. Incluye el servidor y la cafetera donde se origino,
+/// de esta forma queda en la historia de la cuenta, y los ids de los pedidos que lo forman para reconocer sus reintentos
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct AccountAction {
     pub message_type: MessageType,
     pub account_id: usize,
     pub points: usize,
-    pub last_updated_on: u128,
+    pub last_updated_on: HybridTimestamp,
+    pub origin_server_id: usize,
+    pub coffee_maker_id: usize,
+    #[serde(default)]
+    pub request_ids: Vec<RequestId>,
 }
 
 /// Es parte del mensaje de nueva conexion, tiene la fecha mas reciente de actualizacion al enviarse desde el nodo inicial
 /// Al recibirlo cuando de la vuelta se le agregan las actualizaciones a partir de esa fecha
+/// Tambien se agregan las operaciones de la historia de las cuentas posteriores a esa fecha
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct Diff {
-    pub last_update: u128,
+    pub last_update: HybridTimestamp,
     pub changes: Vec<UpdatedAccount>,
+    #[serde(default)]
+    pub history: Vec<AccountAction>,
 }
 
 /// Representa al estado total de una cuenta
@@ -45,19 +215,27 @@ pub struct Diff {
 pub struct UpdatedAccount {
     pub id: usize,
     pub amount: usize,
-    pub last_updated_on: u128,
+    pub last_updated_on: HybridTimestamp,
 }
 
-pub fn create_new_connection_message(sender_id: usize, most_recent_update: u128) -> ServerMessage {
+pub fn create_new_connection_message(
+    sender_id: usize,
+    most_recent_update: HybridTimestamp,
+) -> ServerMessage {
     let diff = Diff {
         last_update: most_recent_update,
         changes: Vec::new(),
+        history: Vec::new(),
     };
     create_server_message(sender_id, ServerMessageType::NewConnection(diff))
 }
 
-pub fn create_token_message(sender_id: usize) -> ServerMessage {
-    create_server_message(sender_id, ServerMessageType::Token(HashMap::new()))
+pub fn create_token_message(sender_id: usize, generation: Generation) -> ServerMessage {
+    let token = Token {
+        generation,
+        data: HashMap::new(),
+    };
+    create_server_message(sender_id, ServerMessageType::Token(token))
 }
 
 pub fn create_maybe_we_lost_the_token_message(sender_id: usize, to_id: usize) -> ServerMessage {
@@ -68,8 +246,38 @@ pub fn create_close_connection_message(sender_id: usize) -> ServerMessage {
     create_server_message(sender_id, ServerMessageType::CloseConnection)
 }
 
-pub fn recreate_token(sender_id: usize, token_data: TokenData) -> ServerMessage {
-    create_server_message(sender_id, ServerMessageType::Token(token_data))
+pub fn create_join_message(sender_id: usize, address: String) -> ServerMessage {
+    create_server_message(sender_id, ServerMessageType::Join(address))
+}
+
+pub fn create_join_accepted_message(sender_id: usize, membership: Membership) -> ServerMessage {
+    let mut message = create_server_message(sender_id, ServerMessageType::JoinAccepted);
+    message.membership = membership;
+    message
+}
+
+pub fn create_leave_message(sender_id: usize) -> ServerMessage {
+    create_server_message(sender_id, ServerMessageType::Leave)
+}
+
+pub fn create_election_message(
+    sender_id: usize,
+    candidate_id: usize,
+    known_generation: Generation,
+) -> ServerMessage {
+    let candidate = Candidate {
+        id: candidate_id,
+        known_generation,
+    };
+    create_server_message(sender_id, ServerMessageType::Election(candidate))
+}
+
+pub fn create_elected_message(sender_id: usize, leader_id: usize) -> ServerMessage {
+    create_server_message(sender_id, ServerMessageType::Elected(leader_id))
+}
+
+pub fn recreate_token(sender_id: usize, token: Token) -> ServerMessage {
+    create_server_message(sender_id, ServerMessageType::Token(token))
 }
 
 fn create_server_message(sender_id: usize, message_type: ServerMessageType) -> ServerMessage {
@@ -77,5 +285,124 @@ fn create_server_message(sender_id: usize, message_type: ServerMessageType) -> S
         message_type,
         sender_id,
         passed_by: HashSet::new(),
+        membership: Membership::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Mensajes de la version 1 del protocolo, con la generacion como un numero
+    #[derive(Debug, Serialize, Deserialize)]
+    #[serde(rename = "ServerMessage")]
+    struct MessageV1 {
+        message_type: MessageTypeV1,
+        sender_id: usize,
+        passed_by: HashSet<usize>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    #[serde(rename = "ServerMessageType")]
+    enum MessageTypeV1 {
+        Token(TokenDataV1),
+        Election(CandidateDataV1),
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    #[serde(rename = "Token")]
+    struct TokenDataV1 {
+        generation: u64,
+        data: TokenData,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    #[serde(rename = "Candidate")]
+    struct CandidateDataV1 {
+        id: ServerId,
+        known_generation: u64,
+    }
+
+    fn action() -> AccountAction {
+        AccountAction {
+            message_type: MessageType::AddPoints,
+            account_id: 4,
+            points: 10,
+            last_updated_on: HybridTimestamp::new(100, 0, 1),
+            origin_server_id: 1,
+            coffee_maker_id: 0,
+            request_ids: vec![],
+        }
+    }
+
+    #[test]
+    fn should_exchange_tokens_and_elections_with_a_server_of_protocol_version_1() {
+        for codec in [Codec::JsonLine, Codec::LengthPrefixed] {
+            let mut token = Token {
+                generation: Generation::new(3, 2),
+                data: HashMap::new(),
+            };
+            token.data.insert(1, vec![action()]);
+            let encoded = recreate_token(2, token.clone())
+                .encode(codec, 1)
+                .expect("Error encoding");
+            let received: MessageV1 = codec.decode(&encoded).expect("Error decoding for v1");
+            match received.message_type {
+                MessageTypeV1::Token(received) => {
+                    assert_eq!(3, received.generation);
+                    assert_eq!(token.data, received.data);
+                }
+                _ => panic!("Expected the token"),
+            }
+
+            // El token vuelve de la version 1 sin el servidor que lo genero, no queda por debajo de la generacion conocida
+            let old = MessageV1 {
+                message_type: MessageTypeV1::Token(TokenDataV1 {
+                    generation: 3,
+                    data: token.data.clone(),
+                }),
+                sender_id: 1,
+                passed_by: HashSet::new(),
+            };
+            let encoded = codec.encode(&old).expect("Error encoding v1");
+            let received: ServerMessage = codec.decode(&encoded).expect("Error decoding");
+            match received.message_type {
+                ServerMessageType::Token(received) => {
+                    assert_eq!(3, received.generation.counter);
+                    assert!(received.generation >= token.generation);
+                    assert_eq!(token.data, received.data);
+                }
+                _ => panic!("Expected the token"),
+            }
+
+            let encoded = create_election_message(2, 2, Generation::new(5, 1))
+                .encode(codec, 1)
+                .expect("Error encoding");
+            let received: MessageV1 = codec.decode(&encoded).expect("Error decoding for v1");
+            assert!(matches!(
+                received.message_type,
+                MessageTypeV1::Election(CandidateDataV1 {
+                    id: 2,
+                    known_generation: 5
+                })
+            ));
+        }
+    }
+
+    #[test]
+    fn should_keep_the_minter_of_the_generation_with_protocol_version_2() {
+        for codec in [Codec::JsonLine, Codec::LengthPrefixed] {
+            let encoded = create_token_message(2, Generation::new(3, 2))
+                .encode(codec, 2)
+                .expect("Error encoding");
+            let received: ServerMessage = codec.decode(&encoded).expect("Error decoding");
+            assert_eq!(
+                ServerMessageType::Token(Token {
+                    generation: Generation::new(3, 2),
+                    data: HashMap::new(),
+                }),
+                received.message_type
+            );
+        }
     }
 }
@@ -0,0 +1,175 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use lib::handshake::Feature;
+use log::{info, warn};
+
+use crate::{
+    constants::{SHUTDOWN_DRAIN_TIMEOUT_IN_MS, SHUTDOWN_POLL_INTERVAL_IN_MS},
+    errors::ServerError,
+    ring_node::NodeState,
+    server_messages::create_leave_message,
+};
+
+/// Retira al servidor del anillo de forma ordenada. Deja de aceptar cafeteras y pedidos nuevos, espera a que el
+/// token aplique los pedidos pendientes y recien entonces avisa que se va. Con el aviso el anterior se conecta con
+/// nuestro siguiente y nosotros pasamos el token antes de cerrar, asi nadie lo da por perdido.
+/// Devuelve false si el servidor ya se estaba retirando, y error sin dejar de atender si el siguiente es de una
+/// version que no puede leer el aviso
+pub fn shut_down(state: &NodeState) -> Result<bool, ServerError> {
+    if !state
+        .connection_status
+        .lock()?
+        .next_supports(&Feature::DynamicMembership)
+    {
+        warn!("[SHUTDOWN] The next server does not support leaving the ring, staying");
+        return Err(ServerError::IncompatibleProtocol);
+    }
+    {
+        let mut shutting_down = state.shutting_down.lock()?;
+        if *shutting_down {
+            return Ok(false);
+        }
+        *shutting_down = true;
+    }
+    info!("[SHUTDOWN] Stopped accepting orders, waiting for the pending ones");
+    wait_for_pending_orders(state, Duration::from_millis(SHUTDOWN_DRAIN_TIMEOUT_IN_MS))?;
+    if state
+        .membership
+        .lock()?
+        .active_members()
+        .contains(&state.id)
+    {
+        info!("[SHUTDOWN] Leaving the ring...");
+        state
+            .to_next_conn_sender
+            .send(create_leave_message(state.id))?;
+    }
+    Ok(true)
+}
+
+/// Espera hasta que la cola de pedidos quede vacia y el token haya pasado al siguiente con sus cambios.
+/// Sin conexion con el anillo el token no llega, por lo que no se espera
+fn wait_for_pending_orders(state: &NodeState, timeout: Duration) -> Result<(), ServerError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let pending = state.orders.lock()?.pending_orders().len();
+        let have_token = *state.have_token.lock()?;
+        if pending == 0 && !have_token {
+            return Ok(());
+        }
+        if !state.connection_status.lock()?.is_online() {
+            warn!(
+                "[SHUTDOWN] Not connected to the ring, {} pending orders will not be applied",
+                pending
+            );
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            warn!(
+                "[SHUTDOWN] Timed out waiting for the token, {} pending orders will not be applied",
+                pending
+            );
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(SHUTDOWN_POLL_INTERVAL_IN_MS));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lib::{
+        handshake::SUPPORTED_FEATURES,
+        local_connection_messages::{CoffeeMakerRequest, MessageType, RequestId},
+    };
+
+    use super::*;
+    use crate::{
+        memory_accounts_manager::MemoryAccountsManager, ring_node::RingNode,
+        server_messages::ServerMessageType,
+    };
+
+    fn ring_node() -> RingNode {
+        RingNode::new_for_test(3, Box::new(MemoryAccountsManager::new()))
+    }
+
+    #[test]
+    fn should_leave_the_ring_after_the_pending_orders_are_applied() {
+        let node = ring_node();
+        {
+            let mut connection_status = node.state.connection_status.lock().expect("Lock error");
+            connection_status.set_prev_online();
+            connection_status.set_next_online();
+            connection_status.set_next_features(SUPPORTED_FEATURES.to_vec());
+        }
+        let request = CoffeeMakerRequest {
+            message_type: MessageType::AddPoints,
+            account_id: 1,
+            points: 5,
+            request_id: RequestId::default(),
+        };
+        node.state
+            .orders
+            .lock()
+            .expect("Lock error")
+            .add(request, 0);
+
+        let state = node.state.clone();
+        let handle = thread::spawn(move || shut_down(&state));
+        assert!(node
+            .next_connection
+            .next_message(Duration::from_millis(500))
+            .is_err());
+        assert!(*node.state.shutting_down.lock().expect("Lock error"));
+
+        // El token aplica el pedido y pasa al siguiente
+        node.state
+            .orders
+            .lock()
+            .expect("Lock error")
+            .get_and_clear_adding_orders();
+        let message = node
+            .next_connection
+            .next_message(Duration::from_secs(5))
+            .expect("Missing leave message");
+        assert_eq!(ServerMessageType::Leave, message.message_type);
+        assert!(handle
+            .join()
+            .expect("Error joining")
+            .expect("Error shutting down"));
+    }
+
+    #[test]
+    fn should_keep_serving_if_the_next_cannot_read_the_leave_message() {
+        let node = ring_node();
+        {
+            let mut connection_status = node.state.connection_status.lock().expect("Lock error");
+            connection_status.set_prev_online();
+            connection_status.set_next_online();
+            connection_status.set_next_features(vec![Feature::BalanceQuery]);
+        }
+
+        assert!(matches!(
+            shut_down(&node.state),
+            Err(ServerError::IncompatibleProtocol)
+        ));
+        assert!(!*node.state.shutting_down.lock().expect("Lock error"));
+        assert!(node.next_connection.next_message(Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn should_leave_the_ring_only_once() {
+        let node = ring_node();
+
+        assert!(shut_down(&node.state).expect("Error shutting down"));
+        assert!(!shut_down(&node.state).expect("Error shutting down"));
+        let message = node
+            .next_connection
+            .next_message(Duration::ZERO)
+            .expect("Missing leave message");
+        assert_eq!(ServerMessageType::Leave, message.message_type);
+        assert!(node.next_connection.next_message(Duration::ZERO).is_err());
+    }
+}
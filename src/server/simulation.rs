@@ -0,0 +1,1110 @@
+use std::{
+    cmp::max,
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use lib::{
+    codec::Codec,
+    common_errors::CoffeeSystemError,
+    connection_protocol::ConnectionProtocol,
+    handshake::{Hello, NodeId, NodeKind, Peer, PROTOCOL_VERSION},
+    local_connection_messages::{
+        CoffeeMakerRequest, CoffeeMakerResponse, MessageType, RequestId, ResponseStatus,
+    },
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    accounts_manager::AccountsManager,
+    address_resolver::AddressResolver,
+    coffee_message_dispatcher::CoffeeMessageDispatcher,
+    connection_server::{ConnectionServer, Network},
+    constants::TO_NEXT_CONN_CHANNEL_TIMEOUT_IN_MS,
+    errors::ServerError,
+    hybrid_clock::{HybridClock, HybridTimestamp},
+    membership::Membership,
+    memory_accounts_manager::MemoryAccountsManager,
+    previous_connection::PrevConnection,
+    ring_node::RingNode,
+    server_messages::{Generation, ServerMessage, ServerMessageType, TokenData},
+};
+
+/// Cantidad de servidores del anillo simulado
+pub const RING_SIZE: usize = 5;
+
+/// Cantidad de cuentas sobre las que operan las cafeteras simuladas
+const ACCOUNTS: usize = 3;
+
+/// Id con el que cada servidor conoce a su cafetera simulada
+const COFFEE_MAKER_ID: usize = 0;
+
+/// Demora minima y maxima de un mensaje entre servidores en ms. Incluye la espera que hace el servidor antes de cada envio
+const MIN_DELAY_IN_MS: u64 = 500;
+const MAX_DELAY_IN_MS: u64 = 1500;
+
+/// Demora de los mensajes retenidos en ms, mayor a la espera de mensajes del siguiente
+const SLOW_DELAY_IN_MS: u64 = 30000;
+const SLOW_MESSAGE_PROBABILITY: f64 = 0.005;
+
+/// Probabilidad de que la cafetera simulada confirme un cafe con puntos en lugar de cancelarlo
+const TAKE_POINTS_PROBABILITY: f64 = 0.8;
+
+/// Tiempo virtual en ms durante el que se inyectan pedidos y fallas en los escenarios al azar
+pub const FAULTS_UNTIL_IN_MS: u64 = 120000;
+
+/// Tiempo virtual en ms que se deja correr el anillo sin fallas antes de verificar que converge
+pub const SETTLE_TIME_IN_MS: u64 = 240000;
+
+/// Suceso de la simulacion, se ejecuta en el instante virtual en el que se programo
+enum Event {
+    /// Intento de conexion del servidor con su siguiente, al iniciar o luego de perderla
+    Reconnect(usize),
+    /// Vence la espera de mensajes para el siguiente del servidor
+    ChannelTimeout(usize),
+    Deliver {
+        link: usize,
+        payload: Vec<u8>,
+        /// Generacion del token si el mensaje lo lleva
+        token: Option<Generation>,
+        /// Cuentas de las operaciones del mensaje que algun servidor todavia no aplico
+        updates: BTreeSet<usize>,
+    },
+    /// Le llega al receptor el cierre del enlace, luego de todos sus mensajes
+    Close(usize),
+    Request(usize, CoffeeMakerRequest),
+    Crash(usize),
+    Partition(usize, usize),
+    Heal(usize, usize),
+}
+
+/// Conexion abierta por un servidor con otro
+struct Link {
+    from: usize,
+    to: usize,
+    codec: Codec,
+    open: bool,
+    /// Se corto por una caida o una particion, los mensajes en viaje se pierden
+    cut: bool,
+    /// Instante de la ultima entrega programada, los mensajes de un enlace llegan en orden
+    last_delivery: u64,
+}
+
+/// Red simulada con reloj virtual. Los mensajes quedan programados como sucesos con una demora al azar
+/// segun la semilla, y se entregan al procesar la cola de sucesos
+struct Wire {
+    now: Arc<AtomicU64>,
+    sequence: u64,
+    rng: StdRng,
+    events: BTreeMap<(u64, u64), Event>,
+    links: Vec<Link>,
+    addresses: HashMap<String, usize>,
+    crashed: BTreeSet<usize>,
+    partitions: BTreeSet<(usize, usize)>,
+    /// Cuentas con operaciones de un mensaje que se perdio o descarto. Esas operaciones solo las conocen
+    /// algunos servidores, y como se aplican solo las mas nuevas que el ultimo cambio de cada cuenta el
+    /// anillo no puede asegurar que sus saldos converjan
+    lost_accounts: BTreeSet<usize>,
+    /// Fallas inyectadas hasta ahora: caidas, particiones y mensajes retenidos mas que la espera del siguiente
+    faults: u64,
+    /// Operaciones perdidas antes de cualquier falla inyectada, el anillo las perdio por un error propio
+    untraced_losses: Vec<(u64, BTreeSet<usize>)>,
+}
+
+impl Wire {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+
+    fn schedule(&mut self, delay: u64, event: Event) {
+        let at = self.now() + delay;
+        self.schedule_at(at, event);
+    }
+
+    fn schedule_at(&mut self, at: u64, event: Event) {
+        self.sequence += 1;
+        self.events.insert((at, self.sequence), event);
+    }
+
+    fn can_reach(&self, from: usize, to: usize) -> bool {
+        !self.crashed.contains(&from)
+            && !self.crashed.contains(&to)
+            && !self.partitions.contains(&pair(from, to))
+    }
+
+    fn connect(&mut self, from: usize, address: &str) -> Result<(usize, usize), CoffeeSystemError> {
+        let to = *self
+            .addresses
+            .get(address)
+            .ok_or(CoffeeSystemError::ConnectionLost)?;
+        if !self.can_reach(from, to) {
+            return Err(CoffeeSystemError::ConnectionLost);
+        }
+        self.links.push(Link {
+            from,
+            to,
+            codec: Codec::default(),
+            open: true,
+            cut: false,
+            last_delivery: self.now(),
+        });
+        Ok((self.links.len() - 1, to))
+    }
+
+    fn send(&mut self, link: usize, payload: &[u8]) -> Result<(), CoffeeSystemError> {
+        let (from, to, codec) = {
+            let link = &self.links[link];
+            (link.from, link.to, link.codec)
+        };
+        if !self.links[link].open || !self.can_reach(from, to) {
+            self.links[link].open = false;
+            return Err(CoffeeSystemError::ConnectionLost);
+        }
+        let delay = if self.rng.gen_bool(SLOW_MESSAGE_PROBABILITY) {
+            self.faults += 1;
+            SLOW_DELAY_IN_MS
+        } else {
+            self.rng.gen_range(MIN_DELAY_IN_MS, MAX_DELAY_IN_MS + 1)
+        };
+        let at = max(self.now() + delay, self.links[link].last_delivery);
+        self.links[link].last_delivery = at;
+        let (token, updates) = match codec.decode::<ServerMessage>(payload) {
+            Ok(message) => (generation(&message), updated_accounts(&message)),
+            _ => (None, BTreeSet::new()),
+        };
+        // Un token con operaciones que saltea a un servidor en pie (ej. por una particion) no se las entrega
+        if token.is_some() && self.skips_alive_server(from, to) {
+            self.lose(updates.clone());
+        }
+        self.schedule_at(
+            at,
+            Event::Deliver {
+                link,
+                payload: payload.to_vec(),
+                token,
+                updates,
+            },
+        );
+        Ok(())
+    }
+
+    /// Indica si entre los dos servidores, en el orden del anillo, queda alguno que no se cayo
+    fn skips_alive_server(&self, from: usize, to: usize) -> bool {
+        (1..RING_SIZE)
+            .map(|offset| (from + offset) % RING_SIZE)
+            .take_while(|id| *id != to)
+            .any(|id| !self.crashed.contains(&id))
+    }
+
+    /// Cierra el enlace, el receptor se entera luego de recibir los mensajes ya enviados
+    fn close(&mut self, link: usize) {
+        if !self.links[link].open {
+            return;
+        }
+        self.links[link].open = false;
+        let at = max(self.now(), self.links[link].last_delivery);
+        self.schedule_at(at, Event::Close(link));
+    }
+
+    /// Corta los enlaces abiertos que cumplen la condicion, los mensajes en viaje se pierden
+    fn cut(&mut self, condition: impl Fn(&Link) -> bool) {
+        let now = self.now();
+        let cut: Vec<usize> = (0..self.links.len())
+            .filter(|link| self.links[*link].open && condition(&self.links[*link]))
+            .collect();
+        for link in cut {
+            self.links[link].open = false;
+            self.links[link].cut = true;
+            self.schedule_at(now, Event::Close(link));
+        }
+    }
+
+    /// Registra que se perdieron operaciones de las cuentas indicadas. Solo puede pasar luego de alguna falla
+    /// inyectada, si no se anota como una perdida que no se explica
+    fn lose(&mut self, accounts: BTreeSet<usize>) {
+        if accounts.is_empty() {
+            return;
+        }
+        if self.faults == 0 {
+            self.untraced_losses.push((self.now(), accounts.clone()));
+        }
+        self.lost_accounts.extend(accounts);
+    }
+
+    fn crash(&mut self, id: usize) {
+        self.faults += 1;
+        self.crashed.insert(id);
+        self.cut(|link| link.from == id || link.to == id);
+    }
+
+    fn partition(&mut self, a: usize, b: usize) {
+        self.faults += 1;
+        self.partitions.insert(pair(a, b));
+        self.cut(|link| pair(link.from, link.to) == pair(a, b));
+    }
+
+    /// Indica si el mensaje en viaje por el enlace todavia puede llegar
+    fn deliverable(&self, link: usize) -> bool {
+        let link = &self.links[link];
+        !link.cut && self.can_reach(link.from, link.to)
+    }
+}
+
+fn pair(a: usize, b: usize) -> (usize, usize) {
+    (a.min(b), a.max(b))
+}
+
+/// Generacion del token si el mensaje lo lleva
+fn generation(message: &ServerMessage) -> Option<Generation> {
+    match &message.message_type {
+        ServerMessageType::Token(token) => Some(token.generation),
+        _ => None,
+    }
+}
+
+/// Cuentas de las operaciones del mensaje que algun servidor todavia no aplico: las de un token, o cualquiera
+/// en un aviso de nueva conexion, que al volver le trae al servidor que se une las que se perdio
+fn updated_accounts(message: &ServerMessage) -> BTreeSet<usize> {
+    match &message.message_type {
+        ServerMessageType::Token(token) => token_accounts(&token.data),
+        ServerMessageType::NewConnection(_) => (0..ACCOUNTS).collect(),
+        _ => BTreeSet::new(),
+    }
+}
+
+fn token_accounts(data: &TokenData) -> BTreeSet<usize> {
+    data.values()
+        .flatten()
+        .map(|action| action.account_id)
+        .collect()
+}
+
+/// Red que usan los servidores de la simulacion. Las conexiones se abren sin handshake y sus mensajes
+/// viajan por la red simulada
+struct SimNetwork {
+    wire: Arc<Mutex<Wire>>,
+}
+
+impl Network for SimNetwork {
+    /// La simulacion le entrega los mensajes directamente a cada servidor, no tiene listeners
+    fn bind(
+        &self,
+        _address: &str,
+        _id: usize,
+        _accepts: NodeKind,
+    ) -> Result<Box<dyn ConnectionServer + Send>, ServerError> {
+        Err(ServerError::ListenerError)
+    }
+
+    fn connect(
+        &self,
+        address: &str,
+        hello: &Hello,
+    ) -> Result<Box<dyn ConnectionProtocol + Send + Sync>, CoffeeSystemError> {
+        let from = match hello.node_id {
+            NodeId::Server(id) => id,
+            _ => return Err(CoffeeSystemError::ConnectionLost),
+        };
+        let (link, to) = self.wire.lock()?.connect(from, address)?;
+        let peer = hello
+            .agree(&Hello::new(NodeId::Server(to)))
+            .map_err(|_| CoffeeSystemError::IncompatibleProtocol)?;
+        self.wire.lock()?.links[link].codec = peer.codec;
+        Ok(Box::new(SimConnection {
+            wire: self.wire.clone(),
+            link,
+            peer,
+            outgoing: true,
+        }))
+    }
+}
+
+/// Extremo de un enlace de la red simulada
+struct SimConnection {
+    wire: Arc<Mutex<Wire>>,
+    link: usize,
+    peer: Peer,
+    /// Solo envia mensajes quien abrio la conexion, al descartarla se cierra el enlace
+    outgoing: bool,
+}
+
+#[async_trait]
+impl ConnectionProtocol for SimConnection {
+    async fn send(&mut self, data: &[u8]) -> Result<(), CoffeeSystemError> {
+        self.wire.lock()?.send(self.link, data)
+    }
+
+    /// Los mensajes los entrega la simulacion al procesar la cola de sucesos
+    async fn recv(&mut self) -> Result<Vec<u8>, CoffeeSystemError> {
+        Err(CoffeeSystemError::ConnectionClosed)
+    }
+
+    fn codec(&self) -> Codec {
+        self.peer.codec
+    }
+
+    fn peer(&self) -> Peer {
+        self.peer.clone()
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn ConnectionProtocol + Send + Sync>, CoffeeSystemError> {
+        Err(CoffeeSystemError::UnexpectedError)
+    }
+}
+
+impl Drop for SimConnection {
+    fn drop(&mut self) {
+        if self.outgoing {
+            if let Ok(mut wire) = self.wire.lock() {
+                wire.close(self.link);
+            }
+        }
+    }
+}
+
+/// Servidor de la simulacion. Sus componentes se ejecutan paso a paso en lugar de en sus hilos
+struct SimNode {
+    id: usize,
+    node: RingNode,
+    /// Receptor de cada enlace abierto hacia el servidor
+    prevs: BTreeMap<usize, PrevConnection>,
+    /// Mensajes para el siguiente que esperan a que el servidor se conecte con el
+    backlog: VecDeque<ServerMessage>,
+    started: bool,
+    reconnecting: bool,
+    timer_armed: bool,
+    last_message_at: u64,
+    coffee_maker_responses: Receiver<CoffeeMakerResponse>,
+    /// Pedidos de puntos de la cafetera simulada que esperan respuesta
+    requested_points: BTreeMap<u64, CoffeeMakerRequest>,
+}
+
+impl SimNode {
+    fn is_next_online(&self) -> bool {
+        self.node
+            .state
+            .connection_status
+            .lock()
+            .map(|status| status.is_next_online())
+            .unwrap_or(false)
+    }
+
+    fn set_prev_online(&self) -> Result<(), ServerError> {
+        self.node.state.connection_status.lock()?.set_prev_online();
+        Ok(())
+    }
+
+    /// Deja de escuchar el enlace. Como el listener del servidor, si queda otro anterior se lo marca en linea
+    fn end_prev(&mut self, link: usize) -> Result<(), ServerError> {
+        self.prevs.remove(&link);
+        if !self.prevs.is_empty() {
+            self.set_prev_online()?;
+        }
+        Ok(())
+    }
+
+    fn dispatch(&mut self, request: CoffeeMakerRequest) -> Result<(), ServerError> {
+        if request.message_type == MessageType::RequestPoints {
+            self.requested_points
+                .insert(request.request_id.sequence, request);
+        }
+        self.node.dispatcher.dispatch(
+            (request, COFFEE_MAKER_ID),
+            &self.node.result_points_sender,
+            &self.node.request_points_result_sender,
+        )
+    }
+
+    /// Entrega las respuestas a la cafetera simulada, que confirma o cancela cada cafe con puntos reservados
+    fn answer_coffee_maker(
+        &mut self,
+        rng: &mut StdRng,
+        sequence: &mut u64,
+    ) -> Result<bool, ServerError> {
+        let mut progress = false;
+        loop {
+            while let Ok((response, machine_id)) =
+                self.node.request_points_result_receiver.try_recv()
+            {
+                CoffeeMessageDispatcher::forward_response(
+                    &self.node.machine_response_senders,
+                    &self.node.state.dedup_cache,
+                    response,
+                    machine_id,
+                );
+            }
+            let response = match self.coffee_maker_responses.try_recv() {
+                Ok(response) => response,
+                Err(_) => return Ok(progress),
+            };
+            progress = true;
+            if response.message_type != MessageType::RequestPoints {
+                continue;
+            }
+            let request = match self.requested_points.remove(&response.request_id.sequence) {
+                Some(request) => request,
+                None => continue,
+            };
+            if !matches!(response.status, ResponseStatus::Ok) {
+                continue;
+            }
+            let message_type = if rng.gen_bool(TAKE_POINTS_PROBABILITY) {
+                MessageType::TakePoints
+            } else {
+                MessageType::CancelPointsRequest
+            };
+            *sequence += 1;
+            self.dispatch(CoffeeMakerRequest {
+                message_type,
+                request_id: request_id(self.id, *sequence),
+                ..request
+            })?;
+        }
+    }
+
+    /// Ejecuta los componentes del servidor hasta que no tengan mas trabajo. Devuelve si hubo alguno
+    fn step(
+        &mut self,
+        wire: &Mutex<Wire>,
+        rng: &mut StdRng,
+        sequence: &mut u64,
+        now: u64,
+    ) -> Result<bool, ServerError> {
+        let mut progress = self.answer_coffee_maker(rng, sequence)?;
+        while let Some(token) = self.node.orders_manager.try_recv_token() {
+            progress = true;
+            if token.generation < *self.node.state.token_generation.lock()? {
+                wire.lock()?.lose(token_accounts(&token.data));
+            }
+            if let Some((token, total_request_orders)) =
+                self.node.orders_manager.take_orders(token)?
+            {
+                self.answer_coffee_maker(rng, sequence)?;
+                self.node
+                    .orders_manager
+                    .finish_orders(token, total_request_orders)?;
+            }
+        }
+        while let Ok(message) = self.node.next_connection.next_message(Duration::ZERO) {
+            self.backlog.push_back(message);
+        }
+        while self.started && self.is_next_online() {
+            let message = match self.backlog.pop_front() {
+                Some(message) => message,
+                None => break,
+            };
+            progress = true;
+            self.last_message_at = now;
+            let updates = updated_accounts(&message);
+            self.node.next_connection.handle_message(message)?;
+            // Si no pudo pasar el token a nadie el servidor queda desconectado y el token se pierde
+            if !self.is_next_online() {
+                wire.lock()?.lose(updates);
+            }
+        }
+        if self.started && !self.is_next_online() && !self.reconnecting {
+            self.reconnecting = true;
+            wire.lock()?.schedule(0, Event::Reconnect(self.id));
+        }
+        Ok(progress)
+    }
+}
+
+/// Simulacion determinista de un anillo de servidores. Un reloj virtual y una cola de sucesos ordenada reemplazan
+/// a los hilos, las esperas y la red, y todas las decisiones al azar salen de la semilla. Asi una misma semilla
+/// reproduce la misma ejecucion, con sus caidas, demoras y particiones
+pub struct Simulation {
+    wire: Arc<Mutex<Wire>>,
+    rng: StdRng,
+    nodes: BTreeMap<usize, SimNode>,
+    /// Cuentas de los servidores caidos, sus operaciones pueden haber llegado a los demas
+    crashed_accounts: Vec<Arc<Mutex<Box<dyn AccountsManager>>>>,
+    sequence: u64,
+    processed_events: u64,
+}
+
+impl Simulation {
+    /// Crea un anillo de `RING_SIZE` servidores. Todos intentan conectarse con su siguiente al iniciar
+    pub fn new(seed: u64) -> Simulation {
+        let now = Arc::new(AtomicU64::new(0));
+        let address_resolver = AddressResolver::new_local(0, RING_SIZE);
+        let addresses = (0..RING_SIZE)
+            .filter_map(|id| {
+                address_resolver
+                    .server_address(id)
+                    .map(|address| (address.clone(), id))
+            })
+            .collect();
+        let wire = Arc::new(Mutex::new(Wire {
+            now: now.clone(),
+            sequence: 0,
+            rng: StdRng::seed_from_u64(seed),
+            events: BTreeMap::new(),
+            links: vec![],
+            addresses,
+            crashed: BTreeSet::new(),
+            partitions: BTreeSet::new(),
+            lost_accounts: BTreeSet::new(),
+            faults: 0,
+            untraced_losses: vec![],
+        }));
+        let network: Arc<dyn Network> = Arc::new(SimNetwork { wire: wire.clone() });
+        let mut nodes = BTreeMap::new();
+        for id in 0..RING_SIZE {
+            let virtual_now = now.clone();
+            let clock = HybridClock::with_physical_time(
+                id,
+                Arc::new(move || virtual_now.load(Ordering::SeqCst)),
+            );
+            let node = RingNode::new(
+                id,
+                Membership::from_resolver(&AddressResolver::new_local(id, RING_SIZE)),
+                Box::new(MemoryAccountsManager::new()),
+                clock,
+                network.clone(),
+            );
+            let (coffee_maker_sender, coffee_maker_responses) = mpsc::channel();
+            if let Ok(mut senders) = node.machine_response_senders.lock() {
+                senders.insert(COFFEE_MAKER_ID, coffee_maker_sender);
+            }
+            nodes.insert(
+                id,
+                SimNode {
+                    id,
+                    node,
+                    prevs: BTreeMap::new(),
+                    backlog: VecDeque::new(),
+                    started: false,
+                    reconnecting: true,
+                    timer_armed: false,
+                    last_message_at: 0,
+                    coffee_maker_responses,
+                    requested_points: BTreeMap::new(),
+                },
+            );
+        }
+        let simulation = Simulation {
+            wire,
+            rng: StdRng::seed_from_u64(seed.wrapping_add(1)),
+            nodes,
+            crashed_accounts: vec![],
+            sequence: 0,
+            processed_events: 0,
+        };
+        for id in 0..RING_SIZE {
+            simulation.schedule(0, Event::Reconnect(id));
+        }
+        simulation
+    }
+
+    /// Arma un escenario al azar segun la semilla: pedidos de las cafeteras a cualquier servidor, y hasta dos
+    /// caidas y dos particiones durante los primeros `FAULTS_UNTIL_IN_MS`
+    pub fn random(seed: u64) -> Simulation {
+        let mut simulation = Simulation::new(seed);
+        let mut rng = StdRng::seed_from_u64(seed.wrapping_add(2));
+        for _ in 0..rng.gen_range(10, 40) {
+            let at = rng.gen_range(0, FAULTS_UNTIL_IN_MS);
+            let message_type = if rng.gen_bool(0.6) {
+                MessageType::AddPoints
+            } else {
+                MessageType::RequestPoints
+            };
+            let account_id = rng.gen_range(0, ACCOUNTS);
+            let points = rng.gen_range(1, 20);
+            simulation.request(
+                at,
+                rng.gen_range(0, RING_SIZE),
+                message_type,
+                account_id,
+                points,
+            );
+        }
+        let mut crashed = BTreeSet::new();
+        for _ in 0..rng.gen_range(0, 3) {
+            let id = rng.gen_range(0, RING_SIZE);
+            if crashed.insert(id) {
+                simulation.crash(rng.gen_range(0, FAULTS_UNTIL_IN_MS), id);
+            }
+        }
+        for _ in 0..rng.gen_range(0, 3) {
+            let a = rng.gen_range(0, RING_SIZE);
+            let b = rng.gen_range(0, RING_SIZE);
+            if a != b {
+                let at = rng.gen_range(0, FAULTS_UNTIL_IN_MS);
+                simulation.partition(at, a, b, rng.gen_range(1000, 30000));
+            }
+        }
+        simulation
+    }
+
+    fn wire(&self) -> MutexGuard<'_, Wire> {
+        self.wire.lock().expect("Lock error")
+    }
+
+    fn schedule(&self, at: u64, event: Event) {
+        self.wire().schedule_at(at, event);
+    }
+
+    /// Programa un pedido de la cafetera del servidor indicado
+    pub fn request(
+        &mut self,
+        at: u64,
+        id: usize,
+        message_type: MessageType,
+        account_id: usize,
+        points: usize,
+    ) {
+        self.sequence += 1;
+        let request = CoffeeMakerRequest {
+            message_type,
+            account_id,
+            points,
+            request_id: request_id(id, self.sequence),
+        };
+        self.schedule(at, Event::Request(id, request));
+    }
+
+    /// Programa la caida del servidor, pierde los mensajes en viaje y no vuelve a la red
+    pub fn crash(&self, at: u64, id: usize) {
+        self.schedule(at, Event::Crash(id));
+    }
+
+    /// Programa una particion entre los dos servidores, que no pueden comunicarse mientras dure
+    pub fn partition(&self, at: u64, a: usize, b: usize, duration: u64) {
+        self.schedule(at, Event::Partition(a, b));
+        self.schedule(at + duration, Event::Heal(a, b));
+    }
+
+    /// Procesa los sucesos hasta el instante indicado, verificando luego de cada uno que haya a lo sumo un
+    /// token de la ultima generacion. Devuelve el invariante que se rompio, si alguno
+    pub fn run_until(&mut self, until: u64) -> Result<(), String> {
+        loop {
+            let next = {
+                let mut wire = self.wire();
+                match wire.events.keys().next().copied() {
+                    Some(key) if key.0 <= until => {
+                        wire.now.store(key.0, Ordering::SeqCst);
+                        wire.events.remove(&key)
+                    }
+                    _ => None,
+                }
+            };
+            let event = match next {
+                Some(event) => event,
+                None => break,
+            };
+            self.processed_events += 1;
+            self.handle(event)
+                .and_then(|_| self.settle())
+                .map_err(|e| format!("server error {:?} at {} ms", e, self.now()))?;
+            self.check_single_token()?;
+        }
+        self.wire().now.store(until, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn now(&self) -> u64 {
+        self.wire().now()
+    }
+
+    fn handle(&mut self, event: Event) -> Result<(), ServerError> {
+        let now = self.now();
+        match event {
+            Event::Reconnect(id) => {
+                let node = match self.nodes.get_mut(&id) {
+                    Some(node) => node,
+                    None => return Ok(()),
+                };
+                node.reconnecting = false;
+                match node.node.next_connection.try_to_connect()? {
+                    Some(wait) => {
+                        node.reconnecting = true;
+                        self.wire
+                            .lock()?
+                            .schedule(wait.as_millis() as u64, Event::Reconnect(id));
+                    }
+                    None => {
+                        if !node.started {
+                            node.started = true;
+                            node.node.next_connection.start_election()?;
+                        }
+                        node.last_message_at = now;
+                        if !node.timer_armed {
+                            node.timer_armed = true;
+                            self.wire.lock()?.schedule(
+                                TO_NEXT_CONN_CHANNEL_TIMEOUT_IN_MS,
+                                Event::ChannelTimeout(id),
+                            );
+                        }
+                    }
+                }
+            }
+            Event::ChannelTimeout(id) => {
+                let node = match self.nodes.get_mut(&id) {
+                    Some(node) => node,
+                    None => return Ok(()),
+                };
+                node.timer_armed = false;
+                // Mientras no hay siguiente el servidor esta reconectandose, al lograrlo vuelve a esperar mensajes
+                if !node.is_next_online() {
+                    return Ok(());
+                }
+                let elapsed = now - node.last_message_at;
+                let wait = if elapsed >= TO_NEXT_CONN_CHANNEL_TIMEOUT_IN_MS {
+                    node.last_message_at = now;
+                    node.node.next_connection.handle_channel_timeout()?;
+                    TO_NEXT_CONN_CHANNEL_TIMEOUT_IN_MS
+                } else {
+                    TO_NEXT_CONN_CHANNEL_TIMEOUT_IN_MS - elapsed
+                };
+                node.timer_armed = true;
+                self.wire.lock()?.schedule(wait, Event::ChannelTimeout(id));
+            }
+            Event::Deliver {
+                link,
+                payload,
+                token,
+                updates,
+            } => {
+                let (from, to, codec, deliverable) = {
+                    let wire = self.wire.lock()?;
+                    let connection = &wire.links[link];
+                    (
+                        connection.from,
+                        connection.to,
+                        connection.codec,
+                        wire.deliverable(link),
+                    )
+                };
+                let node = match self.nodes.get_mut(&to) {
+                    Some(node) if deliverable => node,
+                    _ => {
+                        self.wire.lock()?.lose(updates);
+                        return Ok(());
+                    }
+                };
+                let generation = *node.node.state.token_generation.lock()?;
+                if token.is_some_and(|token| token < generation) {
+                    self.wire.lock()?.lose(updates);
+                }
+                if !node.prevs.contains_key(&link) {
+                    let connection = SimConnection {
+                        wire: self.wire.clone(),
+                        link,
+                        peer: Peer {
+                            node_id: NodeId::Server(from),
+                            protocol_version: PROTOCOL_VERSION,
+                            features: vec![],
+                            codec,
+                        },
+                        outgoing: false,
+                    };
+                    let previous = node.node.state.prev_connection(Box::new(connection));
+                    node.prevs.insert(link, previous);
+                    node.set_prev_online()?;
+                }
+                let closed = match node.prevs.get_mut(&link) {
+                    Some(previous) => previous.handle_message(&payload)?,
+                    None => false,
+                };
+                if closed {
+                    node.end_prev(link)?;
+                }
+            }
+            Event::Close(link) => {
+                let to = self.wire.lock()?.links[link].to;
+                if let Some(node) = self.nodes.get_mut(&to) {
+                    if let Some(previous) = node.prevs.get_mut(&link) {
+                        previous.handle_connection_lost()?;
+                        node.end_prev(link)?;
+                    }
+                }
+            }
+            Event::Request(id, request) => {
+                if let Some(node) = self.nodes.get_mut(&id) {
+                    node.dispatch(request)?;
+                }
+            }
+            Event::Crash(id) => {
+                self.wire.lock()?.crash(id);
+                // Se descarta fuera del lock de la red, al hacerlo se cierran sus conexiones
+                if let Some(node) = self.nodes.remove(&id) {
+                    let mut lost: BTreeSet<usize> =
+                        node.backlog.iter().flat_map(updated_accounts).collect();
+                    // Si tenia el token y no esta entre los mensajes por enviar no se sabe que operaciones llevaba
+                    if *node.node.state.have_token.lock()?
+                        && !node
+                            .backlog
+                            .iter()
+                            .any(|message| generation(message).is_some())
+                    {
+                        lost.extend(0..ACCOUNTS);
+                    }
+                    self.wire.lock()?.lose(lost);
+                    self.crashed_accounts
+                        .push(node.node.state.accounts_manager.clone());
+                }
+            }
+            Event::Partition(a, b) => self.wire.lock()?.partition(a, b),
+            Event::Heal(a, b) => {
+                self.wire.lock()?.partitions.remove(&pair(a, b));
+            }
+        }
+        Ok(())
+    }
+
+    fn settle(&mut self) -> Result<(), ServerError> {
+        let now = self.now();
+        loop {
+            let mut progress = false;
+            for node in self.nodes.values_mut() {
+                progress |= node.step(&self.wire, &mut self.rng, &mut self.sequence, now)?;
+            }
+            if !progress {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Verifica que no haya dos copias del token de la misma generacion entre todas las que siguen en pie: los
+    /// mensajes en viaje que pueden llegar y los que esperan para ser enviados. Las copias de generaciones
+    /// viejas pueden seguir circulando hasta que las descarte un servidor que vio una mas nueva
+    fn check_single_token(&self) -> Result<(), String> {
+        let mut generations: Vec<Generation> = {
+            let wire = self.wire();
+            wire.events
+                .values()
+                .filter_map(|event| match event {
+                    Event::Deliver {
+                        link,
+                        token: Some(generation),
+                        ..
+                    } if wire.deliverable(*link) => Some(*generation),
+                    _ => None,
+                })
+                .collect()
+        };
+        for node in self.nodes.values() {
+            generations.extend(node.backlog.iter().filter_map(
+                |message| match &message.message_type {
+                    ServerMessageType::Token(token) => Some(token.generation),
+                    _ => None,
+                },
+            ));
+        }
+        let mut copies: BTreeMap<Generation, usize> = BTreeMap::new();
+        for generation in generations {
+            *copies.entry(generation).or_default() += 1;
+        }
+        match copies.iter().find(|(_, count)| **count > 1) {
+            Some((generation, count)) => Err(format!(
+                "{} tokens of generation {} at {} ms",
+                count,
+                generation,
+                self.now()
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Verifica que las cafeteras no hayan tomado de ninguna cuenta mas puntos de los que se le sumaron,
+    /// contando las operaciones registradas en todos los servidores, incluso en los caidos. Se saltean las
+    /// cuentas indicadas: si se perdio una operacion de una cuenta los demas servidores pueden haber vuelto a
+    /// dar los mismos puntos
+    pub fn check_no_negative_balances(
+        &self,
+        lost_accounts: &BTreeSet<usize>,
+    ) -> Result<(), String> {
+        let mut accounts: Vec<&Arc<Mutex<Box<dyn AccountsManager>>>> = self
+            .nodes
+            .values()
+            .map(|node| &node.node.state.accounts_manager)
+            .collect();
+        accounts.extend(self.crashed_accounts.iter());
+        let mut actions = BTreeMap::new();
+        for manager in accounts {
+            let manager = manager.lock().map_err(|_| "lock error".to_string())?;
+            for action in manager.get_actions_after(HybridTimestamp::default()) {
+                actions.insert(
+                    (action.last_updated_on, action.account_id),
+                    (action.message_type, action.points as i64),
+                );
+            }
+        }
+        let mut balances: BTreeMap<usize, i64> = BTreeMap::new();
+        for ((_, account_id), (message_type, points)) in actions {
+            let balance = balances.entry(account_id).or_insert(0);
+            match message_type {
+                MessageType::AddPoints => *balance += points,
+                MessageType::TakePoints => *balance -= points,
+                _ => {}
+            }
+        }
+        match balances
+            .iter()
+            .find(|(account_id, balance)| **balance < 0 && !lost_accounts.contains(account_id))
+        {
+            Some((account_id, balance)) => Err(format!(
+                "account {} ends with {} points",
+                account_id, balance
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Cuentas con operaciones de algun mensaje que se perdio y no llego a todos los servidores
+    pub fn lost_accounts(&self) -> BTreeSet<usize> {
+        self.wire().lost_accounts.clone()
+    }
+
+    /// Verifica los saldos al terminar: que ninguna cuenta quede en negativo y que todos los servidores
+    /// coincidan. Si se perdieron operaciones de una cuenta luego de una falla inyectada el anillo no asegura
+    /// que sus saldos converjan, solo esas cuentas no se comparan entre servidores. Perder operaciones sin que
+    /// haya habido fallas rompe el invariante
+    pub fn check_balances(&self) -> Result<(), String> {
+        if let Some((at, accounts)) = self.wire().untraced_losses.first() {
+            return Err(format!(
+                "updates of accounts {:?} lost at {} ms before any injected fault",
+                accounts, at
+            ));
+        }
+        let lost_accounts = self.lost_accounts();
+        self.check_no_negative_balances(&lost_accounts)?;
+        let accounts: Vec<usize> = (0..ACCOUNTS)
+            .filter(|account_id| !lost_accounts.contains(account_id))
+            .collect();
+        self.check_convergence_of(&accounts)
+    }
+
+    /// Verifica que todos los servidores en pie tengan el mismo saldo en cada cuenta
+    pub fn check_convergence(&self) -> Result<(), String> {
+        self.check_convergence_of(&(0..ACCOUNTS).collect::<Vec<_>>())
+    }
+
+    fn check_convergence_of(&self, accounts: &[usize]) -> Result<(), String> {
+        let balances: Vec<Vec<Option<usize>>> = self
+            .balances()
+            .into_values()
+            .map(|view| {
+                accounts
+                    .iter()
+                    .map(|account_id| view[*account_id])
+                    .collect()
+            })
+            .collect();
+        let mut views = balances.iter();
+        if let Some(first) = views.next() {
+            if let Some(other) = views.find(|view| *view != first) {
+                return Err(format!(
+                    "balances of accounts {:?} diverged, {:?} and {:?} at {} ms",
+                    accounts,
+                    first,
+                    other,
+                    self.now()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Saldo de cada cuenta segun cada servidor en pie
+    pub fn balances(&self) -> BTreeMap<usize, Vec<Option<usize>>> {
+        self.nodes
+            .iter()
+            .map(|(id, node)| {
+                let accounts = node.node.state.accounts_manager.lock().expect("Lock error");
+                let view = (0..ACCOUNTS)
+                    .map(|account_id| accounts.get_account(account_id).map(|a| a.amount))
+                    .collect();
+                (*id, view)
+            })
+            .collect()
+    }
+
+    /// Cantidad de sucesos procesados, permite comparar dos ejecuciones con la misma semilla
+    pub fn processed_events(&self) -> u64 {
+        self.processed_events
+    }
+}
+
+fn request_id(id: usize, sequence: u64) -> RequestId {
+    RequestId {
+        dispenser_id: id as u64,
+        sequence,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    /// Cantidad de semillas que se prueban por defecto, se puede cambiar con la variable de entorno SIMULATION_SEEDS
+    /// para una prueba mas larga
+    const DEFAULT_SEEDS: u64 = 50;
+
+    fn seeds() -> u64 {
+        env::var("SIMULATION_SEEDS")
+            .ok()
+            .and_then(|seeds| seeds.parse().ok())
+            .unwrap_or(DEFAULT_SEEDS)
+    }
+
+    fn run_random(seed: u64) -> Result<Simulation, String> {
+        let mut simulation = Simulation::random(seed);
+        simulation.run_until(FAULTS_UNTIL_IN_MS + SETTLE_TIME_IN_MS)?;
+        simulation.check_balances()?;
+        Ok(simulation)
+    }
+
+    #[test]
+    fn should_keep_the_invariants_of_the_ring_with_random_faults() {
+        for seed in 0..seeds() {
+            if let Err(violation) = run_random(seed) {
+                panic!("Seed {} broke an invariant: {}", seed, violation);
+            }
+        }
+    }
+
+    #[test]
+    fn should_reproduce_the_same_run_with_the_same_seed() {
+        let first = run_random(7).expect("Invariant broken");
+        let second = run_random(7).expect("Invariant broken");
+        assert_eq!(first.processed_events(), second.processed_events());
+        assert_eq!(first.balances(), second.balances());
+    }
+
+    #[test]
+    fn should_report_updates_lost_without_an_injected_fault() {
+        let simulation = Simulation::new(1);
+        simulation.wire().lose(BTreeSet::from([1]));
+        assert!(simulation.check_balances().is_err());
+
+        let simulation = Simulation::new(1);
+        simulation.wire().crash(3);
+        simulation.wire().lose(BTreeSet::from([1]));
+        assert_eq!(Ok(()), simulation.check_balances());
+    }
+
+    #[test]
+    fn should_converge_after_a_server_crashes() {
+        let mut simulation = Simulation::new(1);
+        simulation.request(10000, 0, MessageType::AddPoints, 1, 10);
+        simulation.run_until(40000).expect("Invariant broken");
+        simulation.crash(40000, 2);
+        simulation.request(45000, 3, MessageType::AddPoints, 1, 5);
+        simulation.run_until(200000).expect("Invariant broken");
+
+        simulation.check_convergence().expect("Balances diverged");
+        for view in simulation.balances().values() {
+            assert_eq!(Some(15), view[1]);
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//! Escenarios de la documentacion reproducidos con servidores reales que se conectan a traves de `ring_chaos`.
+//! Cada test levanta su propio anillo en 127.0.0.1, en puertos distintos para poder correr en paralelo. Los puertos
+//! quedan debajo del rango efimero, asi no los puede tomar una conexion saliente de otro test
+
+use std::{
+    env, fs,
+    io::Write,
+    path::PathBuf,
+    process::{Child, ChildStdin, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use async_std::task;
+use lib::{
+    connection_protocol::{recv_message, send_message, TcpConnection},
+    handshake::{Hello, NodeId},
+    local_connection_messages::{
+        CoffeeMakerRequest, CoffeeMakerResponse, MessageType, RequestId, ResponseStatus,
+    },
+};
+
+const RING_SIZE: usize = 4;
+const ACCOUNT_ID: usize = 1;
+const CONVERGENCE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Anillo de servidores con el proxy delante de cada uno. Al terminar el test se detienen todos los procesos
+struct ChaosRing {
+    base_port: usize,
+    topology: PathBuf,
+    servers: Vec<Child>,
+    proxy: Child,
+    commands: ChildStdin,
+    sequence: u64,
+}
+
+impl ChaosRing {
+    /// Levanta el proxy y los servidores. Cada servidor escucha el anillo en `base_port + id`, las cafeteras en
+    /// `base_port + 100 + id`, y los demas se conectan a el por el proxy en `base_port + 200 + id`
+    fn start(name: &str, base_port: usize) -> ChaosRing {
+        let servers: Vec<String> = (0..RING_SIZE)
+            .map(|id| {
+                format!(
+                    r#"{{"id": {}, "server_address": "127.0.0.1:{}", "coffee_address": "127.0.0.1:{}", "proxy_address": "127.0.0.1:{}"}}"#,
+                    id,
+                    base_port + id,
+                    base_port + 100 + id,
+                    base_port + 200 + id
+                )
+            })
+            .collect();
+        let topology =
+            env::temp_dir().join(format!("ring_chaos_{}_{}.json", name, std::process::id()));
+        fs::write(
+            &topology,
+            format!(r#"{{"servers": [{}]}}"#, servers.join(",")),
+        )
+        .expect("Error writing the topology");
+
+        let mut proxy = Command::new(env!("CARGO_BIN_EXE_ring_chaos"))
+            .arg(&topology)
+            .args(["--seed", "1"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Error starting ring_chaos");
+        let commands = proxy.stdin.take().expect("Missing proxy stdin");
+        let servers = (0..RING_SIZE)
+            .map(|id| {
+                Command::new(env!("CARGO_BIN_EXE_server"))
+                    .arg(id.to_string())
+                    .arg("--topology")
+                    .arg(&topology)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .spawn()
+                    .expect("Error starting server")
+            })
+            .collect();
+        ChaosRing {
+            base_port,
+            topology,
+            servers,
+            proxy,
+            commands,
+            sequence: 0,
+        }
+    }
+
+    /// Envia un comando al proxy por su entrada estandar
+    fn chaos(&mut self, command: &str) {
+        writeln!(self.commands, "{}", command).expect("Error sending the command to the proxy");
+    }
+
+    fn request(
+        &self,
+        server_id: usize,
+        request: CoffeeMakerRequest,
+    ) -> Option<CoffeeMakerResponse> {
+        let address = format!("127.0.0.1:{}", self.base_port + 100 + server_id);
+        let mut connection =
+            TcpConnection::new_client_connection(&address, &Hello::new(NodeId::CoffeeMaker(1)))
+                .ok()?;
+        task::block_on(send_message(&mut connection, &request)).ok()?;
+        task::block_on(recv_message(&mut connection)).ok()
+    }
+
+    /// Suma puntos a la cuenta conectandose como cafetera al servidor indicado. Reintenta mientras el servidor
+    /// no este escuchando
+    fn add_points(&mut self, server_id: usize, points: usize) {
+        self.sequence += 1;
+        let request = CoffeeMakerRequest {
+            message_type: MessageType::AddPoints,
+            account_id: ACCOUNT_ID,
+            points,
+            request_id: RequestId {
+                dispenser_id: 1,
+                sequence: self.sequence,
+            },
+        };
+        let added = wait_until(|| {
+            self.request(server_id, request)
+                .is_some_and(|response| matches!(response.status, ResponseStatus::Ok))
+        });
+        assert!(added, "Server {} did not accept the points", server_id);
+    }
+
+    /// Devuelve el saldo de la cuenta segun el servidor indicado
+    fn balance(&self, server_id: usize) -> Option<usize> {
+        let request = CoffeeMakerRequest {
+            message_type: MessageType::QueryBalance,
+            account_id: ACCOUNT_ID,
+            points: 0,
+            request_id: RequestId::default(),
+        };
+        match self.request(server_id, request)?.status {
+            ResponseStatus::Balance(balance) => Some(balance.points),
+            _ => None,
+        }
+    }
+
+    /// Espera a que todos los servidores indicados tengan el saldo esperado en la cuenta
+    fn converges(&self, servers: &[usize], expected: usize) -> bool {
+        wait_until(|| servers.iter().all(|id| self.balance(*id) == Some(expected)))
+    }
+}
+
+impl Drop for ChaosRing {
+    fn drop(&mut self) {
+        for process in self.servers.iter_mut().chain(Some(&mut self.proxy)) {
+            let _ = process.kill();
+            let _ = process.wait();
+        }
+        let _ = fs::remove_file(&self.topology);
+    }
+}
+
+fn wait_until<F: FnMut() -> bool>(mut condition: F) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < CONVERGENCE_TIMEOUT {
+        if condition() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+    false
+}
+
+/// `perdida-de-token`: el servidor 2 queda aislado al recibir el token. El 3 detecta que se cerro la conexion con
+/// su anterior, el aviso llega hasta el 1, que se conecta con el 3 y se elige un nuevo servidor que genera el token
+#[test]
+fn should_regenerate_the_token_lost_by_an_isolated_server() {
+    let mut ring = ChaosRing::start("perdida_de_token", 21000);
+    ring.add_points(0, 10);
+    assert!(ring.converges(&[0, 1, 2, 3], 10));
+
+    ring.chaos("on 1>2 Token cut 2");
+    thread::sleep(Duration::from_secs(2));
+    ring.add_points(0, 5);
+    assert!(ring.converges(&[0, 1, 3], 15));
+    assert_eq!(Some(10), ring.balance(2));
+}
+
+/// `nueva-conexion-servidor-caido`: el servidor 2 queda aislado mientras se suman puntos en el 1. Al volver se
+/// conecta con el 3, y el 1 le agrega al mensaje de nueva conexion las actualizaciones que se perdio
+#[test]
+fn should_send_the_missed_updates_to_a_server_that_reconnects() {
+    let mut ring = ChaosRing::start("nueva_conexion_servidor_caido", 22000);
+    ring.add_points(0, 10);
+    assert!(ring.converges(&[0, 1, 2, 3], 10));
+
+    ring.chaos("cut 2");
+    ring.add_points(1, 5);
+    assert!(ring.converges(&[0, 1, 3], 15));
+    assert_eq!(Some(10), ring.balance(2));
+
+    ring.chaos("heal 2");
+    assert!(ring.converges(&[0, 1, 2, 3], 15));
+}